@@ -0,0 +1,927 @@
+//! `[tls.acme]`: the TLS listener's certificate, obtained from an ACME CA
+//! (RFC 8555) by answering HTTP-01 challenges, kept under `certs_dir` and
+//! renewed `renew_before` days before it expires. A certificate stored by
+//! an earlier run is used right away, so restarts need no new order.
+
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use ring::digest::{SHA256, digest};
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use url::Url;
+use x509_parser::extensions::GeneralName;
+
+use crate::common::config::AcmeConfig;
+use crate::net::tls;
+use crate::proxy::dialer::DirectDialer;
+use crate::proxy::http::client::HttpClient;
+
+/// How long one request to the CA may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest challenge request head read
+const MAX_CHALLENGE_REQUEST: usize = 8192;
+
+/// Pause between looks at a pending authorization or order, and how many
+/// looks are taken before giving up
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 60;
+
+/// Longest sleep between expiry checks, and the wait after a failed order
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+const ACCOUNT_KEY_FILE: &str = "account.key";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+#[derive(Error, Debug)]
+pub enum AcmeError {
+    #[error("Invalid URL '{0}': {1}")]
+    InvalidUrl(String, String),
+    #[error("Request to {0} failed: {1}")]
+    Request(String, String),
+    #[error("{0} answered {1}: {2}")]
+    Server(String, u16, String),
+    #[error("Unexpected response from {0}: {1}")]
+    InvalidResponse(String, String),
+    #[error("Order failed: {0}")]
+    Order(String),
+    #[error("Key error: {0}")]
+    Key(String),
+    #[error("TLS setup failed: {0}")]
+    TlsError(#[from] tls::TlsError),
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+/// The certificate presented by the TLS listener, replaced on renewal
+/// without touching established connections.
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// RFC 7807 problem document of an ACME error (RFC 8555 §6.7).
+#[derive(Deserialize, Debug, Default)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+struct Response {
+    status: u16,
+    location: Option<String>,
+    nonce: Option<String>,
+    body: Vec<u8>,
+}
+
+/// The account key, and the state of one run of requests signed with it.
+struct Account {
+    key: EcdsaKeyPair,
+    directory: Directory,
+    /// Account URL, sent as `kid` once the account exists
+    kid: Option<String>,
+    /// Nonce handed out with the last response
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// Public key coordinates of the P-256 account key.
+    fn coordinates(&self) -> (String, String) {
+        // Uncompressed point: 0x04, then x and y
+        let point = self.key.public_key().as_ref();
+        (
+            URL_SAFE_NO_PAD.encode(&point[1..33]),
+            URL_SAFE_NO_PAD.encode(&point[33..65]),
+        )
+    }
+
+    /// RFC 7638 thumbprint of the account key, which every key
+    /// authorization ends with.
+    fn thumbprint(&self) -> String {
+        let (x, y) = self.coordinates();
+        // Members in lexicographic order, without whitespace
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+        URL_SAFE_NO_PAD.encode(digest(&SHA256, jwk.as_bytes()))
+    }
+
+    /// A flattened JWS of `payload` for `url`; `None` gives the empty
+    /// payload of a POST-as-GET.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<Vec<u8>, AcmeError> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => {
+                let (x, y) = self.coordinates();
+                protected["jwk"] = json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y });
+            }
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload
+            .map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{}.{}", protected, payload).as_bytes(),
+            )
+            .map_err(|_| AcmeError::Key("signing failed".to_string()))?;
+        let jws = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature),
+        });
+        Ok(jws.to_string().into_bytes())
+    }
+}
+
+/// Obtains and renews the `[tls]` certificate.
+pub struct Acme {
+    config: AcmeConfig,
+    dir: PathBuf,
+    client: HttpClient,
+    dialer: DirectDialer,
+    resolver: Arc<CertResolver>,
+    /// When the certificate in use expires
+    expires: Mutex<Option<DateTime<Utc>>>,
+    /// Key authorizations by token, for the CA's HTTP-01 requests
+    challenges: Mutex<HashMap<String, String>>,
+}
+
+impl Acme {
+    /// Sets up the client, presenting the certificate stored by an earlier
+    /// run when it covers `domains`.
+    pub fn new(config: &AcmeConfig) -> Result<Self, AcmeError> {
+        let mut client = HttpClient::new(REQUEST_TIMEOUT);
+        if config.directory_url.starts_with("https://") {
+            client = client.with_tls(config.ca_path.as_deref())?;
+        }
+        let acme = Acme {
+            config: config.clone(),
+            dir: PathBuf::from(&config.certs_dir),
+            client,
+            dialer: DirectDialer::new(REQUEST_TIMEOUT),
+            resolver: Arc::new(CertResolver::default()),
+            expires: Mutex::new(None),
+            challenges: Mutex::new(HashMap::new()),
+        };
+        std::fs::create_dir_all(&acme.dir)?;
+        match acme.load() {
+            Ok(Some(expires)) => info!(
+                "Using the stored ACME certificate for {}, valid until {}",
+                acme.config.domains.join(", "),
+                expires
+            ),
+            Ok(None) => {}
+            Err(e) => warn!("Ignoring the stored ACME certificate: {}", e),
+        }
+        Ok(acme)
+    }
+
+    pub fn resolver(&self) -> Arc<CertResolver> {
+        self.resolver.clone()
+    }
+
+    /// Answers HTTP-01 challenges on `listener` and keeps the certificate
+    /// renewed.
+    pub async fn run(self: Arc<Self>, listener: TcpListener) {
+        tokio::join!(self.clone().serve_challenges(listener), self.renew());
+    }
+
+    /// Orders a certificate whenever the one in use is missing or due for
+    /// renewal.
+    async fn renew(&self) {
+        let renew_before = TimeDelta::days(self.config.renew_before as i64);
+        loop {
+            let expires = *self.expires.lock().unwrap();
+            let wait = match expires {
+                Some(expires) if expires - renew_before > Utc::now() => {
+                    let due = (expires - renew_before - Utc::now())
+                        .to_std()
+                        .unwrap_or_default();
+                    due.min(CHECK_INTERVAL)
+                }
+                _ => match self.order().await {
+                    Ok(expires) => {
+                        info!(
+                            "Obtained an ACME certificate for {}, valid until {}",
+                            self.config.domains.join(", "),
+                            expires
+                        );
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Failed to obtain an ACME certificate: {}", e);
+                        RETRY_INTERVAL
+                    }
+                },
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Installs `cert.pem` and `key.pem` from `certs_dir`, returning when
+    /// they expire; `None` when they are missing or leave out a domain.
+    fn load(&self) -> Result<Option<DateTime<Utc>>, AcmeError> {
+        let cert_path = self.dir.join(CERT_FILE);
+        let key_path = self.dir.join(KEY_FILE);
+        if !cert_path.exists() || !key_path.exists() {
+            return Ok(None);
+        }
+        let invalid = |e: String| AcmeError::Key(format!("{}: {}", cert_path.display(), e));
+        let chain = CertificateDer::pem_file_iter(&cert_path)
+            .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid(e.to_string()))?;
+        let key = PrivateKeyDer::from_pem_file(&key_path).map_err(|e| invalid(e.to_string()))?;
+        let Some(leaf) = chain.first() else {
+            return Err(invalid("no certificates found".to_string()));
+        };
+        let (_, parsed) =
+            x509_parser::parse_x509_certificate(leaf).map_err(|e| invalid(e.to_string()))?;
+        let names: Vec<String> = match parsed.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        if !self
+            .config
+            .domains
+            .iter()
+            .all(|domain| names.contains(&domain.to_ascii_lowercase()))
+        {
+            info!("The stored ACME certificate does not cover every domain in tls.acme.domains");
+            return Ok(None);
+        }
+        let expires = DateTime::from_timestamp(parsed.validity().not_after.timestamp(), 0)
+            .ok_or_else(|| invalid("invalid expiry".to_string()))?;
+        let certified = CertifiedKey::from_der(chain, key, &default_provider())
+            .map_err(|e| invalid(e.to_string()))?;
+        *self.resolver.current.write().unwrap() = Some(Arc::new(certified));
+        *self.expires.lock().unwrap() = Some(expires);
+        Ok(Some(expires))
+    }
+
+    /// Runs an order to completion and installs the certificate it yields.
+    async fn order(&self) -> Result<DateTime<Utc>, AcmeError> {
+        let mut account = self.account().await?;
+        let identifiers: Vec<Value> = self
+            .config
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let new_order = account.directory.new_order.clone();
+        let response = self
+            .post(
+                &mut account,
+                &new_order,
+                Some(&json!({ "identifiers": identifiers })),
+            )
+            .await?;
+        let order_url = response.location.clone().ok_or_else(|| {
+            AcmeError::InvalidResponse(new_order.clone(), "no order Location".to_string())
+        })?;
+        let order: Order = parse(&new_order, &response.body)?;
+        for url in &order.authorizations {
+            self.authorize(&mut account, url).await?;
+        }
+
+        let key = KeyPair::generate().map_err(|e| AcmeError::Key(e.to_string()))?;
+        let mut params = CertificateParams::new(self.config.domains.clone())
+            .map_err(|e| AcmeError::Key(e.to_string()))?;
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, &self.config.domains[0]);
+        let csr = params
+            .serialize_request(&key)
+            .map_err(|e| AcmeError::Key(e.to_string()))?;
+        let csr = URL_SAFE_NO_PAD.encode(csr.der());
+        self.post(&mut account, &order.finalize, Some(&json!({ "csr": csr })))
+            .await?;
+
+        let order: Order = self
+            .poll(&mut account, &order_url, |order: &Order| &order.status)
+            .await?;
+        let certificate = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(certificate)) => certificate,
+            (status, _) => {
+                let detail = order.error.map(|error| error.detail).unwrap_or_default();
+                return Err(AcmeError::Order(format!("order is {} {}", status, detail)));
+            }
+        };
+        let chain = self.post(&mut account, &certificate, None).await?.body;
+
+        write_private(&self.dir.join(KEY_FILE), key.serialize_pem().as_bytes())?;
+        std::fs::write(self.dir.join(CERT_FILE), &chain)?;
+        self.load()?.ok_or_else(|| {
+            AcmeError::InvalidResponse(
+                certificate,
+                "certificate does not cover the domains".to_string(),
+            )
+        })
+    }
+
+    /// Proves control of the domain behind the authorization at `url`
+    /// with its HTTP-01 challenge.
+    async fn authorize(&self, account: &mut Account, url: &str) -> Result<(), AcmeError> {
+        let authorization: Authorization = parse(url, &self.post(account, url, None).await?.body)?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let Some(challenge) = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == "http-01")
+        else {
+            return Err(AcmeError::Order(format!(
+                "{} offers no http-01 challenge",
+                url
+            )));
+        };
+        let key_authorization = format!("{}.{}", challenge.token, account.thumbprint());
+        self.challenges
+            .lock()
+            .unwrap()
+            .insert(challenge.token.clone(), key_authorization);
+        let result = async {
+            self.post(account, &challenge.url, Some(&json!({}))).await?;
+            self.poll(account, url, |authorization: &Authorization| {
+                &authorization.status
+            })
+            .await
+        }
+        .await;
+        self.challenges.lock().unwrap().remove(&challenge.token);
+
+        let authorization = result?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let detail = authorization
+            .challenges
+            .into_iter()
+            .find_map(|challenge| challenge.error)
+            .map(|error| error.detail)
+            .unwrap_or_default();
+        Err(AcmeError::Order(format!(
+            "authorization is {} {}",
+            authorization.status, detail
+        )))
+    }
+
+    /// Fetches the resource at `url` until its status is no longer
+    /// `pending` or `processing`.
+    async fn poll<T: DeserializeOwned>(
+        &self,
+        account: &mut Account,
+        url: &str,
+        status: fn(&T) -> &String,
+    ) -> Result<T, AcmeError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: T = parse(url, &self.post(account, url, None).await?.body)?;
+            if !matches!(status(&resource).as_str(), "pending" | "processing") {
+                return Ok(resource);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(AcmeError::Order(format!("{} stayed pending", url)))
+    }
+
+    /// The account key from `certs_dir`, generated on first use, and the
+    /// account it opens at the CA.
+    async fn account(&self) -> Result<Account, AcmeError> {
+        let path = self.dir.join(ACCOUNT_KEY_FILE);
+        let pem = match std::fs::read_to_string(&path) {
+            Ok(pem) => pem,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let key = KeyPair::generate().map_err(|e| AcmeError::Key(e.to_string()))?;
+                write_private(&path, key.serialize_pem().as_bytes())?;
+                key.serialize_pem()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let key = KeyPair::from_pem(&pem)
+            .map_err(|e| AcmeError::Key(format!("{}: {}", path.display(), e)))?;
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &key.serialize_der(),
+            &SystemRandom::new(),
+        )
+        .map_err(|e| AcmeError::Key(format!("{}: {}", path.display(), e)))?;
+
+        let url = &self.config.directory_url;
+        let directory = parse(url, &self.send("GET", url, None).await?.body)?;
+        let mut account = Account {
+            key,
+            directory,
+            kid: None,
+            nonce: None,
+        };
+        let new_account = account.directory.new_account.clone();
+        let payload = json!({
+            "termsOfServiceAgreed": true,
+            "contact": self.config.contact,
+        });
+        let response = self
+            .post(&mut account, &new_account, Some(&payload))
+            .await?;
+        account.kid = Some(response.location.ok_or_else(|| {
+            AcmeError::InvalidResponse(new_account, "no account Location".to_string())
+        })?);
+        Ok(account)
+    }
+
+    /// POSTs `payload` signed by `account`, taking a fresh nonce once if
+    /// the CA turns the one in hand down.
+    async fn post(
+        &self,
+        account: &mut Account,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<Response, AcmeError> {
+        let mut retried = false;
+        loop {
+            let nonce = match account.nonce.take() {
+                Some(nonce) => nonce,
+                None => {
+                    let new_nonce = &account.directory.new_nonce;
+                    self.send("HEAD", new_nonce, None)
+                        .await?
+                        .nonce
+                        .ok_or_else(|| {
+                            AcmeError::InvalidResponse(
+                                new_nonce.clone(),
+                                "no Replay-Nonce".to_string(),
+                            )
+                        })?
+                }
+            };
+            let body = account.sign(url, &nonce, payload)?;
+            let response = self.send("POST", url, Some(&body)).await?;
+            account.nonce = response.nonce.clone();
+            if response.status < 400 {
+                return Ok(response);
+            }
+            let problem: Problem = serde_json::from_slice(&response.body).unwrap_or_default();
+            if problem.kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(AcmeError::Server(
+                url.to_string(),
+                response.status,
+                problem.detail,
+            ));
+        }
+    }
+
+    async fn send(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<&[u8]>,
+    ) -> Result<Response, AcmeError> {
+        let parsed =
+            Url::parse(url).map_err(|e| AcmeError::InvalidUrl(url.to_string(), e.to_string()))?;
+        let headers: &[(&str, &str)] = match body {
+            Some(_) => &[("Content-Type", "application/jose+json")],
+            None => &[],
+        };
+        let response = self
+            .client
+            .send(&self.dialer, method, &parsed, headers, body)
+            .await
+            .map_err(|e| AcmeError::Request(url.to_string(), e.to_string()))?;
+        Ok(Response {
+            status: response.status,
+            location: response.header("location").map(str::to_string),
+            nonce: response.header("replay-nonce").map(str::to_string),
+            body: response.body,
+        })
+    }
+
+    /// Answers `GET /.well-known/acme-challenge/<token>` with the key
+    /// authorization of a challenge in progress.
+    async fn serve_challenges(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept an ACME challenge connection: {}", e);
+                    continue;
+                }
+            };
+            let acme = self.clone();
+            tokio::spawn(async move {
+                let _ = timeout(REQUEST_TIMEOUT, acme.answer_challenge(stream)).await;
+            });
+        }
+    }
+
+    async fn answer_challenge(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 || request.len() + n > MAX_CHALLENGE_REQUEST {
+                return Ok(());
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut head = httparse::Request::new(&mut headers);
+        let key_authorization = match head.parse(&request) {
+            Ok(httparse::Status::Complete(_)) if head.method == Some("GET") => head
+                .path
+                .and_then(|path| path.strip_prefix(CHALLENGE_PATH))
+                .and_then(|token| self.challenges.lock().unwrap().get(token).cloned()),
+            _ => None,
+        };
+        let response = match key_authorization {
+            Some(body) => format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+            None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        };
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+fn parse<T: DeserializeOwned>(url: &str, body: &[u8]) -> Result<T, AcmeError> {
+    serde_json::from_slice(body)
+        .map_err(|e| AcmeError::InvalidResponse(url.to_string(), e.to_string()))
+}
+
+/// Writes a private key readable by its owner only.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateSigningRequestParams, IsCa, Issuer};
+    use ring::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+    use std::net::SocketAddr;
+    use tokio_rustls::rustls::pki_types::CertificateSigningRequestDer;
+
+    /// A CA answering just enough of RFC 8555 for one order, which checks
+    /// the account's signature and fetches the HTTP-01 key authorization.
+    struct MockCa {
+        base: String,
+        http01: SocketAddr,
+        issuer: Issuer<'static, KeyPair>,
+        issuer_pem: String,
+        /// Thumbprint of the account key, once the account exists
+        thumbprint: Mutex<Option<String>>,
+        validated: Mutex<bool>,
+        certificate: Mutex<Option<String>>,
+        /// Whether the first nonce has been turned down
+        nonce_refused: Mutex<bool>,
+    }
+
+    impl MockCa {
+        async fn respond(&self, method: &str, path: &str, body: &[u8]) -> (u16, String, Value) {
+            let base = &self.base;
+            if method == "GET" && path == "/dir" {
+                let directory = json!({
+                    "newNonce": format!("{}/nonce", base),
+                    "newAccount": format!("{}/account", base),
+                    "newOrder": format!("{}/order", base),
+                });
+                return (200, String::new(), directory);
+            }
+            if method == "HEAD" {
+                return (200, String::new(), Value::Null);
+            }
+
+            let jws: Value = serde_json::from_slice(body).unwrap();
+            let field = |name: &str| URL_SAFE_NO_PAD.decode(jws[name].as_str().unwrap()).unwrap();
+            let protected: Value = serde_json::from_slice(&field("protected")).unwrap();
+            let payload: Value = serde_json::from_slice(&field("payload")).unwrap_or_default();
+            assert_eq!(protected["url"], format!("{}{}", base, path));
+            if !std::mem::replace(&mut *self.nonce_refused.lock().unwrap(), true) {
+                let problem = json!({ "type": "urn:ietf:params:acme:error:badNonce" });
+                return (400, String::new(), problem);
+            }
+
+            match path {
+                "/account" => {
+                    let jwk = &protected["jwk"];
+                    let coordinate =
+                        |name: &str| URL_SAFE_NO_PAD.decode(jwk[name].as_str().unwrap()).unwrap();
+                    let point = [&[4u8][..], &coordinate("x"), &coordinate("y")].concat();
+                    let signed = format!(
+                        "{}.{}",
+                        jws["protected"].as_str().unwrap(),
+                        jws["payload"].as_str().unwrap()
+                    );
+                    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &point)
+                        .verify(signed.as_bytes(), &field("signature"))
+                        .unwrap();
+                    let canonical = format!(
+                        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+                        jwk["x"].as_str().unwrap(),
+                        jwk["y"].as_str().unwrap()
+                    );
+                    *self.thumbprint.lock().unwrap() =
+                        Some(URL_SAFE_NO_PAD.encode(digest(&SHA256, canonical.as_bytes())));
+                    assert_eq!(payload["termsOfServiceAgreed"], true);
+                    let location = format!("Location: {}/acct/1\r\n", base);
+                    (201, location, json!({ "status": "valid" }))
+                }
+                "/order" | "/order/1" => {
+                    assert!(protected["kid"].is_string());
+                    let certificate = self.certificate.lock().unwrap().is_some();
+                    let order = json!({
+                        "status": if certificate { "valid" } else { "pending" },
+                        "authorizations": [format!("{}/authz/1", base)],
+                        "finalize": format!("{}/finalize/1", base),
+                        "certificate": format!("{}/cert/1", base),
+                    });
+                    let location = format!("Location: {}/order/1\r\n", base);
+                    (201, location, order)
+                }
+                "/authz/1" => {
+                    let validated = *self.validated.lock().unwrap();
+                    let authorization = json!({
+                        "status": if validated { "valid" } else { "pending" },
+                        "challenges": [
+                            { "type": "dns-01", "url": format!("{}/chall/2", base), "token": "other" },
+                            { "type": "http-01", "url": format!("{}/chall/1", base), "token": "tok" },
+                        ],
+                    });
+                    (200, String::new(), authorization)
+                }
+                "/chall/1" => {
+                    let mut stream = TcpStream::connect(self.http01).await.unwrap();
+                    stream
+                        .write_all(b"GET /.well-known/acme-challenge/tok HTTP/1.1\r\nHost: proxy.test\r\n\r\n")
+                        .await
+                        .unwrap();
+                    let mut response = String::new();
+                    stream.read_to_string(&mut response).await.unwrap();
+                    let expected =
+                        format!("tok.{}", self.thumbprint.lock().unwrap().as_ref().unwrap());
+                    assert!(response.starts_with("HTTP/1.1 200 OK"));
+                    assert!(response.ends_with(&expected));
+                    *self.validated.lock().unwrap() = true;
+                    (200, String::new(), json!({}))
+                }
+                "/finalize/1" => {
+                    let csr = URL_SAFE_NO_PAD
+                        .decode(payload["csr"].as_str().unwrap())
+                        .unwrap();
+                    let csr = CertificateSigningRequestParams::from_der(
+                        &CertificateSigningRequestDer::from(csr),
+                    )
+                    .unwrap();
+                    let leaf = csr.signed_by(&self.issuer).unwrap();
+                    *self.certificate.lock().unwrap() =
+                        Some(format!("{}{}", leaf.pem(), self.issuer_pem));
+                    (200, String::new(), json!({ "status": "processing" }))
+                }
+                "/cert/1" => {
+                    let chain = self.certificate.lock().unwrap().clone().unwrap();
+                    (200, String::new(), Value::String(chain))
+                }
+                _ => (404, String::new(), Value::Null),
+            }
+        }
+    }
+
+    async fn serve_ca(listener: TcpListener, ca: Arc<MockCa>) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let ca = ca.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                let split = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    if let Some(at) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break at + 4;
+                    }
+                };
+                let mut headers = [httparse::EMPTY_HEADER; 16];
+                let mut head = httparse::Request::new(&mut headers);
+                head.parse(&request).unwrap();
+                let method = head.method.unwrap().to_string();
+                let path = head.path.unwrap().to_string();
+                let length: usize = head
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+                    .map(|header| std::str::from_utf8(header.value).unwrap().parse().unwrap())
+                    .unwrap_or(0);
+                while request.len() < split + length {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+
+                let (status, headers, body) = ca.respond(&method, &path, &request[split..]).await;
+                let body = match body {
+                    Value::Null => String::new(),
+                    Value::String(pem) => pem,
+                    body => body.to_string(),
+                };
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\nReplay-Nonce: nonce\r\n{}Content-Length: {}\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acme_order() {
+        let ca_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", ca_listener.local_addr().unwrap());
+        let http01 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Mock ACME CA");
+        let issuer_key = KeyPair::generate().unwrap();
+        let issuer_pem = params.self_signed(&issuer_key).unwrap().pem();
+        let ca = Arc::new(MockCa {
+            base: base.clone(),
+            http01: http01.local_addr().unwrap(),
+            issuer: Issuer::new(params, issuer_key),
+            issuer_pem,
+            thumbprint: Mutex::new(None),
+            validated: Mutex::new(false),
+            certificate: Mutex::new(None),
+            nonce_refused: Mutex::new(false),
+        });
+        tokio::spawn(serve_ca(ca_listener, ca.clone()));
+
+        let dir = std::env::temp_dir().join(format!("rust-proxy-acme-{}", std::process::id()));
+        let config = AcmeConfig {
+            domains: vec!["proxy.test".to_string(), "www.proxy.test".to_string()],
+            contact: vec!["mailto:admin@proxy.test".to_string()],
+            directory_url: format!("{}/dir", base),
+            ca_path: None,
+            certs_dir: dir.to_string_lossy().into_owned(),
+            http01_listen: "127.0.0.1:0".to_string(),
+            renew_before: 30,
+        };
+        let acme = Arc::new(Acme::new(&config).unwrap());
+        assert!(acme.expires.lock().unwrap().is_none());
+        tokio::spawn(acme.clone().serve_challenges(http01));
+
+        let expires = acme.order().await.unwrap();
+        assert!(*ca.validated.lock().unwrap());
+        assert!(expires > Utc::now());
+        assert!(acme.resolver.current.read().unwrap().is_some());
+        assert!(acme.challenges.lock().unwrap().is_empty());
+        for file in [ACCOUNT_KEY_FILE, CERT_FILE, KEY_FILE] {
+            assert!(dir.join(file).exists());
+        }
+
+        // A restart picks up the stored certificate
+        let restarted = Acme::new(&config).unwrap();
+        assert_eq!(*restarted.expires.lock().unwrap(), Some(expires));
+        assert!(restarted.resolver.current.read().unwrap().is_some());
+
+        // Unless it leaves out a domain
+        let mut more = config.clone();
+        more.domains.push("new.proxy.test".to_string());
+        assert!(Acme::new(&more).unwrap().expires.lock().unwrap().is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_challenge_responder() {
+        let dir =
+            std::env::temp_dir().join(format!("rust-proxy-acme-http01-{}", std::process::id()));
+        let acme = Arc::new(
+            Acme::new(&AcmeConfig {
+                domains: vec!["proxy.test".to_string()],
+                contact: Vec::new(),
+                directory_url: "http://127.0.0.1:1/dir".to_string(),
+                ca_path: None,
+                certs_dir: dir.to_string_lossy().into_owned(),
+                http01_listen: "127.0.0.1:0".to_string(),
+                renew_before: 30,
+            })
+            .unwrap(),
+        );
+        acme.challenges
+            .lock()
+            .unwrap()
+            .insert("abc".to_string(), "abc.thumb".to_string());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(acme.serve_challenges(listener));
+
+        for (path, expected) in [
+            ("/.well-known/acme-challenge/abc", "HTTP/1.1 200 OK"),
+            ("/.well-known/acme-challenge/xyz", "HTTP/1.1 404 Not Found"),
+            ("/", "HTTP/1.1 404 Not Found"),
+        ] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: proxy.test\r\n\r\n", path);
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(expected), "{}", response);
+            if expected.ends_with("OK") {
+                assert!(response.ends_with("\r\n\r\nabc.thumb"));
+            }
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
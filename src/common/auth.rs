@@ -1,6 +1,24 @@
+use async_trait::async_trait;
 use bcrypt::{DEFAULT_COST, hash, verify};
-use std::collections::HashMap;
+use chrono::Utc;
+use config::{File, FileFormat};
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::common::config::{
+    Expiry, GroupConfig, ListenerProtocol, PortRange, UserConfig, UsersFileConfig,
+};
+use crate::common::signal::hangup_signal;
+use crate::net::rate_limit::Limit;
 
 #[derive(Error, Debug)]
 pub enum AuthError {
@@ -8,46 +26,430 @@ pub enum AuthError {
     HashingError(#[from] bcrypt::BcryptError),
     #[error("Authentication failed")]
     AuthenticationFailed,
+    #[error("Failed to read users file '{0}': {1}")]
+    UsersFile(String, String),
+    #[error("Authentication backend error: {0}")]
+    Backend(String),
+    #[error("User '{0}' has used up the transfer quota")]
+    QuotaExceeded(String),
+    #[error("User '{0}' is disabled or expired")]
+    UserInactive(String),
+}
+
+/// What an authentication attempt comes with besides the credentials.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthContext {
+    /// Address the client connects from
+    pub client: SocketAddr,
+    /// Protocol the client authenticates over
+    pub protocol: ListenerProtocol,
+}
+
+#[derive(Debug)]
+pub enum AuthDecision {
+    Allow,
+    Deny,
+    /// The provider could not decide, e.g. its backend failed
+    Error(AuthError),
+}
+
+/// A source of the users clients authenticate as. Handlers only go through
+/// this trait; `AuthManager`, backed by `[users]` and the users file, is the
+/// built-in provider.
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Whether clients must authenticate at all; when not, every client is
+    /// let through.
+    fn is_required(&self) -> bool;
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        context: &AuthContext,
+    ) -> AuthDecision;
+
+    /// The user a TLS client certificate names, which counts as
+    /// authenticated without a password. None by default.
+    fn certificate_user(&self, _identity: Option<String>) -> Option<String> {
+        None
+    }
+
+    /// Whether `username` may connect to destination `port`. Any port by
+    /// default.
+    fn allows_port(&self, _username: &str, _port: u16) -> bool {
+        true
+    }
+
+    /// Bytes `username` may transfer per quota period. None by default.
+    fn quota(&self, _username: &str) -> Option<u64> {
+        None
+    }
+
+    /// Whether `username`, already authenticated, may start another
+    /// session. Checked by handlers that identify users without
+    /// `authenticate`; always by default.
+    fn admits(&self, _username: &str) -> Result<(), AuthError> {
+        Ok(())
+    }
+
+    /// Counts `bytes` a finished session of `username` relayed outside its
+    /// connection, as UDP datagrams are; bytes over the connection are
+    /// counted as they pass by its `rate_limits`. Ignored by default.
+    fn record_usage(&self, _username: &str, _bytes: u64) {}
+
+    /// Limits every session of `username` shares. None by default.
+    fn rate_limits(&self, _username: &str) -> Vec<Arc<dyn Limit>> {
+        Vec::new()
+    }
+}
+
+/// Bcrypt comparisons running at once, one per core. A burst of logins
+/// queues here instead of taking over the blocking pool that DNS lookups
+/// and file reads share.
+static VERIFY_PERMITS: LazyLock<Semaphore> = LazyLock::new(|| {
+    Semaphore::new(thread::available_parallelism().map_or(4, |cores| cores.get()))
+});
+
+/// Compares `password` with a bcrypt hash on the blocking thread pool, so
+/// the executor thread of the caller keeps serving other connections.
+pub async fn verify_password(password: &str, password_hash: &str) -> Result<bool, AuthError> {
+    let _permit = VERIFY_PERMITS
+        .acquire()
+        .await
+        .map_err(|_| AuthError::AuthenticationFailed)?;
+    let (password, password_hash) = (password.to_string(), password_hash.to_string());
+    tokio::task::spawn_blocking(move || verify(&password, &password_hash))
+        .await
+        .map_err(|_| AuthError::AuthenticationFailed)?
+        .map_err(AuthError::from)
+}
+
+#[derive(Clone)]
+struct User {
+    password_hash: String,
+    allowed_ports: Vec<PortRange>,
+    quota: Option<u64>,
+    enabled: bool,
+    expires_at: Option<Expiry>,
+}
+
+impl User {
+    /// Whether the user may log in now: enabled and not expired.
+    fn is_active(&self) -> bool {
+        self.enabled && self.expires_at.is_none_or(|expiry| expiry.0 > Utc::now())
+    }
+}
+
+/// A `users.toml` file: the `[users]` table of the configuration on its own
+#[derive(Deserialize)]
+struct UsersToml {
+    #[serde(default)]
+    users: HashMap<String, UserConfig>,
+}
+
+/// The users file and what was last loaded from it
+struct UsersFile {
+    path: PathBuf,
+    reload_interval: Duration,
+    /// Modification time of the file last loaded
+    modified: Mutex<Option<SystemTime>>,
 }
 
 pub struct AuthManager {
-    users: HashMap<String, String>,
+    /// Users of the configuration, which take precedence over the file
+    configured: HashMap<String, User>,
+    /// The table in use, swapped whole when the users file is reloaded
+    users: RwLock<Arc<HashMap<String, User>>>,
+    file: Option<UsersFile>,
+    /// `[groups]` by name, in order
+    groups: BTreeMap<String, GroupConfig>,
 }
 
 impl AuthManager {
-    pub fn new(users: &HashMap<String, String>) -> Result<Self, AuthError> {
-        let mut hashed_users = HashMap::new();
-        for (username, password) in users {
-            let hashed_password = hash(password, DEFAULT_COST)?;
-            hashed_users.insert(username.clone(), hashed_password);
-        }
+    pub fn new(users: &HashMap<String, UserConfig>) -> Result<Self, AuthError> {
+        let configured = hash_users(users)?;
         Ok(AuthManager {
-            users: hashed_users,
+            users: RwLock::new(Arc::new(configured.clone())),
+            configured,
+            file: None,
+            groups: BTreeMap::new(),
         })
     }
 
-    pub fn has_users(&self) -> bool {
-        !self.users.is_empty()
+    /// Gives the members of `groups` the restrictions of their groups.
+    pub fn with_groups(mut self, groups: &HashMap<String, GroupConfig>) -> Self {
+        self.groups = groups
+            .iter()
+            .map(|(name, group)| (name.clone(), group.clone()))
+            .collect();
+        self
+    }
+
+    /// Names of the groups `username` is a member of, in order.
+    pub fn groups_of(&self, username: &str) -> Vec<&str> {
+        self.groups
+            .iter()
+            .filter(|(_, group)| group.members.iter().any(|member| member == username))
+            .map(|(name, _)| name.as_str())
+            .collect()
     }
 
-    /// Bcrypt comparison runs inside `spawn_blocking` to avoid stalling the Tokio runtime.
-    pub async fn authenticate(&self, username: &str, password: &str) -> Result<bool, AuthError> {
-        if self.users.is_empty() {
-            return Ok(true);
+    /// Adds the users of `config.path`, which must be readable.
+    pub fn with_users_file(mut self, config: &UsersFileConfig) -> Result<Self, AuthError> {
+        self.file = Some(UsersFile {
+            path: PathBuf::from(&config.path),
+            reload_interval: Duration::from_secs(config.reload_interval),
+            modified: Mutex::new(None),
+        });
+        self.reload()?;
+        Ok(self)
+    }
+
+    async fn verify(&self, username: &str, password: &str) -> Result<bool, AuthError> {
+        match self.users().get(username) {
+            Some(user) => verify_password(password, &user.password_hash).await,
+            None => Ok(false),
         }
+    }
 
-        match self.users.get(username) {
-            Some(hashed_password) => {
-                let hashed = hashed_password.clone();
-                let pwd = password.to_string();
-                let is_valid = tokio::task::spawn_blocking(move || verify(&pwd, &hashed))
-                    .await
-                    .map_err(|_| AuthError::AuthenticationFailed)??;
-                Ok(is_valid)
+    fn users(&self) -> Arc<HashMap<String, User>> {
+        // Lookups only hold the lock long enough to clone the Arc
+        self.users.read().unwrap().clone()
+    }
+
+    /// Reads the users file again, returning the number of users it lists.
+    /// On failure the table in use is kept.
+    pub fn reload(&self) -> Result<usize, AuthError> {
+        let Some(file) = &self.file else {
+            return Ok(0);
+        };
+        let failed = |e: String| AuthError::UsersFile(file.path.display().to_string(), e);
+        let modified = fs::metadata(&file.path)
+            .map_err(|e| failed(e.to_string()))?
+            .modified()
+            .ok();
+        let contents = fs::read_to_string(&file.path).map_err(|e| failed(e.to_string()))?;
+        let mut users = if file.path.extension().is_some_and(|ext| ext == "toml") {
+            let parsed: UsersToml = config::Config::builder()
+                .add_source(File::from_str(&contents, FileFormat::Toml))
+                .build()
+                .and_then(|parsed| parsed.try_deserialize())
+                .map_err(|e| failed(e.to_string()))?;
+            hash_users(&parsed.users)?
+        } else {
+            parse_htpasswd(&contents).map_err(failed)?
+        };
+        let len = users.len();
+        users.extend(
+            self.configured
+                .iter()
+                .map(|(name, user)| (name.clone(), user.clone())),
+        );
+        *self.users.write().unwrap() = Arc::new(users);
+        *file.modified.lock().unwrap() = modified;
+        Ok(len)
+    }
+
+    /// Reloads the users file on SIGHUP, and unless its `reload_interval`
+    /// is zero, every time the file is found changed after sleeping that
+    /// long. Returns at once without a users file, otherwise runs until
+    /// Ctrl-C / SIGINT is received. Passwords are hashed on the blocking
+    /// pool, as bcrypt would stall the runtime.
+    pub async fn watch(self: Arc<Self>) {
+        let Some(file) = &self.file else {
+            return;
+        };
+        let interval = file.reload_interval;
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+        let mut hangup = hangup_signal();
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {}
+                _ = tokio::time::sleep(interval), if !interval.is_zero() => {
+                    if !file.is_modified() {
+                        continue;
+                    }
+                }
+                _ = &mut shutdown => break,
             }
-            None => Ok(false),
+            let manager = self.clone();
+            match tokio::task::spawn_blocking(move || manager.reload()).await {
+                Ok(Ok(len)) => info!("Reloaded users file {}: {} users", file.path.display(), len),
+                Ok(Err(e)) => error!("{}", e),
+                Err(e) => error!("Reloading users file {} failed: {}", file.path.display(), e),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for AuthManager {
+    /// Stays true with a users file, even while it lists nobody.
+    fn is_required(&self) -> bool {
+        self.file.is_some() || !self.users().is_empty()
+    }
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        context: &AuthContext,
+    ) -> AuthDecision {
+        if !self.is_required() {
+            return AuthDecision::Allow;
+        }
+        if self
+            .users()
+            .get(username)
+            .is_some_and(|user| !user.is_active())
+        {
+            info!(
+                "Refused disabled or expired user '{}' from {} over {:?}",
+                username, context.client, context.protocol
+            );
+            return AuthDecision::Deny;
+        }
+        match self.verify(username, password).await {
+            Ok(true) => AuthDecision::Allow,
+            Ok(false) => {
+                info!(
+                    "Wrong credentials for '{}' from {} over {:?}",
+                    username, context.client, context.protocol
+                );
+                AuthDecision::Deny
+            }
+            Err(e) => AuthDecision::Error(e),
+        }
+    }
+
+    fn certificate_user(&self, identity: Option<String>) -> Option<String> {
+        let users = self.users();
+        identity.filter(|name| users.get(name).is_some_and(User::is_active))
+    }
+
+    /// A user's own port list takes precedence over those of their groups,
+    /// which add up. Users without any, and names unknown to the table
+    /// (authentication disabled), are unrestricted.
+    fn allows_port(&self, username: &str, port: u16) -> bool {
+        let users = self.users();
+        let own = users
+            .get(username)
+            .map(|user| &user.allowed_ports)
+            .filter(|ports| !ports.is_empty());
+        let ranges: Vec<&PortRange> = match own {
+            Some(ports) => ports.iter().collect(),
+            None => self
+                .groups_of(username)
+                .into_iter()
+                .flat_map(|name| &self.groups[name].allowed_ports)
+                .collect(),
+        };
+        ranges.is_empty() || ranges.iter().any(|range| range.contains(port))
+    }
+
+    /// A user's own quota, or else the largest of their groups'.
+    fn quota(&self, username: &str) -> Option<u64> {
+        self.users()
+            .get(username)
+            .and_then(|user| user.quota)
+            .or_else(|| {
+                self.groups_of(username)
+                    .into_iter()
+                    .filter_map(|name| self.groups[name].quota)
+                    .max()
+            })
+    }
+
+    /// Refuses users disabled or expired since they authenticated, or named
+    /// by a token. Names unknown to the table are admitted.
+    fn admits(&self, username: &str) -> Result<(), AuthError> {
+        if self
+            .users()
+            .get(username)
+            .is_some_and(|user| !user.is_active())
+        {
+            return Err(AuthError::UserInactive(username.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl UsersFile {
+    fn is_modified(&self) -> bool {
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified());
+        modified.is_ok_and(|modified| *self.modified.lock().unwrap() != Some(modified))
+    }
+}
+
+/// Whether `password` is a bcrypt hash, taken as is rather than hashed.
+pub fn is_password_hash(password: &str) -> bool {
+    password.len() == 60
+        && ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| password.starts_with(prefix))
+}
+
+/// A bcrypt hash of `password`, as accepted in place of it by `[users]`.
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    Ok(hash(password, DEFAULT_COST)?)
+}
+
+fn hash_users(users: &HashMap<String, UserConfig>) -> Result<HashMap<String, User>, AuthError> {
+    let mut hashed_users = HashMap::new();
+    for (username, user) in users {
+        let password_hash = if is_password_hash(&user.password) {
+            user.password.clone()
+        } else {
+            hash_password(&user.password)?
+        };
+        hashed_users.insert(
+            username.clone(),
+            User {
+                password_hash,
+                allowed_ports: user.allowed_ports.clone(),
+                quota: user.quota,
+                enabled: user.enabled,
+                expires_at: user.expires_at,
+            },
+        );
+    }
+    Ok(hashed_users)
+}
+
+/// Parses `name:hash` lines as written by `htpasswd -B`. Only bcrypt
+/// hashes are accepted; the other schemes htpasswd offers are weak.
+fn parse_htpasswd(contents: &str) -> Result<HashMap<String, User>, String> {
+    let mut users = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
+        let Some((name, password_hash)) = line.split_once(':') else {
+            return Err(format!("line {}: expected name:hash", number + 1));
+        };
+        if !is_password_hash(password_hash) {
+            return Err(format!(
+                "line {}: the hash of '{}' is not bcrypt",
+                number + 1,
+                name
+            ));
+        }
+        users.insert(
+            name.to_string(),
+            User {
+                password_hash: password_hash.to_string(),
+                allowed_ports: Vec::new(),
+                quota: None,
+                enabled: true,
+                expires_at: None,
+            },
+        );
     }
+    Ok(users)
 }
 
 #[cfg(test)]
@@ -55,32 +457,228 @@ mod tests {
     use super::*;
     use std::collections::HashMap;
 
+    /// Whether `username` gets in with `password`.
+    async fn allows(auth: &impl AuthProvider, username: &str, password: &str) -> bool {
+        let context = AuthContext {
+            client: "127.0.0.1:50000".parse().unwrap(),
+            protocol: ListenerProtocol::Socks5,
+        };
+        matches!(
+            auth.authenticate(username, password, &context).await,
+            AuthDecision::Allow
+        )
+    }
+
     #[tokio::test]
     async fn test_authenticate() {
         let mut users = HashMap::new();
-        users.insert("admin".to_string(), "password".to_string());
-        users.insert("user1".to_string(), "pass123".to_string());
+        users.insert("admin".to_string(), "password".to_string().into());
+        users.insert("user1".to_string(), "pass123".to_string().into());
 
         let auth_manager = AuthManager::new(&users).unwrap();
 
-        assert!(
-            auth_manager
-                .authenticate("admin", "password")
-                .await
-                .unwrap()
+        assert!(allows(&auth_manager, "admin", "password").await);
+        assert!(allows(&auth_manager, "user1", "pass123").await);
+        assert!(!allows(&auth_manager, "admin", "wrongpass").await);
+        assert!(!allows(&auth_manager, "nonexistent", "password").await);
+    }
+
+    #[test]
+    fn test_allows_port() {
+        let mut users = HashMap::new();
+        users.insert(
+            "web".to_string(),
+            UserConfig {
+                allowed_ports: vec![
+                    PortRange { start: 80, end: 80 },
+                    PortRange {
+                        start: 8000,
+                        end: 8999,
+                    },
+                ],
+                ..UserConfig::from("password".to_string())
+            },
+        );
+        users.insert("any".to_string(), "password".to_string().into());
+        let auth_manager = AuthManager::new(&users).unwrap();
+
+        assert!(auth_manager.allows_port("web", 80));
+        assert!(auth_manager.allows_port("web", 8443));
+        assert!(!auth_manager.allows_port("web", 443));
+        assert!(auth_manager.allows_port("any", 22));
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut users = HashMap::new();
+        users.insert(
+            "alice".to_string(),
+            UserConfig {
+                allowed_ports: vec![PortRange { start: 22, end: 22 }],
+                quota: Some(100),
+                ..UserConfig::from("secret".to_string())
+            },
         );
-        assert!(auth_manager.authenticate("user1", "pass123").await.unwrap());
-        assert!(
-            !auth_manager
-                .authenticate("admin", "wrongpass")
-                .await
-                .unwrap()
+        users.insert("bob".to_string(), "hunter2".to_string().into());
+        let group = |members: &[&str], ports: &[u16], quota| GroupConfig {
+            members: members.iter().map(|member| member.to_string()).collect(),
+            allowed_ports: ports
+                .iter()
+                .map(|&port| PortRange {
+                    start: port,
+                    end: port,
+                })
+                .collect(),
+            quota,
+        };
+        let mut groups = HashMap::new();
+        groups.insert(
+            "web".to_string(),
+            group(&["alice", "bob"], &[80, 443], Some(1000)),
+        );
+        groups.insert("mail".to_string(), group(&["bob"], &[993], Some(5000)));
+        let auth_manager = AuthManager::new(&users).unwrap().with_groups(&groups);
+
+        assert_eq!(auth_manager.groups_of("bob"), ["mail", "web"]);
+        assert!(auth_manager.groups_of("carol").is_empty());
+        // Their own restrictions take precedence over the groups'
+        assert!(auth_manager.allows_port("alice", 22));
+        assert!(!auth_manager.allows_port("alice", 80));
+        assert_eq!(auth_manager.quota("alice"), Some(100));
+        // Those of several groups add up
+        assert!(auth_manager.allows_port("bob", 443));
+        assert!(auth_manager.allows_port("bob", 993));
+        assert!(!auth_manager.allows_port("bob", 22));
+        assert_eq!(auth_manager.quota("bob"), Some(5000));
+        assert!(auth_manager.allows_port("carol", 22));
+    }
+
+    #[tokio::test]
+    async fn test_inactive_users() {
+        let expiry = |value: &str| Some(Expiry::try_from(value.to_string()).unwrap());
+        let mut users = HashMap::new();
+        users.insert(
+            "disabled".to_string(),
+            UserConfig {
+                enabled: false,
+                ..UserConfig::from("secret".to_string())
+            },
         );
-        assert!(
-            !auth_manager
-                .authenticate("nonexistent", "password")
-                .await
-                .unwrap()
+        users.insert(
+            "expired".to_string(),
+            UserConfig {
+                expires_at: expiry("2020-01-01"),
+                ..UserConfig::from("secret".to_string())
+            },
         );
+        users.insert(
+            "temporary".to_string(),
+            UserConfig {
+                expires_at: expiry("2999-12-31T18:00:00+08:00"),
+                ..UserConfig::from("secret".to_string())
+            },
+        );
+        let auth_manager = AuthManager::new(&users).unwrap();
+
+        assert!(!allows(&auth_manager, "disabled", "secret").await);
+        assert!(!allows(&auth_manager, "expired", "secret").await);
+        assert!(allows(&auth_manager, "temporary", "secret").await);
+        assert!(auth_manager.admits("temporary").is_ok());
+        assert!(matches!(
+            auth_manager.admits("expired"),
+            Err(AuthError::UserInactive(_))
+        ));
+        assert!(auth_manager.admits("token-user").is_ok());
+        assert_eq!(
+            auth_manager.certificate_user(Some("disabled".to_string())),
+            None
+        );
+        assert!(Expiry::try_from("next week".to_string()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_prehashed_password() {
+        let password_hash = hash("password", 4).unwrap();
+        assert!(is_password_hash(&password_hash));
+        assert!(!is_password_hash("$2b$not-a-hash"));
+
+        let mut users = HashMap::new();
+        users.insert("admin".to_string(), password_hash.clone().into());
+        let auth_manager = AuthManager::new(&users).unwrap();
+        assert!(allows(&auth_manager, "admin", "password").await);
+        assert!(!allows(&auth_manager, "admin", &password_hash).await);
+    }
+
+    #[tokio::test]
+    async fn test_verify_off_executor() {
+        // On this single-threaded runtime, a comparison run inline would
+        // finish before the other branch is ever polled
+        let password_hash = hash("password", 10).unwrap();
+        let verified = verify_password("password", &password_hash);
+        tokio::pin!(verified);
+        tokio::select! {
+            biased;
+            _ = &mut verified => panic!("bcrypt ran on the executor thread"),
+            _ = tokio::task::yield_now() => {}
+        }
+        assert!(verified.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_users_file() {
+        let dir = std::env::temp_dir().join(format!("rust-proxy-users-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("users.htpasswd");
+        let line =
+            |name: &str, password: &str| format!("{}:{}\n", name, hash(password, 4).unwrap());
+        fs::write(&path, format!("# comment\n{}", line("alice", "secret"))).unwrap();
+        let file_config = |path: &std::path::Path| UsersFileConfig {
+            path: path.to_string_lossy().into_owned(),
+            reload_interval: 0,
+        };
+        let mut users = HashMap::new();
+        users.insert("admin".to_string(), "password".to_string().into());
+        let auth_manager = AuthManager::new(&users)
+            .unwrap()
+            .with_users_file(&file_config(&path))
+            .unwrap();
+        assert!(allows(&auth_manager, "alice", "secret").await);
+        assert!(allows(&auth_manager, "admin", "password").await);
+
+        // Users come and go without a restart; the configured ones stay
+        fs::write(&path, line("bob", "hunter2")).unwrap();
+        assert_eq!(auth_manager.reload().unwrap(), 1);
+        assert!(!allows(&auth_manager, "alice", "secret").await);
+        assert!(allows(&auth_manager, "bob", "hunter2").await);
+        assert!(allows(&auth_manager, "admin", "password").await);
+
+        // A broken file leaves the table as it was
+        fs::write(&path, "carol:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n").unwrap();
+        assert!(auth_manager.reload().is_err());
+        assert!(allows(&auth_manager, "bob", "hunter2").await);
+
+        // An emptied file still requires authentication
+        fs::write(&path, "").unwrap();
+        assert_eq!(auth_manager.reload().unwrap(), 0);
+        let auth_manager = AuthManager::new(&HashMap::new())
+            .unwrap()
+            .with_users_file(&file_config(&path))
+            .unwrap();
+        assert!(auth_manager.is_required());
+        assert!(!allows(&auth_manager, "bob", "hunter2").await);
+
+        let path = dir.join("users.toml");
+        fs::write(
+            &path,
+            "[users]\ncarol = { password = \"pw\", allowed_ports = [\"80\"] }\n",
+        )
+        .unwrap();
+        let auth_manager = AuthManager::new(&HashMap::new())
+            .unwrap()
+            .with_users_file(&file_config(&path))
+            .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(allows(&auth_manager, "carol", "pw").await);
+        assert!(!auth_manager.allows_port("carol", 443));
     }
 }
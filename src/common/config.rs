@@ -1,9 +1,15 @@
+use chrono::{DateTime, NaiveDate, Utc};
 use config::ConfigError as ConfigLibError;
+use ipnet::{IpNet, Ipv4Net};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::sync::OnceLock;
 use thiserror::Error;
 
+use crate::net::addr::TargetAddr;
+
 #[derive(Error, Debug)]
 pub enum ConfigError {
     #[error("Failed to parse config file: {0}")]
@@ -14,84 +20,1753 @@ pub enum ConfigError {
     ConfigLibError(#[from] ConfigLibError),
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, Default)]
-pub struct Config {
-    #[serde(default = "default_listen_address")]
-    pub listen_address: String,
-    #[serde(default)]
-    pub users: HashMap<String, String>,
-    #[serde(default)]
-    pub log: LoggerConfig,
-    #[serde(default = "default_buffer_size")]
-    pub buffer_size: usize,
-    #[serde(default = "default_max_connections")]
-    pub max_connections: usize,
-    /// Timeout in seconds for connecting to target servers
-    #[serde(default = "default_connect_timeout")]
-    pub connect_timeout: u64,
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Config {
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,
+    #[serde(default)]
+    pub users: HashMap<String, UserConfig>,
+    /// When present, users are also read from this file, which is reloaded
+    /// when it changes
+    #[serde(default)]
+    pub users_file: Option<UsersFileConfig>,
+    /// Named groups of users, which port rules and quotas can be given to
+    /// instead of each member
+    #[serde(default)]
+    pub groups: HashMap<String, GroupConfig>,
+    /// Where users are looked up: `[users]` and the users file, or a
+    /// database
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub log: LoggerConfig,
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// Timeout in seconds for connecting to target servers
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    /// Timeout in seconds for a client to finish its TLS or QUIC handshake
+    /// and send its SOCKS4/6 request or HTTP request head, so a silent or
+    /// trickling client cannot hold a connection slot
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+    /// Target ports no connection is opened to, whatever the protocol,
+    /// e.g. `["25", "465", "6667"]`
+    #[serde(default)]
+    pub blocked_ports: Vec<PortRange>,
+    /// Firewall mark (`SO_MARK`) set on every outbound socket, on Linux
+    #[serde(default)]
+    pub outbound_mark: Option<u32>,
+    /// Options set on every TCP socket, inbound and outbound
+    #[serde(default)]
+    pub tcp: TcpConfig,
+    /// Threads of the async runtime
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub socks5: Socks5Config,
+    /// When present, inbound connections are wrapped in TLS
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub udp: UdpConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+    /// When present, outbound connections go through this parent proxy
+    #[serde(default)]
+    pub upstream: Option<UpstreamConfig>,
+    /// When present, a second listener accepts Shadowsocks clients
+    #[serde(default)]
+    pub shadowsocks: Option<ShadowsocksConfig>,
+    /// When present, SOCKS5 is also served over QUIC
+    #[serde(default)]
+    pub quic: Option<QuicConfig>,
+    /// When present, a listener accepts connections redirected by iptables
+    #[serde(default)]
+    pub transparent: Option<TransparentConfig>,
+    /// When present, traffic routed into a TUN device is proxied
+    #[serde(default)]
+    pub tun: Option<TunConfig>,
+    /// Targets whose connections start with a PROXY protocol v2 header
+    #[serde(default)]
+    pub proxy_protocol: Vec<ProxyProtocolRule>,
+    /// Local ports relayed to fixed targets, like `ssh -L`
+    #[serde(default)]
+    pub forward: Vec<ForwardConfig>,
+    /// When present, a listener serves as a reverse proxy for `routes`
+    #[serde(default)]
+    pub gateway: Option<GatewayConfig>,
+    /// When present, DNS queries are forwarded to a DoH or DoT resolver
+    #[serde(default)]
+    pub dns: Option<DnsConfig>,
+    /// When present, IPv4 targets are reached through a NAT64 gateway
+    #[serde(default)]
+    pub nat64: Option<Nat64Config>,
+    /// Further SOCKS/HTTP listeners next to `listen_address`, each with
+    /// its own protocols, TLS and authentication
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    /// Further parent proxies by name, for `[[rules]]` to route through
+    #[serde(default)]
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    /// Groups of `[upstreams]` by name, for `[[rules]]` to spread
+    /// connections over
+    #[serde(default)]
+    pub upstream_groups: HashMap<String, UpstreamGroupConfig>,
+    /// Routing rules, tried in order; targets matching none are dialed as
+    /// without rules
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /// When present, connections to the domains listed are refused
+    #[serde(default)]
+    pub blocklist: Option<BlocklistConfig>,
+    /// When present, HTTP requests matching these filter lists are answered
+    /// by the proxy instead of forwarded
+    #[serde(default)]
+    pub adblock: Option<AdblockConfig>,
+    /// Refusal of direct connections to private and reserved addresses
+    #[serde(default)]
+    pub ssrf: SsrfConfig,
+    /// Source networks clients may, or may not, connect from
+    #[serde(default)]
+    pub access: AccessConfig,
+    /// When present, users' `quota` is enforced over each period
+    #[serde(default)]
+    pub quotas: Option<QuotaConfig>,
+    /// Caps on the bytes per second connections, users and listeners may
+    /// relay
+    #[serde(default)]
+    pub bandwidth: Option<BandwidthConfig>,
+    /// Per source address caps on open and new connections
+    #[serde(default)]
+    pub client_limits: Option<ClientLimitsConfig>,
+    /// When present, connections over `max_connections` wait a while for
+    /// a slot instead of being dropped
+    #[serde(default)]
+    pub accept_queue: Option<AcceptQueueConfig>,
+    /// When present, sources and usernames failing to log in too often are
+    /// banned for a while
+    #[serde(default)]
+    pub lockout: Option<LockoutConfig>,
+    /// When present, TLS connections are routed by the server name in
+    /// their ClientHello
+    #[serde(default)]
+    pub sniff: Option<SniffConfig>,
+    /// When present, TLS in CONNECT tunnels is terminated so the requests
+    /// inside can be filtered. Requires the `mitm` build feature
+    #[serde(default)]
+    pub mitm: Option<MitmConfig>,
+    /// Fixed addresses for domains, used instead of DNS when dialing them
+    #[serde(default)]
+    pub hosts: Option<HostsConfig>,
+    /// Nameservers targets and parent proxies are resolved with
+    #[serde(default)]
+    pub resolver: ResolverConfig,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct UdpConfig {
+    /// Address advertised in UDP ASSOCIATE replies, e.g. a public IP behind NAT
+    #[serde(default)]
+    pub external_address: Option<IpAddr>,
+    /// Ports the UDP relay may bind, e.g. "40000-40100"; any free port if unset
+    #[serde(default)]
+    pub port_range: Option<PortRange>,
+    /// Answer A/AAAA queries relayed to port 53 from the proxy's resolver
+    #[serde(default)]
+    pub dns_fast_path: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (start, end) = value.split_once('-').unwrap_or((&value, &value));
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port range: {}", value))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == 0 || start > end {
+            return Err(format!("Invalid port range: {}", value));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        format!("{}-{}", range.start, range.end)
+    }
+}
+
+/// A `[users]` entry: either just the password, or a table that also
+/// carries per-user restrictions.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(from = "UserEntry")]
+pub struct UserConfig {
+    pub password: String,
+    /// Destination ports the user may connect to; empty allows any port
+    pub allowed_ports: Vec<PortRange>,
+    /// Bytes the user may transfer per `[quotas]` period
+    pub quota: Option<u64>,
+    /// A disabled user is refused, whatever the password
+    pub enabled: bool,
+    /// When the user stops being let in
+    pub expires_at: Option<Expiry>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UserEntry {
+    Password(String),
+    Table {
+        password: String,
+        #[serde(default)]
+        allowed_ports: Vec<PortRange>,
+        #[serde(default)]
+        quota: Option<u64>,
+        #[serde(default = "default_true")]
+        enabled: bool,
+        #[serde(default)]
+        expires_at: Option<Expiry>,
+    },
+}
+
+impl From<UserEntry> for UserConfig {
+    fn from(entry: UserEntry) -> Self {
+        match entry {
+            UserEntry::Password(password) => password.into(),
+            UserEntry::Table {
+                password,
+                allowed_ports,
+                quota,
+                enabled,
+                expires_at,
+            } => UserConfig {
+                password,
+                allowed_ports,
+                quota,
+                enabled,
+                expires_at,
+            },
+        }
+    }
+}
+
+/// An RFC 3339 time, e.g. `2026-12-31T18:00:00+08:00`, or a date alone,
+/// meaning midnight UTC at its start.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expiry(pub DateTime<Utc>);
+
+impl TryFrom<String> for Expiry {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Ok(time) = DateTime::parse_from_rfc3339(&value) {
+            return Ok(Expiry(time.with_timezone(&Utc)));
+        }
+        NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+            .map(|date| Expiry(date.and_time(Default::default()).and_utc()))
+            .map_err(|_| {
+                format!(
+                    "Invalid expires_at, not an RFC 3339 time or date: {}",
+                    value
+                )
+            })
+    }
+}
+
+impl From<Expiry> for String {
+    fn from(expiry: Expiry) -> Self {
+        expiry.0.to_rfc3339()
+    }
+}
+
+/// A `[groups]` entry. Its restrictions apply to members without their own.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct GroupConfig {
+    /// Usernames in the group, from `[users]`, the users file or tokens
+    pub members: Vec<String>,
+    /// Destination ports members may connect to; empty allows any port
+    #[serde(default)]
+    pub allowed_ports: Vec<PortRange>,
+    /// Bytes each member may transfer per `[quotas]` period
+    #[serde(default)]
+    pub quota: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UsersFileConfig {
+    /// A file with a `[users]` table like the configuration's when its name
+    /// ends in `.toml`, an htpasswd file of bcrypt hashes otherwise
+    pub path: String,
+    /// Seconds between checks of the file for changes; 0 reloads it only
+    /// on SIGHUP
+    #[serde(default = "default_users_reload_interval")]
+    pub reload_interval: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub backend: AuthBackend,
+    /// SQLite database file of the `sqlite` backend, created when missing
+    #[serde(default)]
+    pub database: Option<String>,
+    /// Endpoint of the `webhook` backend
+    #[serde(default)]
+    pub webhook: Option<WebhookConfig>,
+    /// Source networks whose SOCKS and HTTP clients skip authentication
+    #[serde(default)]
+    pub exempt_cidrs: Vec<IpNet>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WebhookConfig {
+    /// http:// or https:// URL credentials are POSTed to
+    pub url: String,
+    /// Seconds one request may take
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: u64,
+    /// Further attempts after a failed request or an answer other than
+    /// 200 and 403
+    #[serde(default = "default_webhook_retries")]
+    pub retries: u32,
+    /// Seconds allowed credentials are remembered; 0 asks every time
+    #[serde(default = "default_webhook_cache_ttl")]
+    pub cache_ttl: u64,
+    /// PEM certificates trusted for an https:// URL instead of the system
+    /// store
+    #[serde(default)]
+    pub ca_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthBackend {
+    /// `[users]` and `users_file`
+    #[default]
+    Config,
+    /// The `auth.database` SQLite database, managed with `rust-proxy user`.
+    /// Requires the `sqlite` build feature
+    Sqlite,
+    /// The `[auth.webhook]` HTTP endpoint
+    Webhook,
+}
+
+impl AuthBackend {
+    /// The name `auth.backend` selects this backend by
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthBackend::Config => "config",
+            AuthBackend::Sqlite => "sqlite",
+            AuthBackend::Webhook => "webhook",
+        }
+    }
+}
+
+impl From<String> for UserConfig {
+    fn from(password: String) -> Self {
+        UserConfig {
+            password,
+            allowed_ports: Vec::new(),
+            quota: None,
+            enabled: true,
+            expires_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HttpConfig {
+    /// Realm sent in `Proxy-Authenticate` with 407 responses
+    #[serde(default = "default_auth_realm")]
+    pub auth_realm: String,
+    /// Extra headers added to 407 responses
+    #[serde(default)]
+    pub auth_headers: HashMap<String, String>,
+    /// HTML body of 407 responses; empty when unset
+    #[serde(default)]
+    pub auth_body: Option<String>,
+    /// Ports CONNECT may tunnel to; others get a 403. Empty allows any port
+    #[serde(default = "default_allowed_connect_ports")]
+    pub allowed_connect_ports: Vec<PortRange>,
+    /// How `X-Forwarded-For` is sent upstream
+    #[serde(default)]
+    pub x_forwarded_for: ForwardedPolicy,
+    /// How the RFC 7239 `Forwarded` header is sent upstream
+    #[serde(default)]
+    pub forwarded: ForwardedPolicy,
+    /// Name this proxy adds to `Via`; requests already carrying it are loops
+    #[serde(default = "default_via_pseudonym")]
+    pub via_pseudonym: String,
+    /// Idle upstream connections kept per origin; 0 disables pooling
+    #[serde(default = "default_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    /// Seconds an upstream connection may sit idle in the pool
+    #[serde(default = "default_pool_idle_timeout")]
+    pub pool_idle_timeout: u64,
+    /// Seconds after which an upstream connection is no longer reused
+    #[serde(default = "default_pool_max_lifetime")]
+    pub pool_max_lifetime: u64,
+    /// Longest request line accepted, in bytes; longer ones get a 414
+    #[serde(default = "default_max_request_line")]
+    pub max_request_line: usize,
+    /// Largest request header section accepted, in bytes; larger ones get a 431
+    #[serde(default = "default_max_header_bytes")]
+    pub max_header_bytes: usize,
+    /// Most request header fields accepted; more get a 431
+    #[serde(default = "default_max_headers")]
+    pub max_headers: usize,
+    /// Seconds to wait for an origin's response head once the request has
+    /// been sent; the client then gets a 504
+    #[serde(default = "default_response_header_timeout")]
+    pub response_header_timeout: u64,
+    /// Streams, each a CONNECT tunnel, an HTTP/2 client may have open at
+    /// once on one connection
+    #[serde(default = "default_max_concurrent_streams")]
+    pub max_concurrent_streams: u32,
+    /// Header rewrites applied, in order, to plain HTTP traffic
+    #[serde(default)]
+    pub header_rules: Vec<HeaderRule>,
+    /// Text replacements applied, in order, to plain HTTP response bodies
+    #[serde(default)]
+    pub body_rules: Vec<BodyRule>,
+    /// Decode gzip, deflate and br response bodies so `body_rules` see
+    /// their text; otherwise compressed bodies pass through untouched
+    #[serde(default)]
+    pub decompress_bodies: bool,
+    /// Largest response body `body_rules` hold back, in bytes, before and
+    /// after decoding; longer bodies pass through untouched
+    #[serde(default = "default_max_filtered_body")]
+    pub max_filtered_body: usize,
+    /// When present, `Proxy-Authorization: Bearer` tokens are accepted
+    /// as well as passwords
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            auth_realm: default_auth_realm(),
+            auth_headers: HashMap::new(),
+            auth_body: None,
+            allowed_connect_ports: default_allowed_connect_ports(),
+            x_forwarded_for: ForwardedPolicy::default(),
+            forwarded: ForwardedPolicy::default(),
+            via_pseudonym: default_via_pseudonym(),
+            pool_max_idle_per_host: default_pool_max_idle_per_host(),
+            pool_idle_timeout: default_pool_idle_timeout(),
+            pool_max_lifetime: default_pool_max_lifetime(),
+            max_request_line: default_max_request_line(),
+            max_header_bytes: default_max_header_bytes(),
+            max_headers: default_max_headers(),
+            response_header_timeout: default_response_header_timeout(),
+            max_concurrent_streams: default_max_concurrent_streams(),
+            header_rules: Vec::new(),
+            body_rules: Vec::new(),
+            decompress_bodies: false,
+            max_filtered_body: default_max_filtered_body(),
+            jwt: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JwtConfig {
+    /// Shared secret of HS256/HS384/HS512 tokens
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// http:// or https:// URL of the JSON Web Key Set that RS*, PS*, ES*
+    /// and EdDSA tokens are verified against
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    /// Seconds the key set is used before it is fetched again
+    #[serde(default = "default_jwks_refresh")]
+    pub jwks_refresh: u64,
+    /// PEM certificates trusted for an https:// `jwks_url` instead of the
+    /// system store
+    #[serde(default)]
+    pub ca_path: Option<String>,
+    /// Required `iss` claim; any when unset
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// Required `aud` claim; any when unset
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// Claim holding the proxy user the token stands for
+    #[serde(default = "default_jwt_user_claim")]
+    pub user_claim: String,
+    /// Seconds of clock skew allowed when checking `exp` and `nbf`
+    #[serde(default = "default_jwt_leeway")]
+    pub leeway: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardedPolicy {
+    /// Forward the client's header unchanged, if any
+    #[default]
+    Pass,
+    /// Add the client IP after any values the client sent
+    Append,
+    /// Drop the header so the origin does not learn the client address
+    Strip,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CacheConfig {
+    /// Cache GET responses on the plain HTTP path
+    #[serde(default)]
+    pub enabled: bool,
+    /// Memory budget in bytes; least recently used entries are evicted
+    #[serde(default = "default_cache_max_size")]
+    pub max_size: u64,
+    /// Largest response, in bytes, that is cached
+    #[serde(default = "default_cache_max_entry_size")]
+    pub max_entry_size: u64,
+    /// Freshness in seconds for responses without explicit expiry; 0 stores
+    /// them only when they can be revalidated
+    #[serde(default)]
+    pub default_ttl: u64,
+    /// Freshness in seconds per host, replacing what the origin sends
+    #[serde(default)]
+    pub ttl_overrides: HashMap<String, u64>,
+    /// Directory for the on-disk tier; memory only when unset
+    #[serde(default)]
+    pub dir: Option<String>,
+    /// Disk budget in bytes; oldest entries are removed first
+    #[serde(default = "default_cache_max_disk_size")]
+    pub max_disk_size: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_size: default_cache_max_size(),
+            max_entry_size: default_cache_max_entry_size(),
+            default_ttl: 0,
+            ttl_overrides: HashMap::new(),
+            dir: None,
+            max_disk_size: default_cache_max_disk_size(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain; unused with `acme`
+    #[serde(default)]
+    pub cert_path: String,
+    /// PEM file with the private key; unused with `acme`
+    #[serde(default)]
+    pub key_path: String,
+    /// ALPN protocol names offered during the handshake, in order of
+    /// preference (e.g. `http/1.1`); ALPN is skipped when empty
+    #[serde(default)]
+    pub alpn: Vec<String>,
+    /// PEM file with the CAs client certificates must chain to. When set,
+    /// clients must present a certificate, and one naming a configured user
+    /// authenticates as that user
+    #[serde(default)]
+    pub client_ca_path: Option<String>,
+    /// Certificate obtained and renewed from an ACME CA instead of read
+    /// from `cert_path` and `key_path`
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+}
+
+/// `[tls.acme]`: the TLS listener's certificate, issued by an ACME CA
+/// (RFC 8555) against HTTP-01 challenges.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AcmeConfig {
+    /// Names the certificate covers; the first is its subject
+    pub domains: Vec<String>,
+    /// Account contact URLs, e.g. `mailto:admin@example.com`
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Directory URL of the CA
+    #[serde(default = "default_acme_directory_url")]
+    pub directory_url: String,
+    /// PEM certificates trusted for the CA's HTTPS endpoints instead of
+    /// the system store
+    #[serde(default)]
+    pub ca_path: Option<String>,
+    /// Directory holding the account key, certificate and its key
+    #[serde(default = "default_acme_certs_dir")]
+    pub certs_dir: String,
+    /// Address answering HTTP-01 challenges; the CA connects to port 80
+    /// of each domain
+    #[serde(default = "default_acme_http01_listen")]
+    pub http01_listen: String,
+    /// Days before expiry the certificate is renewed
+    #[serde(default = "default_acme_renew_before")]
+    pub renew_before: u64,
+}
+
+/// A `[[listeners]]` entry.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ListenerConfig {
+    pub listen_address: String,
+    /// Protocols accepted; others are refused after their first byte
+    #[serde(default = "default_listener_protocols")]
+    pub protocols: Vec<ListenerProtocol>,
+    /// TLS for this listener, independent of `[tls]`
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Whether clients authenticate as one of `[users]`; when false anyone
+    /// may use the listener
+    #[serde(default = "default_true")]
+    pub auth: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ListenerProtocol {
+    Socks4,
+    Socks5,
+    /// Only recognized in builds with the `socks6` feature
+    Socks6,
+    Http,
+}
+
+/// QUIC listener carrying one SOCKS5 session per bidirectional stream.
+/// It presents the `[tls]` certificate.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct QuicConfig {
+    /// UDP address to listen on
+    pub listen_address: String,
+    /// ALPN protocol names offered during the handshake; clients must
+    /// offer one of them when the list is not empty
+    #[serde(default)]
+    pub alpn: Vec<String>,
+}
+
+/// Listener for connections netfilter diverts to the proxy; each is
+/// forwarded to the address it was originally sent to.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TransparentConfig {
+    pub listen_address: String,
+    #[serde(default)]
+    pub mode: TransparentMode,
+    /// Connect to targets from the client's address instead of the
+    /// proxy's; `tproxy` mode only
+    #[serde(default)]
+    pub spoof_source: bool,
+}
+
+/// How connections reach the `[transparent]` listener.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransparentMode {
+    /// iptables `REDIRECT`; the destination is read with `SO_ORIGINAL_DST`
+    #[default]
+    Redirect,
+    /// iptables `TPROXY`; the listener socket is `IP_TRANSPARENT`
+    Tproxy,
+}
+
+/// TUN device whose TCP connections are dialed like any proxied target;
+/// UDP flows are relayed directly. Requires the `tun` build feature.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TunConfig {
+    /// Interface name, created if it does not exist
+    #[serde(default = "default_tun_name")]
+    pub name: String,
+    #[serde(default = "default_tun_mtu")]
+    pub mtu: usize,
+}
+
+/// A `[[proxy_protocol]]` entry. Connections to matching targets begin
+/// with a PROXY protocol v2 header naming the client, for backends that
+/// would otherwise only see the proxy's address.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ProxyProtocolRule {
+    /// Exact host or IP, or `*.example.com` for any subdomain
+    pub host: String,
+    /// Any port when unset
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+/// A `[[forward]]` entry: every connection to `listen_address` is relayed
+/// to `target` without any proxy handshake or authentication.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ForwardConfig {
+    pub listen_address: String,
+    /// `host:port` to connect to, e.g. `db.internal:5432`
+    pub target: String,
+}
+
+/// Reverse proxy listener: each HTTP request goes to the backend of the
+/// first route matching its host, with no proxy authentication.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GatewayConfig {
+    pub listen_address: String,
+    pub routes: Vec<GatewayRoute>,
+}
+
+/// A `[[gateway.routes]]` entry.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GatewayRoute {
+    /// Exact host, `*.example.com` for any subdomain, or `*` for any host
+    pub host: String,
+    /// `host:port` requests are sent to
+    pub backend: String,
+    /// Connect to the backend over TLS; its certificate must name the host
+    /// in `backend`
+    #[serde(default)]
+    pub tls: bool,
+    /// CA bundle the backend's certificate is verified against; required
+    /// with `tls`
+    #[serde(default)]
+    pub ca_path: Option<String>,
+}
+
+/// DNS listener on UDP and TCP whose queries are forwarded, through the
+/// outbound dialer, to an encrypted resolver.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DnsConfig {
+    pub listen_address: String,
+    /// `https://host[:port]/path` for DNS over HTTPS, or `tls://host[:port]`
+    /// for DNS over TLS
+    pub upstream: String,
+    /// CA bundle the resolver's certificate is verified against; the
+    /// system's certificate store when unset
+    #[serde(default)]
+    pub ca_path: Option<String>,
+    /// Seconds allowed for each query, connecting included
+    #[serde(default = "default_dns_timeout")]
+    pub timeout: u64,
+    /// When present, A queries are answered with addresses from a pool,
+    /// mapped back to their domain when clients connect to them
+    #[serde(default)]
+    pub fake_ip: Option<FakeIpConfig>,
+}
+
+/// Fake-IP mode for clients that resolve before connecting, such as those
+/// behind a transparent gateway, so that domain rules still apply.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FakeIpConfig {
+    /// Pool of addresses handed out, one per domain; the least recently
+    /// handed out is reused for a new domain once all are taken
+    pub range: Ipv4Net,
+    /// Domain patterns resolved for real, such as those of NTP servers or
+    /// local names that clients reach without the proxy
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// NAT64 for a proxy on an IPv6-only network: IPv4 targets are dialed at
+/// their address embedded in `prefix`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Nat64Config {
+    /// e.g. the well-known `64:ff9b::/96`
+    pub prefix: ipnet::Ipv6Net,
+    /// Resolve domain targets here and dial only their IPv6 addresses,
+    /// reaching names with no AAAA record through the prefix
+    #[serde(default)]
+    pub force_ipv6: bool,
+}
+
+/// Parent proxy that outbound TCP connections are chained through.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UpstreamConfig {
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+    /// `host:port` of the parent proxy
+    pub address: String,
+    /// Credentials for the parent: RFC 1929 for SOCKS5, Basic for HTTP.
+    /// Without them a SOCKS5 parent is only offered no authentication.
+    /// A Shadowsocks parent takes only the password, its key being derived
+    /// from it. An SSH jump host needs the username, and the password
+    /// unless `private_key_path` is set, in which case it is the key's
+    /// passphrase
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// AEAD cipher for a Shadowsocks parent
+    #[serde(default)]
+    pub cipher: ShadowsocksCipher,
+    /// Private key (OpenSSH or PEM) an SSH jump host is logged into with
+    #[serde(default)]
+    pub private_key_path: Option<String>,
+    /// SHA-256 fingerprint the SSH jump host's key must have, as printed by
+    /// `ssh-keygen -l` (`SHA256:...`)
+    #[serde(default)]
+    pub host_key: Option<String>,
+    /// SSH only: tunnels open through the jump host at once. Each is a
+    /// session of its own, logged into on a blocking thread; more wait for
+    /// one to close, up to `connect_timeout`
+    #[serde(default = "default_ssh_max_channels")]
+    pub max_channels: usize,
+    /// Speak to the parent over TLS, verified against `ca_path`
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM file with the CAs the parent's certificate chains to
+    #[serde(default)]
+    pub ca_path: Option<String>,
+}
+
+/// `[upstreams]` entries a rule naming the group spreads its connections
+/// over, a member that fails to connect giving way to the next.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UpstreamGroupConfig {
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub strategy: BalanceStrategy,
+    /// `url-test` only: URL fetched through each member to time it
+    #[serde(default = "default_test_url")]
+    pub url: String,
+    /// `url-test` only: seconds between rounds of measurements
+    #[serde(default = "default_test_interval")]
+    pub interval: u64,
+    /// `url-test` only: milliseconds another member must be faster by to
+    /// take over from the one in use
+    #[serde(default = "default_test_tolerance")]
+    pub tolerance: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BalanceStrategy {
+    /// Each member in turn
+    #[default]
+    RoundRobin,
+    /// The member with the fewest open connections
+    LeastConnections,
+    /// Members at random, weighted by how fast they connect
+    LatencyWeighted,
+    /// The member that fetched `url` fastest in the last round
+    UrlTest,
+}
+
+/// Domains to refuse, from a hosts file or a list with one per line;
+/// `*.example.com` covers every subdomain.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BlocklistConfig {
+    pub path: String,
+    /// Seconds between checks of the file for changes; 0 reloads it only
+    /// on SIGHUP
+    #[serde(default = "default_blocklist_reload_interval")]
+    pub reload_interval: u64,
+}
+
+/// SNI sniffing for HTTP CONNECT tunnels and the `[transparent]` listener.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SniffConfig {
+    /// Target ports whose connections are sniffed
+    #[serde(default = "default_sniff_ports")]
+    pub ports: Vec<PortRange>,
+    /// Milliseconds to wait for the client's ClientHello before dialing
+    /// without it
+    #[serde(default = "default_sniff_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HostsConfig {
+    /// Hosts file read at startup, e.g. "/etc/hosts"
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Domains and their addresses, taking precedence over the file
+    #[serde(default)]
+    pub entries: HashMap<String, HostAddrs>,
+}
+
+/// A `[hosts.entries]` value: one address or a list of them.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(from = "HostEntry")]
+pub struct HostAddrs(pub Vec<IpAddr>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HostEntry {
+    One(IpAddr),
+    Many(Vec<IpAddr>),
+}
+
+impl From<HostEntry> for HostAddrs {
+    fn from(entry: HostEntry) -> Self {
+        match entry {
+            HostEntry::One(ip) => HostAddrs(vec![ip]),
+            HostEntry::Many(ips) => HostAddrs(ips),
+        }
+    }
+}
+
+/// TLS inspection of HTTP CONNECT tunnels, with leaf certificates issued
+/// by a CA clients must trust.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MitmConfig {
+    /// PEM certificate of the CA issuing the leaf certificates
+    pub ca_cert_path: String,
+    /// PEM private key of that CA
+    pub ca_key_path: String,
+    /// Host patterns whose tunnels are inspected, e.g. "*.example.com";
+    /// every tunnel when empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// CA bundle origins are verified against; the system certificate
+    /// store when unset
+    #[serde(default)]
+    pub origin_ca_path: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct AccessConfig {
+    /// Clients outside these networks are refused; any may connect when
+    /// empty
+    #[serde(default)]
+    pub allow_cidrs: Vec<IpNet>,
+    /// Clients within these networks are refused, even inside
+    /// `allow_cidrs`
+    #[serde(default)]
+    pub deny_cidrs: Vec<IpNet>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct QuotaConfig {
+    /// How often usage starts over, at midnight UTC or the first of the
+    /// month
+    #[serde(default)]
+    pub period: QuotaPeriod,
+    /// Bytes per period for users without a `quota` of their own; no
+    /// limit when unset
+    #[serde(default)]
+    pub default_quota: Option<u64>,
+    /// JSON file usage is kept in across restarts; memory only when unset
+    #[serde(default)]
+    pub state_path: Option<String>,
+    /// Seconds between saves of `state_path`
+    #[serde(default = "default_quota_save_interval")]
+    pub save_interval: u64,
+    /// What happens to the sessions of a user over quota
+    #[serde(default)]
+    pub action: QuotaAction,
+    /// Bytes per second all sessions of a user over quota share, with
+    /// `action = "throttle"`
+    #[serde(default = "default_quota_throttle_rate")]
+    pub throttle_rate: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Daily,
+    #[default]
+    Monthly,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaAction {
+    /// Cut them off and refuse new ones
+    #[default]
+    Block,
+    /// Let them through at `throttle_rate`
+    Throttle,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BandwidthConfig {
+    /// KiB per second each connection may relay, both directions together;
+    /// 0 for no limit
+    #[serde(default)]
+    pub connection_rate: u64,
+    /// KiB per second all connections of one user share; 0 for no limit
+    #[serde(default)]
+    pub user_rate: u64,
+    /// KiB per second all connections of one listener share; 0 for no
+    /// limit
+    #[serde(default)]
+    pub listener_rate: u64,
+    /// KiB each limit lets through at once after a quiet spell
+    #[serde(default = "default_bandwidth_burst")]
+    pub burst: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ClientLimitsConfig {
+    /// Connections one source address may hold open; 0 for no cap
+    #[serde(default)]
+    pub max_connections: usize,
+    /// New connections one source address may open per second, on
+    /// average; 0 for no limit
+    #[serde(default)]
+    pub connections_per_second: u32,
+    /// New connections one source address may open at once, beyond the
+    /// average rate
+    #[serde(default = "default_client_limits_burst")]
+    pub burst: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AcceptQueueConfig {
+    /// Connections waiting for a slot at most; any more are dropped
+    #[serde(default = "default_accept_queue_max_waiting")]
+    pub max_waiting: usize,
+    /// Milliseconds a connection waits for a slot before it is dropped
+    #[serde(default = "default_accept_queue_wait_timeout")]
+    pub wait_timeout: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LockoutConfig {
+    /// Failed logins from one source address within `window` that ban it;
+    /// 0 never bans sources
+    #[serde(default = "default_lockout_max_failures")]
+    pub max_failures: u32,
+    /// Failed logins as one username within `window`, from any source,
+    /// that lock it; 0 never locks usernames
+    #[serde(default = "default_lockout_max_user_failures")]
+    pub max_user_failures: u32,
+    /// Seconds failures are counted over
+    #[serde(default = "default_lockout_window")]
+    pub window: u64,
+    /// Seconds a source stays banned or a username locked
+    #[serde(default = "default_lockout_ban_duration")]
+    pub ban_duration: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TcpConfig {
+    /// `TCP_NODELAY` on sockets clients connected, so small replies are
+    /// not held back
+    #[serde(default = "default_true")]
+    pub inbound_nodelay: bool,
+    /// `TCP_NODELAY` on sockets to targets and parent proxies
+    #[serde(default)]
+    pub outbound_nodelay: bool,
+    /// `SO_RCVBUF` in bytes; 0 leaves it to the system, which tunes it
+    /// per connection
+    #[serde(default)]
+    pub recv_buffer_size: usize,
+    /// `SO_SNDBUF` in bytes; 0 leaves it to the system
+    #[serde(default)]
+    pub send_buffer_size: usize,
+    /// Seconds a connection is idle before keepalive probes are sent; 0
+    /// leaves keepalive off
+    #[serde(default)]
+    pub keepalive_time: u64,
+    /// Seconds between keepalive probes; 0 for the system default
+    #[serde(default)]
+    pub keepalive_interval: u64,
+    /// Seconds sent data may go unacknowledged before the connection is
+    /// dropped (`TCP_USER_TIMEOUT`, Linux only); 0 for the system default
+    #[serde(default)]
+    pub user_timeout: u64,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            inbound_nodelay: true,
+            outbound_nodelay: false,
+            recv_buffer_size: 0,
+            send_buffer_size: 0,
+            keepalive_time: 0,
+            keepalive_interval: 0,
+            user_timeout: 0,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RuntimeConfig {
+    /// Threads running connections; 0 for one per CPU core
+    #[serde(default)]
+    pub worker_threads: usize,
+    /// Threads at most for blocking work, such as file I/O and password
+    /// hashing
+    #[serde(default = "default_max_blocking_threads")]
+    pub max_blocking_threads: usize,
+    /// Name of the runtime's threads, numbered from 0
+    #[serde(default = "default_thread_name")]
+    pub thread_name: String,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: 0,
+            max_blocking_threads: default_max_blocking_threads(),
+            thread_name: default_thread_name(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResolverConfig {
+    /// Nameservers queried, as `ip` or `ip:port`; those of
+    /// `/etc/resolv.conf` when empty
+    #[serde(default)]
+    pub nameservers: Vec<Nameserver>,
+    /// Seconds allowed for each query
+    #[serde(default = "default_resolver_timeout")]
+    pub timeout: u64,
+    /// Times a query that failed or timed out is sent again
+    #[serde(default = "default_resolver_attempts")]
+    pub attempts: usize,
+    /// Names whose answers are kept, for as long as their TTL; 0 turns the
+    /// cache off
+    #[serde(default = "default_resolver_cache_size")]
+    pub cache_size: usize,
+    /// Seconds a name is remembered not to exist; 0 asks again every time
+    #[serde(default = "default_resolver_negative_ttl")]
+    pub negative_ttl: u64,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: Vec::new(),
+            timeout: default_resolver_timeout(),
+            attempts: default_resolver_attempts(),
+            cache_size: default_resolver_cache_size(),
+            negative_ttl: default_resolver_negative_ttl(),
+        }
+    }
+}
+
+/// A nameserver address; port 53 unless given.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Nameserver(pub SocketAddr);
+
+impl TryFrom<String> for Nameserver {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .parse::<SocketAddr>()
+            .or_else(|_| value.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+            .map(Nameserver)
+            .map_err(|_| format!("Invalid nameserver address: {}", value))
+    }
+}
+
+impl From<Nameserver> for String {
+    fn from(nameserver: Nameserver) -> Self {
+        nameserver.0.to_string()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SsrfConfig {
+    /// Refuse loopback, private, link-local and other reserved addresses,
+    /// cloud metadata services among them
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Networks dialed even so, e.g. "10.1.0.0/16"
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+}
+
+impl Default for SsrfConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allow: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AdblockConfig {
+    /// Filter lists in Adblock Plus syntax, such as EasyList
+    pub paths: Vec<String>,
+    /// Status of the response to a blocked request: 204 or 403. Blocked
+    /// CONNECT tunnels always get 403
+    #[serde(default = "default_adblock_status")]
+    pub status: u16,
+}
+
+/// A `[[rules]]` entry, matching targets that meet every condition set;
+/// one without conditions matches all of them.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RuleConfig {
+    /// Exact domain, ignoring case
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// Domain together with all its subdomains
+    #[serde(default)]
+    pub domain_suffix: Option<String>,
+    /// Regular expression searched for in the domain
+    #[serde(default)]
+    pub domain_regex: Option<String>,
+    /// Network of the target's address; domain targets are resolved to
+    /// match it, and dialed at the addresses it matched
+    #[serde(default)]
+    pub cidr: Option<IpNet>,
+    /// Target ports, e.g. `["443", "8000-8999"]`; any when empty
+    #[serde(default)]
+    pub ports: Vec<PortRange>,
+    pub action: RuleAction,
+    /// `[upstreams]` entry or `[upstream_groups]` group a `proxy` rule
+    /// goes through; `[upstream]` when unset
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// Local address a `direct` rule connects from
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
+    /// Network interface a `direct` rule connects through, on Linux
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleAction {
+    /// Connect straight to the target
+    Direct,
+    /// Connect through a parent proxy
+    Proxy,
+    /// Refuse the connection
+    Block,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamProtocol {
+    #[default]
+    Socks5,
+    /// HTTP proxy reached with `CONNECT`
+    Http,
+    /// Shadowsocks server, spoken to with an AEAD cipher (SIP004)
+    Shadowsocks,
+    /// SSH server, tunneled through with `direct-tcpip` channels
+    Ssh,
+}
+
+/// Inbound Shadowsocks listener. Each configured user connects with a key
+/// derived from their password; `password` is the single shared key used
+/// when there are no users.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ShadowsocksConfig {
+    pub listen_address: String,
+    #[serde(default)]
+    pub cipher: ShadowsocksCipher,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShadowsocksCipher {
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[default]
+    #[serde(rename = "chacha20-ietf-poly1305")]
+    Chacha20IetfPoly1305,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Socks5Config {
+    /// Where domain targets are resolved
+    #[serde(default)]
+    pub resolve: ResolveStrategy,
+    /// Timeout in seconds for completing greeting, auth and request
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+    /// Commands clients may issue; others are answered with REP=0x07
+    #[serde(default = "default_allowed_commands")]
+    pub allowed_commands: Vec<Socks5Command>,
+    /// Seconds without traffic after which an established tunnel or UDP
+    /// association is closed; 0 disables the timeout
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Accept clients offering no-auth (0x00) even when users are configured
+    #[serde(default)]
+    pub allow_anonymous: bool,
+    /// Restricts `allow_anonymous` to these source networks; empty means any
+    #[serde(default)]
+    pub anonymous_cidrs: Vec<IpNet>,
+    /// Also accept 0x05 as the username/password sub-negotiation version
+    #[serde(default)]
+    pub lenient_auth_version: bool,
+}
+
+impl Default for Socks5Config {
+    fn default() -> Self {
+        Self {
+            resolve: ResolveStrategy::default(),
+            handshake_timeout: default_handshake_timeout(),
+            allowed_commands: default_allowed_commands(),
+            idle_timeout: default_idle_timeout(),
+            allow_anonymous: false,
+            anonymous_cidrs: Vec::new(),
+            lenient_auth_version: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Socks5Command {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResolveStrategy {
+    /// Resolve domains on the proxy host before dialing
+    #[default]
+    Local,
+    /// Pass domains through unresolved so an upstream proxy resolves them
+    RemoteViaUpstream,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct LoggerConfig {
+    #[serde(default = "default_log_level")]
+    pub level: String,
+    #[serde(default = "default_log_path")]
+    pub path: String,
+    #[serde(default = "default_archive_pattern")]
+    pub archive_pattern: String,
+    #[serde(default = "default_file_count")]
+    pub file_count: u32,
+    /// Max file size in MB
+    #[serde(default = "default_file_size")]
+    pub file_size: u64,
+}
+
+/// A `[[http.header_rules]]` entry. It applies to requests whose target
+/// matches `host` and `path_prefix` (any when unset), and either to the
+/// request sent upstream or to the response relayed back.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HeaderRule {
+    /// Exact host, or `*.example.com` for any subdomain
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    pub direction: HeaderDirection,
+    pub action: HeaderAction,
+    /// Header name, matched case-insensitively
+    pub name: String,
+    /// Value for `set` and `add`; replacement text for `replace`, where
+    /// `$1` or `${name}` stands for a capture group of `pattern`
+    #[serde(default)]
+    pub value: Option<String>,
+    /// Regular expression `replace` looks for in the header value
+    #[serde(default)]
+    pub pattern: Option<String>,
+    /// `pattern`, compiled by validation
+    #[serde(skip)]
+    pub regex: OnceLock<regex::Regex>,
+}
+
+impl HeaderRule {
+    /// The compiled `pattern`, if the rule has one. Validation has already
+    /// checked that it compiles.
+    pub fn regex(&self) -> Option<&regex::Regex> {
+        let pattern = self.pattern.as_deref()?;
+        Some(
+            self.regex
+                .get_or_init(|| regex::Regex::new(pattern).unwrap()),
+        )
+    }
+}
+
+/// A `[[http.body_rules]]` entry: a literal find and replace in the bodies
+/// of responses to requests matching `host` and `path_prefix`, whose
+/// `Content-Type` starts with `content_type` (any when unset).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BodyRule {
+    /// Exact host, or `*.example.com` for any subdomain
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    /// Media type prefix such as `text/html`, matched case-insensitively
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub pattern: String,
+    #[serde(default)]
+    pub replacement: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HeaderDirection {
+    Request,
+    Response,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum HeaderAction {
+    /// Replace every existing field of that name with one holding `value`
+    Set,
+    /// Append a field, keeping existing ones
+    Add,
+    Remove,
+    /// Substitute `value` for each match of `pattern`
+    Replace,
+}
+
+/// Access log of HTTP transactions, rotated like the application log
+/// (`log.file_size` and `log.file_count`).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AccessLogConfig {
+    /// Access log file; unset disables the access log
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default = "default_access_archive_pattern")]
+    pub archive_pattern: String,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            archive_pattern: default_access_archive_pattern(),
+            format: AccessLogFormat::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccessLogFormat {
+    /// Common Log Format
+    #[default]
+    Common,
+    /// Common Log Format plus referer and user agent
+    Combined,
+    /// One JSON object per line, including the latency
+    Json,
+}
+
+impl Default for LoggerConfig {
+    fn default() -> Self {
+        Self {
+            level: default_log_level(),
+            path: default_log_path(),
+            archive_pattern: default_archive_pattern(),
+            file_count: default_file_count(),
+            file_size: default_file_size(),
+        }
+    }
+}
+
+fn default_listen_address() -> String {
+    "127.0.0.1:1080".to_string()
+}
+
+fn default_log_level() -> String {
+    "Info".to_string()
+}
+
+fn default_log_path() -> String {
+    "logs/rust-proxy.log".to_string()
+}
+
+fn default_archive_pattern() -> String {
+    "logs/archive/rust-proxy-{}.log".to_string()
+}
+
+fn default_access_archive_pattern() -> String {
+    "logs/archive/access-{}.log".to_string()
+}
+
+fn default_file_count() -> u32 {
+    5
+}
+
+fn default_file_size() -> u64 {
+    10
+}
+
+fn default_buffer_size() -> usize {
+    4096
+}
+
+fn default_max_connections() -> usize {
+    1024
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+fn default_max_blocking_threads() -> usize {
+    512
+}
+
+fn default_ssh_max_channels() -> usize {
+    64
+}
+
+fn default_thread_name() -> String {
+    "rust-proxy".to_string()
+}
+
+fn default_idle_timeout() -> u64 {
+    300
+}
+
+fn default_auth_realm() -> String {
+    "Proxy".to_string()
+}
+
+fn default_pool_max_idle_per_host() -> usize {
+    8
+}
+
+fn default_pool_idle_timeout() -> u64 {
+    30
+}
+
+fn default_pool_max_lifetime() -> u64 {
+    300
+}
+
+fn default_max_request_line() -> usize {
+    8 * 1024
+}
+
+fn default_max_header_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_max_headers() -> usize {
+    100
+}
+
+fn default_response_header_timeout() -> u64 {
+    60
+}
+
+fn default_max_concurrent_streams() -> u32 {
+    100
+}
+
+fn default_max_filtered_body() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_cache_max_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_cache_max_entry_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_cache_max_disk_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_via_pseudonym() -> String {
+    "rust-proxy".to_string()
+}
+
+fn default_allowed_connect_ports() -> Vec<PortRange> {
+    vec![PortRange {
+        start: 443,
+        end: 443,
+    }]
+}
+
+fn default_acme_directory_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+
+fn default_acme_certs_dir() -> String {
+    "certs/acme".to_string()
+}
+
+fn default_acme_http01_listen() -> String {
+    "0.0.0.0:80".to_string()
+}
+
+fn default_acme_renew_before() -> u64 {
+    30
+}
+
+fn default_allowed_commands() -> Vec<Socks5Command> {
+    vec![
+        Socks5Command::Connect,
+        Socks5Command::Bind,
+        Socks5Command::UdpAssociate,
+    ]
+}
+
+fn default_tun_name() -> String {
+    "rproxy0".to_string()
+}
+
+fn default_tun_mtu() -> usize {
+    1500
+}
+
+fn default_sniff_ports() -> Vec<PortRange> {
+    vec![PortRange {
+        start: 443,
+        end: 443,
+    }]
+}
+
+fn default_sniff_timeout() -> u64 {
+    300
+}
+
+fn default_test_url() -> String {
+    "http://www.gstatic.com/generate_204".to_string()
+}
+
+fn default_test_interval() -> u64 {
+    300
+}
+
+fn default_test_tolerance() -> u64 {
+    50
+}
+
+fn default_dns_timeout() -> u64 {
+    5
+}
+
+fn default_resolver_timeout() -> u64 {
+    5
+}
+
+fn default_resolver_attempts() -> usize {
+    2
+}
+
+fn default_resolver_cache_size() -> usize {
+    1024
+}
+
+fn default_resolver_negative_ttl() -> u64 {
+    30
+}
+
+fn default_blocklist_reload_interval() -> u64 {
+    30
+}
+
+fn default_users_reload_interval() -> u64 {
+    30
+}
+
+fn default_jwks_refresh() -> u64 {
+    300
+}
+
+fn default_jwt_user_claim() -> String {
+    "sub".to_string()
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct LoggerConfig {
-    #[serde(default = "default_log_level")]
-    pub level: String,
-    #[serde(default = "default_log_path")]
-    pub path: String,
-    #[serde(default = "default_archive_pattern")]
-    pub archive_pattern: String,
-    #[serde(default = "default_file_count")]
-    pub file_count: u32,
-    /// Max file size in MB
-    #[serde(default = "default_file_size")]
-    pub file_size: u64,
+fn default_jwt_leeway() -> u64 {
+    60
 }
 
-impl Default for LoggerConfig {
-    fn default() -> Self {
-        Self {
-            level: default_log_level(),
-            path: default_log_path(),
-            archive_pattern: default_archive_pattern(),
-            file_count: default_file_count(),
-            file_size: default_file_size(),
-        }
-    }
+fn default_quota_save_interval() -> u64 {
+    60
 }
 
-fn default_listen_address() -> String {
-    "127.0.0.1:1080".to_string()
+fn default_quota_throttle_rate() -> u64 {
+    64 * 1024
 }
 
-fn default_log_level() -> String {
-    "Info".to_string()
+fn default_bandwidth_burst() -> u64 {
+    64
 }
 
-fn default_log_path() -> String {
-    "logs/rust-proxy.log".to_string()
+fn default_accept_queue_max_waiting() -> usize {
+    256
 }
 
-fn default_archive_pattern() -> String {
-    "logs/archive/rust-proxy-{}.log".to_string()
+fn default_accept_queue_wait_timeout() -> u64 {
+    2000
 }
 
-fn default_file_count() -> u32 {
+fn default_client_limits_burst() -> u32 {
+    10
+}
+
+fn default_lockout_max_failures() -> u32 {
     5
 }
 
-fn default_file_size() -> u64 {
-    10
+fn default_lockout_max_user_failures() -> u32 {
+    20
 }
 
-fn default_buffer_size() -> usize {
-    4096
+fn default_lockout_window() -> u64 {
+    300
 }
 
-fn default_max_connections() -> usize {
-    1024
+fn default_lockout_ban_duration() -> u64 {
+    900
 }
 
-fn default_connect_timeout() -> u64 {
-    10
+fn default_webhook_timeout() -> u64 {
+    5
+}
+
+fn default_webhook_retries() -> u32 {
+    1
+}
+
+fn default_webhook_cache_ttl() -> u64 {
+    60
+}
+
+fn default_adblock_status() -> u16 {
+    204
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_listener_protocols() -> Vec<ListenerProtocol> {
+    vec![
+        ListenerProtocol::Socks4,
+        ListenerProtocol::Socks5,
+        ListenerProtocol::Socks6,
+        ListenerProtocol::Http,
+    ]
 }
 
 impl Config {
@@ -141,6 +1816,910 @@ impl Config {
             ));
         }
 
+        if self.handshake_timeout == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "handshake_timeout must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.runtime.max_blocking_threads == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "runtime.max_blocking_threads must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.runtime.thread_name.is_empty() {
+            return Err(ConfigError::InvalidConfig(
+                "runtime.thread_name must not be empty".to_string(),
+            ));
+        }
+
+        if self.tcp.keepalive_interval > 0 && self.tcp.keepalive_time == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "tcp.keepalive_interval needs tcp.keepalive_time".to_string(),
+            ));
+        }
+
+        if self.http.response_header_timeout == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "http.response_header_timeout must be greater than 0".to_string(),
+            ));
+        }
+
+        if let Some(jwt) = &self.http.jwt {
+            if jwt.secret.is_some() == jwt.jwks_url.is_some() {
+                return Err(ConfigError::InvalidConfig(
+                    "http.jwt needs exactly one of secret and jwks_url".to_string(),
+                ));
+            }
+            if let Some(url) = &jwt.jwks_url
+                && !url::Url::parse(url).is_ok_and(|url| {
+                    url.host().is_some() && matches!(url.scheme(), "http" | "https")
+                })
+            {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "http.jwt.jwks_url must be an http:// or https:// URL: {}",
+                    url
+                )));
+            }
+            if jwt.jwks_refresh == 0 {
+                return Err(ConfigError::InvalidConfig(
+                    "http.jwt.jwks_refresh must be greater than 0".to_string(),
+                ));
+            }
+        }
+
+        if self.socks5.handshake_timeout == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "socks5.handshake_timeout must be greater than 0".to_string(),
+            ));
+        }
+
+        // These end up verbatim in the response head
+        let auth_header_fields = self
+            .http
+            .auth_headers
+            .iter()
+            .flat_map(|(name, value)| [name, value]);
+        if std::iter::once(&self.http.auth_realm)
+            .chain(auth_header_fields)
+            .any(|field| field.contains(['\r', '\n']))
+        {
+            return Err(ConfigError::InvalidConfig(
+                "http.auth_realm and http.auth_headers must not contain line breaks".to_string(),
+            ));
+        }
+
+        // Must stay a single token so it can be found in a Via chain
+        let pseudonym = &self.http.via_pseudonym;
+        if pseudonym.is_empty() || pseudonym.contains(|c: char| c.is_whitespace() || c == ',') {
+            return Err(ConfigError::InvalidConfig(
+                "http.via_pseudonym must be a non-empty token without spaces or commas".to_string(),
+            ));
+        }
+
+        if self.http.max_request_line == 0
+            || self.http.max_header_bytes == 0
+            || self.http.max_headers == 0
+        {
+            return Err(ConfigError::InvalidConfig(
+                "http.max_request_line, http.max_header_bytes and http.max_headers must be greater than 0"
+                    .to_string(),
+            ));
+        }
+
+        if self
+            .tls
+            .iter()
+            .chain(
+                self.listeners
+                    .iter()
+                    .filter_map(|listener| listener.tls.as_ref()),
+            )
+            .flat_map(|tls| &tls.alpn)
+            .any(|protocol| protocol.is_empty() || protocol.len() > 255)
+        {
+            return Err(ConfigError::InvalidConfig(
+                "tls.alpn protocol names must be 1 to 255 bytes long".to_string(),
+            ));
+        }
+        for (name, tls) in self.tls.iter().map(|tls| ("tls", tls)).chain(
+            self.listeners
+                .iter()
+                .filter_map(|listener| listener.tls.as_ref())
+                .map(|tls| ("listeners.tls", tls)),
+        ) {
+            if tls.acme.is_none() && (tls.cert_path.is_empty() || tls.key_path.is_empty()) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "{}.cert_path and {}.key_path must be set unless {}.acme is",
+                    name, name, name
+                )));
+            }
+        }
+        if self
+            .listeners
+            .iter()
+            .any(|listener| listener.tls.as_ref().is_some_and(|tls| tls.acme.is_some()))
+        {
+            return Err(ConfigError::InvalidConfig(
+                "acme is only supported in [tls], not in listeners.tls".to_string(),
+            ));
+        }
+        if let Some(acme) = self.tls.as_ref().and_then(|tls| tls.acme.as_ref()) {
+            if acme.domains.is_empty() || acme.domains.iter().any(|domain| domain.is_empty()) {
+                return Err(ConfigError::InvalidConfig(
+                    "tls.acme.domains must list at least one domain".to_string(),
+                ));
+            }
+            if acme.http01_listen.parse::<std::net::SocketAddr>().is_err() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Invalid tls.acme.http01_listen: {}",
+                    acme.http01_listen
+                )));
+            }
+            if !acme.directory_url.starts_with("https://") {
+                return Err(ConfigError::InvalidConfig(
+                    "tls.acme.directory_url must be an https:// URL".to_string(),
+                ));
+            }
+        }
+
+        let mut listen_addresses = vec![self.listen_address.parse().ok()];
+        for listener in &self.listeners {
+            let Ok(addr) = listener.listen_address.parse::<std::net::SocketAddr>() else {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Invalid listeners.listen_address: {}",
+                    listener.listen_address
+                )));
+            };
+            if listen_addresses.contains(&Some(addr)) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "listeners.listen_address {} is already in use",
+                    addr
+                )));
+            }
+            listen_addresses.push(Some(addr));
+            if listener.protocols.is_empty() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "listener {} accepts no protocols",
+                    addr
+                )));
+            }
+        }
+
+        if let Some(upstream) = &self.upstream {
+            validate_upstream("upstream", upstream)?;
+        }
+        for (name, upstream) in &self.upstreams {
+            validate_upstream(&format!("upstreams.{}", name), upstream)?;
+        }
+        for (name, group) in &self.upstream_groups {
+            if self.upstreams.contains_key(name) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "upstream_groups.{} has the name of an [upstreams] entry",
+                    name
+                )));
+            }
+            if group.members.is_empty() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "upstream_groups.{} has no members",
+                    name
+                )));
+            }
+            if group.strategy == BalanceStrategy::UrlTest {
+                let url = url::Url::parse(&group.url).ok();
+                if !url.is_some_and(|url| {
+                    url.host().is_some() && matches!(url.scheme(), "http" | "https")
+                }) {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "upstream_groups.{}.url must be an http:// or https:// URL: {}",
+                        name, group.url
+                    )));
+                }
+                if group.interval == 0 {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "upstream_groups.{}.interval must be greater than 0",
+                        name
+                    )));
+                }
+            }
+            if let Some(member) = group
+                .members
+                .iter()
+                .find(|member| !self.upstreams.contains_key(*member))
+            {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "upstream_groups.{}: no [upstreams.{}]",
+                    name, member
+                )));
+            }
+        }
+        for rule in &self.rules {
+            self.validate_rule(rule)?;
+        }
+
+        if let Some(adblock) = &self.adblock {
+            if adblock.paths.is_empty() {
+                return Err(ConfigError::InvalidConfig(
+                    "adblock.paths must name at least one filter list".to_string(),
+                ));
+            }
+            if !matches!(adblock.status, 204 | 403) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "adblock.status must be 204 or 403, got {}",
+                    adblock.status
+                )));
+            }
+        }
+
+        if self.auth.backend == AuthBackend::Sqlite {
+            if !cfg!(feature = "sqlite") {
+                return Err(ConfigError::InvalidConfig(
+                    "auth.backend = \"sqlite\" requires building with the `sqlite` feature"
+                        .to_string(),
+                ));
+            }
+            if self.auth.database.as_deref().is_none_or(str::is_empty) {
+                return Err(ConfigError::InvalidConfig(
+                    "auth.database is required by the sqlite backend".to_string(),
+                ));
+            }
+        }
+        if self.auth.backend == AuthBackend::Webhook {
+            let Some(webhook) = &self.auth.webhook else {
+                return Err(ConfigError::InvalidConfig(
+                    "[auth.webhook] is required by the webhook backend".to_string(),
+                ));
+            };
+            let url = url::Url::parse(&webhook.url).ok();
+            if !url
+                .is_some_and(|url| url.host().is_some() && matches!(url.scheme(), "http" | "https"))
+            {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "auth.webhook.url must be an http:// or https:// URL: {}",
+                    webhook.url
+                )));
+            }
+            if webhook.timeout == 0 {
+                return Err(ConfigError::InvalidConfig(
+                    "auth.webhook.timeout must be greater than 0".to_string(),
+                ));
+            }
+        }
+        if let Some(quotas) = &self.quotas {
+            if quotas.save_interval == 0 {
+                return Err(ConfigError::InvalidConfig(
+                    "quotas.save_interval must be greater than 0".to_string(),
+                ));
+            }
+            if quotas.action == QuotaAction::Throttle && quotas.throttle_rate == 0 {
+                return Err(ConfigError::InvalidConfig(
+                    "quotas.throttle_rate must be greater than 0".to_string(),
+                ));
+            }
+        }
+        if let Some(bandwidth) = &self.bandwidth
+            && bandwidth.burst == 0
+        {
+            return Err(ConfigError::InvalidConfig(
+                "bandwidth.burst must be greater than 0".to_string(),
+            ));
+        }
+        if let Some(limits) = &self.client_limits
+            && limits.connections_per_second > 0
+            && limits.burst == 0
+        {
+            return Err(ConfigError::InvalidConfig(
+                "client_limits.burst must be greater than 0 with connections_per_second"
+                    .to_string(),
+            ));
+        }
+        if let Some(queue) = &self.accept_queue
+            && (queue.max_waiting == 0 || queue.wait_timeout == 0)
+        {
+            return Err(ConfigError::InvalidConfig(
+                "accept_queue.max_waiting and accept_queue.wait_timeout must be greater than 0"
+                    .to_string(),
+            ));
+        }
+        if let Some(lockout) = &self.lockout
+            && (lockout.window == 0 || lockout.ban_duration == 0)
+        {
+            return Err(ConfigError::InvalidConfig(
+                "lockout.window and lockout.ban_duration must be greater than 0".to_string(),
+            ));
+        }
+        if self.auth.backend != AuthBackend::Config
+            && (!self.users.is_empty() || self.users_file.is_some())
+        {
+            return Err(ConfigError::InvalidConfig(format!(
+                "[users] and users_file cannot be used with the {} auth backend",
+                self.auth.backend.name()
+            )));
+        }
+        if self.auth.backend != AuthBackend::Config && !self.groups.is_empty() {
+            return Err(ConfigError::InvalidConfig(format!(
+                "[groups] cannot be used with the {} auth backend",
+                self.auth.backend.name()
+            )));
+        }
+        if let Some((name, _)) = self
+            .groups
+            .iter()
+            .find(|(_, group)| group.members.is_empty())
+        {
+            return Err(ConfigError::InvalidConfig(format!(
+                "groups.{}.members must name at least one user",
+                name
+            )));
+        }
+
+        if let Some(sniff) = &self.sniff
+            && sniff.timeout == 0
+        {
+            return Err(ConfigError::InvalidConfig(
+                "sniff.timeout must be greater than 0".to_string(),
+            ));
+        }
+
+        if let Some(hosts) = &self.hosts {
+            for (domain, addrs) in &hosts.entries {
+                if addrs.0.is_empty() {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "hosts.entries.\"{}\" must list at least one address",
+                        domain
+                    )));
+                }
+            }
+        }
+
+        if let Some(mitm) = &self.mitm {
+            if !cfg!(feature = "mitm") {
+                return Err(ConfigError::InvalidConfig(
+                    "[mitm] requires building with the `mitm` feature".to_string(),
+                ));
+            }
+            if mitm.ca_cert_path.is_empty() || mitm.ca_key_path.is_empty() {
+                return Err(ConfigError::InvalidConfig(
+                    "mitm.ca_cert_path and mitm.ca_key_path must be set".to_string(),
+                ));
+            }
+        }
+
+        if let Some(quic) = &self.quic {
+            if quic.listen_address.parse::<std::net::SocketAddr>().is_err() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Invalid quic.listen_address: {}",
+                    quic.listen_address
+                )));
+            }
+            if self.tls.is_none() {
+                return Err(ConfigError::InvalidConfig(
+                    "[quic] requires [tls] for its certificate".to_string(),
+                ));
+            }
+            if quic
+                .alpn
+                .iter()
+                .any(|protocol| protocol.is_empty() || protocol.len() > 255)
+            {
+                return Err(ConfigError::InvalidConfig(
+                    "quic.alpn protocol names must be 1 to 255 bytes long".to_string(),
+                ));
+            }
+        }
+
+        if let Some(transparent) = &self.transparent {
+            match transparent.listen_address.parse::<std::net::SocketAddr>() {
+                Ok(addr) if self.listen_address.parse() == Ok(addr) => {
+                    return Err(ConfigError::InvalidConfig(
+                        "transparent.listen_address must differ from listen_address".to_string(),
+                    ));
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "Invalid transparent.listen_address: {}",
+                        transparent.listen_address
+                    )));
+                }
+            }
+            if transparent.spoof_source && transparent.mode != TransparentMode::Tproxy {
+                return Err(ConfigError::InvalidConfig(
+                    "transparent.spoof_source requires mode = \"tproxy\"".to_string(),
+                ));
+            }
+            // Spoofed connections go straight to the target
+            if transparent.spoof_source && self.upstream.is_some() {
+                return Err(ConfigError::InvalidConfig(
+                    "transparent.spoof_source cannot be combined with [upstream]".to_string(),
+                ));
+            }
+        }
+
+        if let Some(tun) = &self.tun {
+            if !cfg!(feature = "tun") {
+                return Err(ConfigError::InvalidConfig(
+                    "[tun] requires building with the `tun` feature".to_string(),
+                ));
+            }
+            // IFNAMSIZ is 16, including the terminating NUL
+            if tun.name.is_empty() || tun.name.len() > 15 {
+                return Err(ConfigError::InvalidConfig(
+                    "tun.name must be 1 to 15 bytes long".to_string(),
+                ));
+            }
+            if !(576..=65535).contains(&tun.mtu) {
+                return Err(ConfigError::InvalidConfig(
+                    "tun.mtu must be between 576 and 65535".to_string(),
+                ));
+            }
+        }
+
+        if let Some(shadowsocks) = &self.shadowsocks {
+            match shadowsocks.listen_address.parse::<std::net::SocketAddr>() {
+                Ok(addr) if self.listen_address.parse() == Ok(addr) => {
+                    return Err(ConfigError::InvalidConfig(
+                        "shadowsocks.listen_address must differ from listen_address".to_string(),
+                    ));
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "Invalid shadowsocks.listen_address: {}",
+                        shadowsocks.listen_address
+                    )));
+                }
+            }
+            // A shared key alongside users would let anyone holding it
+            // bypass user authentication
+            let has_password = shadowsocks.password.as_ref().is_some_and(|p| !p.is_empty());
+            if self.auth.backend != AuthBackend::Config {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "[shadowsocks] needs plain passwords, which the {} auth backend does not provide",
+                    self.auth.backend.name()
+                )));
+            }
+            let has_users = !self.users.is_empty() || self.users_file.is_some();
+            if has_password == has_users {
+                return Err(ConfigError::InvalidConfig(
+                    "shadowsocks.password is required without [users] or users_file and not allowed with them"
+                        .to_string(),
+                ));
+            }
+        }
+
+        for rule in &self.http.header_rules {
+            validate_header_rule(rule)?;
+        }
+        if self
+            .http
+            .body_rules
+            .iter()
+            .any(|rule| rule.pattern.is_empty())
+        {
+            return Err(ConfigError::InvalidConfig(
+                "http.body_rules entries need a non-empty pattern".to_string(),
+            ));
+        }
+        if self.http.max_concurrent_streams == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "http.max_concurrent_streams must be greater than 0".to_string(),
+            ));
+        }
+        if self.http.max_filtered_body == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "http.max_filtered_body must be greater than 0".to_string(),
+            ));
+        }
+
+        if self
+            .proxy_protocol
+            .iter()
+            .any(|rule| rule.host.is_empty() || rule.port == Some(0))
+        {
+            return Err(ConfigError::InvalidConfig(
+                "proxy_protocol entries need a host and a non-zero port".to_string(),
+            ));
+        }
+
+        for forward in &self.forward {
+            match forward.listen_address.parse::<std::net::SocketAddr>() {
+                Ok(addr) if self.listen_address.parse() == Ok(addr) => {
+                    return Err(ConfigError::InvalidConfig(
+                        "forward.listen_address must differ from listen_address".to_string(),
+                    ));
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "Invalid forward.listen_address: {}",
+                        forward.listen_address
+                    )));
+                }
+            }
+            if !is_host_port(&forward.target) {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Invalid forward.target: {}",
+                    forward.target
+                )));
+            }
+        }
+
+        if let Some(gateway) = &self.gateway {
+            match gateway.listen_address.parse::<std::net::SocketAddr>() {
+                Ok(addr) if self.listen_address.parse() == Ok(addr) => {
+                    return Err(ConfigError::InvalidConfig(
+                        "gateway.listen_address must differ from listen_address".to_string(),
+                    ));
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "Invalid gateway.listen_address: {}",
+                        gateway.listen_address
+                    )));
+                }
+            }
+            if gateway.routes.is_empty() {
+                return Err(ConfigError::InvalidConfig(
+                    "[gateway] requires at least one route".to_string(),
+                ));
+            }
+            for route in &gateway.routes {
+                if route.host.is_empty() {
+                    return Err(ConfigError::InvalidConfig(
+                        "gateway.routes entries need a host".to_string(),
+                    ));
+                }
+                if !is_host_port(&route.backend) {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "Invalid gateway.routes backend: {}",
+                        route.backend
+                    )));
+                }
+                if route.tls && route.ca_path.is_none() {
+                    return Err(ConfigError::InvalidConfig(format!(
+                        "gateway route for {} needs ca_path with tls enabled",
+                        route.host
+                    )));
+                }
+            }
+        }
+
+        if self.resolver.timeout == 0 {
+            return Err(ConfigError::InvalidConfig(
+                "resolver.timeout must be greater than 0".to_string(),
+            ));
+        }
+
+        if let Some(dns) = &self.dns {
+            if dns.listen_address.parse::<std::net::SocketAddr>().is_err() {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "Invalid dns.listen_address: {}",
+                    dns.listen_address
+                )));
+            }
+            let upstream = url::Url::parse(&dns.upstream).ok();
+            if !upstream
+                .is_some_and(|url| url.host().is_some() && matches!(url.scheme(), "https" | "tls"))
+            {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "dns.upstream must be an https:// or tls:// URL: {}",
+                    dns.upstream
+                )));
+            }
+            if dns.timeout == 0 {
+                return Err(ConfigError::InvalidConfig(
+                    "dns.timeout must be greater than 0".to_string(),
+                ));
+            }
+            if let Some(fake_ip) = &dns.fake_ip
+                && fake_ip.range.prefix_len() > 30
+            {
+                return Err(ConfigError::InvalidConfig(format!(
+                    "dns.fake_ip.range is too small: {}",
+                    fake_ip.range
+                )));
+            }
+        }
+
+        if let Some(nat64) = &self.nat64
+            && ![32, 40, 48, 56, 64, 96].contains(&nat64.prefix.prefix_len())
+        {
+            return Err(ConfigError::InvalidConfig(
+                "nat64.prefix must be a /32, /40, /48, /56, /64 or /96".to_string(),
+            ));
+        }
+
+        if self.cache.enabled
+            && (self.cache.max_entry_size > self.cache.max_size
+                || (self.cache.dir.is_some()
+                    && self.cache.max_entry_size > self.cache.max_disk_size))
+        {
+            return Err(ConfigError::InvalidConfig(
+                "cache.max_entry_size must not exceed cache.max_size or cache.max_disk_size"
+                    .to_string(),
+            ));
+        }
+
         Ok(())
     }
+
+    fn validate_rule(&self, rule: &RuleConfig) -> Result<(), ConfigError> {
+        let invalid =
+            |reason: String| Err(ConfigError::InvalidConfig(format!("rules: {}", reason)));
+        if let Some(pattern) = &rule.domain_regex
+            && let Err(e) = regex::Regex::new(pattern)
+        {
+            return invalid(format!("invalid domain_regex {}: {}", pattern, e));
+        }
+        let has_domain =
+            rule.domain.is_some() || rule.domain_suffix.is_some() || rule.domain_regex.is_some();
+        // A target is either a domain or an IP address, never both
+        if has_domain && rule.cidr.is_some() {
+            return invalid("a rule cannot match both a domain and a cidr".to_string());
+        }
+        if rule.action != RuleAction::Direct
+            && (rule.bind_address.is_some() || rule.interface.is_some())
+        {
+            return invalid("only direct rules take a bind_address or interface".to_string());
+        }
+        if rule.interface.as_ref().is_some_and(|name| name.is_empty()) {
+            return invalid("interface must not be empty".to_string());
+        }
+        match (rule.action, &rule.upstream) {
+            (RuleAction::Proxy, Some(name))
+                if !self.upstreams.contains_key(name)
+                    && !self.upstream_groups.contains_key(name) =>
+            {
+                invalid(format!(
+                    "no [upstreams.{}] or [upstream_groups.{}] for a proxy rule",
+                    name, name
+                ))
+            }
+            (RuleAction::Proxy, None) if self.upstream.is_none() => {
+                invalid("a proxy rule without upstream requires [upstream]".to_string())
+            }
+            (RuleAction::Direct | RuleAction::Block, Some(_)) => {
+                invalid("only proxy rules take an upstream".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether `target` is a `host:port` with a host and a non-zero port.
+fn is_host_port(target: &str) -> bool {
+    match TargetAddr::parse(target) {
+        Some(TargetAddr::Domain(host, _)) if host.is_empty() => false,
+        Some(target) => target.port() != 0,
+        None => false,
+    }
+}
+
+/// Checks an `[upstream]` or `[upstreams]` entry, called `name` in errors.
+fn validate_upstream(name: &str, upstream: &UpstreamConfig) -> Result<(), ConfigError> {
+    if upstream.address.rsplit_once(':').is_none() {
+        return Err(ConfigError::InvalidConfig(format!(
+            "{}.address must be host:port, got {}",
+            name, upstream.address
+        )));
+    }
+    if upstream.protocol == UpstreamProtocol::Shadowsocks {
+        if upstream.password.as_ref().is_none_or(String::is_empty)
+            || upstream.username.is_some()
+            || upstream.tls
+        {
+            return Err(ConfigError::InvalidConfig(
+                "a shadowsocks upstream takes a password, and no username or tls".to_string(),
+            ));
+        }
+    } else if upstream.protocol == UpstreamProtocol::Ssh {
+        if upstream.username.is_none()
+            || (upstream.password.is_none() && upstream.private_key_path.is_none())
+            || upstream.tls
+        {
+            return Err(ConfigError::InvalidConfig(
+                "an ssh upstream takes a username and a password or private_key_path, and no tls"
+                    .to_string(),
+            ));
+        }
+        // The jump host sees all tunneled traffic, so it must be pinned
+        if !upstream
+            .host_key
+            .as_ref()
+            .is_some_and(|key| key.starts_with("SHA256:"))
+        {
+            return Err(ConfigError::InvalidConfig(format!(
+                "an ssh upstream requires {}.host_key (SHA256:...)",
+                name
+            )));
+        }
+        if upstream.max_channels == 0 {
+            return Err(ConfigError::InvalidConfig(format!(
+                "{}.max_channels must be greater than 0",
+                name
+            )));
+        }
+    } else if upstream.username.is_some() != upstream.password.is_some() {
+        return Err(ConfigError::InvalidConfig(format!(
+            "{0}.username and {0}.password must be set together",
+            name
+        )));
+    }
+    // RFC 1929 carries each field with a one-byte length
+    if upstream.protocol == UpstreamProtocol::Socks5
+        && [&upstream.username, &upstream.password]
+            .into_iter()
+            .flatten()
+            .any(|field| field.is_empty() || field.len() > 255)
+    {
+        return Err(ConfigError::InvalidConfig(format!(
+            "{0}.username and {0}.password must be 1 to 255 bytes for a SOCKS5 parent",
+            name
+        )));
+    }
+    if upstream.tls && upstream.ca_path.is_none() {
+        return Err(ConfigError::InvalidConfig(format!(
+            "{0}.ca_path is required when {0}.tls is enabled",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn validate_header_rule(rule: &HeaderRule) -> Result<(), ConfigError> {
+    let invalid = |reason: &str| {
+        Err(ConfigError::InvalidConfig(format!(
+            "http.header_rules entry for {}: {}",
+            rule.name, reason
+        )))
+    };
+    // Rewriting these would desynchronize the framing the proxy relays
+    const PROTECTED: &[&str] = &["host", "content-length", "transfer-encoding", "connection"];
+    if rule.name.is_empty() || rule.name.contains([':', ' ', '\r', '\n']) {
+        return invalid("name must be a header field name");
+    }
+    if PROTECTED.contains(&rule.name.to_ascii_lowercase().as_str()) {
+        return invalid("this header is managed by the proxy");
+    }
+    let needs_value = rule.action != HeaderAction::Remove;
+    if needs_value != rule.value.is_some() {
+        return invalid("value must be set for set, add and replace, and only for them");
+    }
+    if (rule.action == HeaderAction::Replace)
+        != rule.pattern.as_ref().is_some_and(|p| !p.is_empty())
+    {
+        return invalid("a non-empty pattern must be set for replace, and only for it");
+    }
+    if rule
+        .value
+        .iter()
+        .chain(&rule.pattern)
+        .any(|text| text.contains(['\r', '\n']))
+    {
+        return invalid("value and pattern must not contain line breaks");
+    }
+    if let Some(pattern) = &rule.pattern {
+        match regex::Regex::new(pattern) {
+            Ok(regex) => {
+                let _ = rule.regex.set(regex);
+            }
+            Err(e) => return invalid(&format!("invalid pattern {}: {}", pattern, e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Config {
+        config::Config::builder()
+            .add_source(config::File::from_str(toml, config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn invalid(toml: &str) -> String {
+        match parse(toml).validate() {
+            Err(ConfigError::InvalidConfig(message)) => message,
+            other => panic!("{:?} for {}", other, toml),
+        }
+    }
+
+    #[test]
+    fn test_validate_body_rules() {
+        let config = parse(
+            "[http]\ndecompress_bodies = true\n\
+             [[http.body_rules]]\ncontent_type = \"text/html\"\npattern = \"ad.js\"\n",
+        );
+        assert!(config.validate().is_ok());
+        assert_eq!(config.http.body_rules[0].replacement, "");
+        assert_eq!(config.http.max_filtered_body, 4 * 1024 * 1024);
+
+        assert_eq!(
+            invalid("[[http.body_rules]]\npattern = \"\"\n"),
+            "http.body_rules entries need a non-empty pattern"
+        );
+        assert_eq!(
+            invalid("[http]\nmax_filtered_body = 0\n"),
+            "http.max_filtered_body must be greater than 0"
+        );
+    }
+
+    #[test]
+    fn test_validate_header_rules() {
+        let rule = "[[http.header_rules]]\ndirection = \"request\"\naction = \"replace\"\n\
+                    name = \"Cookie\"\nvalue = \"$1\"\n";
+        let config = parse(&format!("{}pattern = \"id=(\\\\d+)\"\n", rule));
+        assert!(config.validate().is_ok());
+        assert!(config.http.header_rules[0].regex.get().is_some());
+
+        assert!(
+            invalid(&format!("{}pattern = \"id=(\"\n", rule))
+                .starts_with("http.header_rules entry for Cookie: invalid pattern id=(: ")
+        );
+    }
+
+    #[test]
+    fn test_validate_acme() {
+        let config = parse("[tls.acme]\ndomains = [\"proxy.example.com\"]\n");
+        assert!(config.validate().is_ok());
+        let acme = config.tls.unwrap().acme.unwrap();
+        assert_eq!(
+            acme.directory_url,
+            "https://acme-v02.api.letsencrypt.org/directory"
+        );
+        assert_eq!(acme.certs_dir, "certs/acme");
+        assert_eq!(acme.http01_listen, "0.0.0.0:80");
+        assert_eq!(acme.renew_before, 30);
+
+        assert_eq!(
+            invalid("[tls]\nalpn = [\"h2\"]\n"),
+            "tls.cert_path and tls.key_path must be set unless tls.acme is"
+        );
+        assert_eq!(
+            invalid("[tls.acme]\ndomains = []\n"),
+            "tls.acme.domains must list at least one domain"
+        );
+        assert_eq!(
+            invalid("[tls.acme]\ndomains = [\"a.example\"]\nhttp01_listen = \"80\"\n"),
+            "Invalid tls.acme.http01_listen: 80"
+        );
+        assert_eq!(
+            invalid(
+                "[tls.acme]\ndomains = [\"a.example\"]\ndirectory_url = \"http://ca.example/dir\"\n"
+            ),
+            "tls.acme.directory_url must be an https:// URL"
+        );
+        assert_eq!(
+            invalid(
+                "[[listeners]]\nlisten_address = \"127.0.0.1:1443\"\n\
+                 [listeners.tls.acme]\ndomains = [\"a.example\"]\n"
+            ),
+            "acme is only supported in [tls], not in listeners.tls"
+        );
+    }
+
+    #[test]
+    fn test_validate_runtime() {
+        let config = parse("[runtime]\nworker_threads = 2\n");
+        assert!(config.validate().is_ok());
+        assert_eq!(config.runtime.max_blocking_threads, 512);
+        assert_eq!(config.runtime.thread_name, "rust-proxy");
+
+        assert_eq!(
+            invalid("[runtime]\nmax_blocking_threads = 0\n"),
+            "runtime.max_blocking_threads must be greater than 0"
+        );
+        assert_eq!(
+            invalid("[runtime]\nthread_name = \"\"\n"),
+            "runtime.thread_name must not be empty"
+        );
+    }
 }
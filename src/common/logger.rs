@@ -1,4 +1,4 @@
-use crate::common::config::LoggerConfig;
+use crate::common::config::{AccessLogConfig, LoggerConfig};
 use log::{LevelFilter, debug, error, info, trace, warn};
 use log4rs::{
     append::{
@@ -10,7 +10,7 @@ use log4rs::{
             },
         },
     },
-    config::{Appender, Config, Root},
+    config::{Appender, Config, Logger, Root},
     encode::pattern::PatternEncoder,
     filter::threshold::ThresholdFilter,
 };
@@ -18,41 +18,52 @@ use std::fs;
 use std::path::Path;
 use std::str::FromStr;
 
-pub fn setup_logger(config: LoggerConfig) -> Result<log4rs::Handle, Box<dyn std::error::Error>> {
+pub fn setup_logger(
+    config: LoggerConfig,
+    access_log: AccessLogConfig,
+) -> Result<log4rs::Handle, Box<dyn std::error::Error>> {
     let level = LevelFilter::from_str(&config.level).unwrap_or(LevelFilter::Info);
-    let trigger_file_size = config.file_size * 1024 * 1024;
 
     let stderr = ConsoleAppender::builder().target(Target::Stderr).build();
 
-    let trigger = SizeTrigger::new(trigger_file_size);
-    let roller = FixedWindowRoller::builder()
-        .base(0)
-        .build(&config.archive_pattern, config.file_count)?;
-    let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
-
-    if let Some(parent) = Path::new(&config.path).parent() {
-        fs::create_dir_all(parent)?;
-    }
-
-    let logfile = RollingFileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new(
-            "{d(%Y-%m-%d %H:%M:%S)} - {l} - {m}\n",
-        )))
-        .build(&config.path, Box::new(policy))?;
+    let logfile = rolling_file(
+        &config,
+        &config.path,
+        &config.archive_pattern,
+        "{d(%Y-%m-%d %H:%M:%S)} - {l} - {m}\n",
+    )?;
 
-    let runtime_config = Config::builder()
+    let mut runtime_config = Config::builder()
         .appender(Appender::builder().build("logfile", Box::new(logfile)))
         .appender(
             Appender::builder()
                 .filter(Box::new(ThresholdFilter::new(level)))
                 .build("stderr", Box::new(stderr)),
-        )
-        .build(
-            Root::builder()
-                .appender("logfile")
-                .appender("stderr")
-                .build(level),
-        )?;
+        );
+
+    // Access log entries only ever go to their own file, whatever the level
+    let access_logger = Logger::builder().additive(false);
+    runtime_config = match &access_log.path {
+        Some(path) => {
+            let accessfile = rolling_file(&config, path, &access_log.archive_pattern, "{m}{n}")?;
+            runtime_config
+                .appender(Appender::builder().build("accessfile", Box::new(accessfile)))
+                .logger(
+                    access_logger
+                        .appender("accessfile")
+                        .build(crate::proxy::http::access_log::TARGET, LevelFilter::Info),
+                )
+        }
+        None => runtime_config
+            .logger(access_logger.build(crate::proxy::http::access_log::TARGET, LevelFilter::Off)),
+    };
+
+    let runtime_config = runtime_config.build(
+        Root::builder()
+            .appender("logfile")
+            .appender("stderr")
+            .build(level),
+    )?;
 
     let handle = log4rs::init_config(runtime_config)?;
 
@@ -70,5 +81,31 @@ pub fn setup_logger(config: LoggerConfig) -> Result<log4rs::Handle, Box<dyn std:
         config.path, config.archive_pattern
     );
 
+    if let Some(path) = &access_log.path {
+        info!("Access log file: '{}'", path);
+    }
+
     Ok(handle)
 }
+
+/// File appender rotated by size, per `log.file_size` and `log.file_count`.
+fn rolling_file(
+    config: &LoggerConfig,
+    path: &str,
+    archive_pattern: &str,
+    pattern: &str,
+) -> Result<RollingFileAppender, Box<dyn std::error::Error>> {
+    let trigger = SizeTrigger::new(config.file_size * 1024 * 1024);
+    let roller = FixedWindowRoller::builder()
+        .base(0)
+        .build(archive_pattern, config.file_count)?;
+    let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
+
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    Ok(RollingFileAppender::builder()
+        .encoder(Box::new(PatternEncoder::new(pattern)))
+        .build(path, Box::new(policy))?)
+}
@@ -1,3 +1,8 @@
+pub mod acme;
 pub mod auth;
 pub mod config;
 pub mod logger;
+pub mod signal;
+#[cfg(feature = "sqlite")]
+pub mod user_db;
+pub mod webhook_auth;
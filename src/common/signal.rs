@@ -0,0 +1,38 @@
+//! SIGHUP, on which the files the proxy watches are reloaded.
+
+/// SIGHUP, which never arrives on platforms without it.
+#[cfg(unix)]
+pub fn hangup_signal() -> HangupSignal {
+    use tokio::signal::unix::{SignalKind, signal};
+    HangupSignal(signal(SignalKind::hangup()).ok())
+}
+
+#[cfg(unix)]
+pub struct HangupSignal(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl HangupSignal {
+    pub async fn recv(&mut self) {
+        match &mut self.0 {
+            Some(signal) => {
+                signal.recv().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn hangup_signal() -> HangupSignal {
+    HangupSignal
+}
+
+#[cfg(not(unix))]
+pub struct HangupSignal;
+
+#[cfg(not(unix))]
+impl HangupSignal {
+    pub async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
@@ -0,0 +1,263 @@
+//! The `sqlite` auth backend, behind the `sqlite` feature: users, their
+//! bcrypt hashes, quotas and whether they are enabled, kept in an SQLite
+//! database that `rust-proxy user` edits while the proxy runs. Every
+//! authentication reads the database, so changes apply to the next one.
+
+use async_trait::async_trait;
+use log::info;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::common::auth::{AuthContext, AuthDecision, AuthError, AuthProvider, verify_password};
+
+/// How long a query waits for `rust-proxy user` to finish writing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY NOT NULL,
+    password_hash TEXT NOT NULL,
+    enabled INTEGER NOT NULL DEFAULT 1,
+    quota INTEGER
+)";
+
+#[derive(Error, Debug)]
+pub enum UserDbError {
+    #[error("Database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("No user '{0}'")]
+    NoSuchUser(String),
+    #[error("User '{0}' already exists")]
+    UserExists(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserRecord {
+    pub username: String,
+    pub password_hash: String,
+    pub enabled: bool,
+    /// Bytes the user may transfer; `None` for no limit
+    pub quota: Option<u64>,
+}
+
+pub struct UserDb {
+    conn: Mutex<Connection>,
+}
+
+impl UserDb {
+    /// Opens the database at `path`, creating it and its table if missing.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, UserDbError> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute(SCHEMA, [])?;
+        Ok(UserDb {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn add(
+        &self,
+        username: &str,
+        password_hash: &str,
+        quota: Option<u64>,
+    ) -> Result<(), UserDbError> {
+        let quota = quota.map(|quota| i64::try_from(quota).unwrap_or(i64::MAX));
+        let added = self.conn.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO users (username, password_hash, quota) VALUES (?1, ?2, ?3)",
+            params![username, password_hash, quota],
+        )?;
+        if added == 0 {
+            return Err(UserDbError::UserExists(username.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, username: &str) -> Result<(), UserDbError> {
+        self.update(
+            username,
+            "DELETE FROM users WHERE username = ?1",
+            params![username],
+        )
+    }
+
+    pub fn set_password(&self, username: &str, password_hash: &str) -> Result<(), UserDbError> {
+        self.update(
+            username,
+            "UPDATE users SET password_hash = ?2 WHERE username = ?1",
+            params![username, password_hash],
+        )
+    }
+
+    pub fn set_enabled(&self, username: &str, enabled: bool) -> Result<(), UserDbError> {
+        self.update(
+            username,
+            "UPDATE users SET enabled = ?2 WHERE username = ?1",
+            params![username, enabled],
+        )
+    }
+
+    pub fn get(&self, username: &str) -> Result<Option<UserRecord>, UserDbError> {
+        let user = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT username, password_hash, enabled, quota FROM users WHERE username = ?1",
+                params![username],
+                user_record,
+            )
+            .optional()?;
+        Ok(user)
+    }
+
+    /// Every user, by name.
+    pub fn list(&self) -> Result<Vec<UserRecord>, UserDbError> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT username, password_hash, enabled, quota FROM users ORDER BY username",
+        )?;
+        let users = statement
+            .query_map([], user_record)?
+            .collect::<Result<_, _>>()?;
+        Ok(users)
+    }
+
+    /// Runs a statement on the row of `username`, which must exist.
+    fn update(
+        &self,
+        username: &str,
+        sql: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> Result<(), UserDbError> {
+        if self.conn.lock().unwrap().execute(sql, params)? == 0 {
+            return Err(UserDbError::NoSuchUser(username.to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn user_record(row: &rusqlite::Row) -> rusqlite::Result<UserRecord> {
+    Ok(UserRecord {
+        username: row.get(0)?,
+        password_hash: row.get(1)?,
+        enabled: row.get(2)?,
+        quota: row
+            .get::<_, Option<i64>>(3)?
+            .map(|quota| u64::try_from(quota).unwrap_or(0)),
+    })
+}
+
+/// Authenticates clients against a `UserDb`. Disabled users are refused.
+pub struct SqliteAuth {
+    db: Arc<UserDb>,
+}
+
+impl SqliteAuth {
+    pub fn new(db: UserDb) -> Self {
+        SqliteAuth { db: Arc::new(db) }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for SqliteAuth {
+    fn is_required(&self) -> bool {
+        true
+    }
+
+    /// The lookup runs inside `spawn_blocking`, the bcrypt comparison in
+    /// `verify_password`.
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        context: &AuthContext,
+    ) -> AuthDecision {
+        let db = self.db.clone();
+        let name = username.to_string();
+        let user = match tokio::task::spawn_blocking(move || db.get(&name)).await {
+            Ok(Ok(user)) => user,
+            Ok(Err(e)) => return AuthDecision::Error(AuthError::Backend(e.to_string())),
+            Err(_) => return AuthDecision::Error(AuthError::AuthenticationFailed),
+        };
+        let verified = match user {
+            Some(user) if user.enabled => verify_password(password, &user.password_hash).await,
+            _ => Ok(false),
+        };
+        match verified {
+            Ok(true) => AuthDecision::Allow,
+            Ok(false) => {
+                info!(
+                    "Wrong credentials or disabled user '{}' from {} over {:?}",
+                    username, context.client, context.protocol
+                );
+                AuthDecision::Deny
+            }
+            Err(e) => AuthDecision::Error(e),
+        }
+    }
+
+    fn certificate_user(&self, identity: Option<String>) -> Option<String> {
+        identity.filter(|name| matches!(self.db.get(name), Ok(Some(user)) if user.enabled))
+    }
+
+    fn quota(&self, username: &str) -> Option<u64> {
+        self.db
+            .get(username)
+            .ok()
+            .flatten()
+            .and_then(|user| user.quota)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::config::ListenerProtocol;
+
+    #[tokio::test]
+    async fn test_sqlite_auth() {
+        let db = UserDb::open(":memory:").unwrap();
+        db.add("alice", &bcrypt::hash("secret", 4).unwrap(), Some(1 << 30))
+            .unwrap();
+        assert!(matches!(
+            db.add("alice", "x", None),
+            Err(UserDbError::UserExists(_))
+        ));
+        assert!(matches!(
+            db.set_enabled("bob", false),
+            Err(UserDbError::NoSuchUser(_))
+        ));
+        assert_eq!(db.list().unwrap()[0].quota, Some(1 << 30));
+
+        let auth = SqliteAuth::new(db);
+        let context = AuthContext {
+            client: "127.0.0.1:50000".parse().unwrap(),
+            protocol: ListenerProtocol::Socks5,
+        };
+        let allows = |password: &'static str| {
+            let (auth, context) = (&auth, &context);
+            async move {
+                matches!(
+                    auth.authenticate("alice", password, context).await,
+                    AuthDecision::Allow
+                )
+            }
+        };
+        assert!(allows("secret").await);
+        assert!(!allows("wrong").await);
+
+        // Changes apply to the next authentication
+        auth.db
+            .set_password("alice", &bcrypt::hash("changed", 4).unwrap())
+            .unwrap();
+        assert!(!allows("secret").await);
+        assert!(allows("changed").await);
+        auth.db.set_enabled("alice", false).unwrap();
+        assert!(!allows("changed").await);
+        assert_eq!(auth.certificate_user(Some("alice".to_string())), None);
+        auth.db.remove("alice").unwrap();
+        assert!(auth.db.list().unwrap().is_empty());
+    }
+}
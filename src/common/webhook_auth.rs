@@ -0,0 +1,235 @@
+//! The `webhook` auth backend: each authentication is POSTed as JSON to an
+//! HTTP endpoint, which allows it with 200 and refuses it with 403. Allowed
+//! credentials are remembered for `cache_ttl` seconds, so a busy client does
+//! not cost the endpoint a request per connection.
+
+use async_trait::async_trait;
+use log::{info, warn};
+use ring::digest::{Digest, SHA256, digest};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::common::auth::{AuthContext, AuthDecision, AuthError, AuthProvider};
+use crate::common::config::{ListenerProtocol, WebhookConfig};
+use crate::proxy::dialer::DirectDialer;
+use crate::proxy::http::client::HttpClient;
+
+/// Cached credentials beyond which expired entries are swept out
+const CACHE_SWEEP_SIZE: usize = 4096;
+
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    username: &'a str,
+    password: &'a str,
+    client: IpAddr,
+    protocol: ListenerProtocol,
+}
+
+/// Authenticates clients by asking the `auth.webhook` endpoint.
+pub struct WebhookAuth {
+    url: Url,
+    client: HttpClient,
+    dialer: DirectDialer,
+    retries: u32,
+    cache_ttl: Duration,
+    /// Password digest and expiry of recently allowed users
+    cache: Mutex<HashMap<String, (Digest, Instant)>>,
+}
+
+impl WebhookAuth {
+    pub fn new(config: &WebhookConfig) -> Result<Self, AuthError> {
+        let invalid = |e: String| AuthError::Backend(format!("{}: {}", config.url, e));
+        let url = Url::parse(&config.url).map_err(|e| invalid(e.to_string()))?;
+        let timeout = Duration::from_secs(config.timeout);
+        let mut client = HttpClient::new(timeout);
+        if url.scheme() == "https" {
+            client = client
+                .with_tls(config.ca_path.as_deref())
+                .map_err(|e| invalid(e.to_string()))?;
+        }
+        Ok(WebhookAuth {
+            url,
+            client,
+            dialer: DirectDialer::new(timeout),
+            retries: config.retries,
+            cache_ttl: Duration::from_secs(config.cache_ttl),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn is_cached(&self, username: &str, password: &Digest) -> bool {
+        let cache = self.cache.lock().unwrap();
+        cache.get(username).is_some_and(|(cached, expires)| {
+            cached.as_ref() == password.as_ref() && *expires > Instant::now()
+        })
+    }
+
+    fn remember(&self, username: &str, password: Digest) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        if cache.len() >= CACHE_SWEEP_SIZE {
+            cache.retain(|_, (_, expires)| *expires > now);
+        }
+        cache.insert(username.to_string(), (password, now + self.cache_ttl));
+    }
+
+    /// Sends one request and returns the status code of the answer.
+    async fn ask(&self, body: &[u8]) -> Result<u16, String> {
+        let response = self
+            .client
+            .send(
+                &self.dialer,
+                "POST",
+                &self.url,
+                &[("Content-Type", "application/json")],
+                Some(body),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response.status)
+    }
+}
+
+#[async_trait]
+impl AuthProvider for WebhookAuth {
+    fn is_required(&self) -> bool {
+        true
+    }
+
+    /// Asks the endpoint, retrying failures and answers other than 200 and
+    /// 403 up to `retries` times.
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        context: &AuthContext,
+    ) -> AuthDecision {
+        let password_digest = digest(&SHA256, password.as_bytes());
+        if self.is_cached(username, &password_digest) {
+            return AuthDecision::Allow;
+        }
+        let body = serde_json::to_vec(&WebhookRequest {
+            username,
+            password,
+            client: context.client.ip(),
+            protocol: context.protocol,
+        })
+        .unwrap_or_default();
+        let mut error = String::new();
+        for attempt in 0..=self.retries {
+            match self.ask(&body).await {
+                Ok(200) => {
+                    self.remember(username, password_digest);
+                    return AuthDecision::Allow;
+                }
+                Ok(403) => {
+                    info!(
+                        "Auth webhook refused '{}' from {} over {:?}",
+                        username, context.client, context.protocol
+                    );
+                    return AuthDecision::Deny;
+                }
+                Ok(status) => error = format!("unexpected status {}", status),
+                Err(e) => error = e,
+            }
+            if attempt < self.retries {
+                warn!("Auth webhook attempt {} failed: {}", attempt + 1, error);
+            }
+        }
+        AuthDecision::Error(AuthError::Backend(format!("auth webhook: {}", error)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves a webhook allowing alice:secret, failing the first request
+    /// with a 500.
+    async fn spawn_webhook(requests: Arc<AtomicUsize>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0u8; 4096];
+                let n = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..n]).to_string();
+                let status = match requests.fetch_add(1, Ordering::SeqCst) {
+                    0 => "500 Internal Server Error",
+                    _ if request.contains(r#""username":"alice","password":"secret""#) => "200 OK",
+                    _ => "403 Forbidden",
+                };
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_webhook_auth() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let addr = spawn_webhook(requests.clone()).await;
+        let auth = WebhookAuth::new(&WebhookConfig {
+            url: format!("http://{}/auth", addr),
+            timeout: 5,
+            retries: 1,
+            cache_ttl: 60,
+            ca_path: None,
+        })
+        .unwrap();
+        let context = AuthContext {
+            client: "127.0.0.1:50000".parse().unwrap(),
+            protocol: ListenerProtocol::Socks5,
+        };
+
+        // The 500 is retried
+        let decision = auth.authenticate("alice", "secret", &context).await;
+        assert!(matches!(decision, AuthDecision::Allow));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Allowed credentials are cached, others are not
+        let decision = auth.authenticate("alice", "secret", &context).await;
+        assert!(matches!(decision, AuthDecision::Allow));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        let decision = auth.authenticate("alice", "wrong", &context).await;
+        assert!(matches!(decision, AuthDecision::Deny));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let auth = WebhookAuth::new(&WebhookConfig {
+            url: format!("http://{}/auth", addr),
+            timeout: 1,
+            retries: 0,
+            cache_ttl: 0,
+            ca_path: None,
+        })
+        .unwrap();
+        let context = AuthContext {
+            client: "127.0.0.1:50000".parse().unwrap(),
+            protocol: ListenerProtocol::Http,
+        };
+        let decision = auth.authenticate("alice", "secret", &context).await;
+        assert!(matches!(
+            decision,
+            AuthDecision::Error(AuthError::Backend(_))
+        ));
+    }
+}
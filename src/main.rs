@@ -1,12 +1,31 @@
-use crate::common::auth::AuthManager;
-use crate::common::config::Config;
+use crate::common::acme::Acme;
+use crate::common::auth::{self, AuthManager, AuthProvider};
+use crate::common::config::{
+    AuthBackend, Config, RuntimeConfig, TransparentConfig, TransparentMode,
+};
 use crate::common::logger;
+use crate::net::addr::TargetAddr;
+use crate::net::{quic, tls};
+use crate::proxy::bandwidth::Bandwidth;
+use crate::proxy::blocklist::Blocklist;
+use crate::proxy::dns_forwarder::DnsForwarder;
+use crate::proxy::fake_ip::FakeIpPool;
+use crate::proxy::hosts::Hosts;
+use crate::proxy::http::adblock::FilterList;
+use crate::proxy::http::cache::HttpCache;
+use crate::proxy::http::gateway::Gateway;
+use crate::proxy::http::jwt::JwtAuth;
+use crate::proxy::lockout::Lockout;
+use crate::proxy::quota::{QuotaProvider, Quotas};
 use crate::proxy::tcp::TcpProxy;
 use clap::Parser;
 use log::LevelFilter;
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::server::ResolvesServerCert;
 
 mod common;
 mod net;
@@ -55,12 +74,148 @@ struct Args {
     /// Timeout in seconds for connecting to target servers
     #[arg(long, value_name = "SECONDS")]
     connect_timeout: Option<u64>,
+
+    /// Timeout in seconds for clients to finish their handshake
+    #[arg(long, value_name = "SECONDS")]
+    handshake_timeout: Option<u64>,
+
+    /// Threads running connections (default: one per CPU core)
+    #[arg(long, value_name = "COUNT")]
+    worker_threads: Option<usize>,
+
+    /// Maximum number of threads for blocking work
+    #[arg(long, value_name = "COUNT")]
+    max_blocking_threads: Option<usize>,
+
+    /// Name of the runtime's threads, numbered from 0
+    #[arg(long, value_name = "NAME")]
+    thread_name: Option<String>,
+
+    /// Print a bcrypt hash of the password read from stdin, for [users], and exit
+    #[arg(long)]
+    hash_password: bool,
+
+    #[cfg(feature = "sqlite")]
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Manage the users of the sqlite auth backend in `auth.database`
+    User {
+        #[command(subcommand)]
+        action: UserAction,
+    },
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(clap::Subcommand, Debug)]
+enum UserAction {
+    /// Add a user, with the password read from stdin
+    Add {
+        name: String,
+        /// Bytes the user may transfer
+        #[arg(long, value_name = "BYTES")]
+        quota: Option<u64>,
+    },
+    /// Remove a user
+    Del { name: String },
+    /// Change a user's password, read from stdin
+    Passwd { name: String },
+    /// Let a disabled user authenticate again
+    Enable { name: String },
+    /// Refuse a user without removing them
+    Disable { name: String },
+    /// List the users
+    List,
+}
+
+/// A bcrypt hash of the password on the first line of stdin; exits on
+/// failure.
+fn hash_stdin_password() -> String {
+    let mut password = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut password) {
+        eprintln!("Failed to read the password: {}", e);
+        std::process::exit(1);
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        eprintln!("No password on stdin");
+        std::process::exit(1);
+    }
+    match auth::hash_password(password) {
+        Ok(password_hash) => password_hash,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs a `rust-proxy user` command against `auth.database`; exits on
+/// failure.
+#[cfg(feature = "sqlite")]
+fn manage_users(config: &Config, action: UserAction) {
+    use crate::common::user_db::UserDb;
+
+    let Some(path) = config.auth.database.as_deref() else {
+        eprintln!("auth.database is not set");
+        std::process::exit(1);
+    };
+    let db = match UserDb::open(path) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Failed to open user database {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    let result = match action {
+        UserAction::Add { name, quota } => db.add(&name, &hash_stdin_password(), quota),
+        UserAction::Del { name } => db.remove(&name),
+        UserAction::Passwd { name } => db.set_password(&name, &hash_stdin_password()),
+        UserAction::Enable { name } => db.set_enabled(&name, true),
+        UserAction::Disable { name } => db.set_enabled(&name, false),
+        UserAction::List => db.list().map(|users| {
+            for user in users {
+                let quota = user
+                    .quota
+                    .map_or("-".to_string(), |quota| quota.to_string());
+                let state = if user.enabled { "enabled" } else { "disabled" };
+                println!("{}\t{}\tquota {}", user.username, state, quota);
+            }
+        }),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Binds the `[transparent]` listener; TPROXY needs an `IP_TRANSPARENT`
+/// socket.
+async fn bind_transparent(transparent: &TransparentConfig) -> std::io::Result<TcpListener> {
+    match transparent.mode {
+        TransparentMode::Redirect => TcpListener::bind(&transparent.listen_address).await,
+        TransparentMode::Tproxy => {
+            let addr = transparent
+                .listen_address
+                .parse()
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+            net::transparent::bind_tproxy(addr)
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let args = Args::parse();
 
+    if args.hash_password {
+        println!("{}", hash_stdin_password());
+        return;
+    }
+
     let mut config = match Config::from_file(&args.config) {
         Ok(config) => config,
         Err(e) => {
@@ -69,11 +224,36 @@ async fn main() {
         }
     };
 
-    if let Some(listen_address) = args.listen_address {
-        config.listen_address = listen_address;
+    apply_args(&mut config, &args);
+
+    #[cfg(feature = "sqlite")]
+    if let Some(Command::User { action }) = args.command {
+        manage_users(&config, action);
+        return;
+    }
+
+    if let Err(e) = config.validate() {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    }
+
+    let runtime = match build_runtime(&config.runtime) {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to start the async runtime: {}", e);
+            std::process::exit(1);
+        }
+    };
+    runtime.block_on(run(config));
+}
+
+/// Overrides the settings of `config` given on the command line.
+fn apply_args(config: &mut Config, args: &Args) {
+    if let Some(listen_address) = &args.listen_address {
+        config.listen_address = listen_address.clone();
     }
     if args.log_level.to_lowercase() != config.log.level.to_lowercase() {
-        config.log.level = args.log_level;
+        config.log.level = args.log_level.clone();
     }
     if let Some(buffer_size) = args.buffer_size {
         config.buffer_size = buffer_size;
@@ -84,13 +264,37 @@ async fn main() {
     if let Some(connect_timeout) = args.connect_timeout {
         config.connect_timeout = connect_timeout;
     }
+    if let Some(handshake_timeout) = args.handshake_timeout {
+        config.handshake_timeout = handshake_timeout;
+    }
+    if let Some(worker_threads) = args.worker_threads {
+        config.runtime.worker_threads = worker_threads;
+    }
+    if let Some(max_blocking_threads) = args.max_blocking_threads {
+        config.runtime.max_blocking_threads = max_blocking_threads;
+    }
+    if let Some(thread_name) = &args.thread_name {
+        config.runtime.thread_name = thread_name.clone();
+    }
+}
 
-    if let Err(e) = config.validate() {
-        eprintln!("Invalid configuration: {}", e);
-        std::process::exit(1);
+/// A multi-threaded runtime sized by `[runtime]`.
+fn build_runtime(config: &RuntimeConfig) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if config.worker_threads > 0 {
+        builder.worker_threads(config.worker_threads);
     }
+    let name = config.thread_name.clone();
+    let next = AtomicUsize::new(0);
+    builder
+        .max_blocking_threads(config.max_blocking_threads)
+        .thread_name_fn(move || format!("{}-{}", name, next.fetch_add(1, Ordering::Relaxed)))
+        .enable_all()
+        .build()
+}
 
-    if let Err(e) = logger::setup_logger(config.log.clone()) {
+async fn run(config: Config) {
+    if let Err(e) = logger::setup_logger(config.log.clone(), config.access_log.clone()) {
         eprintln!("Failed to initialize logger: {}", e);
         log::set_boxed_logger(Box::new(SimpleLogger)).unwrap();
         log::set_max_level(LevelFilter::Info);
@@ -98,13 +302,228 @@ async fn main() {
 
     log::info!("Starting with config: {:?}", config);
 
-    let auth_manager = match AuthManager::new(&config.users) {
+    if let Some(mark) = config.outbound_mark {
+        net::sockopt::set_outbound_mark(mark);
+    }
+    net::sockopt::set_tcp_config(config.tcp.clone());
+    if let Err(e) = net::dns::init(&config.resolver) {
+        log::error!("Failed to set up the resolver: {}", e);
+        std::process::exit(1);
+    }
+
+    let auth_manager = AuthManager::new(&config.users)
+        .map(|manager| manager.with_groups(&config.groups))
+        .and_then(|manager| match &config.users_file {
+            Some(users_file) => manager.with_users_file(users_file),
+            None => Ok(manager),
+        });
+    let auth_manager = match auth_manager {
         Ok(manager) => Arc::new(manager),
         Err(e) => {
             log::error!("Failed to create auth manager: {}", e);
             std::process::exit(1);
         }
     };
+    let auth_provider: Arc<dyn AuthProvider> = match config.auth.backend {
+        AuthBackend::Config => auth_manager.clone(),
+        #[cfg(feature = "sqlite")]
+        AuthBackend::Sqlite => {
+            use crate::common::user_db::{SqliteAuth, UserDb};
+            let path = config.auth.database.as_deref().unwrap_or_default();
+            match UserDb::open(path) {
+                Ok(db) => Arc::new(SqliteAuth::new(db)),
+                Err(e) => {
+                    log::error!("Failed to open user database {}: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        AuthBackend::Webhook => {
+            use crate::common::webhook_auth::WebhookAuth;
+            let webhook = config
+                .auth
+                .webhook
+                .as_ref()
+                .expect("validation requires [auth.webhook]");
+            match WebhookAuth::new(webhook) {
+                Ok(auth) => Arc::new(auth),
+                Err(e) => {
+                    log::error!("Failed to set up auth webhook: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        // Validation rejects the sqlite backend in builds without the feature
+        #[cfg(not(feature = "sqlite"))]
+        AuthBackend::Sqlite => unreachable!(),
+    };
+    let quotas = match config.quotas.as_ref().map(Quotas::new) {
+        Some(Ok(quotas)) => Some(Arc::new(quotas)),
+        Some(Err(e)) => {
+            log::error!("Failed to set up quotas: {}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    let auth_provider: Arc<dyn AuthProvider> = match &quotas {
+        Some(quotas) => Arc::new(QuotaProvider::new(auth_provider, quotas.clone())),
+        None => auth_provider,
+    };
+
+    let jwt = match config.http.jwt.as_ref().map(JwtAuth::new) {
+        Some(Ok(jwt)) => Some(Arc::new(jwt)),
+        Some(Err(e)) => {
+            log::error!("Failed to set up bearer token authentication: {}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+
+    let acme = match config.tls.as_ref().and_then(|tls| tls.acme.as_ref()) {
+        Some(acme_config) => match Acme::new(acme_config) {
+            Ok(acme) => Some(Arc::new(acme)),
+            Err(e) => {
+                log::error!("Failed to set up ACME: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let acme_listener = match config.tls.as_ref().and_then(|tls| tls.acme.as_ref()) {
+        Some(acme_config) => match TcpListener::bind(&acme_config.http01_listen).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                log::error!("Failed to bind to {}: {}", acme_config.http01_listen, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let acme_certs = acme
+        .as_ref()
+        .map(|acme| acme.resolver() as Arc<dyn ResolvesServerCert>);
+
+    let tls_acceptor = match &config.tls {
+        Some(tls_config) => match tls::build_acceptor(tls_config, acme_certs.clone()) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                log::error!("Failed to set up TLS: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let upstream_tls = match config.upstream.as_ref().filter(|upstream| upstream.tls) {
+        Some(upstream) => {
+            match tls::build_connector(upstream.ca_path.as_deref().unwrap_or_default()) {
+                Ok(connector) => Some(connector),
+                Err(e) => {
+                    log::error!("Failed to set up TLS to the upstream proxy: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut upstreams_tls = HashMap::new();
+    for (name, upstream) in config.upstreams.iter().filter(|(_, upstream)| upstream.tls) {
+        match tls::build_connector(upstream.ca_path.as_deref().unwrap_or_default()) {
+            Ok(connector) => {
+                upstreams_tls.insert(name.clone(), connector);
+            }
+            Err(e) => {
+                log::error!("Failed to set up TLS to upstream {}: {}", name, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let http_cache = if config.cache.enabled {
+        match HttpCache::new(&config.cache) {
+            Ok(cache) => Some(Arc::new(cache)),
+            Err(e) => {
+                log::error!("Failed to set up the HTTP cache: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let gateway = match &config.gateway {
+        Some(gateway_config) => match Gateway::new(
+            gateway_config,
+            std::time::Duration::from_secs(config.connect_timeout),
+        ) {
+            Ok(gateway) => Some(Arc::new(gateway)),
+            Err(e) => {
+                log::error!("Failed to set up the gateway: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let blocklist = match &config.blocklist {
+        Some(blocklist_config) => match Blocklist::load(&blocklist_config.path) {
+            Ok(blocklist) => Some(Arc::new(blocklist)),
+            Err(e) => {
+                log::error!("Failed to load blocklist {}: {}", blocklist_config.path, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let hosts = match &config.hosts {
+        Some(hosts_config) => match Hosts::load(hosts_config) {
+            Ok(hosts) => {
+                log::info!("Loaded {} static host entries", hosts.len());
+                Some(Arc::new(hosts))
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to load hosts file {}: {}",
+                    hosts_config.path.as_deref().unwrap_or_default(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let adblock = match &config.adblock {
+        Some(adblock_config) => match FilterList::load(adblock_config) {
+            Ok(filters) => {
+                log::info!("Loaded {} adblock filters", filters.filter_count());
+                Some(Arc::new(filters))
+            }
+            Err(e) => {
+                log::error!("Failed to load adblock filter lists: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Validation rejects [mitm] in builds without the feature
+    #[cfg(feature = "mitm")]
+    let mitm = match &config.mitm {
+        Some(mitm_config) => {
+            let connect_timeout = std::time::Duration::from_secs(config.connect_timeout);
+            match proxy::http::mitm::Mitm::new(mitm_config, connect_timeout) {
+                Ok(mitm) => Some(Arc::new(mitm)),
+                Err(e) => {
+                    log::error!("Failed to set up TLS inspection: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
 
     let listener = match TcpListener::bind(&config.listen_address).await {
         Ok(listener) => listener,
@@ -114,15 +533,372 @@ async fn main() {
         }
     };
 
+    let shadowsocks_listener = match &config.shadowsocks {
+        Some(shadowsocks) => match TcpListener::bind(&shadowsocks.listen_address).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                log::error!("Failed to bind to {}: {}", shadowsocks.listen_address, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let transparent_listener = match &config.transparent {
+        Some(transparent) => match bind_transparent(transparent).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                log::error!("Failed to bind to {}: {}", transparent.listen_address, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let gateway_listener = match &config.gateway {
+        Some(gateway_config) => match TcpListener::bind(&gateway_config.listen_address).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                log::error!("Failed to bind to {}: {}", gateway_config.listen_address, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let dns_sockets = match &config.dns {
+        Some(dns_config) => {
+            let address = &dns_config.listen_address;
+            match tokio::try_join!(UdpSocket::bind(address), TcpListener::bind(address)) {
+                Ok(sockets) => Some(sockets),
+                Err(e) => {
+                    log::error!("Failed to bind to {}: {}", address, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut forward_listeners = Vec::new();
+    for forward in &config.forward {
+        match TcpListener::bind(&forward.listen_address).await {
+            // Validated along with the rest of the configuration
+            Ok(listener) => {
+                forward_listeners.push((listener, TargetAddr::parse(&forward.target).unwrap()))
+            }
+            Err(e) => {
+                log::error!("Failed to bind to {}: {}", forward.listen_address, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut extra_listeners = Vec::new();
+    for listener_config in &config.listeners {
+        let tls_acceptor = match &listener_config.tls {
+            Some(tls_config) => match tls::build_acceptor(tls_config, None) {
+                Ok(acceptor) => Some(acceptor),
+                Err(e) => {
+                    log::error!(
+                        "Failed to set up TLS on {}: {}",
+                        listener_config.listen_address,
+                        e
+                    );
+                    std::process::exit(1);
+                }
+            },
+            None => None,
+        };
+        match TcpListener::bind(&listener_config.listen_address).await {
+            Ok(listener) => extra_listeners.push((listener, listener_config.clone(), tls_acceptor)),
+            Err(e) => {
+                log::error!(
+                    "Failed to bind to {}: {}",
+                    listener_config.listen_address,
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Validation rejects [tun] in builds without the feature
+    #[cfg(feature = "tun")]
+    let tun_device = match &config.tun {
+        Some(tun_config) => match net::tun::TunDevice::open(tun_config) {
+            Ok(device) => Some(device),
+            Err(e) => {
+                log::error!("Failed to open TUN device {}: {}", tun_config.name, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Validation guarantees [tls] alongside [quic]
+    let quic_endpoint = match (&config.quic, &config.tls) {
+        (Some(quic_config), Some(tls_config)) => {
+            match quic::bind(tls_config, acme_certs, quic_config) {
+                Ok(endpoint) => Some(endpoint),
+                Err(e) => {
+                    log::error!(
+                        "Failed to set up QUIC on {}: {}",
+                        quic_config.listen_address,
+                        e
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+
     println!("Proxy server listening on {}", config.listen_address);
     println!("Supporting SOCKS5 and HTTP proxy protocols");
+    if tls_acceptor.is_some() {
+        println!("TLS enabled on the listener");
+    }
+    if let Some(acme_config) = config.tls.as_ref().and_then(|tls| tls.acme.as_ref()) {
+        println!(
+            "Certificate for {} from {}, HTTP-01 challenges on {}",
+            acme_config.domains.join(", "),
+            acme_config.directory_url,
+            acme_config.http01_listen
+        );
+    }
+    for listener_config in &config.listeners {
+        println!(
+            "Also listening on {} for {:?}{}",
+            listener_config.listen_address,
+            listener_config.protocols,
+            if listener_config.tls.is_some() {
+                " over TLS"
+            } else {
+                ""
+            }
+        );
+    }
+    if let Some(shadowsocks) = &config.shadowsocks {
+        println!("Shadowsocks listening on {}", shadowsocks.listen_address);
+    }
+    if let Some(transparent) = &config.transparent {
+        println!(
+            "Transparent proxy listening on {}",
+            transparent.listen_address
+        );
+    }
+    if let Some(gateway_config) = &config.gateway {
+        println!("Gateway listening on {}", gateway_config.listen_address);
+    }
+    if let Some(dns_config) = &config.dns {
+        println!(
+            "DNS forwarder listening on {}, resolving via {}",
+            dns_config.listen_address, dns_config.upstream
+        );
+        if let Some(fake_ip) = &dns_config.fake_ip {
+            println!("Answering DNS queries with fake IPs from {}", fake_ip.range);
+        }
+    }
+    for forward in &config.forward {
+        println!(
+            "Forwarding {} to {}",
+            forward.listen_address, forward.target
+        );
+    }
+    if let Some(tun_config) = &config.tun {
+        println!("Proxying traffic routed into {}", tun_config.name);
+    }
+    if let Some(quic_config) = &config.quic {
+        println!("QUIC listening on {}", quic_config.listen_address);
+    }
+    if let Some(mark) = config.outbound_mark {
+        println!("Marking outbound sockets with {:#x}", mark);
+    }
 
-    let proxy = TcpProxy::new(
-        auth_manager,
-        config.buffer_size,
-        config.max_connections,
-        Duration::from_secs(config.connect_timeout),
+    let mut proxy = TcpProxy::new(
+        auth_provider,
+        &config,
+        tls_acceptor,
+        http_cache,
+        upstream_tls,
+        upstreams_tls,
+        gateway,
     );
+    if let Some(blocklist) = &blocklist {
+        proxy = proxy.with_blocklist(blocklist.clone());
+    }
+    if let Some(hosts) = hosts {
+        proxy = proxy.with_hosts(hosts);
+    }
+    let fake_ip = config
+        .dns
+        .as_ref()
+        .and_then(|dns_config| dns_config.fake_ip.as_ref())
+        .map(|fake_ip_config| Arc::new(FakeIpPool::new(fake_ip_config)));
+    if let Some(pool) = &fake_ip {
+        proxy = proxy.with_fake_ip(pool.clone());
+    }
+    if let Some(lockout) = &config.lockout {
+        proxy = proxy.with_lockout(Arc::new(Lockout::new(lockout)));
+    }
+    if let Some(bandwidth) = &config.bandwidth {
+        proxy = proxy.with_bandwidth(Arc::new(Bandwidth::new(bandwidth)));
+    }
+    if let Some(adblock) = adblock {
+        proxy = proxy.with_adblock(adblock);
+    }
+    if let Some(jwt) = jwt {
+        proxy = proxy.with_jwt(jwt);
+    }
+    #[cfg(feature = "mitm")]
+    if let Some(mitm) = mitm {
+        proxy = proxy.with_mitm(mitm);
+    }
+
+    let dns_forwarder = match &config.dns {
+        Some(dns_config) => match DnsForwarder::new(dns_config, proxy.dialer()) {
+            Ok(forwarder) => match fake_ip {
+                Some(pool) => Some(Arc::new(forwarder.with_fake_ip(pool))),
+                None => Some(Arc::new(forwarder)),
+            },
+            Err(e) => {
+                log::error!("Failed to set up the DNS forwarder: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
-    proxy.run(listener).await;
+    let listeners = async {
+        let mut listeners = JoinSet::new();
+        for (listener, listener_config, tls_acceptor) in extra_listeners {
+            let proxy = proxy.clone();
+            listeners.spawn(async move {
+                proxy
+                    .run_listener(listener, &listener_config, tls_acceptor)
+                    .await
+            });
+        }
+        listeners.join_all().await;
+    };
+    let shadowsocks = async {
+        if let Some(shadowsocks_listener) = shadowsocks_listener {
+            proxy.run_shadowsocks(shadowsocks_listener).await;
+        }
+    };
+    let transparent = async {
+        if let Some(transparent_listener) = transparent_listener {
+            proxy.run_transparent(transparent_listener).await;
+        }
+    };
+    let gateway = async {
+        if let Some(gateway_listener) = gateway_listener {
+            proxy.run_gateway(gateway_listener).await;
+        }
+    };
+    let dns = async {
+        if let (Some(forwarder), Some((udp, tcp))) = (dns_forwarder, dns_sockets) {
+            forwarder.run(udp, tcp).await;
+        }
+    };
+    let forwards = async {
+        let mut forwards = JoinSet::new();
+        for (listener, target) in forward_listeners {
+            let proxy = proxy.clone();
+            forwards.spawn(async move { proxy.run_forward(listener, target).await });
+        }
+        forwards.join_all().await;
+    };
+    let tun = async {
+        #[cfg(feature = "tun")]
+        if let Some(device) = tun_device {
+            proxy.run_tun(device).await;
+        }
+    };
+    let blocklist_watch = async {
+        if let (Some(blocklist), Some(blocklist_config)) = (&blocklist, &config.blocklist) {
+            let interval = std::time::Duration::from_secs(blocklist_config.reload_interval);
+            blocklist.watch(interval).await;
+        }
+    };
+    let users_watch = auth_manager.clone().watch();
+    let quotas_save = async {
+        if let Some(quotas) = &quotas {
+            quotas.run().await;
+        }
+    };
+    let url_tests = proxy.run_url_tests();
+    let quic = async {
+        if let Some(endpoint) = quic_endpoint {
+            proxy.run_quic(endpoint).await;
+        }
+    };
+    let acme = async {
+        if let (Some(acme), Some(listener)) = (acme, acme_listener) {
+            acme.run(listener).await;
+        }
+    };
+    let buffer_pool_report = net::buffer_pool::global().report(std::time::Duration::from_secs(60));
+    let dns_report = net::dns::report(std::time::Duration::from_secs(60));
+    let accept_queue_report = proxy
+        .accept_queue()
+        .report(std::time::Duration::from_secs(60));
+    tokio::join!(
+        proxy.run(listener),
+        listeners,
+        shadowsocks,
+        transparent,
+        gateway,
+        dns,
+        forwards,
+        tun,
+        quic,
+        acme,
+        blocklist_watch,
+        users_watch,
+        quotas_save,
+        url_tests,
+        buffer_pool_report,
+        dns_report,
+        accept_queue_report
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args_override_the_config_file() {
+        let mut config: Config = ::config::Config::builder()
+            .add_source(::config::File::from_str(
+                "listen_address = \"127.0.0.1:1080\"\n\
+                 connect_timeout = 30\n\
+                 [runtime]\nworker_threads = 8\nthread_name = \"from-file\"\n",
+                ::config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let args = Args::try_parse_from([
+            "rust-proxy",
+            "--connect-timeout",
+            "5",
+            "--worker-threads",
+            "2",
+            "--thread-name",
+            "from-cli",
+        ])
+        .unwrap();
+        apply_args(&mut config, &args);
+
+        assert_eq!(config.connect_timeout, 5);
+        assert_eq!(config.runtime.worker_threads, 2);
+        assert_eq!(config.runtime.thread_name, "from-cli");
+        // Settings not given on the command line keep the file's values
+        assert_eq!(config.listen_address, "127.0.0.1:1080");
+        assert_eq!(config.runtime.max_blocking_threads, 512);
+    }
 }
@@ -0,0 +1,187 @@
+use ipnet::Ipv6Net;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio_rustls::rustls::pki_types::ServerName;
+use url::Url;
+
+use crate::net::tls::TlsError;
+
+/// Destination requested by a client, kept unresolved when given as a domain
+/// so the resolution policy can decide where the lookup happens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    /// Parses `host:port`, taking IP literals (IPv6 in brackets) as
+    /// addresses and anything else as a domain.
+    pub fn parse(target: &str) -> Option<Self> {
+        if let Ok(addr) = target.parse::<SocketAddr>() {
+            return Some(TargetAddr::Ip(addr));
+        }
+        let (host, port) = target.rsplit_once(':')?;
+        Some(TargetAddr::Domain(host.to_string(), port.parse().ok()?))
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        }
+    }
+}
+
+/// The name a TLS server at `host` must present: an IP address for
+/// literals, bracketed or not, and a DNS name otherwise.
+pub fn server_name(host: &str) -> Result<ServerName<'static>, TlsError> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Ok(ServerName::from(ip)),
+        Err(_) => ServerName::try_from(host.to_string())
+            .map_err(|_| TlsError::InvalidServerName(host.to_string())),
+    }
+}
+
+/// Where `url` points, and the name its TLS server must present. The port
+/// defaults to the scheme's, or to `default_port` for schemes `url` does
+/// not know.
+pub fn url_target(
+    url: &Url,
+    default_port: u16,
+) -> Result<(TargetAddr, ServerName<'static>), TlsError> {
+    let host = url.host_str().unwrap_or_default();
+    let server_name = server_name(host)?;
+    let port = url.port_or_known_default().unwrap_or(default_port);
+    let target = match &server_name {
+        ServerName::IpAddress(ip) => TargetAddr::Ip(SocketAddr::new(IpAddr::from(*ip), port)),
+        _ => TargetAddr::Domain(host.to_string(), port),
+    };
+    Ok((target, server_name))
+}
+
+/// Matches `host` against an exact host, or `*.example.com` for any
+/// subdomain, ignoring case.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let (host, pattern) = (host.to_ascii_lowercase(), pattern.to_ascii_lowercase());
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+        None => host == pattern,
+    }
+}
+
+/// `ip` embedded in a NAT64 prefix as RFC 6052 §2.2 lays out: right after
+/// the prefix, skipping bits 64 to 71. The prefix length must be 32, 40,
+/// 48, 56, 64 or 96.
+pub fn embed_ipv4(prefix: Ipv6Net, ip: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.network().octets();
+    let mut pos = usize::from(prefix.prefix_len()) / 8;
+    for byte in ip.octets() {
+        if pos == 8 {
+            pos += 1;
+        }
+        octets[pos] = byte;
+        pos += 1;
+    }
+    Ipv6Addr::from(octets)
+}
+
+/// The IPv4 address `ip` carries, if it lies in the NAT64 `prefix`; the
+/// reverse of `embed_ipv4`.
+pub fn extract_ipv4(prefix: Ipv6Net, ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    if !prefix.contains(&ip) {
+        return None;
+    }
+    let octets = ip.octets();
+    let mut pos = usize::from(prefix.prefix_len()) / 8;
+    let mut ipv4 = [0; 4];
+    for byte in &mut ipv4 {
+        if pos == 8 {
+            pos += 1;
+        }
+        *byte = octets[pos];
+        pos += 1;
+    }
+    Some(Ipv4Addr::from(ipv4))
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let v4 = TargetAddr::Ip("127.0.0.1:80".parse().unwrap());
+        assert_eq!(v4.to_string(), "127.0.0.1:80");
+
+        let v6 = TargetAddr::Ip("[::1]:443".parse().unwrap());
+        assert_eq!(v6.to_string(), "[::1]:443");
+
+        let domain = TargetAddr::Domain("example.com".to_string(), 8080);
+        assert_eq!(domain.to_string(), "example.com:8080");
+        assert_eq!(domain.port(), 8080);
+
+        assert_eq!(TargetAddr::parse("[::1]:443"), Some(v6));
+        assert_eq!(TargetAddr::parse("example.com:8080"), Some(domain));
+        assert_eq!(TargetAddr::parse("example.com"), None);
+    }
+
+    #[test]
+    fn test_url_target() {
+        let target = |url: &str| url_target(&Url::parse(url).unwrap(), 853);
+
+        let (addr, name) = target("https://[::1]:8443/jwks").unwrap();
+        assert_eq!(addr, TargetAddr::Ip("[::1]:8443".parse().unwrap()));
+        assert_eq!(name, ServerName::from("::1".parse::<IpAddr>().unwrap()));
+
+        let (addr, name) = target("http://Auth.Example.com/check").unwrap();
+        assert_eq!(addr, TargetAddr::Domain("auth.example.com".to_string(), 80));
+        assert_eq!(name, ServerName::try_from("auth.example.com").unwrap());
+
+        let (addr, _) = target("tls://1.1.1.1").unwrap();
+        assert_eq!(addr, TargetAddr::Ip("1.1.1.1:853".parse().unwrap()));
+
+        assert!(matches!(
+            target("https://bad_name!/"),
+            Err(TlsError::InvalidServerName(_))
+        ));
+        assert!(server_name("[2001:db8::1]").is_ok());
+    }
+
+    #[test]
+    fn test_embed_ipv4() {
+        // RFC 6052 §2.4 examples for 192.0.2.33
+        let ip = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, expected) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("64:ff9b::/96", "64:ff9b::c000:221"),
+        ] {
+            let embedded = embed_ipv4(prefix.parse().unwrap(), ip);
+            assert_eq!(embedded, expected.parse::<Ipv6Addr>().unwrap());
+            assert_eq!(extract_ipv4(prefix.parse().unwrap(), embedded), Some(ip));
+        }
+        assert_eq!(
+            extract_ipv4(
+                "64:ff9b::/96".parse().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Read buffers shared across connections. A connection takes one when it
+//! is created and gives it back when dropped, so a busy proxy stops
+//! allocating a fresh buffer for every connection it accepts or dials.
+//!
+//! The pool is a lock-free bounded queue, so it never makes a connection
+//! wait: taking from an empty pool allocates a new buffer, and giving one
+//! back to a full pool drops it.
+
+use bytes::BytesMut;
+use crossbeam_queue::ArrayQueue;
+use log::debug;
+use std::sync::LazyLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Buffers kept at most; any more given back are freed.
+const MAX_IDLE: usize = 1024;
+
+/// Buffers that grew larger than this are freed instead of kept, so one
+/// large transfer does not pin its memory for good.
+const MAX_POOLED_CAPACITY: usize = 256 * 1024;
+
+pub struct BufferPool {
+    idle: ArrayQueue<BytesMut>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+static POOL: LazyLock<BufferPool> = LazyLock::new(BufferPool::new);
+
+/// The pool every `BufferedConnection` draws from.
+pub fn global() -> &'static BufferPool {
+    &POOL
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool {
+            idle: ArrayQueue::new(MAX_IDLE),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// An empty buffer with room for at least `capacity` bytes.
+    pub fn take(&self, capacity: usize) -> BytesMut {
+        match self.idle.pop() {
+            Some(mut buffer) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buffer.reserve(capacity);
+                buffer
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(capacity)
+            }
+        }
+    }
+
+    /// Keeps `buffer` for a later `take`; its contents are discarded.
+    pub fn give_back(&self, mut buffer: BytesMut) {
+        if buffer.capacity() == 0 || buffer.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        buffer.clear();
+        // A full pool hands the buffer back, and it is freed here
+        let _ = self.idle.push(buffer);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Share of takes served from the pool, from 0 to 1; 0 before any.
+    pub fn hit_rate(&self) -> f64 {
+        let hits = self.hits();
+        let total = hits + self.misses();
+        if total == 0 {
+            0.0
+        } else {
+            hits as f64 / total as f64
+        }
+    }
+
+    pub fn idle(&self) -> usize {
+        self.idle.len()
+    }
+
+    /// Logs the pool counters every `interval`, forever.
+    pub async fn report(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            debug!(
+                "Buffer pool: {} hits, {} misses ({:.1}% hit rate), {} idle",
+                self.hits(),
+                self.misses(),
+                self.hit_rate() * 100.0,
+                self.idle()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse_and_hit_rate() {
+        let pool = BufferPool::new();
+        assert_eq!(pool.hit_rate(), 0.0);
+
+        let mut buffer = pool.take(4096);
+        assert!(buffer.capacity() >= 4096);
+        buffer.extend_from_slice(b"leftover");
+        pool.give_back(buffer);
+        assert_eq!(pool.idle(), 1);
+
+        let buffer = pool.take(4096);
+        assert!(buffer.is_empty());
+        assert!(buffer.capacity() >= 4096);
+        assert_eq!((pool.hits(), pool.misses()), (1, 1));
+        assert_eq!(pool.hit_rate(), 0.5);
+
+        pool.give_back(BytesMut::with_capacity(MAX_POOLED_CAPACITY + 1));
+        assert_eq!(pool.idle(), 0);
+    }
+
+    #[test]
+    fn test_idle_is_bounded() {
+        let pool = BufferPool::new();
+        for _ in 0..MAX_IDLE + 1 {
+            pool.give_back(BytesMut::with_capacity(64));
+        }
+        assert_eq!(pool.idle(), MAX_IDLE);
+    }
+}
@@ -1,64 +1,74 @@
+use bytes::{Buf, Bytes, BytesMut};
 use std::io;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::task::{Context, Poll};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+#[cfg(all(feature = "splice", target_os = "linux"))]
 use tokio::net::TcpStream;
 
+use crate::net::buffer_pool;
+use crate::net::rate_limit::{Limit, Throttled};
+use crate::net::stream::Stream;
+
 pub struct BufferedConnection {
-    stream: TcpStream,
-    read_buffer: Vec<u8>,
-    temp_buffer: Vec<u8>,
+    stream: Box<dyn Stream>,
+    /// Bytes read but not yet consumed. Consuming them from the front only
+    /// moves its start, and bytes taken off it share its memory.
+    read_buffer: BytesMut,
+    /// Room made in the buffer for each read from the stream
     buffer_size: usize,
+    bytes_read: u64,
+    bytes_written: u64,
+    /// Limiters `throttle` wrapped the stream in
+    limiters: Vec<Arc<dyn Limit>>,
 }
 
 impl BufferedConnection {
-    pub fn new(stream: TcpStream, buffer_size: usize) -> Self {
+    pub fn new(stream: impl Stream + 'static, buffer_size: usize) -> Self {
         BufferedConnection {
-            stream,
-            read_buffer: Vec::with_capacity(buffer_size),
-            temp_buffer: vec![0u8; buffer_size],
+            stream: Box::new(stream),
+            read_buffer: buffer_pool::global().take(buffer_size),
             buffer_size,
+            bytes_read: 0,
+            bytes_written: 0,
+            limiters: Vec::new(),
         }
     }
 
+    /// Reads once from the stream straight into the buffer.
     pub async fn read(&mut self) -> io::Result<usize> {
-        let n = self.stream.read(&mut self.temp_buffer).await?;
-        if n > 0 {
-            self.read_buffer.extend_from_slice(&self.temp_buffer[..n]);
-        }
+        self.read_buffer.reserve(self.buffer_size);
+        let n = self.stream.read_buf(&mut self.read_buffer).await?;
+        self.bytes_read += n as u64;
         Ok(n)
     }
 
-    pub fn read_from_buffer(&mut self, len: usize) -> Option<Vec<u8>> {
-        if self.read_buffer.len() >= len {
-            let data = self.read_buffer[..len].to_vec();
-            self.read_buffer.drain(..len);
-            Some(data)
-        } else {
-            None
-        }
+    /// Takes the first `len` buffered bytes off the buffer without copying
+    /// them; None while fewer are buffered.
+    pub fn read_from_buffer(&mut self, len: usize) -> Option<Bytes> {
+        (self.read_buffer.len() >= len).then(|| self.read_buffer.split_to(len).freeze())
     }
 
-    #[allow(dead_code)]
-    pub fn buffer_slice(&self, len: usize) -> Option<&[u8]> {
-        if self.read_buffer.len() >= len {
-            Some(&self.read_buffer[..len])
-        } else {
-            None
-        }
+    /// The first `len` buffered bytes, left in the buffer; None while
+    /// fewer are buffered.
+    pub fn peek(&self, len: usize) -> Option<&[u8]> {
+        self.read_buffer.get(..len)
     }
 
-    #[allow(dead_code)]
     pub fn drain_buffer(&mut self, len: usize) -> bool {
         if self.read_buffer.len() >= len {
-            self.read_buffer.drain(..len);
+            self.read_buffer.advance(len);
             true
         } else {
             false
         }
     }
 
-    pub async fn ensure_bytes(&mut self, n: usize) -> io::Result<()> {
+    /// Reads until at least `n` bytes are buffered; EOF before that is an
+    /// `UnexpectedEof` error.
+    pub async fn fill_to(&mut self, n: usize) -> io::Result<()> {
         while self.read_buffer.len() < n {
             if self.read().await? == 0 {
                 return Err(io::Error::new(
@@ -70,8 +80,9 @@ impl BufferedConnection {
         Ok(())
     }
 
-    pub async fn read_exact_bytes(&mut self, n: usize) -> io::Result<Vec<u8>> {
-        self.ensure_bytes(n).await?;
+    /// Takes exactly `n` bytes off the buffer, reading more first if needed.
+    pub async fn read_exact_buffered(&mut self, n: usize) -> io::Result<Bytes> {
+        self.fill_to(n).await?;
         self.read_from_buffer(n)
             .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Buffer underflow"))
     }
@@ -80,9 +91,10 @@ impl BufferedConnection {
     pub async fn read_line(&mut self) -> io::Result<String> {
         loop {
             if let Some(pos) = self.read_buffer.windows(2).position(|w| w == b"\r\n") {
-                let line = String::from_utf8(self.read_buffer[..pos].to_vec())
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                self.read_buffer.drain(..pos + 2);
+                let line = std::str::from_utf8(&self.read_buffer[..pos])
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+                    .to_string();
+                self.read_buffer.advance(pos + 2);
                 return Ok(line);
             }
             if self.read().await? == 0 {
@@ -95,11 +107,15 @@ impl BufferedConnection {
     }
 
     pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
-        self.stream.write_all(data).await
+        self.stream.write_all(data).await?;
+        self.bytes_written += data.len() as u64;
+        Ok(())
     }
 
+    /// Puts `data` back in front of the buffered bytes, to be read again.
+    #[allow(dead_code)]
     pub fn unread(&mut self, data: &[u8]) {
-        let mut new_buffer = Vec::with_capacity(data.len() + self.read_buffer.len());
+        let mut new_buffer = BytesMut::with_capacity(data.len() + self.read_buffer.len());
         new_buffer.extend_from_slice(data);
         new_buffer.extend_from_slice(&self.read_buffer);
         self.read_buffer = new_buffer;
@@ -109,19 +125,159 @@ impl BufferedConnection {
         !self.read_buffer.is_empty()
     }
 
+    /// Total bytes read from the stream so far, through either `read` or
+    /// `AsyncRead`.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Total bytes written to the stream so far, through either `write` or
+    /// `AsyncWrite`.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Counts bytes spliced between the sockets directly, which never pass
+    /// through the connection.
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    pub fn count_spliced(&mut self, read: u64, written: u64) {
+        self.bytes_read += read;
+        self.bytes_written += written;
+    }
+
     pub fn buffer_size(&self) -> usize {
         self.buffer_size
     }
 
-    #[allow(dead_code)]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    pub fn client_identity(&self) -> Option<String> {
+        self.stream.client_identity()
+    }
+
+    /// Bytes read from the stream but not yet consumed.
+    pub fn buffered(&self) -> &[u8] {
+        &self.read_buffer
+    }
+
     pub fn buffer_len(&self) -> usize {
         self.read_buffer.len()
     }
 
-    #[allow(dead_code)]
     pub fn clear_buffer(&mut self) {
         self.read_buffer.clear();
     }
+
+    /// Makes every later read and write wait on `limiter`, along with any
+    /// other limiter set before. Setting the same limiter again does
+    /// nothing.
+    pub fn throttle(&mut self, limiter: Arc<dyn Limit>) {
+        if self.limiters.iter().any(|set| Arc::ptr_eq(set, &limiter)) {
+            return;
+        }
+        self.limiters.push(limiter.clone());
+        let closed = Closed {
+            local_addr: self.local_addr(),
+            peer_addr: self.peer_addr(),
+        };
+        let stream = std::mem::replace(&mut self.stream, Box::new(closed));
+        self.stream = Box::new(Throttled::new(stream, limiter));
+    }
+
+    /// Moves the connection out, leaving a closed one with the same
+    /// addresses in its place, so a layer such as TLS can take ownership
+    /// of a connection only borrowed. Bytes still buffered go with it.
+    #[cfg_attr(not(feature = "mitm"), allow(dead_code))]
+    pub fn detach(&mut self) -> BufferedConnection {
+        let closed = Closed {
+            local_addr: self.local_addr(),
+            peer_addr: self.peer_addr(),
+        };
+        let buffer_size = self.buffer_size;
+        std::mem::replace(self, BufferedConnection::new(closed, buffer_size))
+    }
+}
+
+/// Returns the read buffer to the pool for the next connection.
+impl Drop for BufferedConnection {
+    fn drop(&mut self) {
+        buffer_pool::global().give_back(std::mem::take(&mut self.read_buffer));
+    }
+}
+
+impl Stream for BufferedConnection {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn client_identity(&self) -> Option<String> {
+        self.stream.client_identity()
+    }
+
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        self.stream.as_tcp()
+    }
+}
+
+/// What `detach` leaves behind: reads end at once and writes fail.
+struct Closed {
+    local_addr: io::Result<SocketAddr>,
+    peer_addr: io::Result<SocketAddr>,
+}
+
+impl Stream for Closed {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.local_addr
+            .as_ref()
+            .copied()
+            .map_err(|e| io::Error::new(e.kind(), e.to_string()))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer_addr
+            .as_ref()
+            .copied()
+            .map_err(|e| io::Error::new(e.kind(), e.to_string()))
+    }
+}
+
+impl AsyncRead for Closed {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Closed {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(Err(io::ErrorKind::NotConnected.into()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
 }
 
 /// Residual data in the read buffer is drained first before delegating to
@@ -138,11 +294,37 @@ impl AsyncRead for BufferedConnection {
         if !this.read_buffer.is_empty() {
             let to_copy = std::cmp::min(this.read_buffer.len(), buf.remaining());
             buf.put_slice(&this.read_buffer[..to_copy]);
-            this.read_buffer.drain(..to_copy);
+            this.read_buffer.advance(to_copy);
             return Poll::Ready(Ok(()));
         }
 
-        Pin::new(&mut this.stream).poll_read(cx, buf)
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        this.bytes_read += (buf.filled().len() - before) as u64;
+        result
+    }
+}
+
+/// Hands out the buffered bytes, reading into the buffer when it is empty,
+/// so `tokio::io::copy_buf` writes straight from it with no copy in
+/// between.
+impl AsyncBufRead for BufferedConnection {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.read_buffer.is_empty() {
+            this.read_buffer.resize(this.buffer_size, 0);
+            let mut buf = ReadBuf::new(&mut this.read_buffer);
+            let result = Pin::new(&mut this.stream).poll_read(cx, &mut buf);
+            let n = buf.filled().len();
+            this.read_buffer.truncate(n);
+            ready!(result)?;
+            this.bytes_read += n as u64;
+        }
+        Poll::Ready(Ok(this.read_buffer.chunk()))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().read_buffer.advance(amt);
     }
 }
 
@@ -152,7 +334,12 @@ impl AsyncWrite for BufferedConnection {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            this.bytes_written += written as u64;
+        }
+        result
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
@@ -167,7 +354,7 @@ impl AsyncWrite for BufferedConnection {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::net::TcpListener;
+    use tokio::net::{TcpListener, TcpStream};
 
     #[tokio::test]
     async fn test_buffered_connection() {
@@ -182,13 +369,13 @@ mod tests {
 
         client_conn.write(b"Hello, server!").await.unwrap();
 
-        let data = server_conn.read_exact_bytes(14).await.unwrap();
-        assert_eq!(data, b"Hello, server!");
+        let data = server_conn.read_exact_buffered(14).await.unwrap();
+        assert_eq!(data, &b"Hello, server!"[..]);
 
         server_conn.write(b"Hello, client!").await.unwrap();
 
-        let data = client_conn.read_exact_bytes(14).await.unwrap();
-        assert_eq!(data, b"Hello, client!");
+        let data = client_conn.read_exact_buffered(14).await.unwrap();
+        assert_eq!(data, &b"Hello, client!"[..]);
     }
 
     #[tokio::test]
@@ -230,13 +417,61 @@ mod tests {
 
         client_conn.write(b"\x05\x01\x00").await.unwrap();
 
-        let first = server_conn.read_exact_bytes(1).await.unwrap();
+        let first = server_conn.read_exact_buffered(1).await.unwrap();
         assert_eq!(first[0], 0x05);
 
         server_conn.unread(&first);
         assert!(server_conn.has_data());
 
-        let all = server_conn.read_exact_bytes(3).await.unwrap();
-        assert_eq!(all, b"\x05\x01\x00");
+        let all = server_conn.read_exact_buffered(3).await.unwrap();
+        assert_eq!(all, &b"\x05\x01\x00"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_copy_buf() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut server_conn = BufferedConnection::new(server, 8);
+
+        client.write_all(b"HEAD body").await.unwrap();
+        assert_eq!(
+            server_conn.read_exact_buffered(5).await.unwrap(),
+            &b"HEAD "[..]
+        );
+        client.write_all(b" and more").await.unwrap();
+        drop(client);
+
+        // What is left in the buffer comes first, then the stream
+        let mut body = Vec::new();
+        tokio::io::copy_buf(&mut server_conn, &mut body)
+            .await
+            .unwrap();
+        assert_eq!(body, b"body and more");
+        assert_eq!(server_conn.bytes_read(), 18);
+    }
+
+    #[tokio::test]
+    async fn test_fill_to_and_peek() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client_conn = BufferedConnection::new(client, 4096);
+        let mut server_conn = BufferedConnection::new(server, 4096);
+
+        assert_eq!(server_conn.peek(1), None);
+        client_conn.write(b"\x05\x02").await.unwrap();
+        server_conn.fill_to(2).await.unwrap();
+        // Peeking leaves the bytes for the next read
+        assert_eq!(server_conn.peek(1), Some(&b"\x05"[..]));
+        assert_eq!(server_conn.peek(3), None);
+        assert_eq!(server_conn.buffer_len(), 2);
+
+        client_conn.write(b"\x00\x02").await.unwrap();
+        assert_eq!(
+            server_conn.read_exact_buffered(4).await.unwrap(),
+            &b"\x05\x02\x00\x02"[..]
+        );
+        assert!(!server_conn.has_data());
+
+        drop(client_conn);
+        let eof = server_conn.fill_to(1).await.unwrap_err();
+        assert_eq!(eof.kind(), io::ErrorKind::UnexpectedEof);
     }
 }
@@ -0,0 +1,304 @@
+//! Name resolution for every outbound path: targets, parent proxies, the
+//! UDP relay and its DNS fast path all resolve through one asynchronous
+//! resolver, set up once at startup from `[resolver]`, so a slow
+//! nameserver holds up only the lookups waiting on it.
+
+use hickory_resolver::config::{self, LookupIpStrategy, NameServerConfig, NameServerConfigGroup};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::{ResolveError, TokioResolver};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::common::config::ResolverConfig;
+
+static RESOLVER: OnceLock<Resolver> = OnceLock::new();
+
+/// Sets up the resolver from `[resolver]`. Lookups made before fall back
+/// to the system's nameservers with default timeouts.
+pub fn init(config: &ResolverConfig) -> io::Result<()> {
+    let resolver = build(config)?;
+    let _ = RESOLVER.set(resolver);
+    Ok(())
+}
+
+fn build(config: &ResolverConfig) -> io::Result<Resolver> {
+    let mut builder = if config.nameservers.is_empty() {
+        TokioResolver::builder_tokio().map_err(io::Error::other)?
+    } else {
+        let mut group = NameServerConfigGroup::with_capacity(config.nameservers.len() * 2);
+        for nameserver in &config.nameservers {
+            group.push(NameServerConfig::new(nameserver.0, Protocol::Udp));
+            group.push(NameServerConfig::new(nameserver.0, Protocol::Tcp));
+        }
+        TokioResolver::builder_with_config(
+            config::ResolverConfig::from_parts(None, Vec::new(), group),
+            TokioConnectionProvider::default(),
+        )
+    };
+    let options = builder.options_mut();
+    options.timeout = Duration::from_secs(config.timeout);
+    options.attempts = config.attempts;
+    options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    // Answers are cached by `Resolver`, where hits can be counted
+    options.cache_size = 0;
+    Ok(Resolver::new(builder.build(), config))
+}
+
+fn resolver() -> &'static Resolver {
+    RESOLVER.get_or_init(|| {
+        let config = ResolverConfig::default();
+        build(&config).unwrap_or_else(|e| {
+            warn!("No system nameservers ({}); only hosts entries resolve", e);
+            let inner = TokioResolver::builder_with_config(
+                config::ResolverConfig::from_parts(None, Vec::new(), NameServerConfigGroup::new()),
+                TokioConnectionProvider::default(),
+            )
+            .build();
+            Resolver::new(inner, &config)
+        })
+    })
+}
+
+fn lookup_error(e: ResolveError) -> io::Error {
+    let kind = if e.is_no_records_found() {
+        io::ErrorKind::NotFound
+    } else {
+        io::ErrorKind::Other
+    };
+    io::Error::new(kind, e)
+}
+
+/// The hickory resolver with a cache of answers in front.
+struct Resolver {
+    inner: TokioResolver,
+    cache: Mutex<Cache>,
+    /// Names kept at most; 0 turns the cache off
+    cache_size: usize,
+    /// How long a name is remembered not to exist
+    negative_ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Resolver {
+    fn new(inner: TokioResolver, config: &ResolverConfig) -> Self {
+        Resolver {
+            inner,
+            cache: Mutex::new(Cache::default()),
+            cache_size: config.cache_size,
+            negative_ttl: Duration::from_secs(config.negative_ttl),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    async fn lookup_ip(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if self.cache_size == 0 {
+            return self
+                .query(host)
+                .await
+                .map(|(ips, _)| ips)
+                .map_err(lookup_error);
+        }
+
+        let key = host.to_ascii_lowercase();
+        if let Some(cached) = self.cache.lock().unwrap().get(&key, Instant::now()) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return cached.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("No records for {}", host))
+            });
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        match self.query(host).await {
+            Ok((ips, valid_until)) => {
+                let mut cache = self.cache.lock().unwrap();
+                cache.insert(key, Some(ips.clone()), valid_until, self.cache_size);
+                Ok(ips)
+            }
+            Err(e) => {
+                if e.is_no_records_found() && !self.negative_ttl.is_zero() {
+                    let expires = Instant::now() + self.negative_ttl;
+                    let mut cache = self.cache.lock().unwrap();
+                    cache.insert(key, None, expires, self.cache_size);
+                }
+                Err(lookup_error(e))
+            }
+        }
+    }
+
+    /// Asks the nameservers. Returns the addresses, IPv4 ones first, and
+    /// when the shortest lived of their records expires.
+    async fn query(&self, host: &str) -> Result<(Vec<IpAddr>, Instant), ResolveError> {
+        let lookup = self.inner.lookup_ip(host).await?;
+        let mut ips: Vec<IpAddr> = lookup.iter().collect();
+        // A and AAAA answers come back in whichever order they arrived
+        ips.sort_by_key(IpAddr::is_ipv6);
+        Ok((ips, lookup.valid_until()))
+    }
+}
+
+/// Answers by lowercased name, the least recently used evicted first once
+/// full.
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, CacheEntry>,
+    clock: u64,
+}
+
+struct CacheEntry {
+    /// None for a name known not to exist
+    ips: Option<Vec<IpAddr>>,
+    expires: Instant,
+    /// Clock value of the last use
+    last_used: u64,
+}
+
+impl Cache {
+    /// The cached answer for `host`, unless missing or expired at `now`.
+    fn get(&mut self, host: &str, now: Instant) -> Option<Option<Vec<IpAddr>>> {
+        self.clock += 1;
+        let entry = self.entries.get_mut(host)?;
+        if entry.expires <= now {
+            self.entries.remove(host);
+            return None;
+        }
+        entry.last_used = self.clock;
+        Some(entry.ips.clone())
+    }
+
+    fn insert(
+        &mut self,
+        host: String,
+        ips: Option<Vec<IpAddr>>,
+        expires: Instant,
+        max_entries: usize,
+    ) {
+        self.clock += 1;
+        let entry = CacheEntry {
+            ips,
+            expires,
+            last_used: self.clock,
+        };
+        self.entries.insert(host, entry);
+
+        while self.entries.len() > max_entries {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(host, _)| host.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/// Logs the cache counters every `interval`, forever.
+pub async fn report(interval: Duration) {
+    let resolver = resolver();
+    if resolver.cache_size == 0 {
+        return;
+    }
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let hits = resolver.hits.load(Ordering::Relaxed);
+        let misses = resolver.misses.load(Ordering::Relaxed);
+        let total = (hits + misses).max(1);
+        debug!(
+            "DNS cache: {} hits, {} misses ({:.1}% hit rate), {} names",
+            hits,
+            misses,
+            hits as f64 * 100.0 / total as f64,
+            resolver.cache.lock().unwrap().entries.len()
+        );
+    }
+}
+
+/// Addresses of `host`, IPv4 ones first, as they work on every network;
+/// an IP address is returned as is.
+pub async fn lookup_ip(host: &str) -> io::Result<Vec<IpAddr>> {
+    if let Ok(ip) = host.parse() {
+        return Ok(vec![ip]);
+    }
+    resolver().lookup_ip(host).await
+}
+
+pub async fn lookup(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let ips = lookup_ip(host).await?;
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+/// Like `tokio::net::lookup_host` on a `host:port` string, brackets
+/// around IPv6 addresses included.
+pub async fn resolve(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid address: {}", addr),
+        )
+    };
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    lookup(host, port).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_expiry_and_eviction() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut cache = Cache::default();
+
+        cache.insert("a".to_string(), Some(vec![localhost]), later, 2);
+        cache.insert("missing".to_string(), None, later, 2);
+        assert_eq!(cache.get("a", now), Some(Some(vec![localhost])));
+        assert_eq!(cache.get("missing", now), Some(None));
+        assert_eq!(cache.get("a", later), None);
+
+        // "missing" was used before "b", so it goes when "c" arrives
+        cache.insert("b".to_string(), Some(vec![localhost]), later, 2);
+        cache.insert("c".to_string(), Some(vec![localhost]), later, 2);
+        assert_eq!(cache.get("missing", now), None);
+        assert!(cache.get("b", now).is_some());
+        assert!(cache.get("c", now).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        assert_eq!(
+            resolve("[::1]:80").await.unwrap(),
+            vec!["[::1]:80".parse().unwrap()]
+        );
+        let addrs = resolve("localhost:8080").await.unwrap();
+        assert!(
+            addrs
+                .iter()
+                .all(|addr| addr.ip().is_loopback() && addr.port() == 8080)
+        );
+        assert!(!addrs.is_empty());
+        assert!(resolve("localhost").await.is_err());
+        assert!(resolve("localhost:http").await.is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! HTTP/2 transport for CONNECT tunnels: the DATA frames of one stream
+//! carry the tunneled bytes (RFC 9113 §8.5), so many tunnels share one
+//! connection.
+
+use bytes::{Buf, Bytes};
+use h2::{RecvStream, SendStream};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::net::stream::Stream;
+
+/// The connection preface every HTTP/2 client opens with (RFC 9113 §3.4).
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// One HTTP/2 stream, after a `200` answered its CONNECT.
+pub struct Http2Stream {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    /// Data received but not yet read
+    pending: Bytes,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl Http2Stream {
+    pub fn new(
+        send: SendStream<Bytes>,
+        recv: RecvStream,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+    ) -> Self {
+        Http2Stream {
+            send,
+            recv,
+            pending: Bytes::new(),
+            local_addr,
+            peer_addr,
+        }
+    }
+}
+
+fn h2_error(e: h2::Error) -> io::Error {
+    match e.into_io() {
+        Some(e) => e,
+        None => io::Error::new(io::ErrorKind::ConnectionReset, "HTTP/2 stream reset"),
+    }
+}
+
+impl AsyncRead for Http2Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.pending.is_empty() {
+            match ready!(this.recv.poll_data(cx)) {
+                Some(Ok(data)) => this.pending = data,
+                Some(Err(e)) => return Poll::Ready(Err(h2_error(e))),
+                // END_STREAM: the client is done sending
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.pending[..n]);
+        this.pending.advance(n);
+        // Consumed data opens the flow-control window again
+        this.recv
+            .flow_control()
+            .release_capacity(n)
+            .map_err(h2_error)?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Http2Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        this.send.reserve_capacity(buf.len());
+        let n = match ready!(this.send.poll_capacity(cx)) {
+            Some(Ok(capacity)) => capacity.min(buf.len()),
+            Some(Err(e)) => return Poll::Ready(Err(h2_error(e))),
+            None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        };
+        this.send
+            .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+            .map_err(h2_error)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are queued on the connection, which writes them out
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = self.get_mut().send.send_data(Bytes::new(), true);
+        Poll::Ready(result.map_err(h2_error))
+    }
+}
+
+impl Stream for Http2Stream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+}
@@ -1 +1,20 @@
+pub mod addr;
+pub mod buffer_pool;
 pub mod conn;
+pub mod dns;
+pub mod http2;
+pub mod pool;
+pub mod proxy_protocol;
+pub mod quic;
+pub mod rate_limit;
+pub mod shadowsocks;
+pub mod sni;
+pub mod sockopt;
+#[cfg(all(feature = "splice", target_os = "linux"))]
+pub mod splice;
+pub mod ssh;
+pub mod stream;
+pub mod tls;
+pub mod transparent;
+#[cfg(feature = "tun")]
+pub mod tun;
@@ -0,0 +1,167 @@
+//! Idle upstream connections kept for reuse, keyed by `host:port`.
+//!
+//! A connection is handed out again only while it is younger than the
+//! maximum lifetime, has been idle for less than the idle timeout, and has
+//! nothing to read: EOF or stray bytes mean the peer closed it or the
+//! exchange is out of sync.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::net::conn::BufferedConnection;
+
+/// A connection together with the time it was established.
+pub struct Pooled {
+    pub conn: BufferedConnection,
+    created_at: Instant,
+}
+
+impl Pooled {
+    pub fn new(conn: BufferedConnection) -> Self {
+        Pooled {
+            conn,
+            created_at: Instant::now(),
+        }
+    }
+}
+
+struct Idle {
+    pooled: Pooled,
+    idle_since: Instant,
+}
+
+pub struct ConnectionPool {
+    max_idle_per_key: usize,
+    idle_timeout: Duration,
+    max_lifetime: Duration,
+    idle: Mutex<HashMap<String, Vec<Idle>>>,
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle_per_key: usize, idle_timeout: Duration, max_lifetime: Duration) -> Self {
+        ConnectionPool {
+            max_idle_per_key,
+            idle_timeout,
+            max_lifetime,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes the most recently used reusable connection for `key`, closing
+    /// any expired ones found on the way.
+    pub fn checkout(&self, key: &str) -> Option<Pooled> {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.get_mut(key)?;
+        let now = Instant::now();
+        let found = loop {
+            let Some(mut candidate) = connections.pop() else {
+                break None;
+            };
+            if !self.is_expired(&candidate, now) && is_quiet(&mut candidate.pooled.conn) {
+                break Some(candidate.pooled);
+            }
+        };
+        if connections.is_empty() {
+            idle.remove(key);
+        }
+        found
+    }
+
+    /// Returns a connection after a complete exchange. It is dropped when
+    /// past its lifetime or when `key` already has enough idle connections.
+    pub fn checkin(&self, key: &str, pooled: Pooled) {
+        let now = Instant::now();
+        if now.duration_since(pooled.created_at) >= self.max_lifetime {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        // Sweep every key so idle sockets to origins not visited again are
+        // still closed eventually.
+        idle.retain(|_, connections| {
+            connections.retain(|candidate| !self.is_expired(candidate, now));
+            !connections.is_empty()
+        });
+
+        let connections = idle.entry(key.to_string()).or_default();
+        if connections.len() < self.max_idle_per_key {
+            connections.push(Idle {
+                pooled,
+                idle_since: now,
+            });
+        }
+    }
+
+    fn is_expired(&self, idle: &Idle, now: Instant) -> bool {
+        now.duration_since(idle.idle_since) >= self.idle_timeout
+            || now.duration_since(idle.pooled.created_at) >= self.max_lifetime
+    }
+}
+
+/// Whether an idle connection has nothing to read, without waiting.
+fn is_quiet(conn: &mut BufferedConnection) -> bool {
+    if conn.has_data() {
+        return false;
+    }
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut byte = [0u8; 1];
+    let mut buf = ReadBuf::new(&mut byte);
+    Pin::new(conn).poll_read(&mut cx, &mut buf).is_pending()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn connected_pair() -> (Pooled, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (Pooled::new(BufferedConnection::new(client, 1024)), server)
+    }
+
+    #[tokio::test]
+    async fn test_checkout_skips_closed_and_unexpected_data() {
+        let pool = ConnectionPool::new(4, Duration::from_secs(60), Duration::from_secs(60));
+        let (alive, _alive_server) = connected_pair().await;
+        let (closed, closed_server) = connected_pair().await;
+        let (chatty, mut chatty_server) = connected_pair().await;
+
+        pool.checkin("origin:80", alive);
+        pool.checkin("origin:80", closed);
+        pool.checkin("origin:80", chatty);
+        drop(closed_server);
+        chatty_server.write_all(b"junk").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(pool.checkout("origin:80").is_some());
+        assert!(pool.checkout("origin:80").is_none());
+        assert!(pool.checkout("other:80").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let pool = ConnectionPool::new(1, Duration::from_millis(50), Duration::from_secs(60));
+        let (first, _first_server) = connected_pair().await;
+        let (second, _second_server) = connected_pair().await;
+        pool.checkin("origin:80", first);
+        pool.checkin("origin:80", second);
+        assert_eq!(pool.idle.lock().unwrap()["origin:80"].len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(pool.checkout("origin:80").is_none());
+
+        let expired = ConnectionPool::new(1, Duration::from_secs(60), Duration::ZERO);
+        let (old, _old_server) = connected_pair().await;
+        expired.checkin("origin:80", old);
+        assert!(expired.checkout("origin:80").is_none());
+    }
+}
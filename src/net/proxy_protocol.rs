@@ -0,0 +1,68 @@
+//! PROXY protocol version 2 headers (HAProxy's `proxy-protocol.txt`), sent
+//! ahead of the payload so a backend learns which client a connection is
+//! for.
+
+use std::net::{IpAddr, SocketAddr};
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, `PROXY` command
+const VERSION_PROXY: u8 = 0x21;
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// Header for a TCP connection from `source` to `destination`. When only
+/// one of them is IPv6 the other is sent in its IPv4-mapped form.
+pub fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION_PROXY);
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(TCP_OVER_IPV4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.push(TCP_OVER_IPV6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&v6(src).octets());
+            header.extend_from_slice(&v6(dst).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_v2() {
+        let header = encode_v2(
+            "192.0.2.1:50000".parse().unwrap(),
+            "198.51.100.2:1080".parse().unwrap(),
+        );
+        let mut expected =
+            b"\x0d\x0a\x0d\x0a\x00\x0d\x0a\x51\x55\x49\x54\x0a\x21\x11\x00\x0c".to_vec();
+        expected.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 2, 0xc3, 0x50, 0x04, 0x38]);
+        assert_eq!(header, expected);
+
+        let header = encode_v2(
+            "192.0.2.1:50000".parse().unwrap(),
+            "[2001:db8::1]:1080".parse().unwrap(),
+        );
+        assert_eq!(header.len(), 16 + 36);
+        assert_eq!(&header[13..16], &[0x21, 0x00, 0x24]);
+        assert_eq!(
+            &header[16..32],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 0, 2, 1]
+        );
+        assert_eq!(&header[48..], &[0xc3, 0x50, 0x04, 0x38]);
+    }
+}
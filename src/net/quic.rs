@@ -0,0 +1,197 @@
+//! QUIC transport for the SOCKS5 byte protocol: each bidirectional stream
+//! of a connection carries one independent session, so a lost packet only
+//! stalls the stream it belongs to.
+
+use quinn::crypto::rustls::{NoInitialCipherSuite, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::server::ResolvesServerCert;
+
+use crate::common::config::{QuicConfig, TlsConfig};
+use crate::net::stream::Stream;
+use crate::net::tls::{self, TlsError};
+
+#[derive(Error, Debug)]
+pub enum QuicError {
+    #[error("TLS setup failed: {0}")]
+    TlsError(#[from] TlsError),
+    #[error("TLS configuration is unusable for QUIC: {0}")]
+    Unsupported(#[from] NoInitialCipherSuite),
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+/// Binds the `[quic]` endpoint, presenting the `[tls]` certificate, or the
+/// one `certs` supplies.
+pub fn bind(
+    tls_config: &TlsConfig,
+    certs: Option<Arc<dyn ResolvesServerCert>>,
+    quic: &QuicConfig,
+) -> Result<Endpoint, QuicError> {
+    let mut server_config = tls::server_config(tls_config, certs)?;
+    server_config.alpn_protocols = tls::alpn_protocols(&quic.alpn);
+    let crypto = QuicServerConfig::try_from(server_config)?;
+    let addr = quic
+        .listen_address
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid listen address"))?;
+    Ok(Endpoint::server(
+        quinn::ServerConfig::with_crypto(Arc::new(crypto)),
+        addr,
+    )?)
+}
+
+/// One bidirectional stream of a QUIC connection.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    client_identity: Option<String>,
+}
+
+impl QuicStream {
+    pub fn new(
+        connection: &Connection,
+        local_addr: SocketAddr,
+        (send, recv): (SendStream, RecvStream),
+    ) -> Self {
+        let client_identity = connection
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+            .and_then(|certs| tls::certificate_identity(certs.first()?));
+        QuicStream {
+            send,
+            recv,
+            local_addr,
+            peer_addr: connection.remote_address(),
+            client_identity,
+        }
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.get_mut().send), cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+impl Stream for QuicStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn client_identity(&self) -> Option<String> {
+        self.client_identity.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quinn::crypto::rustls::QuicClientConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    #[tokio::test]
+    async fn test_quic_streams() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("rust-proxy-quic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.signing_key.serialize_pem()).unwrap();
+
+        let tls_config = TlsConfig {
+            cert_path: cert_path.to_string_lossy().into_owned(),
+            key_path: key_path.to_string_lossy().into_owned(),
+            alpn: Vec::new(),
+            client_ca_path: None,
+            acme: None,
+        };
+        let quic = QuicConfig {
+            listen_address: "127.0.0.1:0".to_string(),
+            alpn: vec!["socks5".to_string()],
+        };
+        let server = bind(&tls_config, None, &quic).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            // Two streams, each echoing on its own
+            for _ in 0..2 {
+                let mut stream = QuicStream::new(
+                    &connection,
+                    server_addr,
+                    connection.accept_bi().await.unwrap(),
+                );
+                assert_eq!(stream.peer_addr().unwrap(), connection.remote_address());
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 5];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    stream.write_all(&buf).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+            connection.closed().await;
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let mut client_crypto = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_crypto.alpn_protocols = vec![b"socks5".to_vec()];
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            QuicClientConfig::try_from(client_crypto).unwrap(),
+        )));
+        let connection = client
+            .connect(server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+
+        let (mut send_a, mut recv_a) = connection.open_bi().await.unwrap();
+        let (mut send_b, mut recv_b) = connection.open_bi().await.unwrap();
+        send_b.write_all(b"world").await.unwrap();
+        send_a.write_all(b"hello").await.unwrap();
+        assert_eq!(recv_b.read_to_end(16).await.unwrap(), b"world");
+        assert_eq!(recv_a.read_to_end(16).await.unwrap(), b"hello");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
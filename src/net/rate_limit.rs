@@ -0,0 +1,193 @@
+//! Byte rate limits: a token bucket, shared by every stream it throttles,
+//! and the stream wrapper that waits on it. Reads and writes both draw from
+//! the same bucket. A transfer may overdraw it; the debt is paid by waiting
+//! before the next one. Other budgets, such as transfer quotas, hold
+//! streams back through the same wrapper by implementing `Limit`.
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+use crate::net::stream::Stream;
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// A budget the reads and writes of throttled streams draw on.
+pub trait Limit: Send + Sync {
+    /// How long until the next transfer may start. An error fails the
+    /// transfer, cutting the stream off.
+    fn delay(&self) -> io::Result<Duration>;
+
+    /// Takes `bytes` just transferred off the budget.
+    fn consume(&self, bytes: usize);
+}
+
+pub struct RateLimiter {
+    /// Bytes per second
+    rate: f64,
+    /// Bytes that may pass at once after a quiet spell
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    /// `rate` bytes per second on average, up to `burst` at once.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        let burst = burst.max(1) as f64;
+        RateLimiter {
+            rate: rate.max(1) as f64,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl Limit for RateLimiter {
+    /// Zero while the bucket holds any tokens.
+    fn delay(&self) -> io::Result<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.refilled = now;
+        if bucket.tokens > 0.0 {
+            Ok(Duration::ZERO)
+        } else {
+            Ok(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    fn consume(&self, bytes: usize) {
+        self.bucket.lock().unwrap().tokens -= bytes as f64;
+    }
+}
+
+/// A stream whose reads and writes wait on a `Limit`.
+pub struct Throttled<S> {
+    inner: S,
+    limiter: Arc<dyn Limit>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, limiter: Arc<dyn Limit>) -> Self {
+        Throttled {
+            inner,
+            limiter,
+            sleep: None,
+        }
+    }
+
+    fn poll_tokens(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(sleep) = &mut self.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            let delay = self.limiter.delay()?;
+            if delay.is_zero() {
+                return Poll::Ready(Ok(()));
+            }
+            self.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+    }
+}
+
+impl<S: Stream> Stream for Throttled<S> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn client_identity(&self) -> Option<String> {
+        self.inner.client_identity()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_tokens(cx))?;
+        let before = buf.filled().len();
+        let result = ready!(Pin::new(&mut this.inner).poll_read(cx, buf));
+        this.limiter.consume(buf.filled().len() - before);
+        Poll::Ready(result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_tokens(cx))?;
+        let result = ready!(Pin::new(&mut this.inner).poll_write(cx, buf));
+        if let Ok(written) = result {
+            this.limiter.consume(written);
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_throttled_write() {
+        let limiter = Arc::new(RateLimiter::new(10_000, 1000));
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut client = Throttled::new(client, limiter);
+
+        let started = tokio::time::Instant::now();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            received.len()
+        });
+        // The burst passes at once, and so does the write overdrawing the
+        // bucket; the last two wait out the debt at 10000 bytes per second
+        for _ in 0..4 {
+            client.write_all(&[0u8; 1000]).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+        drop(client);
+        assert_eq!(reader.await.unwrap(), 4000);
+        let elapsed = started.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(190) && elapsed < Duration::from_secs(2),
+            "{:?}",
+            elapsed
+        );
+    }
+}
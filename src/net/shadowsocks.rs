@@ -0,0 +1,490 @@
+//! Shadowsocks AEAD framing (SIP004) over any `Stream`.
+//!
+//! Each direction opens with a random salt from which, with the master key,
+//! a session subkey is derived (HKDF-SHA1, info `ss-subkey`). After it come
+//! chunks of an encrypted 2-byte payload length and the encrypted payload,
+//! each with its own tag and a little-endian counter as nonce.
+//!
+//! A server tells which of several keys a client holds by finding the one
+//! that authenticates the client's first length chunk (`identify`).
+
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::common::config::ShadowsocksCipher;
+use crate::net::stream::Stream;
+
+/// Largest payload a chunk may carry
+const MAX_PAYLOAD: usize = 0x3FFF;
+const TAG_LEN: usize = 16;
+const LENGTH_LEN: usize = 2 + TAG_LEN;
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+
+/// Cipher and master key, derived from the password the way OpenSSL's
+/// `EVP_BytesToKey` does, as every Shadowsocks implementation expects.
+#[derive(Clone)]
+pub struct ShadowsocksKey {
+    algorithm: &'static aead::Algorithm,
+    key: Vec<u8>,
+}
+
+impl ShadowsocksKey {
+    pub fn new(cipher: ShadowsocksCipher, password: &str) -> Self {
+        let algorithm = match cipher {
+            ShadowsocksCipher::Aes128Gcm => &aead::AES_128_GCM,
+            ShadowsocksCipher::Aes256Gcm => &aead::AES_256_GCM,
+            ShadowsocksCipher::Chacha20IetfPoly1305 => &aead::CHACHA20_POLY1305,
+        };
+        ShadowsocksKey {
+            algorithm,
+            key: bytes_to_key(password.as_bytes(), algorithm.key_len()),
+        }
+    }
+
+    /// Salts are as long as the key
+    fn salt_len(&self) -> usize {
+        self.key.len()
+    }
+
+    /// Bytes a client sends before its first payload: the salt and the
+    /// first length chunk.
+    pub fn preamble_len(&self) -> usize {
+        self.salt_len() + LENGTH_LEN
+    }
+}
+
+/// Index of the key in `keys` whose session the client `preamble` opens,
+/// if any. The keys must share a cipher so they agree on its length.
+pub fn identify(keys: &[ShadowsocksKey], preamble: &[u8]) -> Option<usize> {
+    keys.iter().position(|key| {
+        let (salt, length) = preamble.split_at(key.salt_len());
+        let mut length = length.to_vec();
+        Session::new(key, salt).open(&mut length).is_ok()
+    })
+}
+
+fn bytes_to_key(password: &[u8], key_len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(key_len + 16);
+    let mut block = Vec::new();
+    while key.len() < key_len {
+        block.extend_from_slice(password);
+        let digest = md5(&block);
+        key.extend_from_slice(&digest);
+        block = digest.to_vec();
+    }
+    key.truncate(key_len);
+    key
+}
+
+/// One direction of a session: the subkey and the nonce counter.
+struct Session {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Session {
+    fn new(master: &ShadowsocksKey, salt: &[u8]) -> Self {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY, salt).extract(&master.key);
+        let subkey = prk
+            .expand(&[SUBKEY_INFO], master.algorithm)
+            .expect("subkey length is valid for HKDF-SHA1");
+        Session {
+            key: LessSafeKey::new(UnboundKey::from(subkey)),
+            counter: 0,
+        }
+    }
+
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Appends `data` encrypted, followed by its tag, to `out`.
+    fn seal(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(data);
+        let nonce = self.nonce();
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce, Aad::empty(), &mut out[start..])
+            .expect("chunks are far below the AEAD size limit");
+        out.extend_from_slice(tag.as_ref());
+    }
+
+    /// Decrypts `data` (ciphertext and tag) in place, returning the plaintext.
+    fn open<'a>(&mut self, data: &'a mut [u8]) -> io::Result<&'a mut [u8]> {
+        let nonce = self.nonce();
+        self.key
+            .open_in_place(nonce, Aad::empty(), data)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Shadowsocks chunk failed authentication",
+                )
+            })
+    }
+}
+
+/// Encrypts what is written to `inner` and decrypts what is read from it.
+pub struct AeadStream<S> {
+    inner: S,
+    key: ShadowsocksKey,
+    /// Started on the first write, with a fresh salt
+    encoder: Option<Session>,
+    /// Encrypted bytes accepted from the writer but not yet sent
+    pending: Vec<u8>,
+    pending_pos: usize,
+    /// Started once the peer's salt has arrived
+    decoder: Option<Session>,
+    /// Received bytes not yet decrypted
+    received: Vec<u8>,
+    /// Length of the chunk whose payload is awaited
+    payload_len: Option<usize>,
+    plaintext: Vec<u8>,
+    plaintext_pos: usize,
+}
+
+impl<S> AeadStream<S> {
+    pub fn new(inner: S, key: ShadowsocksKey) -> Self {
+        AeadStream {
+            inner,
+            key,
+            encoder: None,
+            pending: Vec::new(),
+            pending_pos: 0,
+            decoder: None,
+            received: Vec::new(),
+            payload_len: None,
+            plaintext: Vec::new(),
+            plaintext_pos: 0,
+        }
+    }
+
+    /// Server side of a session whose client `preamble` was already read
+    /// and matched to `key` by `identify`.
+    pub fn resume(inner: S, key: ShadowsocksKey, preamble: Vec<u8>) -> Self {
+        AeadStream {
+            received: preamble,
+            ..AeadStream::new(inner, key)
+        }
+    }
+
+    fn encrypt_chunk(&mut self, payload: &[u8]) -> io::Result<()> {
+        let encoder = match &mut self.encoder {
+            Some(encoder) => encoder,
+            None => {
+                let mut salt = vec![0u8; self.key.salt_len()];
+                SystemRandom::new()
+                    .fill(&mut salt)
+                    .map_err(|_| io::Error::other("No randomness for the Shadowsocks salt"))?;
+                self.pending.extend_from_slice(&salt);
+                self.encoder.insert(Session::new(&self.key, &salt))
+            }
+        };
+        encoder.seal(&(payload.len() as u16).to_be_bytes(), &mut self.pending);
+        encoder.seal(payload, &mut self.pending);
+        Ok(())
+    }
+
+    /// Decrypts the next chunk from `received` into `plaintext`; `false`
+    /// when more bytes are needed first.
+    fn decrypt_chunk(&mut self) -> io::Result<bool> {
+        let decoder = match &mut self.decoder {
+            Some(decoder) => decoder,
+            None if self.received.len() < self.key.salt_len() => return Ok(false),
+            None => {
+                let salt: Vec<u8> = self.received.drain(..self.key.salt_len()).collect();
+                self.decoder.insert(Session::new(&self.key, &salt))
+            }
+        };
+
+        let payload_len = match self.payload_len {
+            Some(len) => len,
+            None if self.received.len() < LENGTH_LEN => return Ok(false),
+            None => {
+                let length = decoder.open(&mut self.received[..LENGTH_LEN])?;
+                let len = u16::from_be_bytes([length[0], length[1]]) as usize & MAX_PAYLOAD;
+                self.received.drain(..LENGTH_LEN);
+                *self.payload_len.insert(len)
+            }
+        };
+        if self.received.len() < payload_len + TAG_LEN {
+            return Ok(false);
+        }
+
+        let payload = decoder.open(&mut self.received[..payload_len + TAG_LEN])?;
+        self.plaintext.clear();
+        self.plaintext.extend_from_slice(payload);
+        self.plaintext_pos = 0;
+        self.received.drain(..payload_len + TAG_LEN);
+        self.payload_len = None;
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AeadStream<S> {
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_pos += n;
+        }
+        self.pending.clear();
+        self.pending_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for AeadStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.plaintext_pos < this.plaintext.len() {
+                let available = &this.plaintext[this.plaintext_pos..];
+                let n = available.len().min(buf.remaining());
+                buf.put_slice(&available[..n]);
+                this.plaintext_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.decrypt_chunk()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 4096];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            if read_buf.filled().is_empty() {
+                // Only a close between chunks is a clean end of stream
+                if this.received.is_empty() && this.payload_len.is_none() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.received.extend_from_slice(read_buf.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for AeadStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = buf.len().min(MAX_PAYLOAD);
+        this.encrypt_chunk(&buf[..n])?;
+        // The chunk is accepted either way; what is not sent now goes out
+        // on the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_send_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: Stream> Stream for AeadStream<S> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+/// MD5 (RFC 1321), needed only for `EVP_BytesToKey`.
+fn md5(input: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    const K: [u32; 64] = [
+        0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613,
+        0xfd469501, 0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193,
+        0xa679438e, 0x49b40821, 0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d,
+        0x02441453, 0xd8a1e681, 0xe7d3fbc8, 0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed,
+        0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a, 0xfffa3942, 0x8771f681, 0x6d9d6122,
+        0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70, 0x289b7ec6, 0xeaa127fa,
+        0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665, 0xf4292244,
+        0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+        0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb,
+        0xeb86d391,
+    ];
+
+    let mut message = input.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((input.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks_exact(64) {
+        let words: Vec<u32> = block
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(K[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[(i / 16) * 4 + i % 4]);
+            (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+        }
+        for (word, added) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_key_derivation() {
+        assert_eq!(
+            md5(b"abc"),
+            [
+                0x90, 0x01, 0x50, 0x98, 0x3c, 0xd2, 0x4f, 0xb0, 0xd6, 0x96, 0x3f, 0x7d, 0x28, 0xe1,
+                0x7f, 0x72
+            ]
+        );
+        // EVP_BytesToKey chains a second MD5 block for 32-byte keys
+        let key = ShadowsocksKey::new(ShadowsocksCipher::Aes256Gcm, "password");
+        assert_eq!(key.key.len(), 32);
+        assert_eq!(key.key[..16], md5(b"password"));
+        let second = [md5(b"password").as_slice(), b"password"].concat();
+        assert_eq!(key.key[16..], md5(&second));
+    }
+
+    #[tokio::test]
+    async fn test_decodes_reference_stream() {
+        // "hello" under chacha20-ietf-poly1305 with password "secret" and
+        // the salt 00..1f, as produced by an independent implementation
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\
+                   7d9000cc6fe43ae102faf407e2b473b66c95fd8adee290464d2805ed6be44081803e90b4401028";
+        let wire: Vec<u8> = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+
+        let key = ShadowsocksKey::new(ShadowsocksCipher::Chacha20IetfPoly1305, "secret");
+        let mut reader = AeadStream::new(wire.as_slice(), key);
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        for cipher in [
+            ShadowsocksCipher::Aes128Gcm,
+            ShadowsocksCipher::Aes256Gcm,
+            ShadowsocksCipher::Chacha20IetfPoly1305,
+        ] {
+            let key = ShadowsocksKey::new(cipher, "secret");
+            let (left, right) = tokio::io::duplex(1024);
+            let mut writer = AeadStream::new(left, key.clone());
+            let mut reader = AeadStream::new(right, key);
+
+            // Spans several chunks
+            let data: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+            let expected = data.clone();
+            let write = tokio::spawn(async move {
+                writer.write_all(&data).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            write.await.unwrap();
+            assert_eq!(received, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_identify() {
+        let keys: Vec<ShadowsocksKey> = ["alice", "bob"]
+            .iter()
+            .map(|password| ShadowsocksKey::new(ShadowsocksCipher::Aes128Gcm, password))
+            .collect();
+        let (left, mut right) = tokio::io::duplex(1024);
+        let mut writer = AeadStream::new(left, keys[1].clone());
+        writer.write_all(b"hello").await.unwrap();
+        writer.flush().await.unwrap();
+
+        let mut preamble = vec![0u8; keys[0].preamble_len()];
+        right.read_exact(&mut preamble).await.unwrap();
+        assert_eq!(identify(&keys[..1], &preamble), None);
+        assert_eq!(identify(&keys, &preamble), Some(1));
+
+        let mut reader = AeadStream::resume(right, keys[1].clone(), preamble);
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_tampering() {
+        let key = ShadowsocksKey::new(ShadowsocksCipher::Chacha20IetfPoly1305, "secret");
+        let (left, mut right) = tokio::io::duplex(1024);
+        let mut writer = AeadStream::new(left, key.clone());
+        writer.write_all(b"hello").await.unwrap();
+        writer.flush().await.unwrap();
+
+        let mut wire = vec![0u8; 32 + LENGTH_LEN + 5 + TAG_LEN];
+        right.read_exact(&mut wire).await.unwrap();
+        let last = wire.len() - 1;
+        wire[last] ^= 1;
+
+        let mut reader = AeadStream::new(wire.as_slice(), key);
+        let mut buf = [0u8; 5];
+        let err = reader.read_exact(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,220 @@
+//! Server Name Indication sniffing: the host a TLS client asks for, read
+//! from the ClientHello at the start of a connection. The bytes stay in
+//! the connection's buffer, so they are relayed to the target unmodified.
+
+use std::time::Duration;
+use tokio::time::{Instant, timeout_at};
+
+use crate::net::conn::BufferedConnection;
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+/// Longest ClientHello waited for; real ones are a few kilobytes at most
+const MAX_CLIENT_HELLO: usize = 64 * 1024;
+
+/// What the start of a connection says about its server name.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientHello {
+    /// More bytes are needed to tell
+    Incomplete,
+    /// Not a TLS ClientHello
+    NotTls,
+    /// A ClientHello, with its server name if it has one
+    Parsed(Option<String>),
+}
+
+/// Waits up to `wait` for the client's ClientHello and returns the server
+/// name in it. `None` when the client sends something else, nothing in
+/// time, or a ClientHello without a usable name.
+pub async fn sniff(conn: &mut BufferedConnection, wait: Duration) -> Option<String> {
+    let deadline = Instant::now() + wait;
+    loop {
+        match server_name(conn.buffered()) {
+            ClientHello::Parsed(name) => return name,
+            ClientHello::NotTls => return None,
+            ClientHello::Incomplete => match timeout_at(deadline, conn.read()).await {
+                Ok(Ok(n)) if n > 0 => {}
+                _ => return None,
+            },
+        }
+    }
+}
+
+/// Parses the ClientHello at the start of `data`, which may be split
+/// across several handshake records.
+pub fn server_name(data: &[u8]) -> ClientHello {
+    // Reassemble the handshake message from the records' fragments
+    let mut message = Vec::new();
+    let mut rest = data;
+    loop {
+        let Some(header) = rest.get(..5) else {
+            return ClientHello::Incomplete;
+        };
+        // Record type, then a 3.x protocol version
+        if header[0] != RECORD_HANDSHAKE || header[1] != 3 {
+            return ClientHello::NotTls;
+        }
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let Some(fragment) = rest.get(5..5 + len) else {
+            return ClientHello::Incomplete;
+        };
+        message.extend_from_slice(fragment);
+        rest = &rest[5 + len..];
+
+        if message.len() < 4 {
+            continue;
+        }
+        if message[0] != HANDSHAKE_CLIENT_HELLO {
+            return ClientHello::NotTls;
+        }
+        let body_len = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+        if body_len > MAX_CLIENT_HELLO {
+            return ClientHello::NotTls;
+        }
+        if message.len() >= 4 + body_len {
+            return match parse_client_hello(&message[4..4 + body_len]) {
+                Some(name) => ClientHello::Parsed(name),
+                None => ClientHello::NotTls,
+            };
+        }
+    }
+}
+
+/// The server name in a ClientHello body, or `None` when it is malformed.
+fn parse_client_hello(body: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader(body);
+    // Legacy version and random
+    reader.take(2 + 32)?;
+    let session_id_len = reader.u8()? as usize;
+    reader.take(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.take(cipher_suites_len)?;
+    let compression_len = reader.u8()? as usize;
+    reader.take(compression_len)?;
+    if reader.0.is_empty() {
+        // No extensions at all
+        return Some(None);
+    }
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let data = extensions.take(len)?;
+        if extension_type == EXTENSION_SERVER_NAME {
+            return Some(parse_server_name(data));
+        }
+    }
+    Some(None)
+}
+
+fn parse_server_name(data: &[u8]) -> Option<String> {
+    let mut reader = Reader(data);
+    let list_len = reader.u16()? as usize;
+    let mut list = Reader(reader.take(list_len)?);
+    while !list.0.is_empty() {
+        let name_type = list.u8()?;
+        let len = list.u16()? as usize;
+        let name = list.take(len)?;
+        if name_type == NAME_TYPE_HOST_NAME {
+            return valid_host(name);
+        }
+    }
+    None
+}
+
+/// `name` as a domain, if it is one: RFC 6066 §3 rules out IP addresses.
+fn valid_host(name: &[u8]) -> Option<String> {
+    let name = std::str::from_utf8(name).ok()?.trim_end_matches('.');
+    let valid = !name.is_empty()
+        && name.len() <= 253
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_')
+        && name.parse::<std::net::IpAddr>().is_err();
+    valid.then(|| name.to_ascii_lowercase())
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    /// The first flight of a real client, up to the bytes it gets stuck on
+    /// without a server.
+    async fn client_hello(server_name: &str) -> Vec<u8> {
+        let config = ClientConfig::builder()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        tokio::spawn(async move { connector.connect(name, client).await });
+        let mut data = vec![0u8; 64 * 1024];
+        let n = tokio::io::AsyncReadExt::read(&mut server, &mut data)
+            .await
+            .unwrap();
+        data.truncate(n);
+        data
+    }
+
+    #[tokio::test]
+    async fn test_server_name() {
+        let hello = client_hello("Example.COM").await;
+        assert_eq!(
+            server_name(&hello),
+            ClientHello::Parsed(Some("example.com".to_string()))
+        );
+        assert_eq!(
+            server_name(&hello[..hello.len() - 1]),
+            ClientHello::Incomplete
+        );
+        assert_eq!(server_name(&hello[..3]), ClientHello::Incomplete);
+
+        // The same message split across two records
+        let message = &hello[5..];
+        let mut split = Vec::new();
+        for fragment in [&message[..10], &message[10..]] {
+            split.extend_from_slice(&[RECORD_HANDSHAKE, 3, 1]);
+            split.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            split.extend_from_slice(fragment);
+        }
+        assert_eq!(
+            server_name(&split),
+            ClientHello::Parsed(Some("example.com".to_string()))
+        );
+
+        // rustls sends no SNI for an IP address
+        let hello = client_hello("192.0.2.1").await;
+        assert_eq!(server_name(&hello), ClientHello::Parsed(None));
+
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), ClientHello::NotTls);
+        assert_eq!(server_name(b"SSH-2.0-OpenSSH_9.6\r\n"), ClientHello::NotTls);
+    }
+}
@@ -0,0 +1,231 @@
+//! Options for outbound sockets: the local address and the network
+//! interface they are bound to, so a multi-homed host can send some of its
+//! traffic over a particular link, and the firewall mark policy routing
+//! and nftables rules tell the proxy's own traffic apart by. Also the
+//! `[tcp]` options every TCP socket gets, inbound or outbound.
+
+use socket2::{SockRef, TcpKeepalive};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::common::config::TcpConfig;
+
+/// `outbound_mark`, set once at startup: every path out of the proxy,
+/// however deep, marks its sockets the same
+static OUTBOUND_MARK: OnceLock<u32> = OnceLock::new();
+
+pub fn set_outbound_mark(mark: u32) {
+    let _ = OUTBOUND_MARK.set(mark);
+}
+
+/// `[tcp]`, set once at startup before any socket is made
+static TCP: OnceLock<TcpConfig> = OnceLock::new();
+
+pub fn set_tcp_config(config: TcpConfig) {
+    let _ = TCP.set(config);
+}
+
+fn tcp_config() -> &'static TcpConfig {
+    TCP.get_or_init(TcpConfig::default)
+}
+
+/// Sets up a socket a client connected with `[tcp]`.
+pub fn tune_inbound(stream: &TcpStream) -> io::Result<()> {
+    let config = tcp_config();
+    stream.set_nodelay(config.inbound_nodelay)?;
+    apply(config, &SockRef::from(stream))
+}
+
+/// Sets up a socket yet to connect to a target or parent proxy with
+/// `[tcp]`, so buffer sizes are in place before the handshake.
+pub fn tune_outbound(socket: &TcpSocket) -> io::Result<()> {
+    let config = tcp_config();
+    socket.set_nodelay(config.outbound_nodelay)?;
+    apply(config, &SockRef::from(socket))
+}
+
+/// The options both sides share: buffer sizes, and keepalive and the
+/// user timeout, so a peer that vanished is noticed.
+fn apply(config: &TcpConfig, socket: &SockRef<'_>) -> io::Result<()> {
+    if config.recv_buffer_size > 0 {
+        socket.set_recv_buffer_size(config.recv_buffer_size)?;
+    }
+    if config.send_buffer_size > 0 {
+        socket.set_send_buffer_size(config.send_buffer_size)?;
+    }
+    if config.keepalive_time > 0 {
+        let mut keepalive =
+            TcpKeepalive::new().with_time(Duration::from_secs(config.keepalive_time));
+        if config.keepalive_interval > 0 {
+            keepalive = keepalive.with_interval(Duration::from_secs(config.keepalive_interval));
+        }
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    if config.user_timeout > 0 {
+        set_user_timeout(socket, Duration::from_secs(config.user_timeout))?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_user_timeout(socket: &SockRef<'_>, timeout: Duration) -> io::Result<()> {
+    socket.set_tcp_user_timeout(Some(timeout))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_user_timeout(_socket: &SockRef<'_>, _timeout: Duration) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "tcp.user_timeout is only available on Linux",
+    ))
+}
+
+/// Sets the `outbound_mark`, if any, on `socket`. Needs `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+pub fn mark<S: std::os::fd::AsFd>(socket: &S) -> io::Result<()> {
+    set_mark(OUTBOUND_MARK.get().copied(), socket)
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark<S: std::os::fd::AsFd>(mark: Option<u32>, socket: &S) -> io::Result<()> {
+    match mark {
+        Some(mark) => socket2::SockRef::from(socket).set_mark(mark),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mark<S>(_socket: &S) -> io::Result<()> {
+    match OUTBOUND_MARK.get() {
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "outbound_mark is only available on Linux",
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Where outbound sockets are bound; neither set leaves it to the routing
+/// table.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bind {
+    /// Source address connections are made from
+    pub address: Option<IpAddr>,
+    /// Interface connections leave through, with `SO_BINDTODEVICE`
+    pub interface: Option<String>,
+}
+
+impl Bind {
+    pub fn is_empty(&self) -> bool {
+        self.address.is_none() && self.interface.is_none()
+    }
+
+    /// A socket to connect to `addr` with, bound as set and marked. A
+    /// source address of the other family than `addr` fails to bind.
+    pub fn socket(&self, addr: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        mark(&socket)?;
+        tune_outbound(&socket)?;
+        if let Some(interface) = &self.interface {
+            bind_device(&socket, interface)?;
+        }
+        if let Some(ip) = self.address {
+            socket.bind(SocketAddr::new(ip, 0))?;
+        }
+        Ok(socket)
+    }
+}
+
+/// Needs `CAP_NET_RAW` on kernels before 5.7.
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &TcpSocket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only available on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_keepalive() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = SockRef::from(&socket);
+        apply(&TcpConfig::default(), &socket).unwrap();
+        assert!(!socket.keepalive().unwrap());
+
+        let config = TcpConfig {
+            keepalive_time: 30,
+            keepalive_interval: 5,
+            ..TcpConfig::default()
+        };
+        apply(&config, &socket).unwrap();
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            socket.tcp_keepalive_interval().unwrap(),
+            Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn test_apply_buffer_sizes() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = SockRef::from(&socket);
+        let config = TcpConfig {
+            recv_buffer_size: 256 * 1024,
+            send_buffer_size: 128 * 1024,
+            ..TcpConfig::default()
+        };
+        apply(&config, &socket).unwrap();
+        // Linux doubles the size asked for, for its bookkeeping
+        assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 128 * 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_apply_user_timeout() {
+        let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = SockRef::from(&socket);
+        let config = TcpConfig {
+            user_timeout: 20,
+            ..TcpConfig::default()
+        };
+        apply(&config, &socket).unwrap();
+        assert_eq!(
+            socket.tcp_user_timeout().unwrap(),
+            Some(Duration::from_secs(20))
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_set_mark() {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        set_mark(None, &socket).unwrap();
+        assert_eq!(SockRef::from(&socket).mark().unwrap(), 0);
+
+        match set_mark(Some(0x2a), &socket) {
+            Ok(()) => assert_eq!(SockRef::from(&socket).mark().unwrap(), 0x2a),
+            // Without CAP_NET_ADMIN the mark is refused, not silently dropped
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+        }
+    }
+}
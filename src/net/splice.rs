@@ -0,0 +1,131 @@
+//! Kernel-side relaying between two TCP sockets with splice(2), behind the
+//! `splice` feature on Linux. Bytes go from one socket into a pipe and from
+//! the pipe into the other socket without ever being copied to user space.
+
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// Bytes moved per splice call; the default pipe capacity on Linux.
+const CHUNK: usize = 64 * 1024;
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 succeeded, so both descriptors are open and ours
+        unsafe {
+            Ok(Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            fd_in,
+            std::ptr::null_mut(),
+            fd_out,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Relays `from` to `to` until `from` reaches EOF, then shuts down the
+/// write half of `to`. `progress` is called with the size of every chunk
+/// read. Returns the total relayed.
+pub async fn relay(
+    from: &TcpStream,
+    to: &TcpStream,
+    mut progress: impl FnMut(usize),
+) -> io::Result<u64> {
+    let pipe = Pipe::new()?;
+    let mut total = 0;
+    loop {
+        // The pipe is empty here, so a splice that would block is waiting
+        // on the socket, and clearing its readiness is right.
+        let n = loop {
+            from.readable().await?;
+            match from.try_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.write.as_raw_fd(), CHUNK)
+            }) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if n == 0 {
+            socket2::SockRef::from(to).shutdown(Shutdown::Write)?;
+            return Ok(total);
+        }
+        progress(n);
+        total += n as u64;
+
+        let mut pending = n;
+        while pending > 0 {
+            to.writable().await?;
+            match to.try_io(Interest::WRITABLE, || {
+                splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending)
+            }) {
+                Ok(written) => pending -= written,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_relay_until_eof() {
+        let (mut client, from) = socket_pair().await;
+        let (to, mut target) = socket_pair().await;
+
+        let data: Vec<u8> = (0..CHUNK * 3).map(|i| i as u8).collect();
+        let send = async {
+            client.write_all(&data).await.unwrap();
+            client.shutdown().await.unwrap();
+        };
+        let mut chunks = 0;
+        let relay = relay(&from, &to, |_| chunks += 1);
+        let mut received = Vec::new();
+        let receive = target.read_to_end(&mut received);
+
+        let ((), total, read) = tokio::join!(send, relay, receive);
+        assert_eq!(total.unwrap(), data.len() as u64);
+        assert_eq!(read.unwrap(), data.len());
+        assert_eq!(received, data);
+        assert!(chunks >= 3);
+    }
+}
@@ -0,0 +1,222 @@
+//! `direct-tcpip` channels through an SSH jump host, on libssh2.
+//!
+//! Every tunnel gets an SSH session of its own, like `ssh -W`. The session
+//! is set up with blocking calls on a blocking thread, then switched to
+//! non-blocking mode so the channel can be driven from the runtime, waiting
+//! on the socket in whichever direction libssh2 reports it is blocked on.
+
+use base64::{Engine as _, engine::general_purpose};
+use ssh2::{BlockDirections, Channel, ErrorCode, HashType, Session};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::OwnedSemaphorePermit;
+
+use crate::common::config::UpstreamConfig;
+use crate::net::addr::TargetAddr;
+use crate::net::stream::Stream;
+
+// libssh2 error codes
+const ERROR_AUTHENTICATION_FAILED: i32 = -18;
+const ERROR_PUBLICKEY_UNVERIFIED: i32 = -19;
+const ERROR_CHANNEL_FAILURE: i32 = -21;
+
+#[derive(Error, Debug)]
+pub enum SshError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Jump host key {0} does not match upstream.host_key")]
+    HostKeyMismatch(String),
+    #[error("Jump host authentication failed")]
+    AuthenticationFailed,
+    #[error("Jump host refused the channel: {0}")]
+    ChannelRefused(String),
+}
+
+impl From<ssh2::Error> for SshError {
+    fn from(e: ssh2::Error) -> Self {
+        match e.code() {
+            ErrorCode::Session(ERROR_AUTHENTICATION_FAILED | ERROR_PUBLICKEY_UNVERIFIED) => {
+                SshError::AuthenticationFailed
+            }
+            ErrorCode::Session(ERROR_CHANNEL_FAILURE) => {
+                SshError::ChannelRefused(e.message().to_string())
+            }
+            _ => SshError::IoError(e.into()),
+        }
+    }
+}
+
+/// `SHA256:` fingerprint of a host key hash, in the form `ssh-keygen -l`
+/// prints.
+pub fn fingerprint(sha256: &[u8]) -> String {
+    format!("SHA256:{}", general_purpose::STANDARD_NO_PAD.encode(sha256))
+}
+
+/// Logs into the jump host over `stream` and opens a channel to `target`.
+/// Blocks, so it belongs on a blocking thread; `timeout` bounds each step.
+pub fn open(
+    stream: TcpStream,
+    upstream: &UpstreamConfig,
+    target: &TargetAddr,
+    timeout: Duration,
+) -> Result<SshStream, SshError> {
+    let local_addr = stream.local_addr()?;
+    let peer_addr = stream.peer_addr()?;
+    let fd = stream.as_raw_fd();
+
+    let mut session = Session::new()?;
+    session.set_timeout(timeout.as_millis().try_into().unwrap_or(u32::MAX));
+    session.set_tcp_stream(stream);
+    session.handshake()?;
+
+    let host_key = session
+        .host_key_hash(HashType::Sha256)
+        .map(fingerprint)
+        .unwrap_or_default();
+    if upstream.host_key.as_deref() != Some(host_key.as_str()) {
+        return Err(SshError::HostKeyMismatch(host_key));
+    }
+
+    let username = upstream.username.as_deref().unwrap_or_default();
+    match &upstream.private_key_path {
+        Some(path) => session.userauth_pubkey_file(
+            username,
+            None,
+            Path::new(path),
+            upstream.password.as_deref(),
+        )?,
+        None => {
+            session.userauth_password(username, upstream.password.as_deref().unwrap_or_default())?
+        }
+    }
+    if !session.authenticated() {
+        return Err(SshError::AuthenticationFailed);
+    }
+
+    let host = match target {
+        TargetAddr::Ip(addr) => addr.ip().to_string(),
+        TargetAddr::Domain(domain, _) => domain.clone(),
+    };
+    let channel = session.channel_direct_tcpip(&host, target.port(), None)?;
+
+    session.set_blocking(false);
+    Ok(SshStream {
+        fd: AsyncFd::new(fd)?,
+        channel,
+        session,
+        local_addr,
+        peer_addr,
+        permit: None,
+    })
+}
+
+/// A `direct-tcpip` channel, with the session it is the only channel of.
+pub struct SshStream {
+    // Declared first so the socket leaves the reactor before the session
+    // closes it
+    fd: AsyncFd<RawFd>,
+    channel: Channel,
+    session: Session,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl SshStream {
+    /// Holds `permit` until the stream is dropped.
+    pub fn with_permit(mut self, permit: OwnedSemaphorePermit) -> Self {
+        self.permit = Some(permit);
+        self
+    }
+
+    /// Runs `op` until it stops reporting `WouldBlock`, waiting for the
+    /// socket to become ready in the direction libssh2 is blocked on. `op`
+    /// is tried first since libssh2 may hold data it already read.
+    fn poll_io<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        mut op: impl FnMut(&mut Channel) -> io::Result<T>,
+    ) -> Poll<io::Result<T>> {
+        loop {
+            match op(&mut self.channel) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return Poll::Ready(result),
+            }
+            let mut guard = match self.session.block_directions() {
+                BlockDirections::Outbound => ready!(self.fd.poll_write_ready(cx))?,
+                _ => ready!(self.fd.poll_read_ready(cx))?,
+            };
+            match op(&mut self.channel) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => guard.clear_ready(),
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl AsyncRead for SshStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = ready!(
+            self.get_mut()
+                .poll_io(cx, |channel| channel.read(buf.initialize_unfilled()))
+        )?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().poll_io(cx, |channel| channel.write(buf))
+    }
+
+    /// Channel writes are handed to the socket before they return.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_io(cx, |channel| channel.send_eof().map_err(io::Error::from))
+    }
+}
+
+impl Stream for SshStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        // `ssh-keygen -l` prints unpadded base64 of the SHA-256 digest
+        assert_eq!(
+            fingerprint(&[0u8; 32]),
+            "SHA256:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA"
+        );
+    }
+}
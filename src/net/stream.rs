@@ -0,0 +1,96 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::{client, server};
+
+/// Byte stream a `BufferedConnection` can carry: plain TCP or TLS over TCP.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Identity from the client certificate verified during the handshake.
+    fn client_identity(&self) -> Option<String> {
+        None
+    }
+
+    /// The socket itself when the stream is plain TCP, with nothing layered
+    /// on top, so bytes can be spliced straight between sockets.
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+impl Stream for TcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+/// TLS from a client: on the TLS listener, or inside an inspected tunnel.
+impl<S: Stream> Stream for server::TlsStream<S> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn client_identity(&self) -> Option<String> {
+        let cert = self.get_ref().1.peer_certificates()?.first()?;
+        crate::net::tls::certificate_identity(cert)
+    }
+}
+
+/// TLS to an upstream proxy or a gateway backend.
+impl<S: Stream> Stream for client::TlsStream<S> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+}
+
+impl Stream for Box<dyn Stream> {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn client_identity(&self) -> Option<String> {
+        (**self).client_identity()
+    }
+
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        (**self).as_tcp()
+    }
+}
+
+/// In-memory pipe standing in for a connection in tests.
+#[cfg(test)]
+impl Stream for tokio::io::DuplexStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+}
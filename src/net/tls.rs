@@ -0,0 +1,305 @@
+use std::io;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::danger::ClientCertVerifier;
+use tokio_rustls::rustls::server::{ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use x509_parser::extensions::GeneralName;
+
+use crate::common::config::TlsConfig;
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to read certificate '{0}': {1}")]
+    InvalidCertificate(String, String),
+    #[error("Failed to read private key '{0}': {1}")]
+    InvalidPrivateKey(String, String),
+    #[error("TLS configuration error: {0}")]
+    RustlsError(#[from] tokio_rustls::rustls::Error),
+    #[error("Invalid client CA '{0}': {1}")]
+    InvalidClientCa(String, String),
+    #[error("Invalid server name '{0}'")]
+    InvalidServerName(String),
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+/// Builds the acceptor wrapping inbound connections when `[tls]` is configured.
+/// `certs` supplies the certificate instead of `cert_path` and `key_path`.
+pub fn build_acceptor(
+    config: &TlsConfig,
+    certs: Option<Arc<dyn ResolvesServerCert>>,
+) -> Result<TlsAcceptor, TlsError> {
+    let mut server_config = server_config(config, certs)?;
+    server_config.alpn_protocols = alpn_protocols(&config.alpn);
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Certificate and client authentication from `[tls]`, without ALPN, which
+/// each listener sets for itself.
+pub fn server_config(
+    config: &TlsConfig,
+    certs: Option<Arc<dyn ResolvesServerCert>>,
+) -> Result<ServerConfig, TlsError> {
+    let builder = ServerConfig::builder();
+    let builder = match &config.client_ca_path {
+        Some(path) => builder.with_client_cert_verifier(client_verifier(path)?),
+        None => builder.with_no_client_auth(),
+    };
+    if let Some(certs) = certs {
+        return Ok(builder.with_cert_resolver(certs));
+    }
+
+    let certs = read_certificates(&config.cert_path)
+        .map_err(|e| TlsError::InvalidCertificate(config.cert_path.clone(), e))?;
+
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .map_err(|e| TlsError::InvalidPrivateKey(config.key_path.clone(), e.to_string()))?;
+
+    Ok(builder.with_single_cert(certs, key)?)
+}
+
+pub fn alpn_protocols(names: &[String]) -> Vec<Vec<u8>> {
+    names
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect()
+}
+
+/// Client side trusting only the CAs in `path`: `upstream.ca_path` for
+/// `upstream.tls`, or a gateway route's `ca_path`.
+pub fn build_connector(path: &str) -> Result<TlsConnector, TlsError> {
+    Ok(TlsConnector::from(Arc::new(client_config(Some(path))?)))
+}
+
+/// Client configuration trusting the CAs in `path`, or the operating
+/// system's certificate store when it is `None`.
+pub fn client_config(path: Option<&str>) -> Result<ClientConfig, TlsError> {
+    let source = path.unwrap_or("system certificate store");
+    let invalid = |e: String| TlsError::InvalidCertificate(source.to_string(), e);
+    let certs = match path {
+        Some(path) => read_certificates(path).map_err(invalid)?,
+        None => {
+            let loaded = rustls_native_certs::load_native_certs();
+            if loaded.certs.is_empty() {
+                let reason = loaded.errors.first().map(ToString::to_string);
+                return Err(invalid(
+                    reason.unwrap_or("no certificates found".to_string()),
+                ));
+            }
+            loaded.certs
+        }
+    };
+    let mut roots = RootCertStore::empty();
+    for cert in certs {
+        roots.add(cert).map_err(|e| invalid(e.to_string()))?;
+    }
+    Ok(ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+fn read_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| e.to_string())?;
+    if certs.is_empty() {
+        return Err("no certificates found".to_string());
+    }
+    Ok(certs)
+}
+
+/// Requires every client to present a certificate chaining to a CA in `path`.
+fn client_verifier(path: &str) -> Result<Arc<dyn ClientCertVerifier>, TlsError> {
+    let invalid = |e: String| TlsError::InvalidClientCa(path.to_string(), e);
+    let mut roots = RootCertStore::empty();
+    for cert in read_certificates(path).map_err(invalid)? {
+        roots.add(cert).map_err(|e| invalid(e.to_string()))?;
+    }
+    WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(|e| invalid(e.to_string()))
+}
+
+/// Name a verified client certificate identifies its holder by: the
+/// subject CN, or else the first DNS or email subject alternative name.
+pub fn certificate_identity(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    if let Some(cn) = cert
+        .subject()
+        .iter_common_name()
+        .find_map(|cn| cn.as_str().ok())
+    {
+        return Some(cn.to_string());
+    }
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) => Some(name.to_string()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::stream::Stream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    #[tokio::test]
+    async fn test_tls_acceptor() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("rust-proxy-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&cert_path, cert.cert.pem()).unwrap();
+        std::fs::write(&key_path, cert.signing_key.serialize_pem()).unwrap();
+
+        let acceptor = build_acceptor(
+            &TlsConfig {
+                cert_path: cert_path.to_string_lossy().into_owned(),
+                key_path: key_path.to_string_lossy().into_owned(),
+                alpn: vec!["http/1.1".to_string()],
+                client_ca_path: None,
+                acme: None,
+            },
+            None,
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut tls_stream = acceptor.accept(stream).await.unwrap();
+            let mut buf = [0u8; 5];
+            tls_stream.read_exact(&mut buf).await.unwrap();
+            tls_stream.write_all(&buf).await.unwrap();
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let mut client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut tls_stream = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        assert_eq!(
+            tls_stream.get_ref().1.alpn_protocol(),
+            Some(&b"http/1.1"[..])
+        );
+
+        tls_stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        tls_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates() {
+        let dir = std::env::temp_dir().join(format!("rust-proxy-mtls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let server = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(path("cert.pem"), server.cert.pem()).unwrap();
+        std::fs::write(path("key.pem"), server.signing_key.serialize_pem()).unwrap();
+
+        let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca =
+            rcgen::CertifiedIssuer::self_signed(ca_params, rcgen::KeyPair::generate().unwrap())
+                .unwrap();
+        std::fs::write(path("ca.pem"), ca.pem()).unwrap();
+
+        let client_key = rcgen::KeyPair::generate().unwrap();
+        let mut client_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        client_params.distinguished_name = rcgen::DistinguishedName::new();
+        client_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "alice");
+        let client_cert = client_params.signed_by(&client_key, &ca).unwrap();
+
+        let acceptor = build_acceptor(
+            &TlsConfig {
+                cert_path: path("cert.pem"),
+                key_path: path("key.pem"),
+                alpn: Vec::new(),
+                client_ca_path: Some(path("ca.pem")),
+                acme: None,
+            },
+            None,
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_task = tokio::spawn(async move {
+            let mut identities = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                identities.push(
+                    acceptor
+                        .accept(stream)
+                        .await
+                        .ok()
+                        .and_then(|tls_stream| tls_stream.client_identity()),
+                );
+            }
+            identities
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots.add(server.cert.der().clone()).unwrap();
+        let with_cert = ClientConfig::builder()
+            .with_root_certificates(roots.clone())
+            .with_client_auth_cert(
+                vec![client_cert.der().clone()],
+                PrivateKeyDer::Pkcs8(client_key.serialize_der().into()),
+            )
+            .unwrap();
+        let without_cert = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        for client_config in [with_cert, without_cert] {
+            let connector = TlsConnector::from(Arc::new(client_config));
+            let stream = TcpStream::connect(addr).await.unwrap();
+            if let Ok(mut tls_stream) = connector
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+            {
+                let _ = tls_stream.read(&mut [0u8; 1]).await;
+            }
+        }
+
+        assert_eq!(
+            server_task.await.unwrap(),
+            [Some("alice".to_string()), None]
+        );
+
+        // Without a CN the first DNS name identifies the client
+        let mut san_only = rcgen::CertificateParams::new(vec!["bot.example".to_string()]).unwrap();
+        san_only.distinguished_name = rcgen::DistinguishedName::new();
+        let san_cert = san_only.self_signed(&client_key).unwrap();
+        assert_eq!(
+            certificate_identity(san_cert.der()),
+            Some("bot.example".to_string())
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,82 @@
+//! Socket options behind transparent proxying on Linux, where netfilter
+//! hands the proxy connections addressed to somewhere else. `REDIRECT`
+//! rewrites the destination, which `original_dst` recovers; TPROXY leaves
+//! it intact but needs `IP_TRANSPARENT` sockets, which may also connect out
+//! from the client's own address.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Where a connection redirected by an iptables `REDIRECT` rule was headed,
+/// from the `SO_ORIGINAL_DST` conntrack lookup. Fails with `NotFound` for a
+/// connection that was not redirected.
+#[cfg(target_os = "linux")]
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+    let addr = match stream.local_addr()? {
+        SocketAddr::V4(_) => socket.original_dst_v4(),
+        SocketAddr::V6(_) => socket.original_dst_v6(),
+    }?;
+    addr.as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an IP address"))
+}
+
+/// Listens on `addr` with `IP_TRANSPARENT`, so TPROXY rules can deliver
+/// connections addressed anywhere; each one's local address is where the
+/// client was headed. Needs `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+pub fn bind_tproxy(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = transparent_socket(addr.ip())?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// A socket bound to `source` with `IP_TRANSPARENT`, ready to connect from
+/// an address that need not be local. Needs `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+pub fn spoofed_socket(source: IpAddr) -> io::Result<TcpSocket> {
+    let socket = transparent_socket(source)?;
+    crate::net::sockopt::mark(&socket)?;
+    crate::net::sockopt::tune_outbound(&socket)?;
+    socket.bind(SocketAddr::new(source, 0))?;
+    Ok(socket)
+}
+
+#[cfg(target_os = "linux")]
+fn transparent_socket(ip: IpAddr) -> io::Result<TcpSocket> {
+    let socket = match ip {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    let sock_ref = socket2::SockRef::from(&socket);
+    match ip {
+        IpAddr::V4(_) => sock_ref.set_ip_transparent_v4(true)?,
+        IpAddr::V6(_) => sock_ref.set_ip_transparent_v6(true)?,
+    }
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_tproxy(_addr: SocketAddr) -> io::Result<TcpListener> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn spoofed_socket(_source: IpAddr) -> io::Result<TcpSocket> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent proxying is only available on Linux",
+    )
+}
@@ -0,0 +1,700 @@
+//! TUN device mode: the system routes IP packets into a TUN interface, and
+//! a user-space TCP/IP stack (smoltcp) terminates the TCP connections in
+//! them, whatever address they were sent to. Each one surfaces as a
+//! `TunStream` for the proxy to dial out for. UDP datagrams bypass the
+//! stack and are relayed directly, one socket per flow.
+
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, ChecksumCapabilities, DeviceCapabilities, Medium};
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    HardwareAddress, IpAddress, IpCidr, IpListenEndpoint, IpProtocol, Ipv4Packet, Ipv4Repr,
+    Ipv6Packet, Ipv6Repr, TcpPacket, UdpPacket, UdpRepr,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{Notify, mpsc};
+
+use crate::common::config::TunConfig;
+use crate::net::stream::Stream;
+
+/// Bytes buffered in each direction between a TCP socket and its session
+const SESSION_BUFFER: usize = 64 * 1024;
+/// A UDP flow's socket is closed after this long without traffic
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub struct TunDevice {
+    fd: AsyncFd<File>,
+    name: String,
+    mtu: usize,
+}
+
+impl TunDevice {
+    /// Creates (or attaches to) the TUN interface named in `[tun]`. It still
+    /// needs an address, to be up, and routes pointing at it.
+    pub fn open(config: &TunConfig) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")?;
+
+        // SAFETY: `ifreq` is plain data, for which all zeroes is valid
+        let mut ifreq: libc::ifreq = unsafe { std::mem::zeroed() };
+        // Validation keeps the name shorter than IFNAMSIZ, leaving the NUL
+        for (dst, src) in ifreq.ifr_name.iter_mut().zip(config.name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        ifreq.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        // SAFETY: TUNSETIFF reads and writes an `ifreq`, which outlives the call
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut ifreq) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(TunDevice {
+            fd: AsyncFd::new(file)?,
+            name: config.name.clone(),
+            mtu: config.mtu,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Runs the stack over `device`, handing each TCP connection that
+/// completes its handshake to `accept`, until `accept` is closed.
+pub async fn run(device: TunDevice, accept: mpsc::Sender<TunStream>) -> io::Result<()> {
+    let mut stack = Stack::new(device.mtu);
+    let (udp_tx, mut udp_rx) = mpsc::channel::<Vec<u8>>(256);
+    let mut buf = vec![0u8; u16::MAX as usize];
+
+    while !accept.is_closed() {
+        stack.poll();
+        stack.service(&accept);
+        for packet in stack.device.tx.drain(..) {
+            write_packet(&device.fd, &packet);
+        }
+
+        let delay = stack
+            .iface
+            .poll_delay(Instant::now(), &stack.sockets)
+            .map_or(Duration::from_secs(1), |delay| {
+                Duration::from_micros(delay.total_micros())
+            });
+        tokio::select! {
+            guard = device.fd.readable() => {
+                let mut guard = guard?;
+                loop {
+                    match guard.try_io(|fd| fd.get_ref().read(&mut buf)) {
+                        Ok(Ok(n)) => stack.receive(&buf[..n], &udp_tx),
+                        Ok(Err(e)) => return Err(e),
+                        Err(_would_block) => break,
+                    }
+                }
+            }
+            Some(packet) = udp_rx.recv() => write_packet(&device.fd, &packet),
+            _ = stack.notify.notified() => {}
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Writes one packet; IP tolerates the loss when the device is full.
+fn write_packet(fd: &AsyncFd<File>, packet: &[u8]) {
+    if let Err(e) = fd.get_ref().write(packet)
+        && e.kind() != io::ErrorKind::WouldBlock
+    {
+        log::debug!("TUN write failed: {}", e);
+    }
+}
+
+type FlowKey = (SocketAddr, SocketAddr);
+
+struct TcpFlow {
+    key: FlowKey,
+    /// Set once the handshake completes and a session takes the flow
+    pipe: Option<Arc<Mutex<Pipe>>>,
+}
+
+struct Stack {
+    iface: Interface,
+    device: Queues,
+    sockets: SocketSet<'static>,
+    tcp: HashMap<SocketHandle, TcpFlow>,
+    tcp_keys: HashSet<FlowKey>,
+    udp: HashMap<FlowKey, mpsc::Sender<Vec<u8>>>,
+    /// Woken by sessions that read or wrote
+    notify: Arc<Notify>,
+}
+
+impl Stack {
+    fn new(mtu: usize) -> Self {
+        let mut device = Queues {
+            rx: VecDeque::new(),
+            tx: Vec::new(),
+            mtu,
+        };
+        let mut iface = Interface::new(
+            Config::new(HardwareAddress::Ip),
+            &mut device,
+            Instant::now(),
+        );
+        // With a default route through an address of its own, the stack
+        // takes packets for any destination as its own
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(IpAddress::v4(0, 0, 0, 1), 0));
+            let _ = addrs.push(IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1), 0));
+        });
+        let _ = iface
+            .routes_mut()
+            .add_default_ipv4_route(Ipv4Addr::new(0, 0, 0, 1));
+        let _ = iface
+            .routes_mut()
+            .add_default_ipv6_route(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
+        iface.set_any_ip(true);
+
+        Stack {
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            tcp: HashMap::new(),
+            tcp_keys: HashSet::new(),
+            udp: HashMap::new(),
+            notify: Arc::new(Notify::new()),
+        }
+    }
+
+    fn poll(&mut self) {
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+    }
+
+    /// Takes a packet from the device: UDP is relayed here, and a TCP SYN
+    /// gets a socket listening on its destination before the stack sees it.
+    fn receive(&mut self, packet: &[u8], udp_tx: &mpsc::Sender<Vec<u8>>) {
+        let Some((protocol, payload, src_ip, dst_ip)) = parse_ip(packet) else {
+            return;
+        };
+        match protocol {
+            IpProtocol::Udp => {
+                let Ok(udp) = UdpPacket::new_checked(payload) else {
+                    return;
+                };
+                let src = SocketAddr::new(src_ip, udp.src_port());
+                let dst = SocketAddr::new(dst_ip, udp.dst_port());
+                self.relay_udp((src, dst), udp.payload(), udp_tx);
+                return;
+            }
+            IpProtocol::Tcp => {
+                let Ok(tcp) = TcpPacket::new_checked(payload) else {
+                    return;
+                };
+                let src = SocketAddr::new(src_ip, tcp.src_port());
+                let dst = SocketAddr::new(dst_ip, tcp.dst_port());
+                if tcp.syn() && !tcp.ack() && self.tcp_keys.insert((src, dst)) {
+                    self.listen((src, dst));
+                }
+            }
+            _ => {}
+        }
+        self.device.rx.push_back(packet.to_vec());
+    }
+
+    fn listen(&mut self, key: FlowKey) {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; SESSION_BUFFER]),
+            tcp::SocketBuffer::new(vec![0; SESSION_BUFFER]),
+        );
+        let endpoint = IpListenEndpoint {
+            addr: Some(key.1.ip().into()),
+            port: key.1.port(),
+        };
+        if socket.listen(endpoint).is_err() {
+            self.tcp_keys.remove(&key);
+            return;
+        }
+        let handle = self.sockets.add(socket);
+        self.tcp.insert(handle, TcpFlow { key, pipe: None });
+    }
+
+    fn relay_udp(&mut self, key: FlowKey, payload: &[u8], udp_tx: &mpsc::Sender<Vec<u8>>) {
+        let sender = match self.udp.get(&key) {
+            Some(sender) if !sender.is_closed() => sender,
+            _ => {
+                let (sender, receiver) = mpsc::channel(64);
+                let udp_tx = udp_tx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = udp_flow(key, receiver, udp_tx).await {
+                        log::debug!("TUN UDP flow {} -> {} failed: {}", key.0, key.1, e);
+                    }
+                });
+                self.udp.retain(|_, sender| !sender.is_closed());
+                self.udp.entry(key).insert_entry(sender).into_mut()
+            }
+        };
+        // Dropped when the flow is backed up, as the network would
+        let _ = sender.try_send(payload.to_vec());
+    }
+
+    /// Moves data between sockets and their sessions, hands established
+    /// connections to `accept`, and drops finished ones.
+    fn service(&mut self, accept: &mpsc::Sender<TunStream>) {
+        let mut finished = Vec::new();
+        for (&handle, flow) in self.tcp.iter_mut() {
+            let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+            let Some(pipe) = &flow.pipe else {
+                match socket.state() {
+                    tcp::State::SynReceived => {}
+                    tcp::State::Established | tcp::State::CloseWait => {
+                        let pipe = Arc::new(Mutex::new(Pipe::default()));
+                        let stream = TunStream {
+                            pipe: pipe.clone(),
+                            notify: self.notify.clone(),
+                            local_addr: flow.key.1,
+                            peer_addr: flow.key.0,
+                        };
+                        if accept.try_send(stream).is_err() {
+                            socket.abort();
+                        }
+                        flow.pipe = Some(pipe);
+                    }
+                    // Reset or timed out during the handshake
+                    _ => finished.push(handle),
+                }
+                continue;
+            };
+
+            let mut pipe = pipe.lock().unwrap();
+            let (mut readable, mut writable) = (false, false);
+            while socket.can_recv() && pipe.inbound.len() < SESSION_BUFFER {
+                let room = SESSION_BUFFER - pipe.inbound.len();
+                let _ = socket.recv(|data| {
+                    let n = room.min(data.len());
+                    pipe.inbound.extend_from_slice(&data[..n]);
+                    (n, ())
+                });
+                readable = true;
+            }
+            if !socket.may_recv() && !socket.can_recv() && !pipe.read_closed {
+                pipe.read_closed = true;
+                readable = true;
+            }
+            if !pipe.outbound.is_empty() && socket.can_send() {
+                let n = socket.send_slice(&pipe.outbound).unwrap_or(0);
+                pipe.outbound.drain(..n);
+                writable = n > 0;
+            }
+            if (pipe.write_closed || pipe.dropped) && pipe.outbound.is_empty() {
+                socket.close();
+            }
+            if matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait) {
+                if !pipe.reset {
+                    pipe.reset = true;
+                    pipe.read_closed = true;
+                    (readable, writable) = (true, true);
+                }
+                if pipe.dropped {
+                    finished.push(handle);
+                }
+            }
+            if readable && let Some(waker) = pipe.read_waker.take() {
+                waker.wake();
+            }
+            if writable && let Some(waker) = pipe.write_waker.take() {
+                waker.wake();
+            }
+        }
+
+        for handle in finished {
+            if let Some(flow) = self.tcp.remove(&handle) {
+                self.tcp_keys.remove(&flow.key);
+            }
+            self.sockets.remove(handle);
+        }
+    }
+}
+
+/// Source, destination and payload of an unfragmented IP packet.
+fn parse_ip(packet: &[u8]) -> Option<(IpProtocol, &[u8], IpAddr, IpAddr)> {
+    match packet.first()? >> 4 {
+        4 => {
+            let ip = Ipv4Packet::new_checked(packet).ok()?;
+            if ip.more_frags() || ip.frag_offset() != 0 {
+                return None;
+            }
+            let (src, dst) = (ip.src_addr().into(), ip.dst_addr().into());
+            let payload = &packet[ip.header_len() as usize..ip.total_len() as usize];
+            Some((ip.next_header(), payload, src, dst))
+        }
+        6 => {
+            let ip = Ipv6Packet::new_checked(packet).ok()?;
+            let (src, dst) = (ip.src_addr().into(), ip.dst_addr().into());
+            let start = packet.len() - ip.payload().len();
+            Some((ip.next_header(), &packet[start..], src, dst))
+        }
+        _ => None,
+    }
+}
+
+/// An IP packet carrying a UDP datagram from `src` to `dst`.
+fn udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    let udp = UdpRepr {
+        src_port: src.port(),
+        dst_port: dst.port(),
+    };
+    let udp_len = udp.header_len() + payload.len();
+    let caps = ChecksumCapabilities::default();
+    let mut buf;
+    let udp_buf = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => {
+            let ip = Ipv4Repr {
+                src_addr,
+                dst_addr,
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            buf = vec![0u8; ip.buffer_len() + udp_len];
+            ip.emit(&mut Ipv4Packet::new_unchecked(&mut buf[..]), &caps);
+            &mut buf[ip.buffer_len()..]
+        }
+        (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => {
+            let ip = Ipv6Repr {
+                src_addr,
+                dst_addr,
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            buf = vec![0u8; ip.buffer_len() + udp_len];
+            ip.emit(&mut Ipv6Packet::new_unchecked(&mut buf[..]));
+            &mut buf[ip.buffer_len()..]
+        }
+        _ => return None,
+    };
+    udp.emit(
+        &mut UdpPacket::new_unchecked(udp_buf),
+        &src.ip().into(),
+        &dst.ip().into(),
+        payload.len(),
+        |out| out.copy_from_slice(payload),
+        &caps,
+    );
+    Some(buf)
+}
+
+/// Relays one UDP flow until it goes idle, wrapping replies in IP packets
+/// for the device.
+async fn udp_flow(
+    (src, dst): FlowKey,
+    mut from_client: mpsc::Receiver<Vec<u8>>,
+    to_device: mpsc::Sender<Vec<u8>>,
+) -> io::Result<()> {
+    let bind: SocketAddr = match dst {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    crate::net::sockopt::mark(&socket)?;
+    socket.connect(dst).await?;
+    let mut buf = vec![0u8; u16::MAX as usize];
+    loop {
+        tokio::select! {
+            payload = from_client.recv() => match payload {
+                Some(payload) => {
+                    socket.send(&payload).await?;
+                }
+                None => return Ok(()),
+            },
+            n = socket.recv(&mut buf) => {
+                if let Some(packet) = udp_packet(dst, src, &buf[..n?]) {
+                    let _ = to_device.try_send(packet);
+                }
+            }
+            _ = tokio::time::sleep(UDP_IDLE_TIMEOUT) => return Ok(()),
+        }
+    }
+}
+
+/// Packets between the device and the stack: `rx` read from the device,
+/// `tx` to be written to it.
+struct Queues {
+    rx: VecDeque<Vec<u8>>,
+    tx: Vec<Vec<u8>>,
+    mtu: usize,
+}
+
+impl phy::Device for Queues {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0u8; len];
+        let result = f(&mut packet);
+        self.0.push(packet);
+        result
+    }
+}
+
+/// Buffers shared by a TCP socket in the stack and its `TunStream`.
+#[derive(Default)]
+struct Pipe {
+    /// From the client, not yet read by the session
+    inbound: Vec<u8>,
+    /// From the session, not yet taken by the socket
+    outbound: Vec<u8>,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    /// The client sends nothing more
+    read_closed: bool,
+    /// The connection is gone
+    reset: bool,
+    /// The session shut down its sending side
+    write_closed: bool,
+    /// The session let go of the stream
+    dropped: bool,
+}
+
+/// A TCP connection from a client behind the TUN device; `local_addr` is
+/// the destination the client connected to.
+pub struct TunStream {
+    pipe: Arc<Mutex<Pipe>>,
+    notify: Arc<Notify>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl AsyncRead for TunStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut pipe = self.pipe.lock().unwrap();
+        if pipe.inbound.is_empty() {
+            if pipe.read_closed {
+                return Poll::Ready(Ok(()));
+            }
+            pipe.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.remaining().min(pipe.inbound.len());
+        buf.put_slice(&pipe.inbound[..n]);
+        pipe.inbound.drain(..n);
+        self.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TunStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = self.pipe.lock().unwrap();
+        if pipe.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        let room = SESSION_BUFFER - pipe.outbound.len();
+        if room == 0 {
+            pipe.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = room.min(buf.len());
+        pipe.outbound.extend_from_slice(&buf[..n]);
+        self.notify.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    /// Waits until the socket has taken everything written.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pipe = self.pipe.lock().unwrap();
+        if pipe.outbound.is_empty() || pipe.reset {
+            return Poll::Ready(Ok(()));
+        }
+        pipe.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.pipe.lock().unwrap().write_closed = true;
+        self.notify.notify_one();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TunStream {
+    fn drop(&mut self) {
+        self.pipe.lock().unwrap().dropped = true;
+        self.notify.notify_one();
+    }
+}
+
+impl Stream for TunStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::wire::TcpRepr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_udp_packet() {
+        let src: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let packet = udp_packet(src, dst, b"answer").unwrap();
+        let (protocol, payload, src_ip, dst_ip) = parse_ip(&packet).unwrap();
+        assert_eq!(protocol, IpProtocol::Udp);
+        assert_eq!((src_ip, dst_ip), (src.ip(), dst.ip()));
+        let udp = UdpPacket::new_checked(payload).unwrap();
+        assert!(udp.verify_checksum(&src.ip().into(), &dst.ip().into()));
+        assert_eq!(udp.payload(), b"answer");
+
+        let v6: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+        assert!(udp_packet(v6, dst, b"answer").is_none());
+    }
+
+    /// A TCP segment from `src` to `dst` as an IPv4 packet.
+    fn segment(src: SocketAddr, dst: SocketAddr, repr: &TcpRepr) -> Vec<u8> {
+        let (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) = (src.ip(), dst.ip()) else {
+            unreachable!()
+        };
+        let ip = Ipv4Repr {
+            src_addr,
+            dst_addr,
+            next_header: IpProtocol::Tcp,
+            payload_len: repr.buffer_len(),
+            hop_limit: 64,
+        };
+        let mut buf = vec![0u8; ip.buffer_len() + repr.buffer_len()];
+        let caps = ChecksumCapabilities::default();
+        ip.emit(&mut Ipv4Packet::new_unchecked(&mut buf[..]), &caps);
+        repr.emit(
+            &mut TcpPacket::new_unchecked(&mut buf[ip.buffer_len()..]),
+            &src.ip().into(),
+            &dst.ip().into(),
+            &caps,
+        );
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_tcp_handshake() {
+        let client: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let server: SocketAddr = "203.0.113.9:80".parse().unwrap();
+        let mut stack = Stack::new(1500);
+        let (udp_tx, _udp_rx) = mpsc::channel(1);
+        let (accept_tx, mut accept_rx) = mpsc::channel(1);
+
+        let syn = TcpRepr {
+            src_port: client.port(),
+            dst_port: server.port(),
+            control: smoltcp::wire::TcpControl::Syn,
+            seq_number: smoltcp::wire::TcpSeqNumber(1000),
+            ack_number: None,
+            window_len: 65535,
+            window_scale: None,
+            max_seg_size: None,
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            timestamp: None,
+            payload: &[],
+        };
+        stack.receive(&segment(client, server, &syn), &udp_tx);
+        stack.poll();
+
+        // The stack answers for the destination with a SYN-ACK
+        let reply = stack.device.tx.pop().unwrap();
+        let (_, payload, src_ip, dst_ip) = parse_ip(&reply).unwrap();
+        assert_eq!((src_ip, dst_ip), (server.ip(), client.ip()));
+        let syn_ack = TcpPacket::new_checked(payload).unwrap();
+        assert!(syn_ack.syn() && syn_ack.ack());
+
+        let ack = TcpRepr {
+            control: smoltcp::wire::TcpControl::None,
+            seq_number: syn.seq_number + 1,
+            ack_number: Some(syn_ack.seq_number() + 1),
+            payload: b"hello",
+            ..syn
+        };
+        stack.receive(&segment(client, server, &ack), &udp_tx);
+        stack.poll();
+        stack.service(&accept_tx);
+        stack.service(&accept_tx);
+
+        let mut stream = accept_rx.try_recv().unwrap();
+        assert_eq!(stream.local_addr().unwrap(), server);
+        assert_eq!(stream.peer_addr().unwrap(), client);
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // Written data goes out in a segment to the client
+        stream.write_all(b"world").await.unwrap();
+        stack.service(&accept_tx);
+        stack.poll();
+        let sent = stack.device.tx.drain(..).any(|packet| {
+            let (_, payload, _, _) = parse_ip(&packet).unwrap();
+            TcpPacket::new_checked(payload).unwrap().payload() == b"world"
+        });
+        assert!(sent);
+    }
+}
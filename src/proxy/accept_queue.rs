@@ -0,0 +1,189 @@
+//! Admission against `max_connections`. With `[accept_queue]`, a connection
+//! accepted while every slot is taken waits up to `wait_timeout` for one
+//! to free up instead of being dropped, so a short burst is absorbed. At
+//! most `max_waiting` connections wait at once; any more are dropped.
+
+use log::debug;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::timeout;
+
+use crate::common::config::AcceptQueueConfig;
+
+pub struct AcceptQueue {
+    semaphore: Arc<Semaphore>,
+    /// 0 when there is no queue
+    max_waiting: usize,
+    wait_timeout: Duration,
+    waiting: AtomicUsize,
+    /// Connections that had to wait, admitted or not
+    queued: AtomicU64,
+    timed_out: AtomicU64,
+    /// Microseconds spent waiting by admitted connections
+    wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+/// A connection's place: a slot already, or a turn to wait for one.
+pub enum Ticket {
+    Admitted(OwnedSemaphorePermit),
+    Queued(QueuedTicket),
+}
+
+/// Counts as waiting until dropped.
+pub struct QueuedTicket {
+    queue: Arc<AcceptQueue>,
+    since: Instant,
+}
+
+impl AcceptQueue {
+    pub fn new(max_connections: usize, config: Option<&AcceptQueueConfig>) -> Self {
+        AcceptQueue {
+            semaphore: Arc::new(Semaphore::new(max_connections)),
+            max_waiting: config.map_or(0, |config| config.max_waiting),
+            wait_timeout: Duration::from_millis(config.map_or(0, |config| config.wait_timeout)),
+            waiting: AtomicUsize::new(0),
+            queued: AtomicU64::new(0),
+            timed_out: AtomicU64::new(0),
+            wait_micros: AtomicU64::new(0),
+            max_wait_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// A free slot, if any, without queueing.
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.semaphore.clone().try_acquire_owned().ok()
+    }
+
+    /// A slot, or a place in the queue. None when both are full.
+    pub fn enter(self: &Arc<Self>) -> Option<Ticket> {
+        if let Some(permit) = self.try_acquire() {
+            return Some(Ticket::Admitted(permit));
+        }
+        self.waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| {
+                (waiting < self.max_waiting).then_some(waiting + 1)
+            })
+            .ok()?;
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        Some(Ticket::Queued(QueuedTicket {
+            queue: self.clone(),
+            since: Instant::now(),
+        }))
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Logs the queue counters every `interval`, forever.
+    pub async fn report(&self, interval: Duration) {
+        if self.max_waiting == 0 {
+            return;
+        }
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let queued = self.queued.load(Ordering::Relaxed);
+            let timed_out = self.timed_out.load(Ordering::Relaxed);
+            let admitted = queued.saturating_sub(timed_out).max(1);
+            debug!(
+                "Accept queue: {} queued, {} timed out, {:.1} ms average wait, {:.1} ms longest, {} waiting",
+                queued,
+                timed_out,
+                self.wait_micros.load(Ordering::Relaxed) as f64 / admitted as f64 / 1000.0,
+                self.max_wait_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+                self.waiting()
+            );
+        }
+    }
+}
+
+impl Ticket {
+    /// Waits for a slot; None if `wait_timeout` passes first.
+    pub async fn admit(self) -> Option<OwnedSemaphorePermit> {
+        match self {
+            Ticket::Admitted(permit) => Some(permit),
+            Ticket::Queued(ticket) => ticket.wait().await,
+        }
+    }
+}
+
+impl QueuedTicket {
+    async fn wait(self) -> Option<OwnedSemaphorePermit> {
+        let queue = &self.queue;
+        let acquire = queue.semaphore.clone().acquire_owned();
+        match timeout(queue.wait_timeout, acquire).await {
+            Ok(Ok(permit)) => {
+                let waited = self.since.elapsed().as_micros() as u64;
+                queue.wait_micros.fetch_add(waited, Ordering::Relaxed);
+                queue.max_wait_micros.fetch_max(waited, Ordering::Relaxed);
+                Some(permit)
+            }
+            // The semaphore is never closed
+            Ok(Err(_)) => None,
+            Err(_) => {
+                queue.timed_out.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for QueuedTicket {
+    fn drop(&mut self) {
+        self.queue.waiting.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(max_waiting: usize, wait_timeout: u64) -> Arc<AcceptQueue> {
+        let config = AcceptQueueConfig {
+            max_waiting,
+            wait_timeout,
+        };
+        Arc::new(AcceptQueue::new(1, Some(&config)))
+    }
+
+    #[tokio::test]
+    async fn test_queued_until_a_slot_frees() {
+        let queue = queue(1, 5000);
+        let held = queue.enter().unwrap().admit().await.unwrap();
+
+        let ticket = queue.enter().unwrap();
+        assert!(matches!(ticket, Ticket::Queued(_)));
+        assert_eq!(queue.waiting(), 1);
+        // The queue holds one
+        assert!(queue.enter().is_none());
+
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(held);
+        };
+        let (permit, ()) = tokio::join!(ticket.admit(), release);
+        assert!(permit.is_some());
+        assert_eq!(queue.waiting(), 0);
+        assert_eq!(queue.queued.load(Ordering::Relaxed), 1);
+        assert!(queue.max_wait_micros.load(Ordering::Relaxed) >= 50_000);
+    }
+
+    #[tokio::test]
+    async fn test_wait_timeout() {
+        let queue = queue(4, 50);
+        let _held = queue.try_acquire().unwrap();
+        assert!(queue.enter().unwrap().admit().await.is_none());
+        assert_eq!(queue.timed_out.load(Ordering::Relaxed), 1);
+        assert_eq!(queue.waiting(), 0);
+
+        // Without [accept_queue] nothing waits
+        let unqueued = Arc::new(AcceptQueue::new(1, None));
+        let _held = unqueued.try_acquire().unwrap();
+        assert!(unqueued.enter().is_none());
+    }
+}
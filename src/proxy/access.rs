@@ -0,0 +1,69 @@
+//! `[access]`: which source addresses may connect at all, checked as soon
+//! as a connection is accepted and before any handshake.
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::common::config::AccessConfig;
+
+pub struct AccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    /// Connections refused since startup
+    rejected: AtomicU64,
+}
+
+impl AccessList {
+    pub fn new(config: &AccessConfig) -> Self {
+        AccessList {
+            allow: config.allow_cidrs.clone(),
+            deny: config.deny_cidrs.clone(),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether a client at `ip` may connect: outside `deny_cidrs` and, when
+    /// `allow_cidrs` is set, within it.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // Clients of a dual-stack listener show up as IPv4-mapped addresses
+        let ip = ip.to_canonical();
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+
+    /// Like `is_allowed`, counting refusals. Returns the running count
+    /// when `ip` is refused.
+    pub fn check(&self, ip: IpAddr) -> Result<(), u64> {
+        if self.is_allowed(ip) {
+            return Ok(());
+        }
+        Err(self.rejected.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let access = AccessList::new(&AccessConfig {
+            allow_cidrs: vec!["192.168.1.0/24".parse().unwrap()],
+            deny_cidrs: vec!["192.168.1.13/32".parse().unwrap()],
+        });
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(access.check(ip("192.168.1.10")).is_ok());
+        assert!(access.check(ip("::ffff:192.168.1.10")).is_ok());
+        assert_eq!(access.check(ip("192.168.1.13")), Err(1));
+        assert_eq!(access.check(ip("10.0.0.1")), Err(2));
+        assert_eq!(access.rejected(), 2);
+
+        let open = AccessList::new(&AccessConfig::default());
+        assert!(open.is_allowed(ip("203.0.113.7")));
+    }
+}
@@ -0,0 +1,154 @@
+//! `[bandwidth]`: caps on the bytes per second relayed, each a token bucket.
+//! A connection may be held to its own rate, to one all connections of its
+//! user share and to one all connections of its listener share, whichever
+//! is slowest at the moment.
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::common::auth::{AuthContext, AuthDecision, AuthError, AuthProvider};
+use crate::common::config::BandwidthConfig;
+use crate::net::rate_limit::{Limit, RateLimiter};
+
+/// Users with a limiter beyond which those without sessions are swept out
+const SWEEP_SIZE: usize = 4096;
+
+pub struct Bandwidth {
+    /// Bytes per second, 0 for no limit
+    connection_rate: u64,
+    user_rate: u64,
+    listener_rate: u64,
+    /// Bytes each limiter lets through at once
+    burst: u64,
+    users: Mutex<HashMap<String, Arc<RateLimiter>>>,
+}
+
+impl Bandwidth {
+    pub fn new(config: &BandwidthConfig) -> Self {
+        Bandwidth {
+            connection_rate: config.connection_rate * 1024,
+            user_rate: config.user_rate * 1024,
+            listener_rate: config.listener_rate * 1024,
+            burst: config.burst * 1024,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn limiter(&self, rate: u64) -> Option<Arc<RateLimiter>> {
+        (rate > 0).then(|| Arc::new(RateLimiter::new(rate, self.burst)))
+    }
+
+    /// A limiter for one connection alone.
+    pub fn connection_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.limiter(self.connection_rate)
+    }
+
+    /// A limiter for the connections of one listener to share.
+    pub fn listener_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.limiter(self.listener_rate)
+    }
+
+    /// The limiter every connection of `username` shares.
+    fn user_limiter(&self, username: &str) -> Option<Arc<RateLimiter>> {
+        if self.user_rate == 0 {
+            return None;
+        }
+        let mut users = self.users.lock().unwrap();
+        if users.len() >= SWEEP_SIZE {
+            users.retain(|_, limiter| Arc::strong_count(limiter) > 1);
+        }
+        let limiter = users
+            .entry(username.to_string())
+            .or_insert_with(|| Arc::new(RateLimiter::new(self.user_rate, self.burst)));
+        Some(limiter.clone())
+    }
+}
+
+/// Adds the `[bandwidth]` limit of each user to those of `inner`.
+pub struct BandwidthProvider {
+    inner: Arc<dyn AuthProvider>,
+    bandwidth: Arc<Bandwidth>,
+}
+
+impl BandwidthProvider {
+    pub fn new(inner: Arc<dyn AuthProvider>, bandwidth: Arc<Bandwidth>) -> Self {
+        BandwidthProvider { inner, bandwidth }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for BandwidthProvider {
+    fn is_required(&self) -> bool {
+        self.inner.is_required()
+    }
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        context: &AuthContext,
+    ) -> AuthDecision {
+        self.inner.authenticate(username, password, context).await
+    }
+
+    fn certificate_user(&self, identity: Option<String>) -> Option<String> {
+        self.inner.certificate_user(identity)
+    }
+
+    fn allows_port(&self, username: &str, port: u16) -> bool {
+        self.inner.allows_port(username, port)
+    }
+
+    fn quota(&self, username: &str) -> Option<u64> {
+        self.inner.quota(username)
+    }
+
+    fn admits(&self, username: &str) -> Result<(), AuthError> {
+        self.inner.admits(username)
+    }
+
+    fn record_usage(&self, username: &str, bytes: u64) {
+        self.inner.record_usage(username, bytes);
+    }
+
+    fn rate_limits(&self, username: &str) -> Vec<Arc<dyn Limit>> {
+        let mut limits = self.inner.rate_limits(username);
+        if let Some(limiter) = self.bandwidth.user_limiter(username) {
+            limits.push(limiter);
+        }
+        limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::auth::AuthManager;
+
+    #[test]
+    fn test_bandwidth_limiters() {
+        let bandwidth = Arc::new(Bandwidth::new(&BandwidthConfig {
+            connection_rate: 0,
+            user_rate: 128,
+            listener_rate: 1024,
+            burst: 64,
+        }));
+        assert!(bandwidth.connection_limiter().is_none());
+        let listener = bandwidth.listener_limiter().unwrap();
+        assert!(!Arc::ptr_eq(
+            &listener,
+            &bandwidth.listener_limiter().unwrap()
+        ));
+
+        let auth = BandwidthProvider::new(
+            Arc::new(AuthManager::new(&HashMap::new()).unwrap()),
+            bandwidth.clone(),
+        );
+        // Every session of a user shares one limiter
+        let alice = auth.rate_limits("alice");
+        assert_eq!(alice.len(), 1);
+        assert!(Arc::ptr_eq(&alice[0], &auth.rate_limits("alice")[0]));
+        assert!(!Arc::ptr_eq(&alice[0], &auth.rate_limits("bob")[0]));
+    }
+}
@@ -0,0 +1,227 @@
+//! Domain blocklist from a hosts file or a list with one domain per line.
+//! `*.ads.example` blocks every subdomain of `ads.example`, a plain entry
+//! only the domain itself. The file is reloaded on SIGHUP and whenever its
+//! modification time changes, without dropping connections.
+
+use log::{error, info};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::common::signal::hangup_signal;
+
+/// Names hosts files map to the loopback address, which are not ads.
+const HOSTS_BUILTINS: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+    "ip6-allhosts",
+    "0.0.0.0",
+];
+
+/// A trie over domain labels, right to left, so that a lookup costs the
+/// number of labels in the domain whatever the size of the list.
+#[derive(Default)]
+struct DomainSet {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<Box<str>, Node>,
+    /// The domain ending here is listed
+    exact: bool,
+    /// Every subdomain of the domain ending here is listed
+    subdomains: bool,
+}
+
+impl DomainSet {
+    fn parse(contents: &str) -> Self {
+        let mut set = DomainSet::default();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(first) = fields.next() else {
+                continue;
+            };
+            // Hosts file lines name the address first, then one or more hosts
+            if first.parse::<IpAddr>().is_ok() {
+                for host in fields {
+                    if HOSTS_BUILTINS.contains(&host.trim_end_matches('.')) {
+                        continue;
+                    }
+                    set.insert(host);
+                }
+            } else {
+                set.insert(first);
+            }
+        }
+        set
+    }
+
+    fn insert(&mut self, entry: &str) {
+        let entry = entry.trim_end_matches('.').to_ascii_lowercase();
+        let (domain, subdomains) = match entry.strip_prefix("*.") {
+            Some(domain) => (domain, true),
+            None => (entry.as_str(), false),
+        };
+        if domain.is_empty() {
+            return;
+        }
+        let node = domain.rsplit('.').fold(&mut self.root, |node, label| {
+            node.children.entry(label.into()).or_default()
+        });
+        let flag = if subdomains {
+            &mut node.subdomains
+        } else {
+            &mut node.exact
+        };
+        if !*flag {
+            *flag = true;
+            self.len += 1;
+        }
+    }
+
+    fn contains(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let mut node = &self.root;
+        for label in domain.rsplit('.') {
+            if node.subdomains {
+                return true;
+            }
+            match node.children.get(label) {
+                Some(child) => node = child,
+                None => return false,
+            }
+        }
+        node.exact
+    }
+}
+
+pub struct Blocklist {
+    path: PathBuf,
+    domains: RwLock<Arc<DomainSet>>,
+    /// Modification time of the file last loaded
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Blocklist {
+    pub fn load(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let blocklist = Blocklist {
+            path: path.into(),
+            domains: RwLock::new(Arc::new(DomainSet::default())),
+            modified: Mutex::new(None),
+        };
+        blocklist.reload()?;
+        Ok(blocklist)
+    }
+
+    pub fn is_blocked(&self, domain: &str) -> bool {
+        // Lookups only hold the lock long enough to clone the Arc
+        let domains = self.domains.read().unwrap().clone();
+        domains.contains(domain)
+    }
+
+    /// Reads the file again, returning the number of entries. On failure
+    /// the list in use is kept.
+    pub fn reload(&self) -> io::Result<usize> {
+        let modified = fs::metadata(&self.path)?.modified().ok();
+        let domains = DomainSet::parse(&fs::read_to_string(&self.path)?);
+        let len = domains.len;
+        *self.domains.write().unwrap() = Arc::new(domains);
+        *self.modified.lock().unwrap() = modified;
+        Ok(len)
+    }
+
+    fn is_modified(&self) -> bool {
+        let modified = fs::metadata(&self.path).and_then(|metadata| metadata.modified());
+        modified.is_ok_and(|modified| *self.modified.lock().unwrap() != Some(modified))
+    }
+
+    /// Reloads the list on SIGHUP, and when `interval` is not zero, every
+    /// time the file is found changed after sleeping that long. Runs until
+    /// Ctrl-C / SIGINT is received.
+    pub async fn watch(&self, interval: Duration) {
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+        let mut hangup = hangup_signal();
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {}
+                _ = tokio::time::sleep(interval), if !interval.is_zero() => {
+                    if !self.is_modified() {
+                        continue;
+                    }
+                }
+                _ = &mut shutdown => break,
+            }
+            match self.reload() {
+                Ok(len) => info!(
+                    "Reloaded blocklist {}: {} entries",
+                    self.path.display(),
+                    len
+                ),
+                Err(e) => error!("Failed to reload blocklist {}: {}", self.path.display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_set() {
+        let set = DomainSet::parse(
+            "# comment\n\
+             ads.example\n\
+             *.tracker.example  # trailing comment\n\
+             0.0.0.0 banner.example popup.example\n\
+             127.0.0.1 localhost\n\
+             \n",
+        );
+        assert_eq!(set.len, 4);
+        assert!(set.contains("ads.example"));
+        assert!(set.contains("ADS.example."));
+        assert!(!set.contains("www.ads.example"));
+        assert!(set.contains("a.b.tracker.example"));
+        assert!(!set.contains("tracker.example"));
+        assert!(set.contains("popup.example"));
+        assert!(!set.contains("localhost"));
+        assert!(!set.contains("example"));
+    }
+
+    #[test]
+    fn test_reload() {
+        let path =
+            std::env::temp_dir().join(format!("rust-proxy-blocklist-{}", std::process::id()));
+        fs::write(&path, "ads.example\n").unwrap();
+        let blocklist = Blocklist::load(&path).unwrap();
+        assert!(blocklist.is_blocked("ads.example"));
+        assert!(!blocklist.is_modified());
+
+        fs::write(&path, "other.example\n").unwrap();
+        assert_eq!(blocklist.reload().unwrap(), 1);
+        assert!(!blocklist.is_blocked("ads.example"));
+        assert!(blocklist.is_blocked("other.example"));
+
+        // A missing file leaves the list in use
+        fs::remove_file(&path).unwrap();
+        assert!(blocklist.reload().is_err());
+        assert!(blocklist.is_blocked("other.example"));
+    }
+}
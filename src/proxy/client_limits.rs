@@ -0,0 +1,162 @@
+//! `[client_limits]`: per source address caps on open connections and on
+//! how fast new ones may arrive, a token bucket per address. Checked right
+//! after accept, independently of authentication.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::common::config::ClientLimitsConfig;
+
+/// Tracked addresses beyond which idle ones are swept out
+const SWEEP_SIZE: usize = 4096;
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// The address has `max_connections` open already
+    TooManyConnections,
+    /// The address opens connections faster than `connections_per_second`
+    RateLimited,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::TooManyConnections => write!(f, "too many connections from this address"),
+            Refusal::RateLimited => write!(f, "connecting too fast"),
+        }
+    }
+}
+
+struct Client {
+    open: usize,
+    /// New connections the address may open at once
+    tokens: f64,
+    refilled: Instant,
+}
+
+pub struct ClientLimits {
+    max_connections: usize,
+    rate: f64,
+    burst: f64,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+}
+
+impl ClientLimits {
+    pub fn new(config: &ClientLimitsConfig) -> Self {
+        ClientLimits {
+            max_connections: config.max_connections,
+            rate: f64::from(config.connections_per_second),
+            burst: f64::from(config.burst),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Admits a connection from `ip`, counted as open until the returned
+    /// permit is dropped.
+    pub fn admit(self: &Arc<Self>, ip: IpAddr) -> Result<ClientPermit, Refusal> {
+        // Clients of a dual-stack listener show up as IPv4-mapped addresses
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= SWEEP_SIZE {
+            clients.retain(|_, client| {
+                client.open > 0 || self.refill(client.tokens, client.refilled, now) < self.burst
+            });
+        }
+        let client = clients.entry(ip).or_insert(Client {
+            open: 0,
+            tokens: self.burst,
+            refilled: now,
+        });
+        if self.max_connections > 0 && client.open >= self.max_connections {
+            return Err(Refusal::TooManyConnections);
+        }
+        if self.rate > 0.0 {
+            client.tokens = self.refill(client.tokens, client.refilled, now);
+            client.refilled = now;
+            if client.tokens < 1.0 {
+                return Err(Refusal::RateLimited);
+            }
+            client.tokens -= 1.0;
+        }
+        client.open += 1;
+        Ok(ClientPermit {
+            limits: self.clone(),
+            ip,
+        })
+    }
+
+    fn refill(&self, tokens: f64, refilled: Instant, now: Instant) -> f64 {
+        let elapsed = now.duration_since(refilled).as_secs_f64();
+        (tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// A connection counted against the limits of its source address.
+pub struct ClientPermit {
+    limits: Arc<ClientLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for ClientPermit {
+    fn drop(&mut self) {
+        let mut clients = self.limits.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(&self.ip) {
+            client.open = client.open.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_connections() {
+        let limits = Arc::new(ClientLimits::new(&ClientLimitsConfig {
+            max_connections: 2,
+            connections_per_second: 0,
+            burst: 0,
+        }));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = limits.admit(ip).unwrap();
+        let _second = limits.admit(ip).unwrap();
+        assert_eq!(
+            limits.admit("::ffff:192.0.2.1".parse().unwrap()).err(),
+            Some(Refusal::TooManyConnections)
+        );
+        assert!(limits.admit("192.0.2.2".parse().unwrap()).is_ok());
+        drop(first);
+        assert!(limits.admit(ip).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limits = Arc::new(ClientLimits::new(&ClientLimitsConfig {
+            max_connections: 0,
+            connections_per_second: 1,
+            burst: 3,
+        }));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..3 {
+            assert!(limits.admit(ip).is_ok());
+        }
+        assert_eq!(limits.admit(ip).err(), Some(Refusal::RateLimited));
+        assert!(limits.admit("192.0.2.2".parse().unwrap()).is_ok());
+
+        // A second later the bucket holds one more token
+        limits
+            .clients
+            .lock()
+            .unwrap()
+            .get_mut(&ip)
+            .unwrap()
+            .refilled -= std::time::Duration::from_secs(1);
+        assert!(limits.admit(ip).is_ok());
+        assert_eq!(limits.admit(ip).err(), Some(Refusal::RateLimited));
+    }
+}
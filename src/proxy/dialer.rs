@@ -0,0 +1,1405 @@
+//! Outbound TCP connections. Handlers open them through a `Dialer`:
+//! `DirectDialer` connects straight to the target, while `Socks5Dialer`,
+//! `HttpDialer`, `ShadowsocksDialer` and `SshDialer` chain through the
+//! parent proxy configured in `[upstream]`, the first two optionally over
+//! TLS. `SpoofingDialer` connects directly too, but from the client's
+//! address.
+//!
+//! Through a parent, domain targets are passed along unresolved so the
+//! parent performs the lookup. `ProxyProtocolDialer` wraps any of them to
+//! announce the client to the targets listed in `[[proxy_protocol]]`,
+//! `Nat64Dialer` to reach IPv4 targets from an IPv6-only network, and
+//! `BlocklistDialer` to refuse the domains in `[blocklist]` and
+//! `PolicyDialer` the ports in `blocked_ports`. Direct
+//! connections are checked against the `[ssrf]` guard once resolved.
+
+use async_trait::async_trait;
+use base64::{Engine as _, engine::general_purpose};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+
+use crate::common::config::{Nat64Config, ProxyProtocolRule, UpstreamConfig, UpstreamProtocol};
+use crate::net::addr::{self, TargetAddr, host_matches};
+use crate::net::dns;
+use crate::net::proxy_protocol;
+use crate::net::shadowsocks::{AeadStream, ShadowsocksKey};
+use crate::net::sockopt::Bind;
+use crate::net::ssh::{self, SshError};
+use crate::net::stream::Stream;
+use crate::net::transparent;
+use crate::proxy::blocklist::Blocklist;
+use crate::proxy::fake_ip::FakeIpPool;
+use crate::proxy::forward::{self, ConnectError};
+use crate::proxy::hosts::Hosts;
+use crate::proxy::http::codec;
+use crate::proxy::policy::OutboundPolicy;
+use crate::proxy::ssrf::SsrfGuard;
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Opens a byte stream to `target` on behalf of a client.
+#[async_trait]
+pub trait Dialer: Send + Sync {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError>;
+
+    /// Like `dial`, for a client that connected from `source` to the
+    /// listener at `destination`. Only dialers that pass the client's
+    /// address on make use of them.
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        _source: SocketAddr,
+        _destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        self.dial(target).await
+    }
+
+    /// Whether streams to `target` carry the identity of the client they
+    /// were opened for, so must not be reused for another.
+    fn is_per_client(&self, _target: &TargetAddr) -> bool {
+        false
+    }
+
+    /// The dialer `[[rules]]` pick for `target`, for handlers that resolve
+    /// domains themselves to settle on before the lookup; `None` keeps this
+    /// one. A blocked target is an error.
+    fn route(&self, _target: &TargetAddr) -> Result<Option<Arc<dyn Dialer>>, ConnectError> {
+        Ok(None)
+    }
+
+    /// Whether the dialer looks the domain in `target` up itself, to match
+    /// `[[rules]]` against its addresses, so handlers must not resolve it
+    /// first.
+    fn resolves(&self, _target: &TargetAddr) -> bool {
+        false
+    }
+
+    /// Like `dial_from`, or `dial` without a client, where `target` has
+    /// already been resolved to `addrs`: those are dialed rather than a
+    /// fresh lookup, so the addresses rules were checked against are the
+    /// ones connected to. Dialers that leave lookups to a parent ignore
+    /// them.
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        _addrs: &[SocketAddr],
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        match client {
+            Some((source, destination)) => self.dial_from(target, source, destination).await,
+            None => self.dial(target).await,
+        }
+    }
+
+    /// Refuses a UDP datagram to `target`, resolved to `addr`, that the
+    /// outbound policy, the blocklist or `[[rules]]` would refuse to dial.
+    /// Datagrams leave from the proxy host whatever dialer is configured.
+    fn check_datagram(&self, _target: &TargetAddr, _addr: SocketAddr) -> Result<(), ConnectError> {
+        Ok(())
+    }
+}
+
+/// The dialer `[upstream]` calls for: direct when it is absent.
+pub fn from_config(
+    connect_timeout: Duration,
+    upstream: Option<UpstreamConfig>,
+    upstream_tls: Option<TlsConnector>,
+    guard: Option<Arc<SsrfGuard>>,
+) -> Arc<dyn Dialer> {
+    let Some(config) = upstream else {
+        return Arc::new(DirectDialer::new(connect_timeout).with_guard(guard));
+    };
+    let parent = Parent {
+        connect_timeout,
+        config,
+        tls: upstream_tls,
+    };
+    match parent.config.protocol {
+        UpstreamProtocol::Socks5 => Arc::new(Socks5Dialer { parent }),
+        UpstreamProtocol::Http => Arc::new(HttpDialer { parent }),
+        UpstreamProtocol::Shadowsocks => {
+            let password = parent.config.password.as_deref().unwrap_or_default();
+            let key = ShadowsocksKey::new(parent.config.cipher, password);
+            Arc::new(ShadowsocksDialer { parent, key })
+        }
+        UpstreamProtocol::Ssh => Arc::new(SshDialer {
+            channels: Arc::new(Semaphore::new(parent.config.max_channels)),
+            parent,
+        }),
+    }
+}
+
+/// Wraps `inner` in a `ProxyProtocolDialer` when `rules` is not empty.
+pub fn with_proxy_protocol(
+    inner: Arc<dyn Dialer>,
+    rules: Vec<ProxyProtocolRule>,
+) -> Arc<dyn Dialer> {
+    if rules.is_empty() {
+        return inner;
+    }
+    Arc::new(ProxyProtocolDialer { inner, rules })
+}
+
+/// Wraps `inner` in a `Nat64Dialer` when `[nat64]` is configured.
+pub fn with_nat64(inner: Arc<dyn Dialer>, nat64: Option<Nat64Config>) -> Arc<dyn Dialer> {
+    match nat64 {
+        Some(config) => Arc::new(Nat64Dialer { inner, config }),
+        None => inner,
+    }
+}
+
+/// Wraps `inner` in a `BlocklistDialer`.
+pub fn with_blocklist(inner: Arc<dyn Dialer>, blocklist: Arc<Blocklist>) -> Arc<dyn Dialer> {
+    Arc::new(BlocklistDialer { inner, blocklist })
+}
+
+/// Wraps `inner` in a `PolicyDialer`.
+pub fn with_policy(inner: Arc<dyn Dialer>, policy: Arc<OutboundPolicy>) -> Arc<dyn Dialer> {
+    Arc::new(PolicyDialer { inner, policy })
+}
+
+/// Wraps `inner` in a `HostsDialer`.
+pub fn with_hosts(inner: Arc<dyn Dialer>, hosts: Arc<Hosts>) -> Arc<dyn Dialer> {
+    Arc::new(HostsDialer { inner, hosts })
+}
+
+/// Wraps `inner` in a `FakeIpDialer`.
+pub fn with_fake_ip(inner: Arc<dyn Dialer>, pool: Arc<FakeIpPool>) -> Arc<dyn Dialer> {
+    Arc::new(FakeIpDialer { inner, pool })
+}
+
+/// Resolves the target locally and tries each address in turn.
+pub struct DirectDialer {
+    connect_timeout: Duration,
+    guard: Option<Arc<SsrfGuard>>,
+    bind: Bind,
+}
+
+impl DirectDialer {
+    pub fn new(connect_timeout: Duration) -> Self {
+        DirectDialer {
+            connect_timeout,
+            guard: None,
+            bind: Bind::default(),
+        }
+    }
+
+    /// Connects from sockets bound as `bind` sets.
+    pub fn with_bind(mut self, bind: Bind) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    /// Dials only the resolved addresses `guard` allows.
+    pub fn with_guard(mut self, guard: Option<Arc<SsrfGuard>>) -> Self {
+        self.guard = guard;
+        self
+    }
+
+    fn check(
+        &self,
+        target: &TargetAddr,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>, ConnectError> {
+        match &self.guard {
+            Some(guard) => guard.check(target, addrs),
+            None => Ok(addrs),
+        }
+    }
+
+    async fn connect(
+        &self,
+        target: &TargetAddr,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let addrs = self.check(target, addrs)?;
+        let stream = forward::connect_any_bound(&addrs, self.connect_timeout, &self.bind).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[async_trait]
+impl Dialer for DirectDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        let addrs = forward::resolve_all(&target.to_string()).await?;
+        self.connect(target, addrs).await
+    }
+
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+        _client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        self.connect(target, addrs.to_vec()).await
+    }
+}
+
+/// Connects to IP targets from the client's own address, for a TPROXY
+/// gateway whose targets should not see the proxy. Without a client it
+/// dials like `DirectDialer`.
+pub struct SpoofingDialer {
+    direct: DirectDialer,
+}
+
+impl SpoofingDialer {
+    pub fn new(connect_timeout: Duration) -> Self {
+        SpoofingDialer {
+            direct: DirectDialer::new(connect_timeout),
+        }
+    }
+
+    /// Dials only the addresses `guard` allows.
+    pub fn with_guard(mut self, guard: Option<Arc<SsrfGuard>>) -> Self {
+        self.direct = self.direct.with_guard(guard);
+        self
+    }
+}
+
+#[async_trait]
+impl Dialer for SpoofingDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        self.direct.dial(target).await
+    }
+
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        source: SocketAddr,
+        _destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let TargetAddr::Ip(addr) = target else {
+            return self.direct.dial(target).await;
+        };
+        self.direct.check(target, vec![*addr])?;
+        let socket = transparent::spoofed_socket(source.ip())?;
+        let stream = timeout(self.direct.connect_timeout, socket.connect(*addr))
+            .await
+            .map_err(|_| ConnectError::ConnectionTimeout)?
+            .map_err(forward::connect_error)?;
+        Ok(Box::new(stream))
+    }
+
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let Some((source, destination)) = client else {
+            return self.direct.dial_resolved(target, addrs, None).await;
+        };
+        let mut last_error = ConnectError::AddressNotFound;
+        for addr in addrs {
+            match self
+                .dial_from(&TargetAddr::Ip(*addr), source, destination)
+                .await
+            {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Tunnels through a SOCKS5 parent with `CONNECT`.
+pub struct Socks5Dialer {
+    parent: Parent,
+}
+
+#[async_trait]
+impl Dialer for Socks5Dialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        let config = &self.parent.config;
+        self.parent
+            .tunnel(target, async {
+                let mut stream = self.parent.open().await?;
+                socks5_connect(&mut stream, target, config).await?;
+                Ok(stream)
+            })
+            .await
+    }
+}
+
+/// Tunnels through an HTTP parent with `CONNECT`.
+pub struct HttpDialer {
+    parent: Parent,
+}
+
+#[async_trait]
+impl Dialer for HttpDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        let config = &self.parent.config;
+        self.parent
+            .tunnel(target, async {
+                let mut stream = self.parent.open().await?;
+                http_connect(&mut stream, target, config).await?;
+                Ok(stream)
+            })
+            .await
+    }
+}
+
+/// Tunnels through a Shadowsocks parent, which reads the target from the
+/// first bytes of the encrypted stream and sends no reply.
+pub struct ShadowsocksDialer {
+    parent: Parent,
+    key: ShadowsocksKey,
+}
+
+#[async_trait]
+impl Dialer for ShadowsocksDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        self.parent
+            .tunnel(target, async {
+                let stream = self.parent.open().await?;
+                let mut stream = AeadStream::new(stream, self.key.clone());
+                let mut header = Vec::new();
+                encode_address(target, &mut header)?;
+                stream.write_all(&header).await?;
+                stream.flush().await?;
+                Ok(Box::new(stream) as Box<dyn Stream>)
+            })
+            .await
+    }
+}
+
+/// Tunnels through an SSH jump host with a `direct-tcpip` channel.
+pub struct SshDialer {
+    parent: Parent,
+    /// `max_channels` slots, each held from before the login until the
+    /// tunnel closes, so logins cannot take over the blocking pool
+    channels: Arc<Semaphore>,
+}
+
+#[async_trait]
+impl Dialer for SshDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        self.parent
+            .tunnel(target, async {
+                // The semaphore is never closed
+                let permit = self
+                    .channels
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| ConnectError::ConnectionTimeout)?;
+                let stream = forward::connect_with_timeout(
+                    &self.parent.config.address,
+                    self.parent.connect_timeout,
+                )
+                .await?
+                .into_std()?;
+                stream.set_nonblocking(false)?;
+                let (config, target) = (self.parent.config.clone(), target.clone());
+                let timeout = self.parent.connect_timeout;
+                // A login that outlives the timeout keeps its slot until done
+                let stream = tokio::task::spawn_blocking(move || {
+                    ssh::open(stream, &config, &target, timeout)
+                        .map(|stream| stream.with_permit(permit))
+                })
+                .await
+                .map_err(std::io::Error::other)?
+                .map_err(|e| match e {
+                    SshError::IoError(e) => ConnectError::IoError(e),
+                    SshError::AuthenticationFailed => ConnectError::UpstreamAuthFailed,
+                    SshError::ChannelRefused(reason) => ConnectError::ConnectionRefused(reason),
+                    SshError::HostKeyMismatch(_) => {
+                        log::warn!("{}", e);
+                        ConnectError::UpstreamProtocol("unexpected host key")
+                    }
+                })?;
+                Ok(Box::new(stream) as Box<dyn Stream>)
+            })
+            .await
+    }
+}
+
+/// Starts streams to the targets matching a `[[proxy_protocol]]` rule with
+/// a PROXY protocol v2 header naming the client.
+pub struct ProxyProtocolDialer {
+    inner: Arc<dyn Dialer>,
+    rules: Vec<ProxyProtocolRule>,
+}
+
+#[async_trait]
+impl Dialer for ProxyProtocolDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        self.inner.dial(target).await
+    }
+
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let mut stream = self.inner.dial(target).await?;
+        if self.is_per_client(target) {
+            let header = proxy_protocol::encode_v2(source, destination);
+            stream.write_all(&header).await?;
+            stream.flush().await?;
+        }
+        Ok(stream)
+    }
+
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let mut stream = self.inner.dial_resolved(target, addrs, None).await?;
+        if let Some((source, destination)) = client
+            && self.is_per_client(target)
+        {
+            let header = proxy_protocol::encode_v2(source, destination);
+            stream.write_all(&header).await?;
+            stream.flush().await?;
+        }
+        Ok(stream)
+    }
+
+    fn is_per_client(&self, target: &TargetAddr) -> bool {
+        let host = match target {
+            TargetAddr::Ip(addr) => addr.ip().to_string(),
+            TargetAddr::Domain(domain, _) => domain.clone(),
+        };
+        self.rules.iter().any(|rule| {
+            host_matches(&rule.host, &host) && rule.port.is_none_or(|port| port == target.port())
+        })
+    }
+
+    fn check_datagram(&self, target: &TargetAddr, addr: SocketAddr) -> Result<(), ConnectError> {
+        self.inner.check_datagram(target, addr)
+    }
+}
+
+/// Rewrites IPv4 targets to their NAT64 address before `inner` dials them.
+/// With `force_ipv6`, domain targets are resolved here and their IPv6
+/// addresses, or else their synthesized ones, are tried in turn.
+pub struct Nat64Dialer {
+    inner: Arc<dyn Dialer>,
+    config: Nat64Config,
+}
+
+impl Nat64Dialer {
+    fn synthesize(&self, addr: SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(v4) => {
+                SocketAddr::from((addr::embed_ipv4(self.config.prefix, *v4.ip()), v4.port()))
+            }
+            v6 => v6,
+        }
+    }
+
+    /// A domain's IPv6 addresses, or its IPv4 ones through the prefix when
+    /// it has none.
+    fn force_ipv6(&self, resolved: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        if resolved.iter().any(SocketAddr::is_ipv6) {
+            resolved.into_iter().filter(SocketAddr::is_ipv6).collect()
+        } else {
+            resolved
+                .into_iter()
+                .map(|addr| self.synthesize(addr))
+                .collect()
+        }
+    }
+
+    async fn ipv6_targets(&self, target: &TargetAddr) -> Result<Vec<TargetAddr>, ConnectError> {
+        let addrs: Vec<SocketAddr> = match target {
+            TargetAddr::Ip(addr) => vec![self.synthesize(*addr)],
+            TargetAddr::Domain(..) if !self.config.force_ipv6 => return Ok(vec![target.clone()]),
+            TargetAddr::Domain(domain, port) => {
+                let resolved = dns::lookup(domain, *port)
+                    .await
+                    .map_err(|e| ConnectError::AddressResolutionFailed(e.to_string()))?;
+                self.force_ipv6(resolved)
+            }
+        };
+        Ok(addrs.into_iter().map(TargetAddr::Ip).collect())
+    }
+}
+
+#[async_trait]
+impl Dialer for Nat64Dialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        let mut last_error = ConnectError::AddressNotFound;
+        for target in self.ipv6_targets(target).await? {
+            match self.inner.dial(&target).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let mut last_error = ConnectError::AddressNotFound;
+        for target in self.ipv6_targets(target).await? {
+            match self.inner.dial_from(&target, source, destination).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let addrs = if self.config.force_ipv6 {
+            self.force_ipv6(addrs.to_vec())
+        } else {
+            addrs.iter().map(|addr| self.synthesize(*addr)).collect()
+        };
+        self.inner.dial_resolved(target, &addrs, client).await
+    }
+
+    fn is_per_client(&self, target: &TargetAddr) -> bool {
+        self.inner.is_per_client(target)
+    }
+
+    fn check_datagram(&self, target: &TargetAddr, addr: SocketAddr) -> Result<(), ConnectError> {
+        self.inner.check_datagram(target, addr)
+    }
+}
+
+/// Refuses domain targets on the blocklist before `inner` dials them.
+pub struct BlocklistDialer {
+    inner: Arc<dyn Dialer>,
+    blocklist: Arc<Blocklist>,
+}
+
+impl BlocklistDialer {
+    fn check(&self, target: &TargetAddr) -> Result<(), ConnectError> {
+        match target {
+            TargetAddr::Domain(domain, _) if self.blocklist.is_blocked(domain) => {
+                Err(ConnectError::Blocked(target.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for BlocklistDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        self.check(target)?;
+        self.inner.dial(target).await
+    }
+
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        self.check(target)?;
+        self.inner.dial_from(target, source, destination).await
+    }
+
+    fn is_per_client(&self, target: &TargetAddr) -> bool {
+        self.inner.is_per_client(target)
+    }
+
+    fn route(&self, target: &TargetAddr) -> Result<Option<Arc<dyn Dialer>>, ConnectError> {
+        self.check(target)?;
+        self.inner.route(target)
+    }
+
+    fn resolves(&self, target: &TargetAddr) -> bool {
+        self.inner.resolves(target)
+    }
+
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        self.check(target)?;
+        self.inner.dial_resolved(target, addrs, client).await
+    }
+
+    fn check_datagram(&self, target: &TargetAddr, addr: SocketAddr) -> Result<(), ConnectError> {
+        self.check(target)?;
+        self.inner.check_datagram(target, addr)
+    }
+}
+
+/// Hands `inner` the `[hosts]` addresses of the domains listed there, as
+/// if they had been resolved: rules, the SSRF guard and direct connections
+/// see them, while parent proxies still get the domain.
+pub struct HostsDialer {
+    inner: Arc<dyn Dialer>,
+    hosts: Arc<Hosts>,
+}
+
+#[async_trait]
+impl Dialer for HostsDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        match self.hosts.resolve(target) {
+            Some(addrs) => self.inner.dial_resolved(target, &addrs, None).await,
+            None => self.inner.dial(target).await,
+        }
+    }
+
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        match self.hosts.resolve(target) {
+            Some(addrs) => {
+                self.inner
+                    .dial_resolved(target, &addrs, Some((source, destination)))
+                    .await
+            }
+            None => self.inner.dial_from(target, source, destination).await,
+        }
+    }
+
+    fn is_per_client(&self, target: &TargetAddr) -> bool {
+        self.inner.is_per_client(target)
+    }
+
+    fn route(&self, target: &TargetAddr) -> Result<Option<Arc<dyn Dialer>>, ConnectError> {
+        let routed = self.inner.route(target)?;
+        // A dialer routed to directly would resolve the domain itself
+        if self.hosts.resolve(target).is_some() {
+            return Ok(None);
+        }
+        Ok(routed)
+    }
+
+    fn resolves(&self, target: &TargetAddr) -> bool {
+        self.hosts.resolve(target).is_some() || self.inner.resolves(target)
+    }
+
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        match self.hosts.resolve(target) {
+            Some(pinned) => self.inner.dial_resolved(target, &pinned, client).await,
+            None => self.inner.dial_resolved(target, addrs, client).await,
+        }
+    }
+
+    fn check_datagram(&self, target: &TargetAddr, addr: SocketAddr) -> Result<(), ConnectError> {
+        self.inner.check_datagram(target, addr)
+    }
+}
+
+/// Hands `inner` the domain behind each fake address of the `[dns]`
+/// forwarder, so it dials as if the client had asked for the domain.
+pub struct FakeIpDialer {
+    inner: Arc<dyn Dialer>,
+    pool: Arc<FakeIpPool>,
+}
+
+#[async_trait]
+impl Dialer for FakeIpDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        self.inner.dial(&self.pool.restore(target)?).await
+    }
+
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let target = self.pool.restore(target)?;
+        self.inner.dial_from(&target, source, destination).await
+    }
+
+    fn is_per_client(&self, target: &TargetAddr) -> bool {
+        self.pool
+            .restore(target)
+            .is_ok_and(|target| self.inner.is_per_client(&target))
+    }
+
+    fn route(&self, target: &TargetAddr) -> Result<Option<Arc<dyn Dialer>>, ConnectError> {
+        self.inner.route(&self.pool.restore(target)?)
+    }
+
+    fn resolves(&self, target: &TargetAddr) -> bool {
+        self.pool
+            .restore(target)
+            .is_ok_and(|target| self.inner.resolves(&target))
+    }
+
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let target = self.pool.restore(target)?;
+        // A sniffed name arrives with the fake address the client dialed,
+        // which only a fresh lookup can replace
+        if addrs.iter().any(|addr| self.pool.contains(addr)) {
+            return match client {
+                Some((source, destination)) => {
+                    self.inner.dial_from(&target, source, destination).await
+                }
+                None => self.inner.dial(&target).await,
+            };
+        }
+        self.inner.dial_resolved(&target, addrs, client).await
+    }
+
+    fn check_datagram(&self, target: &TargetAddr, addr: SocketAddr) -> Result<(), ConnectError> {
+        self.inner.check_datagram(&self.pool.restore(target)?, addr)
+    }
+}
+
+/// Refuses targets the outbound policy forbids before `inner` dials them.
+pub struct PolicyDialer {
+    inner: Arc<dyn Dialer>,
+    policy: Arc<OutboundPolicy>,
+}
+
+#[async_trait]
+impl Dialer for PolicyDialer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        self.policy.check(target)?;
+        self.inner.dial(target).await
+    }
+
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        self.policy.check(target)?;
+        self.inner.dial_from(target, source, destination).await
+    }
+
+    fn is_per_client(&self, target: &TargetAddr) -> bool {
+        self.inner.is_per_client(target)
+    }
+
+    fn route(&self, target: &TargetAddr) -> Result<Option<Arc<dyn Dialer>>, ConnectError> {
+        self.policy.check(target)?;
+        self.inner.route(target)
+    }
+
+    fn resolves(&self, target: &TargetAddr) -> bool {
+        self.inner.resolves(target)
+    }
+
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        self.policy.check(target)?;
+        self.inner.dial_resolved(target, addrs, client).await
+    }
+
+    fn check_datagram(&self, target: &TargetAddr, addr: SocketAddr) -> Result<(), ConnectError> {
+        self.policy.check(target)?;
+        self.inner.check_datagram(target, addr)
+    }
+}
+
+/// The `[upstream]` parent proxy and how to reach it.
+struct Parent {
+    connect_timeout: Duration,
+    config: UpstreamConfig,
+    /// Set when `upstream.tls` is enabled
+    tls: Option<TlsConnector>,
+}
+
+impl Parent {
+    /// Connects to the parent, over TLS when configured.
+    async fn open(&self) -> Result<Box<dyn Stream>, ConnectError> {
+        let stream =
+            forward::connect_with_timeout(&self.config.address, self.connect_timeout).await?;
+        let Some(connector) = &self.tls else {
+            return Ok(Box::new(stream));
+        };
+        let host = parent_host(&self.config.address);
+        let server_name = addr::server_name(host)
+            .map_err(|_| ConnectError::AddressResolutionFailed(host.to_string()))?;
+        Ok(Box::new(connector.connect(server_name, stream).await?))
+    }
+
+    /// Bounds opening a tunnel to `target`, handshakes included, by the
+    /// connect timeout.
+    async fn tunnel(
+        &self,
+        target: &TargetAddr,
+        open: impl Future<Output = Result<Box<dyn Stream>, ConnectError>>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let stream = timeout(self.connect_timeout, open)
+            .await
+            .map_err(|_| ConnectError::ConnectionTimeout)??;
+        log::debug!(
+            "Connected to {} via upstream {}",
+            target,
+            self.config.address
+        );
+        Ok(stream)
+    }
+}
+
+/// Host part of the parent's `host:port`, which its certificate must name.
+fn parent_host(address: &str) -> &str {
+    address.rsplit_once(':').map_or(address, |(host, _)| host)
+}
+
+fn credentials(upstream: &UpstreamConfig) -> Option<(&str, &str)> {
+    upstream
+        .username
+        .as_deref()
+        .zip(upstream.password.as_deref())
+}
+
+/// RFC 1929 request, which carries each field with a one-byte length.
+fn encode_auth(username: &str, password: &str) -> Result<Vec<u8>, ConnectError> {
+    let mut request = vec![AUTH_VERSION];
+    for field in [username, password] {
+        let len =
+            u8::try_from(field.len()).map_err(|_| ConnectError::UpstreamCredentialsTooLong)?;
+        request.push(len);
+        request.extend_from_slice(field.as_bytes());
+    }
+    Ok(request)
+}
+
+/// Client side of RFC 1928 CONNECT, with RFC 1929 authentication when the
+/// upstream has credentials. Reads exactly the reply, so nothing of the
+/// tunneled stream is consumed.
+async fn socks5_connect(
+    stream: &mut Box<dyn Stream>,
+    target: &TargetAddr,
+    upstream: &UpstreamConfig,
+) -> Result<(), ConnectError> {
+    let auth = credentials(upstream)
+        .map(|(username, password)| encode_auth(username, password))
+        .transpose()?;
+    let greeting: &[u8] = match auth {
+        Some(_) => &[SOCKS_VERSION, 2, METHOD_NO_AUTH, METHOD_USERNAME_PASSWORD],
+        None => &[SOCKS_VERSION, 1, METHOD_NO_AUTH],
+    };
+    stream.write_all(greeting).await?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(ConnectError::UpstreamProtocol("not a SOCKS5 server"));
+    }
+    match (choice[1], auth) {
+        (METHOD_NO_AUTH, _) => {}
+        (METHOD_USERNAME_PASSWORD, Some(request)) => {
+            stream.write_all(&request).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(ConnectError::UpstreamAuthFailed);
+            }
+        }
+        (METHOD_NO_ACCEPTABLE, _) => return Err(ConnectError::UpstreamAuthFailed),
+        _ => return Err(ConnectError::UpstreamProtocol("unexpected method selected")),
+    }
+
+    stream.write_all(&encode_connect(target)?).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(ConnectError::UpstreamProtocol("not a SOCKS5 reply"));
+    }
+    if reply[1] != 0x00 {
+        return Err(ConnectError::UpstreamRejected(reply[1]));
+    }
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(ConnectError::UpstreamProtocol("unknown address type")),
+    };
+    // BND.ADDR and BND.PORT are of no use to the proxy
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Asks an HTTP parent for a tunnel with `CONNECT`, sending Basic
+/// credentials when configured. The response head is read a byte at a time
+/// so no tunneled data is consumed along with it.
+async fn http_connect(
+    stream: &mut Box<dyn Stream>,
+    target: &TargetAddr,
+    upstream: &UpstreamConfig,
+) -> Result<(), ConnectError> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some((username, password)) = credentials(upstream) {
+        let encoded = general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", encoded));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        if head.len() >= codec::MAX_HEAD_SIZE {
+            return Err(ConnectError::UpstreamProtocol(
+                "CONNECT response head too large",
+            ));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let status = match codec::decode_response(&head) {
+        Ok(Some((head, _))) => head.status,
+        _ => return Err(ConnectError::UpstreamProtocol("malformed CONNECT response")),
+    };
+    match status {
+        200..=299 => Ok(()),
+        407 => Err(ConnectError::UpstreamAuthFailed),
+        status => Err(ConnectError::UpstreamStatus(status)),
+    }
+}
+
+fn encode_connect(target: &TargetAddr) -> Result<Vec<u8>, ConnectError> {
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    encode_address(target, &mut request)?;
+    Ok(request)
+}
+
+/// Appends `target` in the SOCKS5 address form, which Shadowsocks shares.
+fn encode_address(target: &TargetAddr, out: &mut Vec<u8>) -> Result<(), ConnectError> {
+    match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&addr.ip().octets());
+        }
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&addr.ip().octets());
+        }
+        TargetAddr::Domain(domain, _) => {
+            let len = u8::try_from(domain.len())
+                .map_err(|_| ConnectError::AddressResolutionFailed(domain.clone()))?;
+            out.extend_from_slice(&[ATYP_DOMAIN, len]);
+            out.extend_from_slice(domain.as_bytes());
+        }
+    }
+    out.extend_from_slice(&target.port().to_be_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::config::{ShadowsocksCipher, TlsConfig};
+    use crate::net::tls;
+    use tokio::net::TcpListener;
+
+    fn upstream(protocol: UpstreamProtocol, address: String) -> UpstreamConfig {
+        UpstreamConfig {
+            protocol,
+            address,
+            username: Some("alice".to_string()),
+            password: Some("secret".to_string()),
+            tls: false,
+            ca_path: None,
+            cipher: ShadowsocksCipher::default(),
+            private_key_path: None,
+            host_key: None,
+            max_channels: 64,
+        }
+    }
+
+    /// A parent that expects `alice:secret`, checks the CONNECT request
+    /// for `example.com:80`, then echoes.
+    async fn spawn_parent() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 2, 0x00, 0x02]);
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+
+            let mut auth = [0u8; 14];
+            stream.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x05alice\x06secret");
+            stream.write_all(&[0x01, 0x00]).await.unwrap();
+
+            let mut request = [0u8; 18];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"\x05\x01\x00\x03\x0bexample.com\x00\x50");
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x1f, 0x90])
+                .await
+                .unwrap();
+
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_connect_via_upstream() {
+        let parent = spawn_parent().await;
+        let dialer = from_config(
+            Duration::from_secs(5),
+            Some(upstream(UpstreamProtocol::Socks5, parent.to_string())),
+            None,
+            None,
+        );
+
+        let mut stream = dialer
+            .dial(&TargetAddr::Domain("example.com".to_string(), 80))
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_connect_via_shadowsocks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let key = ShadowsocksKey::new(ShadowsocksCipher::Aes256Gcm, "secret");
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = AeadStream::new(stream, key);
+            let mut header = [0u8; 15];
+            stream.read_exact(&mut header).await.unwrap();
+            assert_eq!(&header, b"\x03\x0bexample.com\x00\x50");
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let shadowsocks = UpstreamConfig {
+            username: None,
+            cipher: ShadowsocksCipher::Aes256Gcm,
+            ..upstream(UpstreamProtocol::Shadowsocks, addr.to_string())
+        };
+        let dialer = from_config(Duration::from_secs(5), Some(shadowsocks), None, None);
+        let mut stream = dialer
+            .dial(&TargetAddr::Domain("example.com".to_string(), 80))
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_ssh_handshake_failure() {
+        // Not an SSH server: the banner exchange fails and the dial errors
+        // out instead of hanging
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let ssh = UpstreamConfig {
+            host_key: Some("SHA256:AAAA".to_string()),
+            ..upstream(UpstreamProtocol::Ssh, addr.to_string())
+        };
+        let dialer = from_config(Duration::from_secs(5), Some(ssh), None, None);
+        let result = dialer
+            .dial(&TargetAddr::Domain("example.com".to_string(), 80))
+            .await;
+        assert!(matches!(result, Err(ConnectError::IoError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_ssh_max_channels() {
+        // A jump host that never speaks: the first login holds the only
+        // slot, so the second dial waits without connecting
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (accepted_tx, mut accepted) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+                accepted_tx.send(()).unwrap();
+            }
+        });
+
+        let ssh = UpstreamConfig {
+            host_key: Some("SHA256:AAAA".to_string()),
+            max_channels: 1,
+            ..upstream(UpstreamProtocol::Ssh, addr.to_string())
+        };
+        let dialer = from_config(Duration::from_secs(5), Some(ssh), None, None);
+        let target = TargetAddr::Domain("example.com".to_string(), 80);
+        let first = tokio::spawn({
+            let (dialer, target) = (dialer.clone(), target.clone());
+            async move { dialer.dial(&target).await.map(drop) }
+        });
+        accepted.recv().await.unwrap();
+
+        let second = timeout(Duration::from_millis(500), dialer.dial(&target)).await;
+        assert!(second.is_err());
+        assert!(accepted.try_recv().is_err());
+        first.abort();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_credentials_too_long() {
+        // Refused before the greeting, so the parent reads nothing
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let parent = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).await.unwrap();
+            buf
+        });
+
+        let socks5 = UpstreamConfig {
+            username: Some("a".repeat(256)),
+            ..upstream(UpstreamProtocol::Socks5, addr.to_string())
+        };
+        let dialer = from_config(Duration::from_secs(5), Some(socks5), None, None);
+        let result = dialer
+            .dial(&TargetAddr::Domain("example.com".to_string(), 80))
+            .await;
+        assert!(matches!(
+            result,
+            Err(ConnectError::UpstreamCredentialsTooLong)
+        ));
+        assert!(parent.await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_refusal() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[3..], [0x01, 192, 0, 2, 1, 0x01, 0xbb]);
+            stream
+                .write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let anonymous = UpstreamConfig {
+            username: None,
+            password: None,
+            ..upstream(UpstreamProtocol::Socks5, addr.to_string())
+        };
+        let dialer = from_config(Duration::from_secs(5), Some(anonymous), None, None);
+        assert!(matches!(
+            dialer
+                .dial(&TargetAddr::Ip("192.0.2.1:443".parse().unwrap()))
+                .await,
+            Err(ConnectError::UpstreamRejected(0x05))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_nat64_rewrites_ipv4_targets() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // IPv4-mapped addresses stand in for a NAT64 gateway on loopback
+        let nat64 = Nat64Config {
+            prefix: "::ffff:0:0/96".parse().unwrap(),
+            force_ipv6: true,
+        };
+        let dialer = with_nat64(
+            Arc::new(DirectDialer::new(Duration::from_secs(5))),
+            Some(nat64),
+        );
+
+        let target = TargetAddr::Ip(SocketAddr::from(([127, 0, 0, 1], port)));
+        let stream = dialer.dial(&target).await.unwrap();
+        assert_eq!(
+            stream.peer_addr().unwrap(),
+            format!("[::ffff:127.0.0.1]:{}", port).parse().unwrap()
+        );
+        let stream = dialer
+            .dial(&TargetAddr::Domain("127.0.0.1".to_string(), port))
+            .await
+            .unwrap();
+        assert!(stream.peer_addr().unwrap().is_ipv6());
+    }
+
+    #[tokio::test]
+    async fn test_nat64_keeps_the_ssrf_guard() {
+        use crate::common::config::SsrfConfig;
+        use crate::proxy::ssrf::SsrfGuard;
+
+        let prefix: ipnet::Ipv6Net = "64:ff9b::/96".parse().unwrap();
+        let ssrf = SsrfConfig {
+            enabled: true,
+            allow: Vec::new(),
+        };
+        let guard = SsrfGuard::new(&ssrf, Vec::new()).with_nat64_prefix(Some(prefix));
+        let direct = DirectDialer::new(Duration::from_secs(5)).with_guard(Some(Arc::new(guard)));
+        let nat64 = Nat64Config {
+            prefix,
+            force_ipv6: false,
+        };
+        let dialer = with_nat64(Arc::new(direct), Some(nat64));
+
+        for target in ["10.0.0.1:80", "169.254.169.254:80", "127.0.0.1:22"] {
+            let target = TargetAddr::Ip(target.parse().unwrap());
+            assert!(
+                matches!(dialer.dial(&target).await, Err(ConnectError::Denied(_))),
+                "{}",
+                target
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_over_tls() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("rust-proxy-upstream-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(path("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(path("key.pem"), cert.signing_key.serialize_pem()).unwrap();
+
+        let acceptor = tls::build_acceptor(
+            &TlsConfig {
+                cert_path: path("cert.pem"),
+                key_path: path("key.pem"),
+                alpn: Vec::new(),
+                client_ca_path: None,
+                acme: None,
+            },
+            None,
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for status in [
+                "407 Proxy Authentication Required",
+                "200 Connection Established",
+            ] {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = acceptor.accept(stream).await.unwrap();
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                let head = String::from_utf8(head).unwrap();
+                assert!(head.starts_with("CONNECT [2001:db8::1]:443 HTTP/1.1\r\n"));
+                // alice:secret
+                assert!(head.contains("Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n"));
+                // Tunneled bytes sent along with the head must survive
+                let response = format!("HTTP/1.1 {}\r\n\r\nhello", status);
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        let config = UpstreamConfig {
+            tls: true,
+            ca_path: Some(path("cert.pem")),
+            ..upstream(UpstreamProtocol::Http, format!("localhost:{}", port))
+        };
+        let connector = tls::build_connector(config.ca_path.as_deref().unwrap()).unwrap();
+        let dialer = from_config(Duration::from_secs(5), Some(config), Some(connector), None);
+        let ipv6 = TargetAddr::Ip("[2001:db8::1]:443".parse().unwrap());
+
+        assert!(matches!(
+            dialer.dial(&ipv6).await,
+            Err(ConnectError::UpstreamAuthFailed)
+        ));
+        let mut stream = dialer.dial(&ipv6).await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_header() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let listener_addr: SocketAddr = "198.51.100.2:1080".parse().unwrap();
+        tokio::spawn(async move {
+            let header = proxy_protocol::encode_v2(client, listener_addr);
+            for expected in [header, Vec::new()] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; expected.len() + 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf[..expected.len()], expected);
+                assert_eq!(&buf[expected.len()..], b"hello");
+            }
+        });
+
+        let rules = vec![ProxyProtocolRule {
+            host: "127.0.0.1".to_string(),
+            port: Some(addr.port()),
+        }];
+        let dialer =
+            with_proxy_protocol(Arc::new(DirectDialer::new(Duration::from_secs(5))), rules);
+        assert!(dialer.is_per_client(&TargetAddr::Ip(addr)));
+        let mut stream = dialer
+            .dial_from(&TargetAddr::Ip(addr), client, listener_addr)
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+
+        // Without the client's address there is nothing to announce
+        let mut stream = dialer.dial(&TargetAddr::Ip(addr)).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        assert!(!dialer.is_per_client(&TargetAddr::Domain("localhost".to_string(), addr.port())));
+    }
+}
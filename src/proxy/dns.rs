@@ -0,0 +1,219 @@
+//! Minimal DNS message handling for the UDP relay fast path: A and AAAA
+//! questions are answered from the proxy's own resolver; anything else is
+//! left for the relay to forward. The `[dns]` forwarder only needs to tell
+//! queries apart, answer failures and, in fake-IP mode, answer from its
+//! pool.
+
+use std::net::{IpAddr, Ipv4Addr};
+
+const HEADER_LEN: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+const RCODE_NO_ERROR: u8 = 0;
+const RCODE_SERVER_FAILURE: u8 = 2;
+
+/// TTL given to synthesized answers, in seconds
+const ANSWER_TTL: u32 = 60;
+/// TTL of fake-IP answers, short so that clients keep asking rather than
+/// hold on to an address the pool may give another domain
+const FAKE_IP_TTL: u32 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Query {
+    name: String,
+    qtype: u16,
+    /// Offset just past the question section
+    question_end: usize,
+}
+
+impl Query {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.qtype == TYPE_AAAA
+    }
+}
+
+/// Builds the response to `packet`, previously parsed into `query`.
+pub async fn answer(packet: &[u8], query: &Query) -> Vec<u8> {
+    match crate::net::dns::lookup_ip(&query.name).await {
+        Ok(ips) => {
+            let ips: Vec<IpAddr> = ips
+                .into_iter()
+                .filter(|ip| ip.is_ipv6() == (query.qtype == TYPE_AAAA))
+                .collect();
+            build_response(packet, query, RCODE_NO_ERROR, &ips, ANSWER_TTL)
+        }
+        Err(_) => build_response(packet, query, RCODE_SERVER_FAILURE, &[], ANSWER_TTL),
+    }
+}
+
+/// Answers `query` with the fake address `ip`, or an AAAA query with no
+/// address at all so that clients fall back to IPv4.
+pub fn fake_ip_response(packet: &[u8], query: &Query, ip: Option<Ipv4Addr>) -> Vec<u8> {
+    let ips: Vec<IpAddr> = ip.map(IpAddr::V4).into_iter().collect();
+    build_response(packet, query, RCODE_NO_ERROR, &ips, FAKE_IP_TTL)
+}
+
+/// Recognizes a standard query with a single A or AAAA question; `None`
+/// means the packet should be forwarded unchanged.
+pub fn parse_query(packet: &[u8]) -> Option<Query> {
+    let header = packet.get(..HEADER_LEN)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let is_response = flags & 0x8000 != 0;
+    let opcode = (flags >> 11) & 0x0f;
+    let qdcount = u16::from_be_bytes([header[4], header[5]]);
+    if is_response || opcode != 0 || qdcount != 1 {
+        return None;
+    }
+
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression pointers are not expected in a question
+        if len > 63 {
+            return None;
+        }
+        labels.push(std::str::from_utf8(packet.get(pos..pos + len)?).ok()?);
+        pos += len;
+    }
+
+    let fixed = packet.get(pos..pos + 4)?;
+    let qtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let qclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+    if qclass != CLASS_IN || !matches!(qtype, TYPE_A | TYPE_AAAA) || labels.is_empty() {
+        return None;
+    }
+
+    Some(Query {
+        name: labels.join("."),
+        qtype,
+        question_end: pos + 4,
+    })
+}
+
+/// Whether `packet` is at least a header with the query bit set.
+pub fn is_query(packet: &[u8]) -> bool {
+    packet.len() >= HEADER_LEN && packet[2] & 0x80 == 0
+}
+
+/// `SERVFAIL` for a query that could not be answered, echoing its ID,
+/// opcode and RD bit, with every section empty.
+pub fn server_failure(packet: &[u8]) -> Vec<u8> {
+    let mut response = vec![0; HEADER_LEN];
+    response[..2].copy_from_slice(&packet[..2]);
+    response[2] = 0x80 | (packet[2] & 0x79);
+    response[3] = 0x80 | RCODE_SERVER_FAILURE;
+    response
+}
+
+fn build_response(packet: &[u8], query: &Query, rcode: u8, ips: &[IpAddr], ttl: u32) -> Vec<u8> {
+    let mut response = Vec::with_capacity(query.question_end + ips.len() * 28);
+    // ID, then QR + the client's RD bit, RA, and the response code
+    response.extend_from_slice(&packet[..2]);
+    response.push(0x80 | (packet[2] & 0x01));
+    response.push(0x80 | rcode);
+    response.extend_from_slice(&1u16.to_be_bytes());
+    response.extend_from_slice(&(ips.len() as u16).to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(&packet[HEADER_LEN..query.question_end]);
+
+    for ip in ips {
+        // Name is a pointer to the question at offset 12
+        response.extend_from_slice(&[0xc0, 0x0c]);
+        response.extend_from_slice(&query.qtype.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&ttl.to_be_bytes());
+        match ip {
+            IpAddr::V4(ip) => {
+                response.extend_from_slice(&4u16.to_be_bytes());
+                response.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                response.extend_from_slice(&16u16.to_be_bytes());
+                response.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query_packet(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let packet = query_packet("example.com", TYPE_AAAA);
+        assert_eq!(
+            parse_query(&packet),
+            Some(Query {
+                name: "example.com".to_string(),
+                qtype: TYPE_AAAA,
+                question_end: packet.len(),
+            })
+        );
+
+        // MX queries and truncated packets are forwarded instead
+        assert_eq!(parse_query(&query_packet("example.com", 15)), None);
+        assert_eq!(parse_query(&packet[..packet.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_build_response() {
+        let packet = query_packet("example.com", TYPE_A);
+        let query = parse_query(&packet).unwrap();
+        let response = build_response(
+            &packet,
+            &query,
+            RCODE_NO_ERROR,
+            &["192.0.2.1".parse().unwrap()],
+            ANSWER_TTL,
+        );
+
+        assert_eq!(&response[..4], &[0x12, 0x34, 0x81, 0x80]);
+        // One question, one answer
+        assert_eq!(&response[4..8], &[0, 1, 0, 1]);
+        assert_eq!(&response[HEADER_LEN..packet.len()], &packet[HEADER_LEN..]);
+        assert_eq!(&response[response.len() - 4..], &[192, 0, 2, 1]);
+    }
+
+    #[test]
+    fn test_server_failure() {
+        let packet = query_packet("example.com", 15);
+        assert!(is_query(&packet));
+        let response = server_failure(&packet);
+        assert!(!is_query(&response));
+        assert_eq!(response, [0x12, 0x34, 0x81, 0x82, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_answer_localhost() {
+        let packet = query_packet("localhost", TYPE_A);
+        let response = answer(&packet, &parse_query(&packet).unwrap()).await;
+        assert_eq!(response[3] & 0x0f, RCODE_NO_ERROR);
+        assert!(response.ends_with(&[127, 0, 0, 1]));
+    }
+}
@@ -0,0 +1,441 @@
+//! The `[dns]` forwarder: queries from plain DNS clients, over UDP or TCP,
+//! are relayed to a DNS over HTTPS (RFC 8484) or DNS over TLS (RFC 7858)
+//! resolver, reached through the same dialer as proxied connections.
+//! Resolver connections are kept open and reused for later queries. In
+//! fake-IP mode, A and AAAA queries are answered by the forwarder itself.
+
+use log::{debug, info, warn};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::Semaphore;
+use tokio::task;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+use crate::common::config::DnsConfig;
+use crate::net::addr::{self, TargetAddr};
+use crate::net::conn::BufferedConnection;
+use crate::net::tls::{self, TlsError};
+use crate::proxy::dialer::Dialer;
+use crate::proxy::dns;
+use crate::proxy::fake_ip::FakeIpPool;
+use crate::proxy::forward::ConnectError;
+use crate::proxy::http::client;
+use crate::proxy::http::codec::{self, CodecError};
+
+const BUFFER_SIZE: usize = 4096;
+/// Largest DNS message, bounded by the two-byte length prefix over TCP
+const MAX_MESSAGE_SIZE: usize = 65535;
+/// Idle resolver connections kept for reuse
+const MAX_IDLE: usize = 4;
+/// UDP queries and TCP clients served at once; more are dropped
+const MAX_IN_FLIGHT: usize = 256;
+/// TCP clients are disconnected after this long without a query
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum DnsForwarderError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Connection error: {0}")]
+    ConnectError(#[from] ConnectError),
+    #[error("Invalid HTTP response: {0}")]
+    CodecError(#[from] CodecError),
+    #[error("Invalid response from the resolver: {0}")]
+    InvalidResponse(String),
+    #[error("Timed out waiting for the resolver")]
+    Timeout,
+}
+
+/// How queries are carried to the resolver.
+enum Protocol {
+    /// `POST` requests with the query as body
+    Https { authority: String, path: String },
+    /// Length-prefixed messages over a TLS stream
+    Tls,
+}
+
+pub struct DnsForwarder {
+    dialer: Arc<dyn Dialer>,
+    protocol: Protocol,
+    resolver: TargetAddr,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    query_timeout: Duration,
+    idle: Mutex<Vec<BufferedConnection>>,
+    in_flight: Arc<Semaphore>,
+    fake_ip: Option<Arc<FakeIpPool>>,
+}
+
+impl DnsForwarder {
+    /// Loads the CAs the resolver is verified against.
+    pub fn new(config: &DnsConfig, dialer: Arc<dyn Dialer>) -> Result<Self, TlsError> {
+        // Validated along with the rest of the configuration
+        let url = url::Url::parse(&config.upstream).unwrap();
+        let protocol = match url.scheme() {
+            "https" => Protocol::Https {
+                authority: client::authority(&url).to_string(),
+                path: client::path_and_query(&url).to_string(),
+            },
+            _ => Protocol::Tls,
+        };
+        let (resolver, server_name) = addr::url_target(&url, 853)?;
+
+        let mut client_config = tls::client_config(config.ca_path.as_deref())?;
+        if let Protocol::Https { .. } = protocol {
+            client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        }
+        Ok(DnsForwarder {
+            dialer,
+            protocol,
+            resolver,
+            server_name,
+            connector: TlsConnector::from(Arc::new(client_config)),
+            query_timeout: Duration::from_secs(config.timeout),
+            idle: Mutex::new(Vec::new()),
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+            fake_ip: None,
+        })
+    }
+
+    /// Answers the domains `pool` covers with its addresses instead of
+    /// asking the resolver.
+    pub fn with_fake_ip(mut self, pool: Arc<FakeIpPool>) -> Self {
+        self.fake_ip = Some(pool);
+        self
+    }
+
+    /// Serves both sockets until Ctrl-C / SIGINT is received.
+    pub async fn run(self: Arc<Self>, udp: UdpSocket, tcp: TcpListener) {
+        info!("DNS forwarder listening on {}", udp.local_addr().unwrap());
+        let udp = Arc::new(udp);
+        let mut packet = vec![0u8; MAX_MESSAGE_SIZE];
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                result = udp.recv_from(&mut packet) => {
+                    let (len, client) = match result {
+                        Ok(received) => received,
+                        // e.g. an ICMP error for an earlier reply
+                        Err(e) => {
+                            debug!("DNS receive error: {}", e);
+                            continue;
+                        }
+                    };
+                    let query = packet[..len].to_vec();
+                    if !dns::is_query(&query) {
+                        continue;
+                    }
+                    let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+                        warn!("Too many DNS queries in flight, dropping one from {}", client);
+                        continue;
+                    };
+                    let (forwarder, udp) = (self.clone(), udp.clone());
+                    task::spawn(async move {
+                        let response = forwarder.resolve(&query).await;
+                        if let Err(e) = udp.send_to(&response, client).await {
+                            debug!("DNS reply to {} failed: {}", client, e);
+                        }
+                        drop(permit);
+                    });
+                }
+                result = tcp.accept() => {
+                    let (stream, client) = match result {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            log::error!("Accept error: {}", e);
+                            tokio::time::sleep(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    };
+                    let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+                        warn!("Too many DNS queries in flight, rejecting {}", client);
+                        continue;
+                    };
+                    let forwarder = self.clone();
+                    task::spawn(async move {
+                        if let Err(e) = forwarder.serve_tcp(stream).await {
+                            debug!("DNS connection error from {}: {}", client, e);
+                        }
+                        drop(permit);
+                    });
+                }
+                _ = &mut shutdown => {
+                    info!("Received shutdown signal");
+                    break;
+                }
+            }
+        }
+
+        info!("Stopped the DNS forwarder");
+    }
+
+    /// Answers length-prefixed queries until the client closes the
+    /// connection or stays idle too long.
+    async fn serve_tcp(&self, stream: TcpStream) -> Result<(), DnsForwarderError> {
+        let mut conn = BufferedConnection::new(stream, BUFFER_SIZE);
+        loop {
+            let query = match timeout(TCP_IDLE_TIMEOUT, read_framed(&mut conn)).await {
+                Ok(Ok(query)) => query,
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => return Ok(()),
+            };
+            if !dns::is_query(&query) {
+                return Ok(());
+            }
+            let response = self.resolve(&query).await;
+            conn.write(&framed(&response)).await?;
+        }
+    }
+
+    /// The resolver's response to `query`, or `SERVFAIL` when there is none.
+    async fn resolve(&self, query: &[u8]) -> Vec<u8> {
+        if let Some(pool) = &self.fake_ip
+            && let Some(parsed) = dns::parse_query(query)
+            && pool.covers(parsed.name())
+        {
+            let ip = (!parsed.is_ipv6()).then(|| pool.allocate(parsed.name()));
+            return dns::fake_ip_response(query, &parsed, ip);
+        }
+        match self.exchange(query).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("DNS query to {} failed: {}", self.resolver, e);
+                dns::server_failure(query)
+            }
+        }
+    }
+
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>, DnsForwarderError> {
+        timeout(self.query_timeout, async {
+            // An idle connection may have been closed by the resolver since
+            let idle = self.idle.lock().unwrap().pop();
+            if let Some(mut conn) = idle {
+                match self.send(&mut conn, query).await {
+                    Ok((response, reusable)) => {
+                        self.release(conn, reusable);
+                        return Ok(response);
+                    }
+                    Err(e) => debug!("Reused DNS connection failed: {}", e),
+                }
+            }
+            let stream = self.dialer.dial(&self.resolver).await?;
+            let stream = self
+                .connector
+                .connect(self.server_name.clone(), stream)
+                .await?;
+            let mut conn = BufferedConnection::new(stream, BUFFER_SIZE);
+            let (response, reusable) = self.send(&mut conn, query).await?;
+            self.release(conn, reusable);
+            Ok(response)
+        })
+        .await
+        .map_err(|_| DnsForwarderError::Timeout)?
+    }
+
+    /// Sends one query and reads its response, returning it along with
+    /// whether the connection may carry another.
+    async fn send(
+        &self,
+        conn: &mut BufferedConnection,
+        query: &[u8],
+    ) -> Result<(Vec<u8>, bool), DnsForwarderError> {
+        let (response, reusable) = match &self.protocol {
+            Protocol::Tls => {
+                conn.write(&framed(query)).await?;
+                (read_framed(conn).await?, true)
+            }
+            Protocol::Https { authority, path } => {
+                let mut request = format!(
+                    "POST {} HTTP/1.1\r\nHost: {}\r\n\
+                     Content-Type: application/dns-message\r\n\
+                     Accept: application/dns-message\r\n\
+                     Content-Length: {}\r\n\r\n",
+                    path,
+                    authority,
+                    query.len()
+                )
+                .into_bytes();
+                request.extend_from_slice(query);
+                conn.write(&request).await?;
+                read_http_response(conn).await?
+            }
+        };
+        // Responses carry the ID of the query they answer
+        if response.len() < 2 || response[..2] != query[..2] {
+            return Err(DnsForwarderError::InvalidResponse(
+                "mismatched message ID".to_string(),
+            ));
+        }
+        Ok((response, reusable))
+    }
+
+    fn release(&self, conn: BufferedConnection, reusable: bool) {
+        let mut idle = self.idle.lock().unwrap();
+        if reusable && idle.len() < MAX_IDLE {
+            idle.push(conn);
+        }
+    }
+}
+
+/// Reads a DoH response, whose body must be delimited by `Content-Length`.
+async fn read_http_response(
+    conn: &mut BufferedConnection,
+) -> Result<(Vec<u8>, bool), DnsForwarderError> {
+    let head = loop {
+        if let Some((head, consumed)) = codec::decode_response(conn.buffered())? {
+            conn.drain_buffer(consumed);
+            break head;
+        }
+        if conn.read().await? == 0 {
+            return Err(DnsForwarderError::IoError(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Resolver closed before end of response head",
+            )));
+        }
+    };
+    let header = |name: &str| {
+        head.headers
+            .iter()
+            .find(|header| header.name_lower == name)
+            .map(|header| header.value.as_str())
+    };
+    if head.status != 200 {
+        return Err(DnsForwarderError::InvalidResponse(format!(
+            "HTTP status {}",
+            head.status
+        )));
+    }
+    let length = header("content-length")
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|&length| length <= MAX_MESSAGE_SIZE)
+        .ok_or_else(|| {
+            DnsForwarderError::InvalidResponse("missing or invalid Content-Length".to_string())
+        })?;
+    let body = conn.read_exact_buffered(length).await?.into();
+    let reusable = !header("connection").is_some_and(|value| value.eq_ignore_ascii_case("close"));
+    Ok((body, reusable))
+}
+
+/// `message` with the two-byte length prefix DNS uses over streams.
+fn framed(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(message);
+    framed
+}
+
+async fn read_framed(conn: &mut BufferedConnection) -> std::io::Result<Vec<u8>> {
+    let len = conn.read_exact_buffered(2).await?;
+    conn.read_exact_buffered(u16::from_be_bytes([len[0], len[1]]) as usize)
+        .await
+        .map(Vec::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::config::TlsConfig;
+    use crate::proxy::dialer::DirectDialer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsAcceptor;
+
+    const QUERY: &[u8] =
+        b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07example\x03com\x00\x00\x01\x00\x01";
+    const RESPONSE: &[u8] = b"\x12\x34\x81\x80\x00\x00\x00\x00\x00\x00\x00\x00";
+
+    /// A resolver listening on localhost with a self-signed certificate,
+    /// and a forwarder configured to trust it.
+    async fn resolver(name: &str, scheme: &str) -> (TcpListener, TlsAcceptor, DnsForwarder) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("rust-proxy-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        std::fs::write(path("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(path("key.pem"), cert.signing_key.serialize_pem()).unwrap();
+        let acceptor = tls::build_acceptor(
+            &TlsConfig {
+                cert_path: path("cert.pem"),
+                key_path: path("key.pem"),
+                alpn: Vec::new(),
+                client_ca_path: None,
+                acme: None,
+            },
+            None,
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let config = DnsConfig {
+            listen_address: "127.0.0.1:0".to_string(),
+            upstream: format!("{}://localhost:{}/dns-query", scheme, port),
+            ca_path: Some(path("cert.pem")),
+            timeout: 5,
+            fake_ip: None,
+        };
+        let dialer = Arc::new(DirectDialer::new(Duration::from_secs(5)));
+        let forwarder = DnsForwarder::new(&config, dialer).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        (listener, acceptor, forwarder)
+    }
+
+    #[tokio::test]
+    async fn test_doh_connection_reused() {
+        let (listener, acceptor, forwarder) = resolver("doh", "https").await;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            let mut conn = BufferedConnection::new(stream, BUFFER_SIZE);
+            for _ in 0..2 {
+                let mut head = String::new();
+                loop {
+                    let line = conn.read_line().await.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    head.push_str(&line);
+                    head.push_str("\r\n");
+                }
+                assert!(head.starts_with("POST /dns-query HTTP/1.1\r\nHost: localhost:"));
+                assert!(head.contains("Content-Type: application/dns-message\r\n"));
+                assert_eq!(conn.read_exact_buffered(QUERY.len()).await.unwrap(), QUERY);
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+                    RESPONSE.len()
+                )
+                .into_bytes();
+                response.extend_from_slice(RESPONSE);
+                conn.write(&response).await.unwrap();
+            }
+        });
+
+        // The resolver accepts a single connection
+        assert_eq!(forwarder.resolve(QUERY).await, RESPONSE);
+        assert_eq!(forwarder.resolve(QUERY).await, RESPONSE);
+    }
+
+    #[tokio::test]
+    async fn test_dot_mismatched_id() {
+        let (listener, acceptor, forwarder) = resolver("dot", "tls").await;
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.unwrap();
+            let mut query = vec![0u8; QUERY.len() + 2];
+            stream.read_exact(&mut query).await.unwrap();
+            assert_eq!(query, framed(QUERY));
+            let mut response = RESPONSE.to_vec();
+            response[1] = 0x35;
+            stream.write_all(&framed(&response)).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        assert_eq!(forwarder.resolve(QUERY).await, dns::server_failure(QUERY));
+    }
+}
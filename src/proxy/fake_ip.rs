@@ -0,0 +1,154 @@
+//! Fake-IP mode of the `[dns]` forwarder: each domain is answered with an
+//! address of its own from `fake_ip.range`, and connections to that address
+//! are dialed by the domain again, so rules and the blocklist see the name
+//! even for clients that resolved it before connecting.
+
+use ipnet::Ipv4Net;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+
+use crate::common::config::FakeIpConfig;
+use crate::net::addr::{TargetAddr, host_matches};
+use crate::proxy::forward::ConnectError;
+
+pub struct FakeIpPool {
+    range: Ipv4Net,
+    exclude: Vec<String>,
+    state: Mutex<PoolState>,
+}
+
+struct PoolState {
+    by_domain: HashMap<String, Ipv4Addr>,
+    /// Domains by the offset of their address in the range
+    by_offset: HashMap<u32, String>,
+    /// Offset of the address the next new domain is given
+    next: u32,
+}
+
+impl FakeIpPool {
+    pub fn new(config: &FakeIpConfig) -> Self {
+        FakeIpPool {
+            range: config.range.trunc(),
+            exclude: config.exclude.clone(),
+            state: Mutex::new(PoolState {
+                by_domain: HashMap::new(),
+                by_offset: HashMap::new(),
+                next: 1,
+            }),
+        }
+    }
+
+    /// Whether queries for `domain` are answered from the pool.
+    pub fn covers(&self, domain: &str) -> bool {
+        !self
+            .exclude
+            .iter()
+            .any(|pattern| host_matches(pattern, &normalize(domain)))
+    }
+
+    /// The address of `domain`, handed out now unless it already has one.
+    /// Once the pool is exhausted, the address handed out longest ago is
+    /// taken from its domain.
+    pub fn allocate(&self, domain: &str) -> Ipv4Addr {
+        let domain = normalize(domain);
+        let mut state = self.state.lock().unwrap();
+        if let Some(ip) = state.by_domain.get(&domain) {
+            return *ip;
+        }
+        let offset = state.next;
+        // The network and broadcast addresses are never handed out
+        state.next = if offset + 2 >= self.size() {
+            1
+        } else {
+            offset + 1
+        };
+        if let Some(previous) = state.by_offset.insert(offset, domain.clone()) {
+            state.by_domain.remove(&previous);
+        }
+        let ip = Ipv4Addr::from(u32::from(self.range.network()) + offset);
+        state.by_domain.insert(domain, ip);
+        ip
+    }
+
+    /// `target` with an address of the pool replaced by the domain it was
+    /// handed out for. An address no domain holds, such as one handed out
+    /// before a restart, cannot be dialed.
+    pub fn restore(&self, target: &TargetAddr) -> Result<TargetAddr, ConnectError> {
+        let TargetAddr::Ip(addr) = target else {
+            return Ok(target.clone());
+        };
+        let Some(offset) = self.offset(addr.ip()) else {
+            return Ok(target.clone());
+        };
+        match self.state.lock().unwrap().by_offset.get(&offset) {
+            Some(domain) => Ok(TargetAddr::Domain(domain.clone(), addr.port())),
+            None => Err(ConnectError::AddressResolutionFailed(format!(
+                "{} is a fake address no domain holds",
+                addr.ip()
+            ))),
+        }
+    }
+
+    /// Whether `addr` is one the pool hands out.
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.offset(addr.ip()).is_some()
+    }
+
+    fn offset(&self, ip: IpAddr) -> Option<u32> {
+        match ip {
+            IpAddr::V4(ip) if self.range.contains(&ip) => {
+                Some(u32::from(ip) - u32::from(self.range.network()))
+            }
+            _ => None,
+        }
+    }
+
+    fn size(&self) -> u32 {
+        1 << (32 - self.range.prefix_len())
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_and_restore() {
+        let pool = FakeIpPool::new(&FakeIpConfig {
+            range: "198.18.0.0/30".parse().unwrap(),
+            exclude: vec!["*.lan".to_string()],
+        });
+        assert!(pool.covers("example.com"));
+        assert!(!pool.covers("printer.lan."));
+
+        let first = pool.allocate("Example.com.");
+        assert_eq!(first, Ipv4Addr::new(198, 18, 0, 1));
+        assert_eq!(pool.allocate("example.com"), first);
+        let second = pool.allocate("example.org");
+        assert_eq!(second, Ipv4Addr::new(198, 18, 0, 2));
+
+        let target = |s: &str| TargetAddr::parse(s).unwrap();
+        assert_eq!(
+            pool.restore(&target("198.18.0.2:443")).unwrap(),
+            target("example.org:443")
+        );
+        assert_eq!(
+            pool.restore(&target("10.0.0.1:80")).unwrap(),
+            target("10.0.0.1:80")
+        );
+        assert!(pool.restore(&target("198.18.0.3:80")).is_err());
+
+        // The pool of two is full: the oldest address changes hands
+        assert_eq!(pool.allocate("example.net"), first);
+        assert_eq!(
+            pool.restore(&target("198.18.0.1:80")).unwrap(),
+            target("example.net:80")
+        );
+        assert_eq!(pool.allocate("example.com"), second);
+    }
+}
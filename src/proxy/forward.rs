@@ -1,9 +1,18 @@
-use std::time::Duration;
-use tokio::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::TcpStream;
+use tokio::task::JoinSet;
 use tokio::time::timeout;
 
 use crate::net::conn::BufferedConnection;
+use crate::net::dns;
+use crate::net::sockopt::Bind;
+#[cfg(all(feature = "splice", target_os = "linux"))]
+use crate::net::{splice, stream::Stream};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ConnectError {
@@ -17,36 +26,516 @@ pub enum ConnectError {
     ConnectionRefused(String),
     #[error("Target address not found")]
     AddressNotFound,
+    #[error("Upstream proxy authentication failed")]
+    UpstreamAuthFailed,
+    #[error("Upstream proxy username or password is longer than 255 bytes")]
+    UpstreamCredentialsTooLong,
+    #[error("Upstream proxy refused the connection (reply {0:#04x})")]
+    UpstreamRejected(u8),
+    #[error("Upstream proxy answered CONNECT with status {0}")]
+    UpstreamStatus(u16),
+    #[error("Invalid reply from upstream proxy: {0}")]
+    UpstreamProtocol(&'static str),
+    #[error("Connection to {0} blocked by a routing rule, the blocklist or blocked_ports")]
+    Blocked(String),
+    #[error("Connection to {0} denied: private or reserved address")]
+    Denied(String),
 }
 
-pub async fn resolve_address(addr: &str) -> Result<std::net::SocketAddr, ConnectError> {
-    tokio::net::lookup_host(addr)
+pub async fn resolve_address(addr: &str) -> Result<SocketAddr, ConnectError> {
+    dns::resolve(addr)
         .await
         .map_err(|e| ConnectError::AddressResolutionFailed(e.to_string()))?
+        .into_iter()
         .next()
         .ok_or(ConnectError::AddressNotFound)
 }
 
+/// All addresses for `addr`, alternating between address families so a
+/// broken IPv6 or IPv4 path is not retried several times in a row. IPv6
+/// comes first when there is any, as RFC 8305 prefers.
+pub async fn resolve_all(addr: &str) -> Result<Vec<SocketAddr>, ConnectError> {
+    let addrs = dns::resolve(addr)
+        .await
+        .map_err(|e| ConnectError::AddressResolutionFailed(e.to_string()))?;
+    if addrs.is_empty() {
+        return Err(ConnectError::AddressNotFound);
+    }
+    Ok(interleave_families(addrs))
+}
+
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6());
+    let mut result = Vec::with_capacity(preferred.len() + other.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return result,
+            (a, b) => result.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Resolves `addr` and tries its addresses as `connect_any_bound` does,
+/// each attempt bounded by `connect_timeout`. The last failure is reported
+/// if none succeeds.
 pub async fn connect_with_timeout(
     addr: &str,
     connect_timeout: Duration,
 ) -> Result<TcpStream, ConnectError> {
-    let target_addr = resolve_address(addr).await?;
-    timeout(connect_timeout, TcpStream::connect(target_addr))
-        .await
-        .map_err(|_| ConnectError::ConnectionTimeout)?
-        .map_err(|e| ConnectError::ConnectionRefused(e.to_string()))
+    let addrs = resolve_all(addr).await?;
+    connect_any(&addrs, connect_timeout).await
+}
+
+pub async fn connect_any(
+    addrs: &[SocketAddr],
+    connect_timeout: Duration,
+) -> Result<TcpStream, ConnectError> {
+    connect_any_bound(addrs, connect_timeout, &Bind::default()).await
+}
+
+/// How long an attempt may go unanswered before the next address is tried
+/// alongside it, the Connection Attempt Delay of RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Like `connect_any`, from sockets bound as `bind` sets. Addresses are
+/// tried in order, Happy Eyeballs style (RFC 8305): while attempts are
+/// pending, the next address is tried every `CONNECTION_ATTEMPT_DELAY`,
+/// and at once when one fails. The first connection made wins and the
+/// other attempts are dropped.
+pub async fn connect_any_bound(
+    addrs: &[SocketAddr],
+    connect_timeout: Duration,
+    bind: &Bind,
+) -> Result<TcpStream, ConnectError> {
+    let mut last_error = ConnectError::AddressNotFound;
+    let mut next = addrs.iter().copied().peekable();
+    let mut attempts = JoinSet::new();
+    let start = |attempts: &mut JoinSet<_>, addr: SocketAddr| {
+        let bind = bind.clone();
+        attempts.spawn(async move {
+            let connect = async { bind.socket(addr)?.connect(addr).await };
+            (addr, timeout(connect_timeout, connect).await)
+        });
+    };
+
+    loop {
+        if attempts.is_empty() {
+            match next.next() {
+                Some(addr) => start(&mut attempts, addr),
+                None => return Err(last_error),
+            }
+        }
+        tokio::select! {
+            Some(joined) = attempts.join_next() => {
+                let (addr, result) = joined.map_err(io::Error::other)?;
+                match result {
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err(e)) => {
+                        log::debug!("Connect to {} failed: {}", addr, e);
+                        last_error = connect_error(e);
+                    }
+                    Err(_) => {
+                        log::debug!("Connect to {} timed out", addr);
+                        last_error = ConnectError::ConnectionTimeout;
+                    }
+                }
+                if let Some(addr) = next.next() {
+                    start(&mut attempts, addr);
+                }
+            }
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if next.peek().is_some() => {
+                if let Some(addr) = next.next() {
+                    start(&mut attempts, addr);
+                }
+            }
+        }
+    }
+}
+
+/// Classifies a failed `connect`, telling refusals apart.
+pub fn connect_error(e: io::Error) -> ConnectError {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => ConnectError::ConnectionRefused(e.to_string()),
+        _ => ConnectError::IoError(e),
+    }
 }
 
+/// Bytes relayed over a session: `up` from the client, `down` to it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    pub up: u64,
+    pub down: u64,
+}
+
+/// Relays both directions until both sides are done sending. A side that
+/// shuts down its write half has the EOF passed on as a shutdown of the
+/// other's, while the opposite direction keeps flowing, as HTTP/1.0 and
+/// git rely on. With `idle_timeout` set, the tunnel is also torn down once
+/// no bytes have flowed in either direction for that long.
 pub async fn forward_bidirectional(
     conn1: &mut BufferedConnection,
     conn2: &mut BufferedConnection,
+    idle_timeout: Option<Duration>,
+) -> io::Result<Transfer> {
+    let activity = Activity::new();
+    let mut client = Tracked::new(conn1, &activity);
+    let mut target = Tracked::new(conn2, &activity);
+
+    let idle_watch = async {
+        let Some(idle_timeout) = idle_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let remaining = idle_timeout.saturating_sub(activity.idle_for());
+            if remaining.is_zero() {
+                return idle_timeout;
+            }
+            tokio::time::sleep(remaining).await;
+        }
+    };
+
+    let idle = tokio::select! {
+        result = relay(&mut client, &mut target) => {
+            result?;
+            None
+        }
+        idle_timeout = idle_watch => Some(idle_timeout),
+    };
+
+    match idle {
+        Some(idle_timeout) => {
+            // Best effort: the peers may already be gone
+            let _ = client.inner.shutdown().await;
+            let _ = target.inner.shutdown().await;
+            log::info!(
+                "Closed tunnel after {}s idle: {} bytes client->target, {} bytes target->client",
+                idle_timeout.as_secs(),
+                client.bytes_read,
+                target.bytes_read,
+            );
+        }
+        None => log::debug!(
+            "Forwarded {} bytes client->target, {} bytes target->client",
+            client.bytes_read,
+            target.bytes_read,
+        ),
+    }
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    {
+        client.inner.count_spliced(client.spliced, target.spliced);
+        target.inner.count_spliced(target.spliced, client.spliced);
+    }
+    Ok(Transfer {
+        up: client.bytes_read,
+        down: target.bytes_read,
+    })
+}
+
+/// Relays both directions, kernel-side when the `splice` feature is built
+/// on Linux and both ends are plain TCP sockets.
+async fn relay(
+    client: &mut Tracked<'_, BufferedConnection>,
+    target: &mut Tracked<'_, BufferedConnection>,
+) -> io::Result<()> {
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    if client.inner.as_tcp().is_some() && target.inner.as_tcp().is_some() {
+        return splice_bidirectional(client, target).await;
+    }
+    io::copy_bidirectional(client, target).await?;
+    Ok(())
+}
+
+#[cfg(all(feature = "splice", target_os = "linux"))]
+async fn splice_bidirectional(
+    client: &mut Tracked<'_, BufferedConnection>,
+    target: &mut Tracked<'_, BufferedConnection>,
 ) -> io::Result<()> {
-    let (c2s, s2c) = tokio::io::copy_bidirectional(conn1, conn2).await?;
-    log::debug!(
-        "Forwarded {} bytes client->target, {} bytes target->client",
-        c2s,
-        s2c,
-    );
+    // Bytes read ahead during negotiation are in user space already and
+    // go out first
+    pass_buffered(client, target.inner).await?;
+    pass_buffered(target, client.inner).await?;
+
+    let Tracked {
+        inner: client_conn,
+        activity,
+        bytes_read: up,
+        spliced: up_spliced,
+    } = client;
+    let Tracked {
+        inner: target_conn,
+        bytes_read: down,
+        spliced: down_spliced,
+        ..
+    } = target;
+    let (Some(client_tcp), Some(target_tcp)) = (client_conn.as_tcp(), target_conn.as_tcp()) else {
+        unreachable!("relay splices plain TCP sockets only");
+    };
+    let upstream = splice::relay(client_tcp, target_tcp, |n| {
+        *up += n as u64;
+        *up_spliced += n as u64;
+        activity.touch();
+    });
+    let downstream = splice::relay(target_tcp, client_tcp, |n| {
+        *down += n as u64;
+        *down_spliced += n as u64;
+        activity.touch();
+    });
+    tokio::try_join!(upstream, downstream)?;
     Ok(())
 }
+
+/// Writes the bytes buffered on `from` to `to`.
+#[cfg(all(feature = "splice", target_os = "linux"))]
+async fn pass_buffered(
+    from: &mut Tracked<'_, BufferedConnection>,
+    to: &mut BufferedConnection,
+) -> io::Result<()> {
+    let len = from.inner.buffer_len();
+    if let Some(data) = from
+        .inner
+        .read_from_buffer(len)
+        .filter(|data| !data.is_empty())
+    {
+        to.write(&data).await?;
+        from.bytes_read += len as u64;
+        from.activity.touch();
+    }
+    Ok(())
+}
+
+/// Time of the last read on either side of a tunnel, in milliseconds since
+/// the tunnel started.
+struct Activity {
+    start: Instant,
+    last_read_ms: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last_read_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last_read_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last_read = Duration::from_millis(self.last_read_ms.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last_read)
+    }
+}
+
+/// Counts bytes read from the wrapped stream and records activity.
+struct Tracked<'a, S> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+    bytes_read: u64,
+    /// Of `bytes_read`, those spliced kernel-side, which the stream never
+    /// saw
+    #[cfg(all(feature = "splice", target_os = "linux"))]
+    spliced: u64,
+}
+
+impl<'a, S> Tracked<'a, S> {
+    fn new(inner: &'a mut S, activity: &'a Activity) -> Self {
+        Self {
+            inner,
+            activity,
+            bytes_read: 0,
+            #[cfg(all(feature = "splice", target_os = "linux"))]
+            spliced: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            self.bytes_read += n as u64;
+            self.activity.touch();
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:80", "[::2]:80", "[::3]:80", "10.0.0.1:80"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let ordered: Vec<String> = interleave_families(addrs)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(ordered, ["[::1]:80", "10.0.0.1:80", "[::2]:80", "[::3]:80"]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_falls_back_to_next_address() {
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+
+        let stream = connect_any(&[closed, open], Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+
+        assert!(matches!(
+            connect_any(&[closed], Duration::from_secs(1)).await,
+            Err(ConnectError::ConnectionRefused(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_address_does_not_hold_up_next() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // TEST-NET-1 (RFC 5737): never answers, or is unreachable at once
+        let blackhole: SocketAddr = "192.0.2.1:80".parse().unwrap();
+
+        let started = Instant::now();
+        let stream = connect_any(&[blackhole, open], Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_half_close_keeps_other_direction() {
+        let (mut client, client_side) = socket_pair().await;
+        let (target_side, mut target) = socket_pair().await;
+        let mut conn1 = BufferedConnection::new(client_side, 1024);
+        let mut conn2 = BufferedConnection::new(target_side, 1024);
+        let forward =
+            tokio::spawn(async move { forward_bidirectional(&mut conn1, &mut conn2, None).await });
+
+        // The client sends a request and shuts down its write half
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        // The target answers after seeing EOF, and the answer still arrives
+        target.write_all(b"response").await.unwrap();
+        target.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        let transfer = timeout(Duration::from_secs(2), forward)
+            .await
+            .expect("tunnel should end once both sides are done")
+            .unwrap()
+            .unwrap();
+        assert_eq!(transfer, Transfer { up: 7, down: 8 });
+    }
+
+    #[tokio::test]
+    async fn test_buffered_bytes_go_first() {
+        let (mut client, client_side) = socket_pair().await;
+        let (target_side, mut target) = socket_pair().await;
+        let mut conn1 = BufferedConnection::new(client_side, 1024);
+        let mut conn2 = BufferedConnection::new(target_side, 1024);
+
+        // Read ahead during negotiation, before the tunnel starts
+        client.write_all(b"early ").await.unwrap();
+        conn1.fill_to(6).await.unwrap();
+
+        let forward = tokio::spawn(async move {
+            let transfer = forward_bidirectional(&mut conn1, &mut conn2, None).await;
+            (transfer, conn1.bytes_read(), conn2.bytes_written())
+        });
+        client.write_all(b"late").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"early late");
+        target.shutdown().await.unwrap();
+
+        let (transfer, read, written) = forward.await.unwrap();
+        assert_eq!(transfer.unwrap(), Transfer { up: 10, down: 0 });
+        assert_eq!((read, written), (10, 10));
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_closes_both_sides() {
+        let (mut client, client_side) = socket_pair().await;
+        let (target_side, mut target) = socket_pair().await;
+        let mut conn1 = BufferedConnection::new(client_side, 1024);
+        let mut conn2 = BufferedConnection::new(target_side, 1024);
+
+        let forward = tokio::spawn(async move {
+            forward_bidirectional(&mut conn1, &mut conn2, Some(Duration::from_millis(200))).await
+        });
+
+        // Traffic keeps the tunnel open past the idle timeout
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            target.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+        }
+
+        let transfer = timeout(Duration::from_secs(2), forward)
+            .await
+            .expect("idle tunnel should be closed")
+            .unwrap()
+            .unwrap();
+        assert_eq!(transfer, Transfer { up: 12, down: 0 });
+
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(target.read(&mut buf).await.unwrap(), 0);
+    }
+}
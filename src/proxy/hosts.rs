@@ -0,0 +1,111 @@
+//! `[hosts]`: fixed addresses for domains, from the configuration and an
+//! optional hosts file, dialed instead of what DNS would return.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::common::config::HostsConfig;
+use crate::net::addr::TargetAddr;
+
+pub struct Hosts {
+    entries: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Hosts {
+    /// Reads the hosts file, if any; the configuration's own entries take
+    /// precedence over it.
+    pub fn load(config: &HostsConfig) -> io::Result<Self> {
+        let mut hosts = match &config.path {
+            Some(path) => Hosts::parse(&fs::read_to_string(path)?),
+            None => Hosts {
+                entries: HashMap::new(),
+            },
+        };
+        for (domain, addrs) in &config.entries {
+            hosts.entries.insert(normalize(domain), addrs.0.clone());
+        }
+        Ok(hosts)
+    }
+
+    /// Parses hosts file lines: an address, then the names it is for. A
+    /// name on several lines gets all of their addresses.
+    fn parse(contents: &str) -> Self {
+        let mut entries: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(Ok(ip)) = fields.next().map(str::parse::<IpAddr>) else {
+                continue;
+            };
+            for name in fields {
+                let addrs = entries.entry(normalize(name)).or_default();
+                if !addrs.contains(&ip) {
+                    addrs.push(ip);
+                }
+            }
+        }
+        Hosts { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The fixed addresses of a domain target, with its port; `None` for
+    /// IP targets and domains not listed.
+    pub fn resolve(&self, target: &TargetAddr) -> Option<Vec<SocketAddr>> {
+        let TargetAddr::Domain(domain, port) = target else {
+            return None;
+        };
+        let addrs = self.entries.get(&normalize(domain))?;
+        Some(addrs.iter().map(|ip| SocketAddr::new(*ip, *port)).collect())
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::config::HostAddrs;
+
+    #[test]
+    fn test_resolve() {
+        let hosts = Hosts::parse(
+            "# comment\n\
+             127.0.0.1 localhost\n\
+             ::1 localhost ip6-localhost\n\
+             10.0.0.5\tinternal.corp db.corp # inline comment\n\
+             not-an-address example.com\n",
+        );
+        let resolve = |hosts: &Hosts, target: &str| {
+            hosts
+                .resolve(&TargetAddr::parse(target).unwrap())
+                .map(|addrs| addrs.iter().map(ToString::to_string).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            resolve(&hosts, "localhost:80").unwrap(),
+            ["127.0.0.1:80", "[::1]:80"]
+        );
+        assert_eq!(
+            resolve(&hosts, "Internal.Corp.:443").unwrap(),
+            ["10.0.0.5:443"]
+        );
+        assert_eq!(resolve(&hosts, "example.com:80"), None);
+        assert_eq!(resolve(&hosts, "10.0.0.5:80"), None);
+
+        let config = HostsConfig {
+            path: None,
+            entries: HashMap::from([(
+                "DB.corp".to_string(),
+                HostAddrs(vec!["10.0.0.6".parse().unwrap()]),
+            )]),
+        };
+        let hosts = Hosts::load(&config).unwrap();
+        assert_eq!(resolve(&hosts, "db.corp:5432").unwrap(), ["10.0.0.6:5432"]);
+    }
+}
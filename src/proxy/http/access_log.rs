@@ -0,0 +1,154 @@
+//! Per-transaction access log entries, emitted on the `access` log target
+//! which the logger routes to a file of its own.
+//!
+//! `Common` and `Combined` follow the Apache formats so existing analyzers
+//! can read them; `Json` carries every field, including the latency.
+
+use chrono::{DateTime, FixedOffset, Local};
+use log::info;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::common::config::AccessLogFormat;
+
+/// Log target the logger routes to the access log file
+pub const TARGET: &str = "access";
+
+pub struct AccessRecord<'a> {
+    pub client: IpAddr,
+    pub user: Option<&'a str>,
+    pub method: &'a str,
+    /// Request target as sent: an absolute URL, or `host:port` for CONNECT
+    pub target: &'a str,
+    pub version: &'a str,
+    /// `None` when the connection ended without a response
+    pub status: Option<u16>,
+    /// Bytes sent to the client, response head included
+    pub bytes: u64,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub duration: Duration,
+}
+
+impl AccessRecord<'_> {
+    pub fn log(&self, format: AccessLogFormat) {
+        info!(target: TARGET, "{}", self.format(format, Local::now().fixed_offset()));
+    }
+
+    fn format(&self, format: AccessLogFormat, time: DateTime<FixedOffset>) -> String {
+        if format == AccessLogFormat::Json {
+            return serde_json::json!({
+                "time": time.to_rfc3339(),
+                "client": self.client.to_string(),
+                "user": self.user,
+                "method": self.method,
+                "target": self.target,
+                "version": self.version,
+                "status": self.status,
+                "bytes": self.bytes,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+                "duration_ms": self.duration.as_millis() as u64,
+            })
+            .to_string();
+        }
+
+        let mut line = format!(
+            "{} - {} [{}] \"{} {} {}\" ",
+            self.client,
+            self.user.map_or_else(|| "-".to_string(), escape),
+            time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(self.method),
+            escape(self.target),
+            escape(self.version),
+        );
+        match self.status {
+            Some(status) => write!(line, "{} ", status),
+            None => write!(line, "- "),
+        }
+        .unwrap();
+        match self.bytes {
+            0 => line.push('-'),
+            bytes => write!(line, "{}", bytes).unwrap(),
+        }
+        if format == AccessLogFormat::Combined {
+            for field in [self.referer, self.user_agent] {
+                write!(
+                    line,
+                    " \"{}\"",
+                    field.map_or_else(|| "-".to_string(), escape)
+                )
+                .unwrap();
+            }
+        }
+        line
+    }
+}
+
+/// Escapes quotes, backslashes and control characters the way Apache does,
+/// so a field cannot break out of its quotes or forge a log line.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_ascii_control() => write!(escaped, "\\x{:02x}", c as u8).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_formats() {
+        let record = AccessRecord {
+            client: "192.0.2.7".parse().unwrap(),
+            user: Some("alice"),
+            method: "GET",
+            target: "http://example.com/a\"b",
+            version: "HTTP/1.1",
+            status: Some(200),
+            bytes: 1234,
+            referer: None,
+            user_agent: Some("curl/8.0\n"),
+            duration: Duration::from_millis(42),
+        };
+        let time = DateTime::parse_from_rfc3339("2000-10-10T13:55:36-07:00").unwrap();
+
+        assert_eq!(
+            record.format(AccessLogFormat::Common, time),
+            "192.0.2.7 - alice [10/Oct/2000:13:55:36 -0700] \
+             \"GET http://example.com/a\\\"b HTTP/1.1\" 200 1234"
+        );
+        assert!(
+            record
+                .format(AccessLogFormat::Combined, time)
+                .ends_with("200 1234 \"-\" \"curl/8.0\\x0a\"")
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&record.format(AccessLogFormat::Json, time)).unwrap();
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["duration_ms"], 42);
+        assert_eq!(json["referer"], serde_json::Value::Null);
+
+        let unanswered = AccessRecord {
+            status: None,
+            bytes: 0,
+            ..record
+        };
+        assert!(
+            unanswered
+                .format(AccessLogFormat::Common, time)
+                .ends_with("\" - -")
+        );
+    }
+}
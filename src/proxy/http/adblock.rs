@@ -0,0 +1,477 @@
+//! `[adblock]`: filter lists in Adblock Plus syntax, such as EasyList,
+//! applied to the requests passing through the HTTP proxy.
+//!
+//! URL filters are supported with their anchors (`|`, `||`, a trailing
+//! `|`), wildcards (`*`), separators (`^`), `/regex/` patterns and `@@`
+//! exceptions. Of the options, `third-party` and `domain=` are judged from
+//! the `Referer`, `match-case` is honoured and resource types are ignored,
+//! since a proxy cannot tell them apart; filters with any other option, and
+//! element hiding rules, are skipped.
+
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use crate::common::config::AdblockConfig;
+
+/// Resource type options, which match every request here.
+const TYPE_OPTIONS: &[&str] = &[
+    "script",
+    "image",
+    "stylesheet",
+    "css",
+    "object",
+    "xmlhttprequest",
+    "xhr",
+    "subdocument",
+    "frame",
+    "ping",
+    "media",
+    "font",
+    "websocket",
+    "other",
+    "important",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Byte(u8),
+    /// `*`: any run of characters
+    Wildcard,
+    /// `^`: a character other than a letter, digit or `_-.%`, or the end
+    Separator,
+}
+
+enum Pattern {
+    Glob {
+        tokens: Vec<Token>,
+        /// `||`: the pattern starts at the beginning of a host label
+        host_anchor: bool,
+        /// `|` at the start: the pattern starts at the beginning of the URL
+        start_anchor: bool,
+        /// `|` at the end: the pattern ends at the end of the URL
+        end_anchor: bool,
+        /// Longest literal run, which any matching URL contains
+        literal: String,
+    },
+    Regex(Regex),
+}
+
+struct Filter {
+    pattern: Pattern,
+    match_case: bool,
+    /// `third-party` (`Some(true)`) or `~third-party` (`Some(false)`)
+    third_party: Option<bool>,
+    /// `domain=` entries the referring page must be on
+    include_domains: Vec<String>,
+    /// `domain=~` entries the referring page must not be on
+    exclude_domains: Vec<String>,
+}
+
+/// Blocking filters or exceptions, those anchored to a host indexed by it.
+#[derive(Default)]
+struct FilterSet {
+    by_host: HashMap<String, Vec<Filter>>,
+    generic: Vec<Filter>,
+}
+
+pub struct FilterList {
+    blocking: FilterSet,
+    exceptions: FilterSet,
+    len: usize,
+    /// Status answered to blocked plain HTTP requests
+    pub status: u16,
+}
+
+/// A request, as filters see it.
+struct Request<'a> {
+    url: &'a str,
+    url_lower: String,
+    host: &'a str,
+    /// Host of the `Referer`, if any
+    referrer: Option<&'a str>,
+}
+
+impl FilterList {
+    pub fn load(config: &AdblockConfig) -> io::Result<Self> {
+        let mut list = FilterList {
+            blocking: FilterSet::default(),
+            exceptions: FilterSet::default(),
+            len: 0,
+            status: config.status,
+        };
+        for path in &config.paths {
+            list.add(&fs::read_to_string(path)?);
+        }
+        Ok(list)
+    }
+
+    /// Number of filters in use.
+    pub fn filter_count(&self) -> usize {
+        self.len
+    }
+
+    fn add(&mut self, contents: &str) {
+        for line in contents.lines() {
+            let line = line.trim();
+            let (line, set) = match line.strip_prefix("@@") {
+                Some(line) => (line, &mut self.exceptions),
+                None => (line, &mut self.blocking),
+            };
+            if let Some((host, filter)) = parse_filter(line) {
+                match host {
+                    Some(host) => set.by_host.entry(host).or_default().push(filter),
+                    None => set.generic.push(filter),
+                }
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Whether a request for `url` on `host`, made from a page whose
+    /// `Referer` is `referer`, is blocked.
+    pub fn is_blocked(&self, url: &str, host: &str, referer: Option<&str>) -> bool {
+        let referrer = referer
+            .and_then(|referer| url::Url::parse(referer).ok())
+            .and_then(|referer| referer.host_str().map(str::to_ascii_lowercase));
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let request = Request {
+            url,
+            url_lower: url.to_ascii_lowercase(),
+            host: &host,
+            referrer: referrer.as_deref(),
+        };
+        self.blocking.matches(&request) && !self.exceptions.matches(&request)
+    }
+
+    /// Whether a CONNECT tunnel to `host` is blocked: whether a request for
+    /// the root of the host would be, without a referring page.
+    pub fn is_host_blocked(&self, host: &str) -> bool {
+        self.is_blocked(&format!("https://{}/", host), host, None)
+    }
+}
+
+impl FilterSet {
+    fn matches(&self, request: &Request) -> bool {
+        // Filters indexed by the host or any of its parent domains
+        let indexed = std::iter::successors(Some(request.host), |domain| {
+            domain.split_once('.').map(|(_, parent)| parent)
+        })
+        .filter_map(|domain| self.by_host.get(domain))
+        .flatten();
+        indexed
+            .chain(&self.generic)
+            .any(|filter| filter.matches(request))
+    }
+}
+
+impl Filter {
+    fn matches(&self, request: &Request) -> bool {
+        if let Some(third_party) = self.third_party {
+            let is_third_party = request
+                .referrer
+                .is_some_and(|referrer| base_domain(referrer) != base_domain(request.host));
+            if third_party != is_third_party {
+                return false;
+            }
+        }
+        if !self.include_domains.is_empty() || !self.exclude_domains.is_empty() {
+            let Some(referrer) = request.referrer else {
+                return self.include_domains.is_empty();
+            };
+            let on = |domain: &String| is_subdomain(referrer, domain);
+            if self.exclude_domains.iter().any(on)
+                || (!self.include_domains.is_empty() && !self.include_domains.iter().any(on))
+            {
+                return false;
+            }
+        }
+        let url = if self.match_case {
+            request.url
+        } else {
+            &request.url_lower
+        };
+        match &self.pattern {
+            Pattern::Regex(regex) => regex.is_match(url),
+            Pattern::Glob {
+                tokens,
+                host_anchor,
+                start_anchor,
+                end_anchor,
+                literal,
+            } => {
+                if !url.contains(literal.as_str()) {
+                    return false;
+                }
+                let url = url.as_bytes();
+                let matches_at = |start: usize| glob(tokens, &url[start..], *end_anchor);
+                if *host_anchor {
+                    host_label_starts(request.url).any(matches_at)
+                } else if *start_anchor {
+                    matches_at(0)
+                } else {
+                    (0..=url.len()).any(matches_at)
+                }
+            }
+        }
+    }
+}
+
+/// Parses a filter line, returning it with the host it is indexed by.
+/// Comments, element hiding rules and unsupported filters yield `None`.
+fn parse_filter(line: &str) -> Option<(Option<String>, Filter)> {
+    if line.is_empty()
+        || line.starts_with('!')
+        || line.starts_with('[')
+        || line.contains("##")
+        || line.contains("#@#")
+        || line.contains("#?#")
+        || line.contains("#$#")
+    {
+        return None;
+    }
+    // Options never contain `/`, so a `$` followed by one is in a regex
+    let (pattern, options) = match line.rsplit_once('$') {
+        Some((pattern, options)) if !options.contains('/') => (pattern, options),
+        _ => (line, ""),
+    };
+
+    let mut match_case = false;
+    let mut third_party = None;
+    let mut include_domains = Vec::new();
+    let mut exclude_domains = Vec::new();
+    for option in options
+        .split(',')
+        .map(str::trim)
+        .filter(|option| !option.is_empty())
+    {
+        match option {
+            "third-party" | "3p" | "~first-party" | "~1p" => third_party = Some(true),
+            "~third-party" | "~3p" | "first-party" | "1p" => third_party = Some(false),
+            "match-case" => match_case = true,
+            _ => {
+                if let Some(domains) = option.strip_prefix("domain=") {
+                    for domain in domains.split('|') {
+                        let domain = domain.to_ascii_lowercase();
+                        match domain.strip_prefix('~') {
+                            Some(domain) => exclude_domains.push(domain.to_string()),
+                            None => include_domains.push(domain),
+                        }
+                    }
+                } else if !TYPE_OPTIONS.contains(&option.trim_start_matches('~')) {
+                    return None;
+                }
+            }
+        }
+    }
+    let (host, pattern) = parse_pattern(pattern, match_case)?;
+    let filter = Filter {
+        pattern,
+        match_case,
+        third_party,
+        include_domains,
+        exclude_domains,
+    };
+    Some((host, filter))
+}
+
+/// Compiles the pattern part of a filter, returning it with the host it is
+/// indexed by.
+fn parse_pattern(pattern: &str, match_case: bool) -> Option<(Option<String>, Pattern)> {
+    if let Some(regex) = pattern
+        .strip_prefix('/')
+        .and_then(|pattern| pattern.strip_suffix('/'))
+        .filter(|regex| !regex.is_empty())
+    {
+        let regex = if match_case {
+            Regex::new(regex)
+        } else {
+            Regex::new(&format!("(?i){}", regex))
+        };
+        return Some((None, Pattern::Regex(regex.ok()?)));
+    }
+
+    let mut pattern = if match_case {
+        pattern.to_string()
+    } else {
+        pattern.to_ascii_lowercase()
+    };
+    let host_anchor = pattern.starts_with("||");
+    let start_anchor = !host_anchor && pattern.starts_with('|');
+    let end_anchor = pattern.len() > 1 && pattern.ends_with('|');
+    if end_anchor {
+        pattern.pop();
+    }
+    let pattern = pattern.trim_start_matches('|');
+    if pattern.is_empty() || pattern == "*" {
+        return None;
+    }
+
+    let mut tokens: Vec<Token> = Vec::new();
+    for byte in pattern.bytes() {
+        let token = match byte {
+            b'*' => Token::Wildcard,
+            b'^' => Token::Separator,
+            byte => Token::Byte(byte),
+        };
+        if !(token == Token::Wildcard && tokens.last() == Some(&Token::Wildcard)) {
+            tokens.push(token);
+        }
+    }
+    let literal = pattern
+        .split(['*', '^'])
+        .max_by_key(|run| run.len())
+        .unwrap_or_default()
+        .to_string();
+
+    // `||ads.example^` can only match requests to ads.example or below it
+    let host = pattern
+        .find(['^', '/', ':'])
+        .map(|end| &pattern[..end])
+        .filter(|host| {
+            host_anchor && !host.is_empty() && !host.contains('*') && !host.ends_with('.')
+        })
+        .map(|host| host.to_ascii_lowercase());
+
+    let pattern = Pattern::Glob {
+        tokens,
+        host_anchor,
+        start_anchor,
+        end_anchor,
+        literal,
+    };
+    Some((host, pattern))
+}
+
+/// Whether `text` starts with a match of `tokens`, ending at the end of
+/// `text` when `end_anchor` is set.
+fn glob(tokens: &[Token], text: &[u8], end_anchor: bool) -> bool {
+    match tokens.split_first() {
+        None => !end_anchor || text.is_empty(),
+        Some((Token::Wildcard, rest)) => {
+            (0..=text.len()).any(|start| glob(rest, &text[start..], end_anchor))
+        }
+        Some((Token::Separator, rest)) => match text.split_first() {
+            None => glob(rest, text, end_anchor),
+            Some((&byte, tail)) => is_separator(byte) && glob(rest, tail, end_anchor),
+        },
+        Some((Token::Byte(expected), rest)) => match text.split_first() {
+            Some((&byte, tail)) => byte == *expected && glob(rest, tail, end_anchor),
+            None => false,
+        },
+    }
+}
+
+fn is_separator(byte: u8) -> bool {
+    !(byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-' | b'.' | b'%'))
+}
+
+/// Offsets in `url` of the host and of each label after the first.
+fn host_label_starts(url: &str) -> impl Iterator<Item = usize> + '_ {
+    let start = url.find("://").map_or(0, |i| i + 3);
+    let end = url[start..]
+        .find(['/', ':', '?', '#'])
+        .map_or(url.len(), |i| start + i);
+    std::iter::once(start).chain(
+        url[start..end]
+            .match_indices('.')
+            .map(move |(i, _)| start + i + 1),
+    )
+}
+
+/// Whether `host` is `domain` or one of its subdomains.
+fn is_subdomain(host: &str, domain: &str) -> bool {
+    host.strip_suffix(domain)
+        .is_some_and(|sub| sub.is_empty() || sub.ends_with('.'))
+}
+
+/// The last two labels of `host`, standing in for its registrable domain.
+fn base_domain(host: &str) -> &str {
+    match host.rmatch_indices('.').nth(1) {
+        Some((i, _)) => &host[i + 1..],
+        None => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_list(contents: &str) -> FilterList {
+        let mut list = FilterList {
+            blocking: FilterSet::default(),
+            exceptions: FilterSet::default(),
+            len: 0,
+            status: 204,
+        };
+        list.add(contents);
+        list
+    }
+
+    #[test]
+    fn test_filters() {
+        let list = filter_list(
+            "[Adblock Plus 2.0]\n\
+             ! Title: test\n\
+             ||ads.example^\n\
+             ||cdn.example/banners/*.gif|\n\
+             /track?id=\n\
+             |http://plain.example/\n\
+             ||widgets.example^$third-party\n\
+             ||stats.example^$domain=news.example|~sports.news.example\n\
+             /\\/pixel[0-9]+\\.png/\n\
+             ||popup.example^$popup\n\
+             example.org##.ad-banner\n\
+             @@||ads.example/allowed/\n",
+        );
+        assert_eq!(list.filter_count(), 8);
+        let blocked = |url: &str, referer: Option<&str>| {
+            let host = url::Url::parse(url)
+                .unwrap()
+                .host_str()
+                .unwrap()
+                .to_string();
+            list.is_blocked(url, &host, referer)
+        };
+
+        assert!(blocked("http://ads.example/x.js", None));
+        assert!(blocked("https://eu.ADS.example:8443/", None));
+        assert!(!blocked("http://notads.example/", None));
+        assert!(!blocked("http://ads.example.org/", None));
+        assert!(!blocked("http://ads.example/allowed/x.js", None));
+
+        assert!(blocked("http://cdn.example/banners/top.gif", None));
+        assert!(!blocked("http://cdn.example/banners/top.gif?v=2", None));
+
+        assert!(blocked("http://any.example/track?id=1", None));
+        assert!(blocked("http://plain.example/", None));
+        assert!(!blocked("https://plain.example/", None));
+
+        assert!(blocked(
+            "http://widgets.example/w.js",
+            Some("http://blog.example/post")
+        ));
+        assert!(!blocked(
+            "http://widgets.example/w.js",
+            Some("http://www.widgets.example/")
+        ));
+        assert!(!blocked("http://widgets.example/w.js", None));
+
+        assert!(blocked(
+            "http://stats.example/s.js",
+            Some("http://www.news.example/")
+        ));
+        assert!(!blocked(
+            "http://stats.example/s.js",
+            Some("http://sports.news.example/")
+        ));
+        assert!(!blocked("http://stats.example/s.js", None));
+
+        assert!(blocked("http://img.example/pixel42.png", None));
+        assert!(!blocked("http://popup.example/", None));
+
+        assert!(list.is_host_blocked("ads.example"));
+        assert!(!list.is_host_blocked("cdn.example"));
+    }
+}
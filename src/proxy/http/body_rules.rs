@@ -0,0 +1,323 @@
+//! `[[http.body_rules]]`: literal find and replace in response bodies,
+//! seen through gzip, deflate and br when `http.decompress_bodies` is on.
+
+use crate::common::config::BodyRule;
+use crate::net::addr::host_matches;
+use std::io::{self, Read, Write};
+
+/// Content codings a body can be decoded from and encoded back to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+}
+
+impl Coding {
+    /// The coding a `Content-Encoding` value names; `None` for unknown
+    /// codings and for several stacked ones.
+    pub fn parse(content_encoding: Option<&str>) -> Option<Coding> {
+        let Some(value) = content_encoding else {
+            return Some(Coding::Identity);
+        };
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Some(Coding::Identity),
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "deflate" => Some(Coding::Deflate),
+            "br" => Some(Coding::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn token(self) -> &'static str {
+        match self {
+            Coding::Identity => "identity",
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+            Coding::Brotli => "br",
+        }
+    }
+}
+
+/// The rules that apply to a response to `host` and `path` carrying
+/// `content_type`.
+pub fn matching<'a>(
+    rules: &'a [BodyRule],
+    host: &str,
+    path: &str,
+    content_type: Option<&str>,
+) -> Vec<&'a BodyRule> {
+    let content_type = content_type.unwrap_or_default().trim().to_ascii_lowercase();
+    rules
+        .iter()
+        .filter(|rule| {
+            rule.host
+                .as_deref()
+                .is_none_or(|pattern| host_matches(pattern, host))
+                && rule
+                    .path_prefix
+                    .as_deref()
+                    .is_none_or(|prefix| path.starts_with(prefix))
+                && rule
+                    .content_type
+                    .as_deref()
+                    .is_none_or(|prefix| content_type.starts_with(&prefix.to_ascii_lowercase()))
+        })
+        .collect()
+}
+
+/// The body to send for a held-back response body `body` sent in `coding`,
+/// and the coding it is sent in; `None` leaves the response untouched.
+///
+/// A rewritten body goes back in its original coding when the client's
+/// `Accept-Encoding` admits it, and unencoded otherwise.
+pub fn filter(
+    rules: &[BodyRule],
+    body: &[u8],
+    coding: Option<Coding>,
+    decompress: bool,
+    limit: usize,
+    accept_encoding: Option<&str>,
+) -> io::Result<Option<(Vec<u8>, Coding)>> {
+    let decoded = match coding {
+        Some(Coding::Identity) => None,
+        Some(coding) if decompress => match decode(coding, body, limit)? {
+            Some(decoded) => Some(decoded),
+            None => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    let Some(rewritten) = apply(rules, decoded.as_deref().unwrap_or(body)) else {
+        return Ok(None);
+    };
+    match coding {
+        Some(coding) if coding != Coding::Identity && accepts(accept_encoding, coding) => {
+            Ok(Some((encode(coding, &rewritten)?, coding)))
+        }
+        _ => Ok(Some((rewritten, Coding::Identity))),
+    }
+}
+
+/// Applies `rules` in order; `None` when none of them found its pattern.
+fn apply(rules: &[BodyRule], body: &[u8]) -> Option<Vec<u8>> {
+    let mut rewritten: Option<Vec<u8>> = None;
+    for rule in rules {
+        let current = rewritten.as_deref().unwrap_or(body);
+        if let Some(replaced) = replace(
+            current,
+            rule.pattern.as_bytes(),
+            rule.replacement.as_bytes(),
+        ) {
+            rewritten = Some(replaced);
+        }
+    }
+    rewritten
+}
+
+fn replace(haystack: &[u8], pattern: &[u8], replacement: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut rest = haystack;
+    let mut found = false;
+    while let Some(at) = rest
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+    {
+        out.extend_from_slice(&rest[..at]);
+        out.extend_from_slice(replacement);
+        rest = &rest[at + pattern.len()..];
+        found = true;
+    }
+    out.extend_from_slice(rest);
+    found.then_some(out)
+}
+
+/// Decodes `body`; `None` once it would exceed `limit` bytes.
+fn decode(coding: Coding, body: &[u8], limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let reader: Box<dyn Read + '_> = match coding {
+        Coding::Identity => return Ok(Some(body.to_vec())),
+        Coding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(body)),
+        // "deflate" is the zlib format (RFC 9110 §8.4.1.2), but some
+        // servers send a bare deflate stream
+        Coding::Deflate if body.first().is_some_and(|b| b & 0x0f == 8) => {
+            Box::new(flate2::read::ZlibDecoder::new(body))
+        }
+        Coding::Deflate => Box::new(flate2::read::DeflateDecoder::new(body)),
+        Coding::Brotli => Box::new(brotli::Decompressor::new(body, 4096)),
+    };
+    let mut decoded = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut decoded)?;
+    Ok((decoded.len() <= limit).then_some(decoded))
+}
+
+fn encode(coding: Coding, body: &[u8]) -> io::Result<Vec<u8>> {
+    let level = flate2::Compression::default();
+    match coding {
+        Coding::Identity => Ok(body.to_vec()),
+        Coding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), level);
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Coding::Deflate => {
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), level);
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Coding::Brotli => {
+            // Quality 5 and a 4 MiB window, a common choice for dynamic content
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+            encoder.write_all(body)?;
+            Ok(encoder.into_inner())
+        }
+    }
+}
+
+/// Whether an `Accept-Encoding` value admits `coding` (RFC 9110 §12.5.3).
+/// Without the field any coding is acceptable.
+fn accepts(accept_encoding: Option<&str>, coding: Coding) -> bool {
+    let Some(value) = accept_encoding else {
+        return true;
+    };
+    let mut wildcard = None;
+    for element in value.split(',') {
+        let mut params = element.split(';');
+        let token = params
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        let accepted = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .next()
+            .is_none_or(|q| q.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+        if !token.is_empty() && Coding::parse(Some(&token)) == Some(coding) {
+            return accepted;
+        }
+        if token == "*" {
+            wildcard = Some(accepted);
+        }
+    }
+    wildcard.unwrap_or(coding == Coding::Identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: &str, replacement: &str) -> BodyRule {
+        BodyRule {
+            host: None,
+            path_prefix: None,
+            content_type: None,
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_matching() {
+        let mut scoped = rule("a", "b");
+        scoped.host = Some("*.example.com".to_string());
+        scoped.path_prefix = Some("/news".to_string());
+        scoped.content_type = Some("Text/HTML".to_string());
+        let rules = [scoped];
+
+        let html = Some("text/html; charset=utf-8");
+        assert_eq!(
+            matching(&rules, "www.example.com", "/news/1", html).len(),
+            1
+        );
+        assert!(matching(&rules, "www.example.com", "/news/1", Some("image/png")).is_empty());
+        assert!(matching(&rules, "www.example.com", "/news/1", None).is_empty());
+        assert!(matching(&rules, "www.example.com", "/about", html).is_empty());
+        assert!(matching(&rules, "example.org", "/news/1", html).is_empty());
+    }
+
+    #[test]
+    fn test_accepts() {
+        assert!(accepts(None, Coding::Brotli));
+        assert!(accepts(Some("gzip, deflate, br"), Coding::Brotli));
+        assert!(accepts(Some("x-gzip"), Coding::Gzip));
+        assert!(!accepts(Some("gzip;q=0, *"), Coding::Gzip));
+        assert!(accepts(Some("*;q=0.5"), Coding::Deflate));
+        assert!(!accepts(Some("*;q=0"), Coding::Deflate));
+        assert!(!accepts(Some("identity"), Coding::Gzip));
+        assert!(accepts(Some("identity"), Coding::Identity));
+    }
+
+    #[test]
+    fn test_filter_through_codings() {
+        let rules = [rule("tracker.js", "blank.js"), rule("blank", "empty")];
+        let page = b"<script src=\"tracker.js\"></script>".repeat(10);
+        let expected = b"<script src=\"empty.js\"></script>".repeat(10);
+
+        for coding in [Coding::Gzip, Coding::Deflate, Coding::Brotli] {
+            let body = encode(coding, &page).unwrap();
+            let (rewritten, sent) = filter(&rules, &body, Some(coding), true, 1 << 20, None)
+                .unwrap()
+                .unwrap();
+            assert_eq!(sent, coding);
+            assert_eq!(
+                decode(coding, &rewritten, 1 << 20).unwrap().unwrap(),
+                expected
+            );
+
+            // A client that does not take the coding gets the plain text
+            let (rewritten, sent) =
+                filter(&rules, &body, Some(coding), true, 1 << 20, Some("identity"))
+                    .unwrap()
+                    .unwrap();
+            assert_eq!((rewritten, sent), (expected.clone(), Coding::Identity));
+
+            // Compressed bodies are left alone unless decompress_bodies is on
+            assert!(
+                filter(&rules, &body, Some(coding), false, 1 << 20, None)
+                    .unwrap()
+                    .is_none()
+            );
+        }
+
+        // A bare deflate stream is read too
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&page).unwrap();
+        let raw = encoder.finish().unwrap();
+        assert_eq!(
+            decode(Coding::Deflate, &raw, 1 << 20).unwrap().unwrap(),
+            page
+        );
+
+        assert!(
+            filter(&rules, &page, None, true, 1 << 20, None)
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            filter(
+                &rules,
+                b"nothing here",
+                Some(Coding::Identity),
+                true,
+                1 << 20,
+                None
+            )
+            .unwrap()
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn test_decode_limit() {
+        // A small body that inflates past the limit is not decoded
+        let bomb = encode(Coding::Gzip, &[0u8; 64 * 1024]).unwrap();
+        assert!(decode(Coding::Gzip, &bomb, 1024).unwrap().is_none());
+        assert_eq!(
+            decode(Coding::Gzip, &bomb, 64 * 1024)
+                .unwrap()
+                .unwrap()
+                .len(),
+            64 * 1024
+        );
+    }
+}
@@ -0,0 +1,529 @@
+//! Shared cache for plain-HTTP GET responses.
+//!
+//! Entries are kept in memory up to `cache.max_size` bytes, evicting the
+//! least recently used first. With `cache.dir` set every entry is also
+//! written to disk, which holds up to `cache.max_disk_size` bytes and
+//! survives restarts. Freshness follows `Cache-Control`, `Expires` and
+//! `cache.ttl_overrides`; stale entries with an `ETag` or `Last-Modified`
+//! are revalidated by the caller instead of refetched.
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use super::codec::{Header, ResponseHead};
+use crate::common::config::CacheConfig;
+
+/// Statuses stored by the cache; all are cacheable by default (RFC 9110 §15.1)
+const CACHEABLE_STATUSES: &[u16] = &[200, 203, 300, 301, 404, 410];
+
+/// Fields of a `304 Not Modified` that must not replace stored ones
+const NOT_MODIFIED_SKIPPED: &[&str] = &["content-length", "transfer-encoding", "connection"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    /// Absolute request URL
+    pub key: String,
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    /// Origin header fields, in their original order and case
+    pub headers: Vec<Header>,
+    pub stored_at: SystemTime,
+    pub expires_at: SystemTime,
+    /// Everything after the head, exactly as the origin sent it
+    #[serde(skip)]
+    pub body: Vec<u8>,
+}
+
+impl CachedResponse {
+    /// An entry for `head` stored now, already expired and without a body;
+    /// the caller sets both once it knows them.
+    pub fn new(key: String, head: ResponseHead) -> Self {
+        let now = SystemTime::now();
+        CachedResponse {
+            key,
+            version: head.version,
+            status: head.status,
+            reason: head.reason,
+            headers: head.headers,
+            stored_at: now,
+            expires_at: now,
+            body: Vec::new(),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.name_lower == name)
+            .map(|header| header.value.as_str())
+    }
+
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        now < self.expires_at
+    }
+
+    /// Whole seconds since the response was stored, for `Age`
+    pub fn age(&self, now: SystemTime) -> u64 {
+        now.duration_since(self.stored_at)
+            .unwrap_or_default()
+            .as_secs()
+    }
+
+    /// Conditional request fields that revalidate this entry.
+    pub fn validators(&self) -> Vec<(&'static str, &str)> {
+        let mut validators = Vec::new();
+        if let Some(etag) = self.header("etag") {
+            validators.push(("If-None-Match", etag));
+        }
+        if let Some(last_modified) = self.header("last-modified") {
+            validators.push(("If-Modified-Since", last_modified));
+        }
+        validators
+    }
+
+    /// The entry after a `304 Not Modified`: fields sent with the 304 replace
+    /// stored ones of the same name (RFC 9111 §4.3.4). Expiry is left for the
+    /// caller to recompute.
+    pub fn refreshed(&self, headers: &[Header]) -> CachedResponse {
+        let updates: Vec<&Header> = headers
+            .iter()
+            .filter(|header| !NOT_MODIFIED_SKIPPED.contains(&header.name_lower.as_str()))
+            .collect();
+        let mut entry = self.clone();
+        entry.headers.retain(|stored| {
+            !updates
+                .iter()
+                .any(|update| update.name_lower == stored.name_lower)
+        });
+        entry.headers.extend(updates.into_iter().cloned());
+        entry.stored_at = SystemTime::now();
+        entry.expires_at = entry.stored_at;
+        entry
+    }
+
+    /// Whether the captured body matches the framing announced in the head,
+    /// so a connection cut short is never cached.
+    pub fn is_complete(&self) -> bool {
+        if self
+            .header("transfer-encoding")
+            .is_some_and(super::is_chunked)
+        {
+            return self.body.ends_with(b"\r\n\r\n");
+        }
+        match self.header("content-length") {
+            Some(len) => len.parse::<usize>().ok() == Some(self.body.len()),
+            None => true,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        let head: usize = self
+            .headers
+            .iter()
+            .map(|header| header.name.len() + header.value.len())
+            .sum();
+        (self.key.len() + head + self.body.len()) as u64
+    }
+}
+
+pub struct HttpCache {
+    config: CacheConfig,
+    memory: Mutex<MemoryTier>,
+    disk: Option<DiskTier>,
+}
+
+impl HttpCache {
+    pub fn new(config: &CacheConfig) -> io::Result<Self> {
+        let disk = match &config.dir {
+            Some(dir) => Some(DiskTier::open(PathBuf::from(dir), config.max_disk_size)?),
+            None => None,
+        };
+        Ok(HttpCache {
+            config: config.clone(),
+            memory: Mutex::new(MemoryTier::default()),
+            disk,
+        })
+    }
+
+    pub fn max_entry_size(&self) -> usize {
+        self.config.max_entry_size as usize
+    }
+
+    /// Looks `key` up in memory, then on disk. Stale entries are returned
+    /// too so the caller can revalidate them.
+    pub async fn get(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        if let Some(entry) = self.memory.lock().unwrap().get(key) {
+            return Some(entry);
+        }
+        let entry = Arc::new(self.disk.as_ref()?.load(key).await?);
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(entry.clone(), self.config.max_size);
+        Some(entry)
+    }
+
+    pub async fn put(&self, entry: CachedResponse) {
+        if entry.body.len() > self.max_entry_size() {
+            return;
+        }
+        let entry = Arc::new(entry);
+        self.memory
+            .lock()
+            .unwrap()
+            .insert(entry.clone(), self.config.max_size);
+        if let Some(disk) = &self.disk {
+            disk.store(&entry).await;
+        }
+    }
+
+    /// Drops `key`, e.g. after an unsafe request to the same URL (RFC 9111 §4.4).
+    pub async fn invalidate(&self, key: &str) {
+        self.memory.lock().unwrap().remove(key);
+        if let Some(disk) = &self.disk {
+            disk.remove(key).await;
+        }
+    }
+
+    /// How long `entry` stays fresh, or `None` when it must not be stored.
+    /// `host` selects a `cache.ttl_overrides` entry.
+    pub fn freshness_lifetime(&self, host: &str, entry: &CachedResponse) -> Option<Duration> {
+        if !CACHEABLE_STATUSES.contains(&entry.status) {
+            return None;
+        }
+        // Responses varying per client or setting cookies are not shared
+        if entry.header("vary").is_some() || entry.header("set-cookie").is_some() {
+            return None;
+        }
+
+        let directives = cache_control(&entry.headers);
+        if directives.contains_key("no-store") || directives.contains_key("private") {
+            return None;
+        }
+
+        let max_age = |name: &str| directives.get(name).and_then(|v| v.parse::<u64>().ok());
+        let ttl = if directives.contains_key("no-cache") {
+            0
+        } else if let Some(ttl) = self.config.ttl_overrides.get(host) {
+            *ttl
+        } else if let Some(ttl) = max_age("s-maxage").or_else(|| max_age("max-age")) {
+            ttl
+        } else if let Some(expires) = entry.header("expires") {
+            // An invalid Expires means already expired (RFC 9111 §5.3)
+            let date = entry
+                .header("date")
+                .and_then(|date| httpdate::parse_http_date(date).ok())
+                .unwrap_or(entry.stored_at);
+            httpdate::parse_http_date(expires)
+                .ok()
+                .and_then(|expires| expires.duration_since(date).ok())
+                .map_or(0, |ttl| ttl.as_secs())
+        } else {
+            self.config.default_ttl
+        };
+
+        if ttl == 0 && entry.validators().is_empty() {
+            return None;
+        }
+        Some(Duration::from_secs(ttl))
+    }
+}
+
+/// `Cache-Control` directives, lowercased, with unquoted arguments.
+pub fn cache_control(headers: &[Header]) -> HashMap<String, String> {
+    headers
+        .iter()
+        .filter(|header| header.name_lower == "cache-control")
+        .flat_map(|header| header.value.split(','))
+        .map(|directive| {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            (
+                name.trim().to_ascii_lowercase(),
+                value.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct MemoryTier {
+    /// Entry and the clock value of its last use
+    entries: HashMap<String, (Arc<CachedResponse>, u64)>,
+    size: u64,
+    clock: u64,
+}
+
+impl MemoryTier {
+    fn get(&mut self, key: &str) -> Option<Arc<CachedResponse>> {
+        self.clock += 1;
+        let (entry, last_used) = self.entries.get_mut(key)?;
+        *last_used = self.clock;
+        Some(entry.clone())
+    }
+
+    fn insert(&mut self, entry: Arc<CachedResponse>, max_size: u64) {
+        self.remove(&entry.key);
+        self.clock += 1;
+        self.size += entry.size();
+        self.entries.insert(entry.key.clone(), (entry, self.clock));
+
+        while self.size > max_size {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((entry, _)) = self.entries.remove(key) {
+            self.size -= entry.size();
+        }
+    }
+}
+
+/// One file per entry: a JSON line with the metadata, then the raw body.
+struct DiskTier {
+    dir: PathBuf,
+    max_size: u64,
+    /// File name to size and modification time
+    files: Mutex<HashMap<String, (u64, SystemTime)>>,
+}
+
+impl DiskTier {
+    fn open(dir: PathBuf, max_size: u64) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut files = HashMap::new();
+        for file in std::fs::read_dir(&dir)? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            if name.ends_with(".entry") {
+                let metadata = file.metadata()?;
+                files.insert(name, (metadata.len(), metadata.modified()?));
+            }
+        }
+        Ok(DiskTier {
+            dir,
+            max_size,
+            files: Mutex::new(files),
+        })
+    }
+
+    async fn load(&self, key: &str) -> Option<CachedResponse> {
+        let data = tokio::fs::read(self.dir.join(file_name(key))).await.ok()?;
+        let split = data.iter().position(|&b| b == b'\n')?;
+        let mut entry: CachedResponse = serde_json::from_slice(&data[..split]).ok()?;
+        // Different URLs may share a file name
+        if entry.key != key {
+            return None;
+        }
+        entry.body = data[split + 1..].to_vec();
+        Some(entry)
+    }
+
+    async fn store(&self, entry: &CachedResponse) {
+        let name = file_name(&entry.key);
+        let mut data = match serde_json::to_vec(entry) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to encode cache entry for {}: {}", entry.key, e);
+                return;
+            }
+        };
+        data.push(b'\n');
+        data.extend_from_slice(&entry.body);
+
+        // Written aside and renamed so readers never see a partial file
+        let path = self.dir.join(&name);
+        let tmp = path.with_extension("tmp");
+        let written = match tokio::fs::write(&tmp, &data).await {
+            Ok(()) => tokio::fs::rename(&tmp, &path).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!("Failed to write cache entry {}: {}", path.display(), e);
+            return;
+        }
+
+        let evicted = {
+            let mut files = self.files.lock().unwrap();
+            files.insert(name, (data.len() as u64, SystemTime::now()));
+            let mut total: u64 = files.values().map(|(size, _)| size).sum();
+            let mut evicted = Vec::new();
+            while total > self.max_size {
+                let Some(oldest) = files
+                    .iter()
+                    .min_by_key(|(_, (_, modified))| *modified)
+                    .map(|(name, _)| name.clone())
+                else {
+                    break;
+                };
+                if let Some((size, _)) = files.remove(&oldest) {
+                    total -= size;
+                }
+                evicted.push(oldest);
+            }
+            evicted
+        };
+        for name in evicted {
+            let _ = tokio::fs::remove_file(self.dir.join(name)).await;
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        let name = file_name(key);
+        if self.files.lock().unwrap().remove(&name).is_some() {
+            let _ = tokio::fs::remove_file(self.dir.join(name)).await;
+        }
+    }
+}
+
+/// Stable across runs, unlike the std hasher: 64-bit FNV-1a of the key.
+fn file_name(key: &str) -> String {
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}.entry", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> Header {
+        Header {
+            name: name.to_string(),
+            name_lower: name.to_ascii_lowercase(),
+            value: value.to_string(),
+        }
+    }
+
+    fn entry(key: &str, headers: Vec<Header>, body: &[u8]) -> CachedResponse {
+        let now = SystemTime::now();
+        CachedResponse {
+            key: key.to_string(),
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            reason: "OK".to_string(),
+            headers,
+            stored_at: now,
+            expires_at: now + Duration::from_secs(60),
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_freshness_lifetime() {
+        let config = CacheConfig {
+            ttl_overrides: HashMap::from([("static.example.com".to_string(), 3600)]),
+            ..CacheConfig::default()
+        };
+        let cache = HttpCache::new(&config).unwrap();
+        let lifetime = |headers: Vec<Header>| {
+            cache.freshness_lifetime("example.com", &entry("k", headers, b""))
+        };
+
+        assert_eq!(
+            lifetime(vec![header(
+                "Cache-Control",
+                "public, max-age=60, s-maxage=30"
+            )]),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            lifetime(vec![
+                header("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                header("Expires", "Sun, 06 Nov 1994 08:50:37 GMT"),
+            ]),
+            Some(Duration::from_secs(60))
+        );
+        // Must revalidate every time, which needs a validator
+        assert_eq!(
+            lifetime(vec![
+                header("Cache-Control", "no-cache"),
+                header("ETag", "\"v1\"")
+            ]),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(lifetime(vec![header("Cache-Control", "no-cache")]), None);
+        assert_eq!(
+            lifetime(vec![header("Cache-Control", "private, max-age=60")]),
+            None
+        );
+        assert_eq!(lifetime(vec![]), None);
+
+        let overridden = entry("k", vec![header("Cache-Control", "max-age=5")], b"");
+        assert_eq!(
+            cache.freshness_lifetime("static.example.com", &overridden),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn test_refreshed_and_complete() {
+        let stored = entry(
+            "k",
+            vec![
+                header("ETag", "\"v1\""),
+                header("Content-Length", "5"),
+                header("Cache-Control", "max-age=1"),
+            ],
+            b"hello",
+        );
+        assert!(stored.is_complete());
+        assert_eq!(stored.validators(), vec![("If-None-Match", "\"v1\"")]);
+
+        let refreshed = stored.refreshed(&[
+            header("Cache-Control", "max-age=120"),
+            header("Content-Length", "0"),
+        ]);
+        assert_eq!(refreshed.header("cache-control"), Some("max-age=120"));
+        assert_eq!(refreshed.header("content-length"), Some("5"));
+        assert_eq!(refreshed.body, b"hello");
+
+        let truncated = entry("k", vec![header("Content-Length", "10")], b"hello");
+        assert!(!truncated.is_complete());
+    }
+
+    #[tokio::test]
+    async fn test_memory_eviction_and_disk_tier() {
+        let dir = std::env::temp_dir().join(format!("rust-proxy-cache-{}", std::process::id()));
+        let config = CacheConfig {
+            enabled: true,
+            max_size: 200,
+            max_entry_size: 100,
+            dir: Some(dir.to_string_lossy().into_owned()),
+            ..CacheConfig::default()
+        };
+        let cache = HttpCache::new(&config).unwrap();
+
+        cache.put(entry("http://a/", vec![], &[b'a'; 90])).await;
+        cache.put(entry("http://b/", vec![], &[b'b'; 90])).await;
+        // Touch a, so b is the least recently used when c arrives
+        assert!(cache.get("http://a/").await.is_some());
+        cache.put(entry("http://c/", vec![], &[b'c'; 90])).await;
+        assert!(cache.memory.lock().unwrap().get("http://b/").is_none());
+
+        // Still on disk, and readable by a cache opened later
+        let reopened = HttpCache::new(&config).unwrap();
+        let b = reopened.get("http://b/").await.unwrap();
+        assert_eq!(b.body, vec![b'b'; 90]);
+        assert_eq!(b.status, 200);
+
+        reopened.invalidate("http://b/").await;
+        assert!(reopened.get("http://b/").await.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+//! A small HTTP client for the requests the proxy makes itself: the auth
+//! webhook, JWKS fetches, ACME and `url-test` probes. Each request takes a
+//! connection of its own and is sent as HTTP/1.0, so the response is never
+//! chunked and ends at its `Content-Length` or with the connection.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+use crate::net::addr;
+use crate::net::stream::Stream;
+use crate::net::tls::{self, TlsError};
+use crate::proxy::dialer::Dialer;
+use crate::proxy::forward::ConnectError;
+use crate::proxy::http::codec::{self, CodecError, Header};
+
+/// Largest response read, head included
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Connection error: {0}")]
+    ConnectError(#[from] ConnectError),
+    #[error("{0}")]
+    TlsError(#[from] TlsError),
+    #[error("Invalid HTTP response: {0}")]
+    CodecError(#[from] CodecError),
+    #[error("https:// URL without TLS set up")]
+    TlsNotSetUp,
+    #[error("Response exceeds {0} bytes")]
+    TooLarge(usize),
+    #[error("Timed out")]
+    Timeout,
+}
+
+/// Status, header fields and body of a response.
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<Header>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// The first field named `name`, given in lowercase.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|header| header.name_lower == name)
+            .map(|header| header.value.as_str())
+    }
+}
+
+pub struct HttpClient {
+    /// Bounds a whole request, dialing included
+    timeout: Duration,
+    /// Client side of https:// requests
+    connector: Option<TlsConnector>,
+}
+
+impl HttpClient {
+    pub fn new(timeout: Duration) -> Self {
+        HttpClient {
+            timeout,
+            connector: None,
+        }
+    }
+
+    /// Takes https:// URLs too, verifying servers against `ca_path`, or
+    /// the system store when unset.
+    pub fn with_tls(mut self, ca_path: Option<&str>) -> Result<Self, TlsError> {
+        let config = tls::client_config(ca_path)?;
+        self.connector = Some(TlsConnector::from(Arc::new(config)));
+        Ok(self)
+    }
+
+    /// Sends `method` to `url` through `dialer`, with `headers` and, when
+    /// given, `body`, and reads the whole response.
+    pub async fn send(
+        &self,
+        dialer: &dyn Dialer,
+        method: &str,
+        url: &Url,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> Result<Response, ClientError> {
+        let (target, server_name) = addr::url_target(url, 80)?;
+        let connector = match (url.scheme(), &self.connector) {
+            ("https", Some(connector)) => Some(connector),
+            ("https", None) => return Err(ClientError::TlsNotSetUp),
+            _ => None,
+        };
+        let request = async {
+            let stream = dialer.dial(&target).await?;
+            let mut stream: Box<dyn Stream> = match connector {
+                Some(connector) => Box::new(connector.connect(server_name, stream).await?),
+                None => stream,
+            };
+
+            let mut head = format!(
+                "{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: rust-proxy/{}\r\n",
+                method,
+                path_and_query(url),
+                authority(url),
+                env!("CARGO_PKG_VERSION")
+            );
+            for (name, value) in headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            if let Some(body) = body {
+                head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+            }
+            head.push_str("\r\n");
+            let mut request = head.into_bytes();
+            request.extend_from_slice(body.unwrap_or_default());
+            stream.write_all(&request).await?;
+            read_response(&mut stream, method == "HEAD").await
+        };
+        timeout(self.timeout, request)
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+}
+
+/// The `Host` value for `url`: its host, with the port when not the
+/// scheme's default.
+pub fn authority(url: &Url) -> &str {
+    &url[url::Position::BeforeHost..url::Position::AfterPort]
+}
+
+/// The origin-form request target for `url`.
+pub fn path_and_query(url: &Url) -> &str {
+    &url[url::Position::BeforePath..url::Position::AfterQuery]
+}
+
+/// Reads a response to the end of its body; a response to `HEAD` has none.
+async fn read_response(
+    stream: &mut Box<dyn Stream>,
+    head_only: bool,
+) -> Result<Response, ClientError> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let (head, consumed) = loop {
+        if let Some(decoded) = codec::decode_response(&buf)? {
+            break decoded;
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(ClientError::IoError(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before end of response head",
+            )));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let mut body = buf.split_off(consumed);
+    let length = match head.status {
+        _ if head_only => Some(0),
+        100..=199 | 204 | 304 => Some(0),
+        _ => head
+            .headers
+            .iter()
+            .find(|header| header.name_lower == "content-length")
+            .and_then(|header| header.value.parse::<usize>().ok()),
+    };
+    let limit = length.unwrap_or(MAX_RESPONSE_SIZE);
+    if limit > MAX_RESPONSE_SIZE {
+        return Err(ClientError::TooLarge(MAX_RESPONSE_SIZE));
+    }
+    while body.len() < limit {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&chunk[..n]);
+    }
+    match length {
+        Some(length) if body.len() < length => Err(ClientError::IoError(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed before end of response body",
+        ))),
+        Some(length) => {
+            body.truncate(length);
+            Ok(Response {
+                status: head.status,
+                headers: head.headers,
+                body,
+            })
+        }
+        // Without a length the body runs to the end of the stream
+        None if body.len() >= MAX_RESPONSE_SIZE => Err(ClientError::TooLarge(MAX_RESPONSE_SIZE)),
+        None => Ok(Response {
+            status: head.status,
+            headers: head.headers,
+            body,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::dialer::DirectDialer;
+    use tokio::net::TcpListener;
+
+    /// Answers one request with `response` and returns what was received.
+    async fn serve_once(listener: TcpListener, response: &'static [u8]) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream.write_all(response).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let client = HttpClient::new(Duration::from_secs(5));
+        let dialer = DirectDialer::new(Duration::from_secs(5));
+
+        // A body delimited by Content-Length, with more bytes behind it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/check?x=1",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let server = tokio::spawn(serve_once(
+            listener,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Id: 7\r\n\r\nokjunk",
+        ));
+        let response = client
+            .send(
+                &dialer,
+                "POST",
+                &url,
+                &[("Content-Type", "application/json")],
+                Some(b"{}"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("x-id"), Some("7"));
+        assert_eq!(response.body, b"ok");
+        let request = server.await.unwrap();
+        assert!(request.starts_with(&format!(
+            "POST /check?x=1 HTTP/1.0\r\nHost: {}\r\n",
+            authority(&url)
+        )));
+        assert!(request.contains("Content-Type: application/json\r\nContent-Length: 2\r\n\r\n"));
+
+        // No length: the body ends with the connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(serve_once(listener, b"HTTP/1.0 404 Not Found\r\n\r\ngone"));
+        let response = client.send(&dialer, "GET", &url, &[], None).await.unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"gone");
+
+        // https:// needs with_tls
+        let url = Url::parse(&format!("https://{}/", url.authority())).unwrap();
+        assert!(matches!(
+            client.send(&dialer, "GET", &url, &[], None).await,
+            Err(ClientError::TlsNotSetUp)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_truncated_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(serve_once(
+            listener,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort",
+        ));
+        let client = HttpClient::new(Duration::from_secs(5));
+        let dialer = DirectDialer::new(Duration::from_secs(5));
+        assert!(matches!(
+            client.send(&dialer, "GET", &url, &[], None).await,
+            Err(ClientError::IoError(_))
+        ));
+    }
+}
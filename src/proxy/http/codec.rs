@@ -0,0 +1,311 @@
+//! HTTP/1.x message head parsing on top of `httparse`, decoupled from IO.
+//!
+//! `decode_request` and `decode_response` inspect a connection's read
+//! buffer and return
+//! `Ok(Some((head, consumed)))` once the whole head has arrived, `Ok(None)`
+//! when more bytes are needed, or an error as soon as the input is known to
+//! be malformed or over its `Limits`.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Maximum number of header fields in a response head
+pub const MAX_HEADERS: usize = 100;
+/// Maximum size in bytes of a response head
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Response fields that decide how the proxy frames the body; any other
+/// response value is only relayed, so bytes outside UTF-8 are replaced
+/// rather than failing the response.
+const FRAMING_HEADERS: [&str; 3] = ["connection", "content-length", "transfer-encoding"];
+
+/// Bounds on a request head, checked while it is still arriving so a
+/// client cannot make the proxy buffer an endless head.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Bytes in the request line, without its line ending
+    pub max_request_line: usize,
+    /// Bytes in the header section following the request line
+    pub max_header_bytes: usize,
+    pub max_headers: usize,
+}
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("Malformed request head: {0}")]
+    Malformed(httparse::Error),
+    #[error("More than {0} header fields")]
+    TooManyHeaders(usize),
+    #[error("Header section exceeds {0} bytes")]
+    HeadTooLarge(usize),
+    #[error("Request line exceeds {0} bytes")]
+    RequestLineTooLong(usize),
+    #[error("Header {0} is not valid UTF-8")]
+    InvalidHeaderValue(String),
+    #[error("Header continued with obsolete line folding")]
+    ObsoleteLineFolding,
+}
+
+pub type Decoded<T> = Result<Option<(T, usize)>, CodecError>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub name: String,
+    pub name_lower: String,
+    pub value: String,
+}
+
+/// Request line and header fields, in their original order and case.
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub headers: Vec<Header>,
+}
+
+/// Status line and header fields of an upstream response.
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Vec<Header>,
+}
+
+pub fn decode_request(buf: &[u8], limits: &Limits) -> Decoded<RequestHead> {
+    let line_len = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let line = &buf[..end];
+            if line.strip_suffix(b"\r").unwrap_or(line).len() > limits.max_request_line {
+                return Err(CodecError::RequestLineTooLong(limits.max_request_line));
+            }
+            end + 1
+        }
+        // Leave room for the CR of a line ending yet to arrive
+        None if buf.len() > limits.max_request_line + 1 => {
+            return Err(CodecError::RequestLineTooLong(limits.max_request_line));
+        }
+        None => buf.len(),
+    };
+
+    let mut headers = vec![httparse::EMPTY_HEADER; limits.max_headers];
+    let mut request = httparse::Request::new(&mut headers);
+    let status = request.parse(buf);
+    // httparse refuses folded request headers as a bad header name; they are
+    // reported apart since they are a request smuggling vector
+    if status.is_err() && has_line_folding(buf) {
+        return Err(CodecError::ObsoleteLineFolding);
+    }
+    let Some(consumed) = check_status(status, buf.len(), line_len, limits)? else {
+        return Ok(None);
+    };
+
+    // A complete parse always fills the request line
+    let head = RequestHead {
+        method: request.method.unwrap_or_default().to_string(),
+        path: request.path.unwrap_or_default().to_string(),
+        version: format!("HTTP/1.{}", request.version.unwrap_or(1)),
+        headers: convert_headers(request.headers, |_| true)?,
+    };
+    Ok(Some((head, consumed)))
+}
+
+pub fn decode_response(buf: &[u8]) -> Decoded<ResponseHead> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    let limits = Limits {
+        max_request_line: MAX_HEAD_SIZE,
+        max_header_bytes: MAX_HEAD_SIZE,
+        max_headers: MAX_HEADERS,
+    };
+    let Some(consumed) = check_status(response.parse(buf), buf.len(), 0, &limits)? else {
+        return Ok(None);
+    };
+
+    let head = ResponseHead {
+        version: format!("HTTP/1.{}", response.version.unwrap_or(1)),
+        status: response.code.unwrap_or_default(),
+        reason: response.reason.unwrap_or_default().to_string(),
+        headers: convert_headers(response.headers, |name| FRAMING_HEADERS.contains(&name))?,
+    };
+    Ok(Some((head, consumed)))
+}
+
+/// Maps an `httparse` result to the head length, applying the header limits
+/// to both complete and partial heads. The first `line_len` bytes are the
+/// start line, which is not counted as header bytes.
+fn check_status(
+    status: httparse::Result<usize>,
+    buffered: usize,
+    line_len: usize,
+    limits: &Limits,
+) -> Result<Option<usize>, CodecError> {
+    let too_large = |len: usize| len - line_len.min(len) > limits.max_header_bytes;
+    match status {
+        Ok(httparse::Status::Complete(consumed)) if too_large(consumed) => {
+            Err(CodecError::HeadTooLarge(limits.max_header_bytes))
+        }
+        Ok(httparse::Status::Complete(consumed)) => Ok(Some(consumed)),
+        Ok(httparse::Status::Partial) if too_large(buffered) => {
+            Err(CodecError::HeadTooLarge(limits.max_header_bytes))
+        }
+        Ok(httparse::Status::Partial) => Ok(None),
+        Err(httparse::Error::TooManyHeaders) => Err(CodecError::TooManyHeaders(limits.max_headers)),
+        Err(e) => Err(CodecError::Malformed(e)),
+    }
+}
+
+/// Whether a header line starts with whitespace, continuing the previous one
+/// (`obs-fold`, RFC 9112 §5.2).
+fn has_line_folding(buf: &[u8]) -> bool {
+    let head_end = buf
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap_or(buf.len());
+    buf[..head_end]
+        .windows(2)
+        .any(|pair| pair[0] == b'\n' && matches!(pair[1], b' ' | b'\t'))
+}
+
+/// Converts `httparse` fields, requiring UTF-8 in the values of the fields
+/// `strict` picks by lowercase name and decoding the rest lossily.
+fn convert_headers(
+    headers: &[httparse::Header],
+    strict: impl Fn(&str) -> bool,
+) -> Result<Vec<Header>, CodecError> {
+    headers
+        .iter()
+        .map(|header| {
+            let name_lower = header.name.to_ascii_lowercase();
+            let value = match std::str::from_utf8(header.value) {
+                Ok(value) => value.into(),
+                Err(_) if strict(&name_lower) => {
+                    return Err(CodecError::InvalidHeaderValue(header.name.to_string()));
+                }
+                Err(_) => String::from_utf8_lossy(header.value),
+            };
+            Ok(Header {
+                name: header.name.to_string(),
+                name_lower,
+                value: value.trim().to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_request_line: 64,
+        max_header_bytes: 256,
+        max_headers: 4,
+    };
+
+    #[test]
+    fn test_decode_request() {
+        let frame = b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\nX-Mixed-Case:  v \r\n\r\nbody";
+        for len in 0..frame.len() - 4 {
+            assert!(matches!(decode_request(&frame[..len], &LIMITS), Ok(None)));
+        }
+
+        let (head, consumed) = decode_request(frame, &LIMITS).unwrap().unwrap();
+        assert_eq!(consumed, frame.len() - 4);
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.path, "http://example.com/");
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.headers.len(), 2);
+        assert_eq!(head.headers[1].name, "X-Mixed-Case");
+        assert_eq!(head.headers[1].name_lower, "x-mixed-case");
+        assert_eq!(head.headers[1].value, "v");
+    }
+
+    #[test]
+    fn test_bare_lf_line_endings() {
+        let (head, consumed) = decode_request(b"GET / HTTP/1.0\nHost: a\n\n", &LIMITS)
+            .unwrap()
+            .unwrap();
+        assert_eq!(consumed, 24);
+        assert_eq!(head.version, "HTTP/1.0");
+        assert_eq!(head.headers[0].value, "a");
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let frame = b"HTTP/1.1 404 Not Found\r\nVia: 1.0 fred\r\n\r\n";
+        for len in 0..frame.len() {
+            assert!(matches!(decode_response(&frame[..len]), Ok(None)));
+        }
+
+        let (head, consumed) = decode_response(frame).unwrap().unwrap();
+        assert_eq!(consumed, frame.len());
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.status, 404);
+        assert_eq!(head.reason, "Not Found");
+        assert_eq!(head.headers[0].value, "1.0 fred");
+    }
+
+    #[test]
+    fn test_non_utf8_response_values() {
+        // A Latin-1 filename is relayed with the byte replaced
+        let frame =
+            b"HTTP/1.1 200 OK\r\nContent-Disposition: attachment; filename=caf\xe9.txt\r\n\r\n";
+        let (head, _) = decode_response(frame).unwrap().unwrap();
+        assert_eq!(
+            head.headers[0].value,
+            "attachment; filename=caf\u{fffd}.txt"
+        );
+
+        // Framing fields still have to be valid
+        assert!(matches!(
+            decode_response(b"HTTP/1.1 200 OK\r\nContent-Length: 1\xe9\r\n\r\n"),
+            Err(CodecError::InvalidHeaderValue(_))
+        ));
+        assert!(matches!(
+            decode_request(b"GET / HTTP/1.1\r\nX-Name: caf\xe9\r\n\r\n", &LIMITS),
+            Err(CodecError::InvalidHeaderValue(_))
+        ));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut many = b"GET / HTTP/1.1\r\n".to_vec();
+        for i in 0..=LIMITS.max_headers {
+            many.extend_from_slice(format!("X-{}: 1\r\n", i).as_bytes());
+        }
+        assert!(matches!(
+            decode_request(&many, &LIMITS),
+            Err(CodecError::TooManyHeaders(4))
+        ));
+
+        // The request line does not count towards the header bytes
+        let mut large = format!("GET /{} HTTP/1.1\r\nX-Large: ", "a".repeat(50)).into_bytes();
+        let line_len = large.len() - 9;
+        large.resize(line_len + LIMITS.max_header_bytes, b'a');
+        assert!(matches!(decode_request(&large, &LIMITS), Ok(None)));
+        large.push(b'a');
+        assert!(matches!(
+            decode_request(&large, &LIMITS),
+            Err(CodecError::HeadTooLarge(256))
+        ));
+
+        let mut long_line = b"GET /".to_vec();
+        long_line.resize(LIMITS.max_request_line + 1, b'a');
+        assert!(matches!(decode_request(&long_line, &LIMITS), Ok(None)));
+        long_line.push(b'a');
+        assert!(matches!(
+            decode_request(&long_line, &LIMITS),
+            Err(CodecError::RequestLineTooLong(64))
+        ));
+        let exact = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(50));
+        assert!(decode_request(exact.as_bytes(), &LIMITS).unwrap().is_some());
+
+        assert!(matches!(
+            decode_request(b"GET / HTTP/1.1\r\nBad Header\r\n\r\n", &LIMITS),
+            Err(CodecError::Malformed(_))
+        ));
+        assert!(matches!(
+            decode_request(b"GET / HTTP/1.1\r\nX-A: 1\r\n\tX-B: 2\r\n\r\n", &LIMITS),
+            Err(CodecError::ObsoleteLineFolding)
+        ));
+    }
+}
@@ -0,0 +1,135 @@
+//! Reverse proxy routing for the `[gateway]` listener: requests are sent to
+//! a fixed backend chosen by their host rather than to the host itself.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+use crate::common::config::GatewayConfig;
+use crate::net::addr::{self, TargetAddr, host_matches};
+use crate::net::stream::Stream;
+use crate::net::tls::{self, TlsError};
+use crate::proxy::dialer::Dialer;
+use crate::proxy::forward::ConnectError;
+
+/// The `[gateway]` routes, in the order they are tried.
+pub struct Gateway {
+    routes: Vec<Route>,
+    connect_timeout: Duration,
+}
+
+/// A route, with the backend address parsed and its TLS client set up.
+pub struct Route {
+    host: String,
+    pub backend: TargetAddr,
+    /// Set when the backend is reached over TLS
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+}
+
+impl Route {
+    /// Key of the backend's idle connections in the shared pool, kept apart
+    /// from plain connections to the same address.
+    pub fn pool_key(&self) -> String {
+        match self.tls {
+            Some(_) => format!("tls:{}", self.backend),
+            None => self.backend.to_string(),
+        }
+    }
+}
+
+impl Gateway {
+    /// Loads the CA bundle of every TLS route.
+    pub fn new(config: &GatewayConfig, connect_timeout: Duration) -> Result<Self, TlsError> {
+        let mut routes = Vec::with_capacity(config.routes.len());
+        for route in &config.routes {
+            // Validated along with the rest of the configuration
+            let backend = TargetAddr::parse(&route.backend).unwrap();
+            let tls = match &route.ca_path {
+                Some(ca_path) if route.tls => {
+                    let server_name = match &backend {
+                        TargetAddr::Ip(addr) => ServerName::from(addr.ip()),
+                        TargetAddr::Domain(host, _) => addr::server_name(host)?,
+                    };
+                    Some((tls::build_connector(ca_path)?, server_name))
+                }
+                _ => None,
+            };
+            routes.push(Route {
+                host: route.host.clone(),
+                backend,
+                tls,
+            });
+        }
+        Ok(Gateway {
+            routes,
+            connect_timeout,
+        })
+    }
+
+    /// The first route whose host pattern matches `host`.
+    pub fn route(&self, host: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|route| route.host == "*" || host_matches(&route.host, host))
+    }
+
+    /// Opens a connection to the route's backend through `dialer`, with the
+    /// TLS handshake, if any, bounded by the connect timeout.
+    pub async fn connect(
+        &self,
+        route: &Route,
+        dialer: &dyn Dialer,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let stream = dialer
+            .dial_from(&route.backend, source, destination)
+            .await?;
+        let Some((connector, server_name)) = &route.tls else {
+            return Ok(stream);
+        };
+        let handshake = connector.connect(server_name.clone(), stream);
+        let stream = timeout(self.connect_timeout, handshake)
+            .await
+            .map_err(|_| ConnectError::ConnectionTimeout)??;
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::config::GatewayRoute;
+
+    #[test]
+    fn test_route() {
+        let route = |host: &str, backend: &str| GatewayRoute {
+            host: host.to_string(),
+            backend: backend.to_string(),
+            tls: false,
+            ca_path: None,
+        };
+        let config = GatewayConfig {
+            listen_address: "127.0.0.1:8080".to_string(),
+            routes: vec![
+                route("app.example.com", "10.0.0.1:80"),
+                route("*.example.com", "10.0.0.2:80"),
+                route("*", "10.0.0.3:80"),
+            ],
+        };
+        let gateway = Gateway::new(&config, Duration::from_secs(5)).unwrap();
+        let backend = |host: &str| gateway.route(host).unwrap().backend.to_string();
+        assert_eq!(backend("APP.example.com"), "10.0.0.1:80");
+        assert_eq!(backend("api.example.com"), "10.0.0.2:80");
+        assert_eq!(backend("example.org"), "10.0.0.3:80");
+
+        let config = GatewayConfig {
+            routes: vec![route("app.example.com", "10.0.0.1:80")],
+            ..config
+        };
+        let gateway = Gateway::new(&config, Duration::from_secs(5)).unwrap();
+        assert!(gateway.route("example.com").is_none());
+    }
+}
@@ -0,0 +1,403 @@
+//! CONNECT over HTTP/2 (RFC 9113 §8.5): a client that opens with the
+//! HTTP/2 preface, after negotiating `h2` through `tls.alpn` or with prior
+//! knowledge, runs any number of tunnels over one connection. Each stream
+//! is authenticated, checked and dialed like an HTTP/1.1 CONNECT.
+
+use bytes::Bytes;
+use h2::RecvStream;
+use h2::server::SendResponse;
+use http::{Method, Request, Response, StatusCode};
+use log::{debug, info};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::access_log::AccessRecord;
+use super::codec::Header;
+use super::{Body, HttpProxy, HttpProxyError, HttpRequest, connect_target};
+use crate::net::addr::TargetAddr;
+use crate::net::conn::BufferedConnection;
+use crate::net::http2::{Http2Stream, PREFACE};
+use crate::proxy::forward;
+
+/// Where a stream's connection comes from.
+#[derive(Clone)]
+struct Client {
+    peer: SocketAddr,
+    local: SocketAddr,
+    identity: Option<String>,
+}
+
+/// Whether `conn` opens with the HTTP/2 preface. Reads only while what has
+/// arrived could still be the preface, so HTTP/1 requests are not held up.
+pub async fn is_preface(conn: &mut BufferedConnection) -> std::io::Result<bool> {
+    loop {
+        let buffered = conn.buffered();
+        let n = buffered.len().min(PREFACE.len());
+        if buffered[..n] != PREFACE[..n] {
+            return Ok(false);
+        }
+        if n == PREFACE.len() {
+            return Ok(true);
+        }
+        if conn.read().await? == 0 {
+            return Ok(false);
+        }
+    }
+}
+
+impl HttpProxy {
+    /// Serves the streams of an HTTP/2 connection until the client closes
+    /// it. Only CONNECT is served; other methods get 405.
+    pub async fn handle_h2_connection(
+        self: Arc<Self>,
+        conn: &mut BufferedConnection,
+        handshake_timeout: Duration,
+    ) -> Result<(), HttpProxyError> {
+        let client = Client {
+            peer: conn.peer_addr()?,
+            local: conn.local_addr()?,
+            identity: conn.client_identity(),
+        };
+        let handshake = h2::server::Builder::new()
+            .max_concurrent_streams(self.config.max_concurrent_streams)
+            .handshake::<_, Bytes>(conn);
+        let mut connection = tokio::time::timeout(handshake_timeout, handshake)
+            .await
+            .map_err(|_| HttpProxyError::HandshakeTimeout)?
+            .map_err(h2_error)?;
+
+        while let Some(accepted) = connection.accept().await {
+            let (request, respond) = match accepted {
+                Ok(accepted) => accepted,
+                // Reset or closed by the client
+                Err(e) if e.is_go_away() || e.is_reset() => break,
+                Err(e) => return Err(h2_error(e)),
+            };
+            let proxy = self.clone();
+            let client = client.clone();
+            tokio::spawn(async move {
+                if let Err(e) = proxy.serve_h2_stream(request, respond, client).await {
+                    debug!("HTTP/2 stream error: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Serves one stream, logging it like an HTTP/1.1 request.
+    async fn serve_h2_stream(
+        &self,
+        request: Request<RecvStream>,
+        mut respond: SendResponse<Bytes>,
+        client: Client,
+    ) -> Result<(), HttpProxyError> {
+        let (parts, body) = request.into_parts();
+        let head = HttpRequest {
+            method: parts.method.to_string(),
+            path: parts
+                .uri
+                .authority()
+                .map(|authority| authority.to_string())
+                .unwrap_or_default(),
+            version: "HTTP/2.0".to_string(),
+            headers: parts
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some(Header {
+                        name: name.to_string(),
+                        name_lower: name.to_string(),
+                        value: value.to_str().ok()?.to_string(),
+                    })
+                })
+                .collect(),
+            body: Body::None,
+            tls: false,
+        };
+
+        let started = Instant::now();
+        let mut user = None;
+        let mut bytes = 0;
+        let result = self
+            .handle_h2_connect(&head, body, &mut respond, &client, &mut user, &mut bytes)
+            .await;
+        if let Some(format) = self.access_log {
+            let status = match &result {
+                Ok(status) => Some(*status),
+                Err(e) => e.response_status(),
+            };
+            AccessRecord {
+                client: client.peer.ip(),
+                user: user.as_deref(),
+                method: &head.method,
+                target: &head.path,
+                version: &head.version,
+                status,
+                bytes,
+                referer: head.get_header("referer"),
+                user_agent: head.get_header("user-agent"),
+                duration: started.elapsed(),
+            }
+            .log(format);
+        }
+        // A stream left without a response is reset when `respond` drops
+        result.map(drop)
+    }
+
+    /// Opens the tunnel a CONNECT stream asks for and relays it, returning
+    /// the status sent. `user` is set once the client has authenticated,
+    /// and `bytes` counts what was relayed.
+    async fn handle_h2_connect(
+        &self,
+        request: &HttpRequest,
+        body: RecvStream,
+        respond: &mut SendResponse<Bytes>,
+        client: &Client,
+        user: &mut Option<String>,
+        bytes: &mut u64,
+    ) -> Result<u16, HttpProxyError> {
+        if request.method != Method::CONNECT.as_str() {
+            respond_status(respond, StatusCode::METHOD_NOT_ALLOWED, &[], "")?;
+            return Err(HttpProxyError::UnsupportedMethod(request.method.clone()));
+        }
+        if request.via_contains(&self.config.via_pseudonym) {
+            respond_status(respond, StatusCode::LOOP_DETECTED, &[], "")?;
+            return Err(HttpProxyError::LoopDetected);
+        }
+
+        let auth_required = self.auth_manager.is_required() || self.jwt.is_some();
+        let mut limits = Vec::new();
+        if auth_required {
+            let name = match self
+                .check_credentials(
+                    client.peer,
+                    client.identity.clone(),
+                    request.get_header("proxy-authorization"),
+                )
+                .await
+            {
+                Ok(name) => name,
+                Err(e) => {
+                    let body = self.config.auth_body.as_deref().unwrap_or_default();
+                    respond_status(
+                        respond,
+                        StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+                        &self.proxy_auth_headers(),
+                        body,
+                    )?;
+                    return Err(e);
+                }
+            };
+            limits = self.auth_manager.rate_limits(&name);
+            *user = Some(name);
+        }
+
+        let Some(target_addr) = connect_target(&request.path) else {
+            respond_status(respond, StatusCode::BAD_REQUEST, &[], "")?;
+            return Err(HttpProxyError::InvalidRequest(format!(
+                "Invalid CONNECT target: {}",
+                request.path
+            )));
+        };
+        let port = target_addr.port();
+        if !self.is_connect_port_allowed(port) {
+            respond_status(respond, StatusCode::FORBIDDEN, &[], "")?;
+            return Err(HttpProxyError::ConnectPortNotAllowed(port));
+        }
+        if let TargetAddr::Domain(host, _) = &target_addr
+            && self.is_host_blocked(host)
+        {
+            info!("CONNECT {} blocked by a filter list", target_addr);
+            respond_status(respond, StatusCode::FORBIDDEN, &[], "")?;
+            return Ok(403);
+        }
+
+        let target_stream = match self
+            .dialer
+            .dial_from(&target_addr, client.peer, client.local)
+            .await
+        {
+            Ok(stream) => stream,
+            Err(e) => {
+                let e = HttpProxyError::from(e);
+                let status = e.response_status().unwrap_or(502);
+                let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+                respond_status(respond, status, &[], "")?;
+                return Err(e);
+            }
+        };
+        if self.is_own_listener(target_stream.peer_addr()?, client.local) {
+            respond_status(respond, StatusCode::LOOP_DETECTED, &[], "")?;
+            return Err(HttpProxyError::LoopDetected);
+        }
+
+        let send = respond
+            .send_response(Response::new(()), false)
+            .map_err(h2_error)?;
+        info!("CONNECT tunnel to {} over HTTP/2", target_addr);
+
+        let stream = Http2Stream::new(send, body, client.local, client.peer);
+        let mut conn = BufferedConnection::new(stream, self.buffer_size);
+        for limiter in limits {
+            conn.throttle(limiter);
+        }
+        let mut target_conn = BufferedConnection::new(target_stream, self.buffer_size);
+        let result = forward::forward_bidirectional(&mut conn, &mut target_conn, None).await;
+        *bytes = conn.bytes_read() + conn.bytes_written();
+        result?;
+        Ok(200)
+    }
+}
+
+/// Sends a final response that ends the stream.
+fn respond_status(
+    respond: &mut SendResponse<Bytes>,
+    status: StatusCode,
+    headers: &[(String, String)],
+    body: &str,
+) -> Result<(), HttpProxyError> {
+    let mut response = Response::builder().status(status);
+    for (name, value) in headers {
+        response = response.header(name.as_str(), value.as_str());
+    }
+    let response = response
+        .body(())
+        .map_err(|e| HttpProxyError::InvalidRequest(e.to_string()))?;
+    let mut send = respond
+        .send_response(response, body.is_empty())
+        .map_err(h2_error)?;
+    if !body.is_empty() {
+        send.send_data(Bytes::copy_from_slice(body.as_bytes()), true)
+            .map_err(h2_error)?;
+    }
+    Ok(())
+}
+
+fn h2_error(e: h2::Error) -> HttpProxyError {
+    match e.into_io() {
+        Some(e) => e.into(),
+        None => HttpProxyError::InvalidRequest("HTTP/2 protocol error".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::auth::AuthManager;
+    use crate::common::config::{HttpConfig, UserConfig};
+    use crate::proxy::dialer::DirectDialer;
+    use base64::{Engine as _, engine::general_purpose};
+    use std::collections::HashMap;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_is_preface() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        for (sent, expected) in [
+            (PREFACE, true),
+            (&b"PUT / HTTP/1.1\r\n\r\n"[..], false),
+            // Short of the preface, then closed
+            (&PREFACE[..10], false),
+        ] {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            client.write_all(sent).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            assert_eq!(is_preface(&mut conn).await.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_streams() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            assert!(is_preface(&mut conn).await.unwrap());
+            let users = HashMap::from([(
+                "alice".to_string(),
+                UserConfig::from("open-sesame".to_string()),
+            )]);
+            let proxy = HttpProxy::new(
+                Arc::new(AuthManager::new(&users).unwrap()),
+                4096,
+                Arc::new(DirectDialer::new(Duration::from_secs(5))),
+                Arc::new(HttpConfig {
+                    allowed_connect_ports: Vec::new(),
+                    ..Default::default()
+                }),
+                None,
+                None,
+                None,
+            );
+            let _ = Arc::new(proxy)
+                .handle_h2_connection(&mut conn, Duration::from_secs(5))
+                .await;
+        });
+
+        let stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let mut client = client.ready().await.unwrap();
+        let connect = |password: &str| {
+            let credentials = general_purpose::STANDARD.encode(format!("alice:{}", password));
+            Request::builder()
+                .method(Method::CONNECT)
+                .uri(echo_addr.to_string())
+                .header("proxy-authorization", format!("Basic {}", credentials))
+                .body(())
+                .unwrap()
+        };
+
+        // Two tunnels at once on the one connection
+        let (first, mut first_send) = client.send_request(connect("open-sesame"), false).unwrap();
+        let (second, mut second_send) = client.send_request(connect("open-sesame"), false).unwrap();
+        let (refused, _) = client.send_request(connect("wrong"), false).unwrap();
+
+        let refused = refused.await.unwrap();
+        assert_eq!(refused.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        assert!(refused.headers().contains_key("proxy-authenticate"));
+
+        let mut first = first.await.unwrap();
+        let mut second = second.await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        second_send
+            .send_data(Bytes::from_static(b"second"), false)
+            .unwrap();
+        first_send
+            .send_data(Bytes::from_static(b"first"), false)
+            .unwrap();
+        let first = first.body_mut().data().await.unwrap().unwrap();
+        let second = second.body_mut().data().await.unwrap().unwrap();
+        assert_eq!((&first[..], &second[..]), (&b"first"[..], &b"second"[..]));
+
+        // Only CONNECT is served
+        let get = Request::builder()
+            .uri(format!("http://{}/", echo_addr))
+            .body(())
+            .unwrap();
+        let (response, _) = client.send_request(get, true).unwrap();
+        assert_eq!(
+            response.await.unwrap().status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+}
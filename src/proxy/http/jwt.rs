@@ -0,0 +1,343 @@
+//! `[http.jwt]`: bearer tokens in `Proxy-Authorization`, verified against a
+//! shared secret or a JSON Web Key Set fetched from a URL, each standing
+//! for the proxy user one of its claims names.
+
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{AlgorithmParameters, Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use serde_json::{Map, Value};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use url::Url;
+
+use crate::common::config::JwtConfig;
+use crate::proxy::dialer::DirectDialer;
+use crate::proxy::http::client::HttpClient;
+
+/// How long fetching the key set may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Least time between fetches prompted by tokens signed with unknown keys
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum JwtError {
+    #[error("Invalid jwks_url '{0}': {1}")]
+    InvalidUrl(String, String),
+    #[error("Invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+    #[error("No key in the key set for kid {0:?}")]
+    UnknownKey(Option<String>),
+    #[error("Token has no string claim '{0}'")]
+    MissingUser(String),
+    #[error("Failed to fetch the key set: {0}")]
+    Fetch(String),
+}
+
+/// The key set at `jwks_url`, fetched when first needed and again once it
+/// is `refresh` old or a token names a key it lacks.
+struct Jwks {
+    url: Url,
+    client: HttpClient,
+    dialer: DirectDialer,
+    refresh: Duration,
+    /// The last set fetched, and when
+    cached: Mutex<Option<(Arc<JwkSet>, Instant)>>,
+    /// Held while fetching, so concurrent requests wait for one fetch
+    fetching: tokio::sync::Mutex<()>,
+}
+
+impl Jwks {
+    fn new(config: &JwtConfig, url: &str) -> Result<Self, JwtError> {
+        let invalid = |e: String| JwtError::InvalidUrl(url.to_string(), e);
+        let url = Url::parse(url).map_err(|e| invalid(e.to_string()))?;
+        let mut client = HttpClient::new(FETCH_TIMEOUT);
+        if url.scheme() == "https" {
+            client = client
+                .with_tls(config.ca_path.as_deref())
+                .map_err(|e| invalid(e.to_string()))?;
+        }
+        Ok(Jwks {
+            url,
+            client,
+            dialer: DirectDialer::new(FETCH_TIMEOUT),
+            refresh: Duration::from_secs(config.jwks_refresh),
+            cached: Mutex::new(None),
+            fetching: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// The key named `kid`, or the only key of a set of one when the token
+    /// names none.
+    async fn key(&self, kid: Option<&str>) -> Result<Jwk, JwtError> {
+        if let Some(jwk) = self.cached_key(kid, self.refresh) {
+            return Ok(jwk);
+        }
+        let _fetching = self.fetching.lock().await;
+        // Fetched by another request while this one waited
+        if let Some(jwk) = self.cached_key(kid, self.refresh) {
+            return Ok(jwk);
+        }
+        let fetched_at = self.cached.lock().unwrap().as_ref().map(|(_, at)| *at);
+        if fetched_at.is_none_or(|at| at.elapsed() >= MIN_REFETCH_INTERVAL) {
+            match self.fetch().await {
+                Ok(set) => {
+                    info!("Fetched {} keys from the JWKS URL", set.keys.len());
+                    *self.cached.lock().unwrap() = Some((Arc::new(set), Instant::now()));
+                }
+                // The keys fetched before stay in use
+                Err(e) if fetched_at.is_some() => warn!("{}", e),
+                Err(e) => return Err(e),
+            }
+        }
+        self.cached_key(kid, Duration::MAX)
+            .ok_or_else(|| JwtError::UnknownKey(kid.map(str::to_string)))
+    }
+
+    /// Looks `kid` up in a set fetched less than `max_age` ago.
+    fn cached_key(&self, kid: Option<&str>, max_age: Duration) -> Option<Jwk> {
+        let cached = self.cached.lock().unwrap();
+        let (set, fetched_at) = cached.as_ref()?;
+        if fetched_at.elapsed() >= max_age {
+            return None;
+        }
+        match kid {
+            Some(kid) => set.find(kid).cloned(),
+            None if set.keys.len() == 1 => set.keys.first().cloned(),
+            None => None,
+        }
+    }
+
+    async fn fetch(&self) -> Result<JwkSet, JwtError> {
+        let response = self
+            .client
+            .send(
+                &self.dialer,
+                "GET",
+                &self.url,
+                &[("Accept", "application/json")],
+                None,
+            )
+            .await
+            .map_err(|e| JwtError::Fetch(e.to_string()))?;
+        if response.status != 200 {
+            return Err(JwtError::Fetch(format!(
+                "unexpected status {}",
+                response.status
+            )));
+        }
+        serde_json::from_slice(&response.body).map_err(|e| JwtError::Fetch(e.to_string()))
+    }
+}
+
+enum Keys {
+    Secret(DecodingKey),
+    Jwks(Box<Jwks>),
+}
+
+/// Verifies bearer tokens, returning the user each stands for.
+pub struct JwtAuth {
+    keys: Keys,
+    issuer: Option<String>,
+    audience: Option<String>,
+    user_claim: String,
+    leeway: u64,
+}
+
+impl JwtAuth {
+    pub fn new(config: &JwtConfig) -> Result<Self, JwtError> {
+        let keys = match (&config.secret, &config.jwks_url) {
+            (Some(secret), _) => Keys::Secret(DecodingKey::from_secret(secret.as_bytes())),
+            (None, Some(url)) => Keys::Jwks(Box::new(Jwks::new(config, url)?)),
+            // Validation requires one of them
+            (None, None) => unreachable!(),
+        };
+        Ok(JwtAuth {
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            user_claim: config.user_claim.clone(),
+            leeway: config.leeway,
+        })
+    }
+
+    /// Checks the signature and claims of `token` and returns its user.
+    pub async fn verify(&self, token: &str) -> Result<String, JwtError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = match &self.keys {
+            Keys::Secret(key) => {
+                if !matches!(
+                    header.alg,
+                    Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+                ) {
+                    return Err(
+                        jsonwebtoken::errors::Error::from(ErrorKind::InvalidAlgorithm).into(),
+                    );
+                }
+                key.clone()
+            }
+            Keys::Jwks(jwks) => {
+                let jwk = jwks.key(header.kid.as_deref()).await?;
+                // A public key set holds no shared secrets, and a key
+                // naming its algorithm is used with that one only
+                let alg_mismatch = jwk
+                    .common
+                    .key_algorithm
+                    .is_some_and(|alg| alg.to_string() != format!("{:?}", header.alg));
+                if matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)) || alg_mismatch {
+                    return Err(
+                        jsonwebtoken::errors::Error::from(ErrorKind::InvalidAlgorithm).into(),
+                    );
+                }
+                DecodingKey::from_jwk(&jwk)?
+            }
+        };
+
+        // Only the header's algorithm, which must suit the key's family
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway;
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let claims = jsonwebtoken::decode::<Map<String, Value>>(token, &key, &validation)?.claims;
+        claims
+            .get(&self.user_claim)
+            .and_then(Value::as_str)
+            .filter(|user| !user.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| JwtError::MissingUser(self.user_claim.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use jsonwebtoken::{EncodingKey, Header, encode, get_current_timestamp};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn config() -> JwtConfig {
+        JwtConfig {
+            secret: None,
+            jwks_url: None,
+            jwks_refresh: 300,
+            ca_path: None,
+            issuer: Some("https://issuer.example".to_string()),
+            audience: None,
+            user_claim: "sub".to_string(),
+            leeway: 0,
+        }
+    }
+
+    fn claims(sub: &str, expires_in: i64) -> Value {
+        json!({
+            "sub": sub,
+            "iss": "https://issuer.example",
+            "exp": get_current_timestamp() as i64 + expires_in,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_jwt_secret() {
+        let auth = JwtAuth::new(&JwtConfig {
+            secret: Some("s3cret".to_string()),
+            ..config()
+        })
+        .unwrap();
+        let key = EncodingKey::from_secret(b"s3cret");
+        let sign = |claims: &Value| encode(&Header::default(), claims, &key).unwrap();
+
+        assert_eq!(
+            auth.verify(&sign(&claims("alice", 60))).await.unwrap(),
+            "alice"
+        );
+        assert!(auth.verify(&sign(&claims("alice", -60))).await.is_err());
+        let other_key = EncodingKey::from_secret(b"other");
+        let forged = encode(&Header::default(), &claims("alice", 60), &other_key).unwrap();
+        assert!(auth.verify(&forged).await.is_err());
+        let mut wrong_issuer = claims("alice", 60);
+        wrong_issuer["iss"] = json!("https://other.example");
+        assert!(auth.verify(&sign(&wrong_issuer)).await.is_err());
+        let mut no_user = claims("alice", 60);
+        no_user.as_object_mut().unwrap().remove("sub");
+        assert!(matches!(
+            auth.verify(&sign(&no_user)).await,
+            Err(JwtError::MissingUser(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_jwt_jwks() {
+        let key_pair = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        // An uncompressed point: 0x04, then x and y
+        let point = key_pair.public_key_raw();
+        let jwks = json!({"keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "one",
+            "alg": "ES256",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]})
+        .to_string();
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let served = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                served.fetch_add(1, Ordering::SeqCst);
+                let mut request = vec![0u8; 4096];
+                let _ = stream.read(&mut request).await;
+                let response = format!("HTTP/1.0 200 OK\r\n\r\n{}", jwks);
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let auth = JwtAuth::new(&JwtConfig {
+            jwks_url: Some(format!("http://{}/jwks.json", addr)),
+            ..config()
+        })
+        .unwrap();
+        let key = EncodingKey::from_ec_pem(key_pair.serialize_pem().as_bytes()).unwrap();
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("one".to_string());
+        let token = encode(&header, &claims("bob", 60), &key).unwrap();
+
+        assert_eq!(auth.verify(&token).await.unwrap(), "bob");
+        assert_eq!(auth.verify(&token).await.unwrap(), "bob");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // An unknown key is looked for again, but not right away
+        header.kid = Some("two".to_string());
+        let token = encode(&header, &claims("bob", 60), &key).unwrap();
+        assert!(matches!(
+            auth.verify(&token).await,
+            Err(JwtError::UnknownKey(_))
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // A token claiming HMAC with the public key as secret is refused
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("one".to_string());
+        let forged = encode(
+            &header,
+            &claims("bob", 60),
+            &EncodingKey::from_secret(point),
+        )
+        .unwrap();
+        assert!(auth.verify(&forged).await.is_err());
+    }
+}
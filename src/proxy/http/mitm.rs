@@ -0,0 +1,254 @@
+//! TLS inspection of CONNECT tunnels, behind the `mitm` feature. The
+//! client's TLS is terminated with a leaf certificate for the host it asked
+//! for, issued on the fly by the `[mitm]` CA, so the requests inside are
+//! filtered like plain HTTP ones before being sent to the origin over a
+//! TLS connection of the proxy's own.
+
+use chrono::{Datelike, Days, Utc};
+use rcgen::{
+    CertificateParams, DnType, ExtendedKeyUsagePurpose, Issuer, KeyPair, KeyUsagePurpose,
+    SerialNumber,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::timeout;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::{LazyConfigAcceptor, TlsConnector};
+
+use crate::common::config::MitmConfig;
+use crate::net::addr::{self, TargetAddr, host_matches};
+use crate::net::conn::BufferedConnection;
+use crate::net::stream::Stream;
+use crate::net::tls::{self, TlsError};
+use crate::proxy::forward::ConnectError;
+
+/// Leaf certificates kept for reuse; the cache starts over once it is full
+const MAX_CACHED_CERTS: usize = 1024;
+/// Days a leaf certificate is valid, well within what clients accept
+const LEAF_VALIDITY_DAYS: u64 = 365;
+/// Time the client has to complete its TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum MitmError {
+    #[error("Failed to read CA '{0}': {1}")]
+    InvalidCa(String, String),
+    #[error("Failed to issue a certificate for '{0}': {1}")]
+    Certificate(String, rcgen::Error),
+    #[error("TLS error: {0}")]
+    Tls(#[from] TlsError),
+    #[error("TLS handshake with the client timed out")]
+    HandshakeTimeout,
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+}
+
+pub struct Mitm {
+    issuer: Issuer<'static, KeyPair>,
+    ca_cert: CertificateDer<'static>,
+    /// Key of every leaf certificate: one per host would cost far more
+    /// than the signature
+    leaf_key: KeyPair,
+    hosts: Vec<String>,
+    /// Server configurations by host name, each with its leaf certificate
+    configs: Mutex<HashMap<String, Arc<ServerConfig>>>,
+    /// Client side towards origins, verifying them as a browser would
+    connector: TlsConnector,
+    connect_timeout: Duration,
+}
+
+impl Mitm {
+    /// Loads the CA and the certificates origins are verified against.
+    pub fn new(config: &MitmConfig, connect_timeout: Duration) -> Result<Self, MitmError> {
+        let invalid = |e: String| MitmError::InvalidCa(config.ca_cert_path.clone(), e);
+        let cert_pem =
+            std::fs::read_to_string(&config.ca_cert_path).map_err(|e| invalid(e.to_string()))?;
+        let key_pem = std::fs::read_to_string(&config.ca_key_path)
+            .map_err(|e| MitmError::InvalidCa(config.ca_key_path.clone(), e.to_string()))?;
+        let ca_key = KeyPair::from_pem(&key_pem)
+            .map_err(|e| MitmError::InvalidCa(config.ca_key_path.clone(), e.to_string()))?;
+        let issuer =
+            Issuer::from_ca_cert_pem(&cert_pem, ca_key).map_err(|e| invalid(e.to_string()))?;
+        let ca_cert = CertificateDer::from_pem_slice(cert_pem.as_bytes())
+            .map_err(|e| invalid(e.to_string()))?;
+        let leaf_key = KeyPair::generate()
+            .map_err(|e| MitmError::Certificate(config.ca_cert_path.clone(), e))?;
+
+        let mut client_config = tls::client_config(config.origin_ca_path.as_deref())?;
+        // Requests are relayed as HTTP/1.1, whatever the client offered
+        client_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Mitm {
+            issuer,
+            ca_cert,
+            leaf_key,
+            hosts: config.hosts.clone(),
+            configs: Mutex::new(HashMap::new()),
+            connector: TlsConnector::from(Arc::new(client_config)),
+            connect_timeout,
+        })
+    }
+
+    /// Whether tunnels to `target` are inspected.
+    pub fn inspects(&self, target: &TargetAddr) -> bool {
+        let host = match target {
+            TargetAddr::Domain(host, _) => host.clone(),
+            TargetAddr::Ip(addr) => addr.ip().to_string(),
+        };
+        self.hosts.is_empty()
+            || self
+                .hosts
+                .iter()
+                .any(|pattern| host_matches(pattern, &host))
+    }
+
+    /// Completes the client's TLS handshake on `conn` with a certificate
+    /// for the server name it sends, or for `host` when it sends none.
+    pub async fn accept(
+        &self,
+        conn: BufferedConnection,
+        host: &str,
+    ) -> Result<BufferedConnection, MitmError> {
+        let buffer_size = conn.buffer_size();
+        let handshake = async {
+            let start = LazyConfigAcceptor::new(Acceptor::default(), conn).await?;
+            let name = start
+                .client_hello()
+                .server_name()
+                .unwrap_or(host)
+                .to_ascii_lowercase();
+            let config = self.server_config(&name)?;
+            Ok::<_, MitmError>(start.into_stream(config).await?)
+        };
+        let stream = timeout(HANDSHAKE_TIMEOUT, handshake)
+            .await
+            .map_err(|_| MitmError::HandshakeTimeout)??;
+        Ok(BufferedConnection::new(stream, buffer_size))
+    }
+
+    /// Opens TLS to the origin `host` over `stream`, bounded by the
+    /// connect timeout.
+    pub async fn connect(
+        &self,
+        host: &str,
+        stream: Box<dyn Stream>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let server_name = addr::server_name(host)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let handshake = self.connector.connect(server_name, stream);
+        let stream = timeout(self.connect_timeout, handshake)
+            .await
+            .map_err(|_| ConnectError::ConnectionTimeout)??;
+        Ok(Box::new(stream))
+    }
+
+    fn server_config(&self, name: &str) -> Result<Arc<ServerConfig>, MitmError> {
+        if let Some(config) = self.configs.lock().unwrap().get(name) {
+            return Ok(config.clone());
+        }
+        let leaf = self.issue(name)?;
+        let key = PrivateKeyDer::try_from(self.leaf_key.serialize_der())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![leaf, self.ca_cert.clone()], key)
+            .map_err(TlsError::from)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let config = Arc::new(config);
+
+        let mut configs = self.configs.lock().unwrap();
+        if configs.len() >= MAX_CACHED_CERTS {
+            configs.clear();
+        }
+        configs.insert(name.to_string(), config.clone());
+        Ok(config)
+    }
+
+    /// A leaf certificate for `name`, a domain or an IP address.
+    fn issue(&self, name: &str) -> Result<CertificateDer<'static>, MitmError> {
+        let failed = |e| MitmError::Certificate(name.to_string(), e);
+        let mut params = CertificateParams::new(vec![name.to_string()]).map_err(failed)?;
+        params.distinguished_name.push(DnType::CommonName, name);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        // Leaves share a key, so their serial numbers must differ
+        let mut serial = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut serial)
+            .map_err(|_| io::Error::other("No random source for a serial number"))?;
+        serial[0] &= 0x7f;
+        params.serial_number = Some(SerialNumber::from_slice(&serial));
+        // Backdated a day against clocks running behind
+        let today = Utc::now().date_naive();
+        let date = |date: chrono::NaiveDate| {
+            rcgen::date_time_ymd(date.year(), date.month() as u8, date.day() as u8)
+        };
+        params.not_before = date(today - Days::new(1));
+        params.not_after = date(today + Days::new(LEAF_VALIDITY_DAYS));
+        let cert = params
+            .signed_by(&self.leaf_key, &self.issuer)
+            .map_err(failed)?;
+        Ok(cert.der().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, IsCa};
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+    #[tokio::test]
+    async fn test_accept() {
+        let dir = std::env::temp_dir().join(format!("rust-proxy-mitm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let ca_key = KeyPair::generate().unwrap();
+        let ca_cert = params.self_signed(&ca_key).unwrap();
+        let (cert_path, key_path) = (dir.join("ca.pem"), dir.join("ca-key.pem"));
+        std::fs::write(&cert_path, ca_cert.pem()).unwrap();
+        std::fs::write(&key_path, ca_key.serialize_pem()).unwrap();
+        let config = MitmConfig {
+            ca_cert_path: cert_path.to_string_lossy().into_owned(),
+            ca_key_path: key_path.to_string_lossy().into_owned(),
+            hosts: vec!["*.example.com".to_string()],
+            origin_ca_path: Some(cert_path.to_string_lossy().into_owned()),
+        };
+        let mitm = Mitm::new(&config, Duration::from_secs(5)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let target = |s: &str| TargetAddr::parse(s).unwrap();
+        assert!(mitm.inspects(&target("www.example.com:443")));
+        assert!(!mitm.inspects(&target("example.org:443")));
+
+        // A client trusting only the CA accepts the issued certificate
+        let mut roots = RootCertStore::empty();
+        roots.add(ca_cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let name = ServerName::try_from("www.example.com").unwrap();
+        let client = tokio::spawn(async move { connector.connect(name, client).await });
+        let accepted = mitm
+            .accept(BufferedConnection::new(server, 4096), "192.0.2.1")
+            .await;
+        assert!(accepted.is_ok());
+        assert!(client.await.unwrap().is_ok());
+        assert!(mitm.configs.lock().unwrap().contains_key("www.example.com"));
+    }
+}
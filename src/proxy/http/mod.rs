@@ -0,0 +1,2595 @@
+use base64::{Engine as _, engine::general_purpose};
+use log::{debug, info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::common::auth::{AuthContext, AuthDecision, AuthProvider};
+use crate::common::config::{
+    AccessLogFormat, BodyRule, ForwardedPolicy, HeaderDirection, HttpConfig, ListenerProtocol,
+    SniffConfig,
+};
+use crate::net::addr::TargetAddr;
+use crate::net::conn::BufferedConnection;
+use crate::net::pool::{ConnectionPool, Pooled};
+use crate::net::sni;
+use crate::proxy::dialer::Dialer;
+use crate::proxy::forward;
+
+pub mod access_log;
+pub mod adblock;
+pub mod body_rules;
+pub mod cache;
+pub mod client;
+pub mod codec;
+pub mod gateway;
+pub mod http2;
+pub mod jwt;
+#[cfg(feature = "mitm")]
+pub mod mitm;
+pub mod rules;
+
+use access_log::AccessRecord;
+use adblock::FilterList;
+use body_rules::Coding;
+use cache::{CachedResponse, HttpCache};
+use codec::{CodecError, Header, RequestHead, ResponseHead};
+use gateway::Gateway;
+use jwt::JwtAuth;
+#[cfg(feature = "mitm")]
+use mitm::Mitm;
+
+#[derive(Error, Debug)]
+pub enum HttpProxyError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Invalid HTTP request: {0}")]
+    InvalidRequest(String),
+    #[error("Proxy authentication required")]
+    ProxyAuthRequired,
+    #[error("Authentication failed")]
+    AuthenticationFailed(#[from] crate::common::auth::AuthError),
+    #[error("Bearer token refused: {0}")]
+    TokenRefused(#[from] jwt::JwtError),
+    #[error("Unsupported HTTP method: {0}")]
+    UnsupportedMethod(String),
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(#[from] url::ParseError),
+    #[error("Connection error: {0}")]
+    ConnectError(#[from] crate::proxy::forward::ConnectError),
+    #[error("Invalid HTTP request: {0}")]
+    CodecError(#[from] CodecError),
+    #[error("CONNECT to port {0} is not allowed")]
+    ConnectPortNotAllowed(u16),
+    #[error("Proxy loop detected")]
+    LoopDetected,
+    #[error("Timed out waiting for the upstream response")]
+    ResponseTimeout,
+    #[error("Ambiguous request framing: {0}")]
+    AmbiguousFraming(&'static str),
+    #[error("No gateway route for host {0}")]
+    NoRoute(String),
+    #[error("Request head not received within the handshake timeout")]
+    HandshakeTimeout,
+    #[cfg(feature = "mitm")]
+    #[error("TLS inspection failed: {0}")]
+    MitmError(#[from] mitm::MitmError),
+}
+
+impl HttpProxyError {
+    /// Status of the error response the client was sent before this error
+    /// was returned, if any.
+    fn response_status(&self) -> Option<u16> {
+        match self {
+            HttpProxyError::ProxyAuthRequired
+            | HttpProxyError::AuthenticationFailed(_)
+            | HttpProxyError::TokenRefused(_) => Some(407),
+            HttpProxyError::ConnectPortNotAllowed(_)
+            | HttpProxyError::ConnectError(
+                forward::ConnectError::Blocked(_) | forward::ConnectError::Denied(_),
+            ) => Some(403),
+            HttpProxyError::NoRoute(_) => Some(404),
+            HttpProxyError::UnsupportedMethod(_) => Some(405),
+            HttpProxyError::LoopDetected => Some(508),
+            HttpProxyError::ResponseTimeout
+            | HttpProxyError::ConnectError(forward::ConnectError::ConnectionTimeout) => Some(504),
+            _ => None,
+        }
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    version: String,
+    headers: Vec<Header>,
+    body: Body,
+    /// Received inside an inspected CONNECT tunnel, so the origin is
+    /// reached over TLS
+    tls: bool,
+}
+
+/// How a message body is delimited. The body itself stays on the sending
+/// connection and is streamed on after the head has been sent.
+#[derive(Debug, PartialEq, Eq)]
+enum Body {
+    None,
+    Length(u64),
+    Chunked,
+    /// Responses only: the body ends when the origin closes the connection
+    UntilClose,
+}
+
+impl HttpRequest {
+    /// A GET without a body, credentials, ranges or client validators, which
+    /// the shared cache may answer and store. Stored bodies keep the origin's
+    /// framing, so only HTTP/1.1 clients are served from the cache.
+    fn is_cacheable(&self) -> bool {
+        const UNCACHEABLE: &[&str] = &[
+            "authorization",
+            "range",
+            "if-match",
+            "if-none-match",
+            "if-modified-since",
+            "if-unmodified-since",
+            "if-range",
+        ];
+        self.method == "GET"
+            && self.version == "HTTP/1.1"
+            && matches!(self.body, Body::None)
+            && !self.is_upgrade()
+            && !self
+                .headers
+                .iter()
+                .any(|header| UNCACHEABLE.contains(&header.name_lower.as_str()))
+            && !cache::cache_control(&self.headers).contains_key("no-store")
+    }
+
+    /// The client asked for an end-to-end reload, so stored entries are
+    /// bypassed (the fresh response may still be stored).
+    fn wants_reload(&self) -> bool {
+        cache::cache_control(&self.headers).contains_key("no-cache")
+            || self
+                .get_header("pragma")
+                .is_some_and(|pragma| pragma.eq_ignore_ascii_case("no-cache"))
+    }
+
+    /// Whether a `Via` element was added by an intermediary named `pseudonym`.
+    fn via_contains(&self, pseudonym: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|header| header.name_lower == "via")
+            .flat_map(|header| header.value.split(','))
+            .filter_map(|element| element.split_whitespace().nth(1))
+            .any(|received_by| received_by.eq_ignore_ascii_case(pseudonym))
+    }
+
+    /// The absolute URL of the request. Origin-form targets (`/path`), sent
+    /// by clients that reach the proxy as a gateway, through transparent
+    /// redirection or inside an inspected tunnel, are resolved against the
+    /// `Host` header.
+    fn target_url(&self) -> Result<url::Url, HttpProxyError> {
+        if !self.path.starts_with('/') {
+            return Ok(url::Url::parse(&self.path)?);
+        }
+        let host = self.get_header("host").ok_or_else(|| {
+            HttpProxyError::InvalidRequest("No Host header for origin-form target".to_string())
+        })?;
+        let scheme = if self.tls { "https" } else { "http" };
+        Ok(url::Url::parse(&format!(
+            "{}://{}{}",
+            scheme, host, self.path
+        ))?)
+    }
+
+    fn get_header(&self, name: &str) -> Option<&str> {
+        let lower = name.to_lowercase();
+        self.headers
+            .iter()
+            .find(|h| h.name_lower == lower)
+            .map(|h| h.value.as_str())
+    }
+
+    fn is_chunked(&self) -> bool {
+        matches!(self.body, Body::Chunked)
+    }
+
+    /// Protocol upgrade such as WebSocket: `Connection: upgrade` plus an
+    /// `Upgrade` header naming the protocol.
+    fn is_upgrade(&self) -> bool {
+        self.get_header("upgrade").is_some()
+            && self.get_header("connection").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+            })
+    }
+}
+
+/// Framing of a request body. Anything a downstream server might delimit
+/// differently is refused rather than normalized (RFC 9112 §6.3): both
+/// `Transfer-Encoding` and `Content-Length`, or more than one length.
+fn request_body(headers: &[Header]) -> Result<Body, HttpProxyError> {
+    let values = |name: &str| -> Vec<&str> {
+        headers
+            .iter()
+            .filter(|h| h.name_lower == name)
+            .flat_map(|h| h.value.split(','))
+            .map(str::trim)
+            .collect()
+    };
+    let transfer_encoding = values("transfer-encoding");
+    let content_length = values("content-length");
+
+    if !transfer_encoding.is_empty() {
+        if !content_length.is_empty() {
+            return Err(HttpProxyError::AmbiguousFraming(
+                "both Transfer-Encoding and Content-Length",
+            ));
+        }
+        let transfer_encoding = transfer_encoding.join(", ");
+        if !is_chunked(&transfer_encoding) {
+            return Err(HttpProxyError::InvalidRequest(format!(
+                "Unsupported Transfer-Encoding: {}",
+                transfer_encoding
+            )));
+        }
+        return Ok(Body::Chunked);
+    }
+
+    match content_length.as_slice() {
+        [] => Ok(Body::None),
+        // A bare digit string: `parse` would also take a leading '+'
+        [len] if !len.is_empty() && len.bytes().all(|b| b.is_ascii_digit()) => len
+            .parse::<u64>()
+            .map(Body::Length)
+            .map_err(|_| HttpProxyError::InvalidRequest("Invalid Content-Length".to_string())),
+        [_] => Err(HttpProxyError::AmbiguousFraming("invalid Content-Length")),
+        _ => Err(HttpProxyError::AmbiguousFraming(
+            "multiple Content-Length values",
+        )),
+    }
+}
+
+/// `chunked` must be the final transfer coding when present.
+fn is_chunked(transfer_encoding: &str) -> bool {
+    transfer_encoding
+        .rsplit(',')
+        .next()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Copies a body from `from` to `to` as it arrives, leaving any chunked
+/// framing intact, so memory use does not grow with the body size.
+async fn stream_body<W: AsyncWrite + Unpin>(
+    from: &mut BufferedConnection,
+    to: &mut W,
+    body: &Body,
+) -> Result<(), HttpProxyError> {
+    match body {
+        Body::None => Ok(()),
+        Body::Length(len) => copy_exact(from, to, *len).await,
+        Body::Chunked => stream_chunked_body(from, to).await,
+        Body::UntilClose => {
+            tokio::io::copy_buf(from, to).await?;
+            Ok(())
+        }
+    }
+}
+
+async fn stream_chunked_body<W: AsyncWrite + Unpin>(
+    from: &mut BufferedConnection,
+    to: &mut W,
+) -> Result<(), HttpProxyError> {
+    loop {
+        let size_line = from.read_line().await?;
+        let size = chunk_size(&size_line)?;
+        to.write_all(format!("{}\r\n", size_line).as_bytes())
+            .await?;
+        if size == 0 {
+            break;
+        }
+
+        copy_exact(from, to, size).await?;
+        if from.read_exact_buffered(2).await? != b"\r\n"[..] {
+            return Err(HttpProxyError::InvalidRequest(
+                "Chunk not terminated by CRLF".to_string(),
+            ));
+        }
+        to.write_all(b"\r\n").await?;
+    }
+
+    // Trailer fields, ended by an empty line
+    loop {
+        let line = from.read_line().await?;
+        to.write_all(format!("{}\r\n", line).as_bytes()).await?;
+        if line.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+/// Relays only the data of a chunked body, dropping its framing and any
+/// trailer fields.
+async fn stream_dechunked_body<W: AsyncWrite + Unpin>(
+    from: &mut BufferedConnection,
+    to: &mut W,
+) -> Result<(), HttpProxyError> {
+    loop {
+        let size = chunk_size(&from.read_line().await?)?;
+        if size == 0 {
+            break;
+        }
+        copy_exact(from, to, size).await?;
+        if from.read_exact_buffered(2).await? != b"\r\n"[..] {
+            return Err(HttpProxyError::InvalidRequest(
+                "Chunk not terminated by CRLF".to_string(),
+            ));
+        }
+    }
+    while !from.read_line().await?.is_empty() {}
+    Ok(())
+}
+
+fn chunk_size(size_line: &str) -> Result<u64, HttpProxyError> {
+    // Chunk extensions follow a ';'
+    let size = size_line.split(';').next().unwrap_or_default().trim();
+    u64::from_str_radix(size, 16)
+        .map_err(|_| HttpProxyError::InvalidRequest("Invalid chunk size".to_string()))
+}
+
+async fn copy_exact<W: AsyncWrite + Unpin>(
+    from: &mut BufferedConnection,
+    to: &mut W,
+    len: u64,
+) -> Result<(), HttpProxyError> {
+    let copied = tokio::io::copy_buf(&mut (&mut *from).take(len), to).await?;
+    if copied < len {
+        return Err(HttpProxyError::IoError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "Connection closed mid-body",
+        )));
+    }
+    Ok(())
+}
+
+/// Writer that keeps a copy of what passes through, until it would exceed
+/// `limit` bytes.
+struct Capture<'a> {
+    inner: &'a mut BufferedConnection,
+    captured: Option<Vec<u8>>,
+    limit: usize,
+}
+
+impl<'a> Capture<'a> {
+    fn new(inner: &'a mut BufferedConnection, limit: usize) -> Self {
+        Capture {
+            inner,
+            captured: Some(Vec::new()),
+            limit,
+        }
+    }
+}
+
+impl AsyncWrite for Capture<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut *this.inner).poll_write(cx, buf))?;
+        if this
+            .captured
+            .as_ref()
+            .is_some_and(|captured| captured.len() + written > this.limit)
+        {
+            this.captured = None;
+        }
+        if let Some(captured) = &mut this.captured {
+            captured.extend_from_slice(&buf[..written]);
+        }
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Writer that holds a response body back for `[[http.body_rules]]`, up
+/// to `limit` bytes. Past that it writes `head`, what it held and the rest
+/// straight to the client.
+struct Holdback<'a> {
+    inner: &'a mut BufferedConnection,
+    held: Option<Vec<u8>>,
+    limit: usize,
+    /// Once released: `head` and the held bytes, of which `written` are out
+    pending: Vec<u8>,
+    written: usize,
+}
+
+impl<'a> Holdback<'a> {
+    fn new(inner: &'a mut BufferedConnection, head: Vec<u8>, limit: usize) -> Self {
+        Holdback {
+            inner,
+            held: Some(Vec::new()),
+            limit,
+            pending: head,
+            written: 0,
+        }
+    }
+}
+
+impl AsyncWrite for Holdback<'_> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if let Some(held) = &mut this.held {
+            if held.len() + buf.len() <= this.limit {
+                held.extend_from_slice(buf);
+                return Poll::Ready(Ok(buf.len()));
+            }
+            this.pending.append(held);
+            this.held = None;
+        }
+        while this.written < this.pending.len() {
+            let written =
+                ready!(Pin::new(&mut *this.inner).poll_write(cx, &this.pending[this.written..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            this.written += written;
+        }
+        Pin::new(&mut *this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+const CONNECT_OK: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+pub struct HttpProxy {
+    auth_manager: Arc<dyn AuthProvider>,
+    buffer_size: usize,
+    dialer: Arc<dyn Dialer>,
+    config: Arc<HttpConfig>,
+    cache: Option<Arc<HttpCache>>,
+    pool: Option<Arc<ConnectionPool>>,
+    /// Format of access log entries; `None` when the access log is off
+    access_log: Option<AccessLogFormat>,
+    /// Set on the `[gateway]` listener, which acts as a reverse proxy
+    gateway: Option<Arc<Gateway>>,
+    adblock: Option<Arc<FilterList>>,
+    sniff: Option<Arc<SniffConfig>>,
+    jwt: Option<Arc<JwtAuth>>,
+    handshake_timeout: Option<Duration>,
+    /// Address the listener is bound to; the connection's local address
+    /// stands in when unset
+    listen_address: Option<SocketAddr>,
+    #[cfg(feature = "mitm")]
+    mitm: Option<Arc<Mitm>>,
+}
+
+impl HttpProxy {
+    pub fn new(
+        auth_manager: Arc<dyn AuthProvider>,
+        buffer_size: usize,
+        dialer: Arc<dyn Dialer>,
+        config: Arc<HttpConfig>,
+        cache: Option<Arc<HttpCache>>,
+        pool: Option<Arc<ConnectionPool>>,
+        access_log: Option<AccessLogFormat>,
+    ) -> Self {
+        HttpProxy {
+            auth_manager,
+            buffer_size,
+            dialer,
+            config,
+            cache,
+            pool,
+            access_log,
+            gateway: None,
+            adblock: None,
+            sniff: None,
+            jwt: None,
+            handshake_timeout: None,
+            listen_address: None,
+            #[cfg(feature = "mitm")]
+            mitm: None,
+        }
+    }
+
+    /// Answers `408` to clients whose request head has not arrived within
+    /// `limit`.
+    pub fn with_handshake_timeout(mut self, limit: Duration) -> Self {
+        self.handshake_timeout = Some(limit);
+        self
+    }
+
+    /// Refuses targets that resolve to the listener bound at `addr`, which
+    /// on a wildcard address is reached through any address of this host.
+    pub fn with_listen_address(mut self, addr: SocketAddr) -> Self {
+        self.listen_address = Some(addr);
+        self
+    }
+
+    /// Terminates TLS in CONNECT tunnels `mitm` inspects, handling the
+    /// requests inside like plain HTTP ones.
+    #[cfg(feature = "mitm")]
+    pub fn with_mitm(mut self, mitm: Arc<Mitm>) -> Self {
+        self.mitm = Some(mitm);
+        self
+    }
+
+    /// Answers CONNECT to the sniffed ports before dialing, to read the
+    /// server name from the client's ClientHello first.
+    pub fn with_sniff(mut self, sniff: Arc<SniffConfig>) -> Self {
+        self.sniff = Some(sniff);
+        self
+    }
+
+    /// Answers requests the `[adblock]` filters match instead of forwarding
+    /// them.
+    pub fn with_adblock(mut self, adblock: Arc<FilterList>) -> Self {
+        self.adblock = Some(adblock);
+        self
+    }
+
+    /// Accepts `Proxy-Authorization: Bearer` tokens `jwt` verifies, and
+    /// requires clients to authenticate even without users.
+    pub fn with_jwt(mut self, jwt: Arc<JwtAuth>) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    /// Serves as a reverse proxy instead: requests go to the backend routed
+    /// by their host, CONNECT is refused and clients do not authenticate.
+    pub fn with_gateway(mut self, gateway: Arc<Gateway>) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    /// Whether the connection to `target` leads back into this listener;
+    /// `local` is the client connection's local address.
+    fn is_own_listener(&self, target: SocketAddr, local: SocketAddr) -> bool {
+        is_own_listener(target, self.listen_address.unwrap_or(local))
+    }
+
+    fn is_connect_port_allowed(&self, port: u16) -> bool {
+        let allowed = &self.config.allowed_connect_ports;
+        allowed.is_empty() || allowed.iter().any(|range| range.contains(port))
+    }
+
+    /// Header fields of a 407 response, from `http.auth_realm` and
+    /// `http.auth_headers`, plus the content type of `http.auth_body`.
+    fn proxy_auth_headers(&self) -> Vec<(String, String)> {
+        let realm = self
+            .config
+            .auth_realm
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        let mut headers = vec![(
+            "Proxy-Authenticate".to_string(),
+            format!("Basic realm=\"{}\"", realm),
+        )];
+        if self.jwt.is_some() {
+            headers.push((
+                "Proxy-Authenticate".to_string(),
+                format!("Bearer realm=\"{}\"", realm),
+            ));
+        }
+        for (name, value) in &self.config.auth_headers {
+            headers.push((name.clone(), value.clone()));
+        }
+        if self
+            .config
+            .auth_body
+            .as_ref()
+            .is_some_and(|body| !body.is_empty())
+        {
+            headers.push((
+                "Content-Type".to_string(),
+                "text/html; charset=utf-8".to_string(),
+            ));
+        }
+        headers
+    }
+
+    /// 407 response built from `http.auth_realm`, `http.auth_headers` and
+    /// `http.auth_body`.
+    fn proxy_auth_required(&self) -> Vec<u8> {
+        let body = self.config.auth_body.as_deref().unwrap_or_default();
+        let mut response = "HTTP/1.1 407 Proxy Authentication Required\r\n".to_string();
+        for (name, value) in self.proxy_auth_headers() {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        response.into_bytes()
+    }
+
+    pub async fn handle_connection(
+        &self,
+        conn: &mut BufferedConnection,
+    ) -> Result<(), HttpProxyError> {
+        self.serve(conn, false).await
+    }
+
+    /// Reads and serves one request; `tls` when `conn` is the client side
+    /// of an inspected tunnel.
+    async fn serve(&self, conn: &mut BufferedConnection, tls: bool) -> Result<(), HttpProxyError> {
+        let parsed = match self.handshake_timeout {
+            Some(limit) => tokio::time::timeout(limit, self.parse_request(conn))
+                .await
+                .unwrap_or(Err(HttpProxyError::HandshakeTimeout)),
+            None => self.parse_request(conn).await,
+        };
+        let request = match parsed {
+            Ok(request) => HttpRequest { tls, ..request },
+            Err(HttpProxyError::AmbiguousFraming(reason)) => {
+                return Self::reject_ambiguous(conn, reason).await;
+            }
+            Err(HttpProxyError::CodecError(e)) => {
+                let status = match e {
+                    CodecError::RequestLineTooLong(_) => "414 URI Too Long",
+                    CodecError::TooManyHeaders(_) | CodecError::HeadTooLarge(_) => {
+                        "431 Request Header Fields Too Large"
+                    }
+                    _ => "400 Bad Request",
+                };
+                conn.write(&error_response(status, &format!("{}\n", e)))
+                    .await?;
+                return Err(e.into());
+            }
+            Err(HttpProxyError::HandshakeTimeout) => {
+                conn.write(&error_response(
+                    "408 Request Timeout",
+                    "Request head not received in time\n",
+                ))
+                .await?;
+                return Err(HttpProxyError::HandshakeTimeout);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let started = Instant::now();
+        let written = conn.bytes_written();
+        let mut user = None;
+        let result = self.handle_request(conn, &request, &mut user).await;
+
+        if let Some(format) = self.access_log {
+            let status = match &result {
+                Ok(status) => Some(*status),
+                Err(e) => e.response_status(),
+            };
+            AccessRecord {
+                client: conn.peer_addr()?.ip(),
+                user: user.as_deref(),
+                method: &request.method,
+                target: &request.path,
+                version: &request.version,
+                status,
+                bytes: conn.bytes_written() - written,
+                referer: request.get_header("referer"),
+                user_agent: request.get_header("user-agent"),
+                duration: started.elapsed(),
+            }
+            .log(format);
+        }
+        result.map(|_| ())
+    }
+
+    /// Serves a parsed request, returning the status of the final response
+    /// sent. `user` is set once the client has authenticated.
+    async fn handle_request(
+        &self,
+        conn: &mut BufferedConnection,
+        request: &HttpRequest,
+        user: &mut Option<String>,
+    ) -> Result<u16, HttpProxyError> {
+        if request.via_contains(&self.config.via_pseudonym) {
+            return Self::reject_loop(conn).await;
+        }
+
+        // Inside an inspected tunnel, the client authenticated for the tunnel
+        let auth_required = self.auth_manager.is_required() || self.jwt.is_some();
+        if auth_required && self.gateway.is_none() && !request.tls {
+            let name = self.authenticate(conn, request).await?;
+            for limiter in self.auth_manager.rate_limits(&name) {
+                conn.throttle(limiter);
+            }
+            *user = Some(name);
+        }
+
+        let status = match request.method.as_str() {
+            "CONNECT" if self.gateway.is_some() => {
+                conn.write(&error_response(
+                    "405 Method Not Allowed",
+                    "CONNECT is not supported by this gateway\n",
+                ))
+                .await?;
+                return Err(HttpProxyError::UnsupportedMethod(request.method.clone()));
+            }
+            "CONNECT" if request.tls => {
+                conn.write(&error_response(
+                    "405 Method Not Allowed",
+                    "CONNECT is not supported inside an inspected tunnel\n",
+                ))
+                .await?;
+                return Err(HttpProxyError::UnsupportedMethod(request.method.clone()));
+            }
+            "CONNECT" => self.handle_connect(conn, request).await?,
+            "GET" | "POST" | "PUT" | "DELETE" | "HEAD" | "OPTIONS" | "PATCH" => {
+                // Both timeouts strike before any final response is relayed
+                match self.handle_http_request(conn, request).await {
+                    Err(
+                        e @ (HttpProxyError::ResponseTimeout
+                        | HttpProxyError::ConnectError(forward::ConnectError::ConnectionTimeout)),
+                    ) => {
+                        conn.write(&error_response("504 Gateway Timeout", &format!("{}\n", e)))
+                            .await?;
+                        return Err(e);
+                    }
+                    Err(
+                        e @ HttpProxyError::ConnectError(
+                            forward::ConnectError::Blocked(_) | forward::ConnectError::Denied(_),
+                        ),
+                    ) => {
+                        conn.write(&error_response("403 Forbidden", &format!("{}\n", e)))
+                            .await?;
+                        return Err(e);
+                    }
+                    result => result?,
+                }
+            }
+            _ => {
+                conn.write(&error_response(
+                    "405 Method Not Allowed",
+                    &format!("{} is not supported by this proxy\n", request.method),
+                ))
+                .await?;
+                return Err(HttpProxyError::UnsupportedMethod(request.method.clone()));
+            }
+        };
+
+        Ok(status)
+    }
+
+    async fn reject_loop<T>(conn: &mut BufferedConnection) -> Result<T, HttpProxyError> {
+        conn.write(&error_response(
+            "508 Loop Detected",
+            "Request has already passed through this proxy\n",
+        ))
+        .await?;
+        Err(HttpProxyError::LoopDetected)
+    }
+
+    /// Answers a request whose body boundaries other HTTP implementations
+    /// could read differently, the basis of request smuggling, and closes
+    /// the connection without forwarding anything.
+    async fn reject_ambiguous(
+        conn: &mut BufferedConnection,
+        reason: &'static str,
+    ) -> Result<(), HttpProxyError> {
+        warn!(
+            "Rejected possible request smuggling from {}: {}",
+            conn.peer_addr()?,
+            reason
+        );
+        conn.write(&error_response("400 Bad Request", &format!("{}\n", reason)))
+            .await?;
+        Err(HttpProxyError::AmbiguousFraming(reason))
+    }
+
+    /// `Via` value with this proxy's element appended to what `headers`
+    /// already carry, for a message of the given HTTP version.
+    fn via_value(&self, headers: &[Header], version: &str) -> String {
+        let protocol = version.strip_prefix("HTTP/").unwrap_or(version);
+        let element = format!("{} {}", protocol, self.config.via_pseudonym);
+        appended_value(headers, "via", element)
+    }
+
+    /// Response head for the client: the origin's fields with any `Via`
+    /// replaced by ours, plus `Age` when served from the cache. The client
+    /// connection is closed after every final response other than a `101`,
+    /// so its `Connection` fields are replaced as well.
+    fn response_head(
+        &self,
+        version: &str,
+        status: u16,
+        reason: &str,
+        headers: &[Header],
+        age: Option<u64>,
+    ) -> Vec<u8> {
+        let closes = status >= 200;
+        let mut response = format!("{} {} {}\r\n", version, status, reason);
+        for header in headers {
+            let skip = match header.name_lower.as_str() {
+                "via" => true,
+                "age" => age.is_some(),
+                "connection" | "keep-alive" => closes,
+                _ => false,
+            };
+            if !skip {
+                response.push_str(&format!("{}: {}\r\n", header.name, header.value));
+            }
+        }
+        if closes {
+            response.push_str("Connection: close\r\n");
+        }
+        if let Some(age) = age {
+            response.push_str(&format!("Age: {}\r\n", age));
+        }
+        response.push_str(&format!(
+            "Via: {}\r\n\r\n",
+            self.via_value(headers, version)
+        ));
+        response.into_bytes()
+    }
+
+    /// Sends the request head and body, then reads the response head.
+    /// Interim `1xx` responses other than `101` are passed to the client and
+    /// skipped.
+    async fn exchange(
+        &self,
+        conn: &mut BufferedConnection,
+        target_conn: &mut BufferedConnection,
+        request: &HttpRequest,
+        request_data: &[u8],
+    ) -> Result<ResponseHead, HttpProxyError> {
+        target_conn.write(request_data).await?;
+        stream_body(conn, target_conn, &request.body).await?;
+        let response_timeout = Duration::from_secs(self.config.response_header_timeout);
+        loop {
+            let head = tokio::time::timeout(response_timeout, read_response_head(target_conn))
+                .await
+                .map_err(|_| HttpProxyError::ResponseTimeout)??;
+            if !(100..200).contains(&head.status) || head.status == 101 {
+                return Ok(head);
+            }
+            conn.write(&self.response_head(
+                &head.version,
+                head.status,
+                &head.reason,
+                &head.headers,
+                None,
+            ))
+            .await?;
+        }
+    }
+
+    /// Returns an upstream connection to the pool after a complete exchange.
+    fn release(&self, key: &str, upstream: Pooled, reusable: bool) {
+        if reusable && let Some(pool) = &self.pool {
+            pool.checkin(key, upstream);
+        }
+    }
+
+    /// Answers from the cache; the connection is then closed as it would
+    /// be after a relayed response.
+    async fn serve_cached(
+        &self,
+        conn: &mut BufferedConnection,
+        entry: &CachedResponse,
+    ) -> Result<(), HttpProxyError> {
+        let age = entry.age(SystemTime::now());
+        let mut response = self.response_head(
+            &entry.version,
+            entry.status,
+            &entry.reason,
+            &entry.headers,
+            Some(age),
+        );
+        response.extend_from_slice(&entry.body);
+        conn.write(&response).await?;
+        conn.shutdown().await?;
+        Ok(())
+    }
+
+    async fn parse_request(
+        &self,
+        conn: &mut BufferedConnection,
+    ) -> Result<HttpRequest, HttpProxyError> {
+        let limits = codec::Limits {
+            max_request_line: self.config.max_request_line,
+            max_header_bytes: self.config.max_header_bytes,
+            max_headers: self.config.max_headers,
+        };
+        let RequestHead {
+            method,
+            path,
+            version,
+            headers,
+        } = loop {
+            let decoded = match codec::decode_request(conn.buffered(), &limits) {
+                Err(CodecError::ObsoleteLineFolding) => {
+                    return Err(HttpProxyError::AmbiguousFraming("obsolete line folding"));
+                }
+                decoded => decoded?,
+            };
+            if let Some((head, consumed)) = decoded {
+                conn.drain_buffer(consumed);
+                break head;
+            }
+            if conn.read().await? == 0 {
+                return Err(HttpProxyError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Connection closed before end of request head",
+                )));
+            }
+        };
+
+        let body = request_body(&headers)?;
+
+        Ok(HttpRequest {
+            method,
+            path,
+            version,
+            headers,
+            body,
+            tls: false,
+        })
+    }
+
+    /// Authenticates the client, answering 407 when it fails.
+    async fn authenticate(
+        &self,
+        conn: &mut BufferedConnection,
+        request: &HttpRequest,
+    ) -> Result<String, HttpProxyError> {
+        let result = self
+            .check_credentials(
+                conn.peer_addr()?,
+                conn.client_identity(),
+                request.get_header("proxy-authorization"),
+            )
+            .await;
+        if result
+            .as_ref()
+            .is_err_and(|e| e.response_status() == Some(407))
+        {
+            conn.write(&self.proxy_auth_required()).await?;
+        }
+        result
+    }
+
+    /// The user a client certificate or a `Proxy-Authorization` value
+    /// stands for. Errors with a 407 status call for that response.
+    async fn check_credentials(
+        &self,
+        client: SocketAddr,
+        identity: Option<String>,
+        authorization: Option<&str>,
+    ) -> Result<String, HttpProxyError> {
+        if let Some(user) = self.auth_manager.certificate_user(identity) {
+            return Ok(user);
+        }
+        if let Some(jwt) = &self.jwt
+            && let Some(token) = authorization.and_then(|value| value.strip_prefix("Bearer "))
+        {
+            let user = jwt.verify(token.trim()).await?;
+            // Token users are held to quotas like the others
+            self.auth_manager.admits(&user)?;
+            return Ok(user);
+        }
+        // Credentials that do not decode are wrong ones
+        if let Some(encoded) = authorization.and_then(|value| value.strip_prefix("Basic "))
+            && let Ok(decoded) = general_purpose::STANDARD.decode(encoded.trim())
+            && let Ok(credentials) = String::from_utf8(decoded)
+            && let Some((username, password)) = credentials.split_once(':')
+        {
+            let context = AuthContext {
+                client,
+                protocol: ListenerProtocol::Http,
+            };
+            match self
+                .auth_manager
+                .authenticate(username, password, &context)
+                .await
+            {
+                AuthDecision::Allow => return Ok(username.to_string()),
+                AuthDecision::Deny => {}
+                AuthDecision::Error(e) => return Err(HttpProxyError::AuthenticationFailed(e)),
+            }
+        }
+        Err(HttpProxyError::ProxyAuthRequired)
+    }
+
+    async fn handle_connect(
+        &self,
+        conn: &mut BufferedConnection,
+        request: &HttpRequest,
+    ) -> Result<u16, HttpProxyError> {
+        let target_addr = connect_target(&request.path).ok_or_else(|| {
+            HttpProxyError::InvalidRequest(format!("Invalid CONNECT target: {}", request.path))
+        })?;
+        let port = target_addr.port();
+        if !self.is_connect_port_allowed(port) {
+            let reason = format!("CONNECT to port {} is not allowed\n", port);
+            conn.write(&error_response("403 Forbidden", &reason))
+                .await?;
+            return Err(HttpProxyError::ConnectPortNotAllowed(port));
+        }
+        if let TargetAddr::Domain(host, _) = &target_addr
+            && self.is_host_blocked(host)
+        {
+            info!("CONNECT {} blocked by a filter list", target_addr);
+            conn.write(&error_response(
+                "403 Forbidden",
+                "Blocked by a filter list\n",
+            ))
+            .await?;
+            return Ok(403);
+        }
+        #[cfg(feature = "mitm")]
+        if let Some(mitm) = &self.mitm
+            && mitm.inspects(&target_addr)
+        {
+            return self.handle_inspected_connect(conn, target_addr, mitm).await;
+        }
+        if let Some(sniff) = &self.sniff
+            && sniff.ports.iter().any(|range| range.contains(port))
+        {
+            let wait = Duration::from_millis(sniff.timeout);
+            return self.handle_sniffed_connect(conn, target_addr, wait).await;
+        }
+
+        let target_stream = match self
+            .dialer
+            .dial_from(&target_addr, conn.peer_addr()?, conn.local_addr()?)
+            .await
+        {
+            Err(e @ (forward::ConnectError::Blocked(_) | forward::ConnectError::Denied(_))) => {
+                conn.write(&error_response("403 Forbidden", &format!("{}\n", e)))
+                    .await?;
+                return Err(e.into());
+            }
+            result => result?,
+        };
+        if self.is_own_listener(target_stream.peer_addr()?, conn.local_addr()?) {
+            return Self::reject_loop(conn).await;
+        }
+
+        conn.write(CONNECT_OK).await?;
+        info!("CONNECT tunnel to {}", target_addr);
+
+        let mut target_conn = BufferedConnection::new(target_stream, self.buffer_size);
+        forward::forward_bidirectional(conn, &mut target_conn, None).await?;
+
+        Ok(200)
+    }
+
+    /// CONNECT with the tunnel opened before dialing, so the server name the
+    /// client's ClientHello asks for can decide the connection. There is no
+    /// status left to report failures with, so the tunnel just closes.
+    async fn handle_sniffed_connect(
+        &self,
+        conn: &mut BufferedConnection,
+        target_addr: TargetAddr,
+        wait: Duration,
+    ) -> Result<u16, HttpProxyError> {
+        conn.write(CONNECT_OK).await?;
+        let client = (conn.peer_addr()?, conn.local_addr()?);
+        let server_name = sni::sniff(conn, wait).await;
+        let target_stream = match (&target_addr, server_name) {
+            (TargetAddr::Domain(host, port), Some(name)) if *host != name => {
+                // A name other than the one asked for must pass the same
+                // checks, or any allowed host could front for a blocked one
+                info!("CONNECT {} carries server name {}", target_addr, name);
+                if self.is_host_blocked(&name) {
+                    info!("CONNECT {} blocked by a filter list", name);
+                    return Ok(200);
+                }
+                let named = TargetAddr::Domain(name, *port);
+                self.dialer.route(&named)?;
+                self.dialer
+                    .dial_from(&target_addr, client.0, client.1)
+                    .await?
+            }
+            // Rules and the blocklist see the name; a direct connection
+            // still goes to the address the client chose
+            (TargetAddr::Ip(addr), Some(name)) => {
+                info!("CONNECT {} carries server name {}", target_addr, name);
+                if self.is_host_blocked(&name) {
+                    info!("CONNECT {} blocked by a filter list", name);
+                    return Ok(200);
+                }
+                let named = TargetAddr::Domain(name, addr.port());
+                self.dialer
+                    .dial_resolved(&named, &[*addr], Some(client))
+                    .await?
+            }
+            _ => {
+                self.dialer
+                    .dial_from(&target_addr, client.0, client.1)
+                    .await?
+            }
+        };
+        if self.is_own_listener(target_stream.peer_addr()?, client.1) {
+            return Err(HttpProxyError::LoopDetected);
+        }
+        info!("CONNECT tunnel to {}", target_addr);
+
+        let mut target_conn = BufferedConnection::new(target_stream, self.buffer_size);
+        forward::forward_bidirectional(conn, &mut target_conn, None).await?;
+
+        Ok(200)
+    }
+
+    /// CONNECT with TLS terminated at the proxy: the client is answered
+    /// with a certificate `mitm` issues, and its request inside the tunnel
+    /// is served like a plain HTTP one, over TLS to the origin.
+    #[cfg(feature = "mitm")]
+    async fn handle_inspected_connect(
+        &self,
+        conn: &mut BufferedConnection,
+        target_addr: TargetAddr,
+        mitm: &Mitm,
+    ) -> Result<u16, HttpProxyError> {
+        conn.write(CONNECT_OK).await?;
+        let host = match &target_addr {
+            TargetAddr::Domain(host, _) => host.clone(),
+            TargetAddr::Ip(addr) => addr.ip().to_string(),
+        };
+        let mut inner = mitm.accept(conn.detach(), &host).await?;
+        info!("CONNECT tunnel to {} (inspected)", target_addr);
+        Box::pin(self.serve(&mut inner, true)).await?;
+        Ok(200)
+    }
+
+    fn is_host_blocked(&self, host: &str) -> bool {
+        self.adblock
+            .as_ref()
+            .is_some_and(|adblock| adblock.is_host_blocked(host))
+    }
+
+    /// Serializes the request head sent to the origin: hop-by-hop proxy
+    /// headers are skipped and the rest keep their original order and case,
+    /// except for the forwarding headers governed by the config. A stale
+    /// cache entry adds its validators to make the request conditional.
+    ///
+    /// The origin is always spoken to in HTTP/1.1, so `Host` is taken from
+    /// `authority`, the request target's host and port, as RFC 9112 §3.2.2
+    /// requires of proxies; HTTP/1.0 clients often omit it.
+    ///
+    /// `headers` are the client's header fields once `http.header_rules`
+    /// have been applied.
+    fn upstream_request_head(
+        &self,
+        request: &HttpRequest,
+        headers: &[Header],
+        authority: &str,
+        relative_path: &str,
+        client_ip: IpAddr,
+        revalidate: Option<&CachedResponse>,
+    ) -> Vec<u8> {
+        let mut request_data = Vec::new();
+        request_data.extend_from_slice(
+            format!(
+                "{} {} HTTP/1.1\r\nHost: {}\r\n",
+                request.method, relative_path, authority
+            )
+            .as_bytes(),
+        );
+
+        // A chunked body is relayed with its framing, so any Content-Length
+        // alongside it is dropped.
+        let chunked = request.is_chunked();
+        let xff = self.config.x_forwarded_for;
+        let forwarded = self.config.forwarded;
+        for header in headers {
+            let skip = match header.name_lower.as_str() {
+                "host" | "connection" | "keep-alive" => true,
+                "content-length" => chunked,
+                "x-forwarded-for" => xff != ForwardedPolicy::Pass,
+                "forwarded" => forwarded != ForwardedPolicy::Pass,
+                "via" => true,
+                name => name.starts_with("proxy-"),
+            };
+            if !skip {
+                request_data
+                    .extend_from_slice(format!("{}: {}\r\n", header.name, header.value).as_bytes());
+            }
+        }
+
+        if xff == ForwardedPolicy::Append {
+            let value = appended_value(headers, "x-forwarded-for", client_ip.to_string());
+            request_data.extend_from_slice(format!("X-Forwarded-For: {}\r\n", value).as_bytes());
+        }
+        if forwarded == ForwardedPolicy::Append {
+            // RFC 7239 §6: IPv6 nodes are bracketed and must be quoted
+            let node = match client_ip {
+                IpAddr::V4(ip) => format!("for={}", ip),
+                IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+            };
+            let value = appended_value(headers, "forwarded", node);
+            request_data.extend_from_slice(format!("Forwarded: {}\r\n", value).as_bytes());
+        }
+        let via = self.via_value(headers, &request.version);
+        request_data.extend_from_slice(format!("Via: {}\r\n", via).as_bytes());
+        for (name, value) in revalidate
+            .map(CachedResponse::validators)
+            .unwrap_or_default()
+        {
+            request_data.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+
+        if request.is_upgrade() {
+            request_data.extend_from_slice(b"Connection: Upgrade\r\n\r\n");
+        } else if self.pool.is_some() {
+            request_data.extend_from_slice(b"Connection: keep-alive\r\n\r\n");
+        } else {
+            request_data.extend_from_slice(b"Connection: close\r\n\r\n");
+        }
+        request_data
+    }
+
+    async fn handle_http_request(
+        &self,
+        conn: &mut BufferedConnection,
+        request: &HttpRequest,
+    ) -> Result<u16, HttpProxyError> {
+        let url = match request.target_url() {
+            Ok(url) => url,
+            Err(e) => {
+                conn.write(&error_response("400 Bad Request", &format!("{}\n", e)))
+                    .await?;
+                return Err(e);
+            }
+        };
+        let target = url.as_str();
+        let host = url
+            .host_str()
+            .ok_or_else(|| HttpProxyError::InvalidRequest("No host in URL".to_string()))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| HttpProxyError::InvalidRequest("No port in URL".to_string()))?;
+        let route = match &self.gateway {
+            Some(gateway) => match gateway.route(host) {
+                Some(route) => Some(route),
+                None => {
+                    conn.write(&error_response(
+                        "404 Not Found",
+                        "No backend for this host\n",
+                    ))
+                    .await?;
+                    return Err(HttpProxyError::NoRoute(host.to_string()));
+                }
+            },
+            None => None,
+        };
+        if let Some(adblock) = &self.adblock
+            && adblock.is_blocked(target, host, request.get_header("referer"))
+        {
+            info!(
+                "HTTP {} {} blocked by a filter list",
+                request.method, target
+            );
+            let response = match adblock.status {
+                204 => b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n".to_vec(),
+                _ => error_response("403 Forbidden", "Blocked by a filter list\n"),
+            };
+            conn.write(&response).await?;
+            return Ok(adblock.status);
+        }
+
+        // Unsafe methods invalidate what is cached for the URL (RFC 9111 §4.4)
+        if let Some(cache) = &self.cache
+            && !matches!(request.method.as_str(), "GET" | "HEAD" | "OPTIONS")
+        {
+            cache.invalidate(target).await;
+        }
+        let cache = self.cache.as_ref().filter(|_| request.is_cacheable());
+        let mut stale = None;
+        if let Some(cache) = cache
+            && !request.wants_reload()
+            && let Some(entry) = cache.get(target).await
+        {
+            if entry.is_fresh(SystemTime::now()) {
+                info!("HTTP {} {} (cache hit)", request.method, target);
+                self.serve_cached(conn, &entry).await?;
+                return Ok(entry.status);
+            }
+            stale = Some(entry);
+        }
+
+        let relative_path = match url.query() {
+            None => url.path().to_string(),
+            Some(q) => format!("{}?{}", url.path(), q),
+        };
+
+        let client_ip = conn.peer_addr()?.ip();
+        let authority = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        let mut headers = request.headers.clone();
+        let rules = &self.config.header_rules;
+        rules::apply(
+            rules,
+            HeaderDirection::Request,
+            host,
+            url.path(),
+            &mut headers,
+        );
+        let request_data = self.upstream_request_head(
+            request,
+            &headers,
+            &authority,
+            &relative_path,
+            client_ip,
+            stale.as_deref(),
+        );
+        let upgrade = request.is_upgrade();
+
+        // An idle pooled connection may be closed by the origin just as it is
+        // reused; a request without a body is then retried on a fresh one.
+        let (origin, target_addr) = match route {
+            Some(route) => (route.backend.clone(), route.pool_key()),
+            None => {
+                let target_addr = format!("{}:{}", host, port);
+                let origin = TargetAddr::parse(&target_addr).ok_or_else(|| {
+                    HttpProxyError::InvalidRequest(format!("Invalid target: {}", target_addr))
+                })?;
+                // TLS connections are pooled apart from plain ones
+                if request.tls {
+                    (origin, format!("tls:{}", target_addr))
+                } else {
+                    (origin, target_addr)
+                }
+            }
+        };
+        // A connection announcing this client must not serve another one
+        let per_client = self.dialer.is_per_client(&origin);
+        let (mut upstream, mut head) = loop {
+            let pooled = self
+                .pool
+                .as_ref()
+                .filter(|_| !per_client)
+                .and_then(|pool| pool.checkout(&target_addr));
+            let reused = pooled.is_some();
+            let mut upstream = match pooled {
+                Some(pooled) => pooled,
+                None => {
+                    let (source, destination) = (conn.peer_addr()?, conn.local_addr()?);
+                    let target_stream = match (&self.gateway, route) {
+                        (Some(gateway), Some(route)) => {
+                            gateway
+                                .connect(route, &*self.dialer, source, destination)
+                                .await?
+                        }
+                        _ => self.dialer.dial_from(&origin, source, destination).await?,
+                    };
+                    if self.is_own_listener(target_stream.peer_addr()?, conn.local_addr()?) {
+                        return Self::reject_loop(conn).await;
+                    }
+                    #[cfg(feature = "mitm")]
+                    let target_stream = match &self.mitm {
+                        Some(mitm) if request.tls => mitm.connect(host, target_stream).await?,
+                        _ => target_stream,
+                    };
+                    Pooled::new(BufferedConnection::new(target_stream, self.buffer_size))
+                }
+            };
+            match self
+                .exchange(conn, &mut upstream.conn, request, &request_data)
+                .await
+            {
+                Ok(head) => break (upstream, head),
+                Err(e)
+                    if reused
+                        && request.body == Body::None
+                        && !matches!(e, HttpProxyError::ResponseTimeout) =>
+                {
+                    debug!("Reused connection to {} failed: {}", target_addr, e);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        info!("HTTP {} {}", request.method, target);
+
+        let body = response_body(request, &head);
+        let reusable = !per_client && !upgrade && body != Body::UntilClose && is_persistent(&head);
+        // HTTP/1.0 clients cannot parse chunked framing; the body is sent
+        // bare instead and ends when the connection closes.
+        let dechunk = request.version == "HTTP/1.0" && body == Body::Chunked;
+        rules::apply(
+            rules,
+            HeaderDirection::Response,
+            host,
+            url.path(),
+            &mut head.headers,
+        );
+
+        if let (Some(cache), Some(stale)) = (cache, &stale)
+            && head.status == 304
+        {
+            info!("HTTP {} {} (revalidated)", request.method, target);
+            let mut entry = stale.refreshed(&head.headers);
+            let ttl = cache.freshness_lifetime(host, &entry);
+            self.serve_cached(conn, &entry).await?;
+            self.release(&target_addr, upstream, reusable);
+            let status = entry.status;
+            if let Some(ttl) = ttl {
+                entry.expires_at = entry.stored_at + ttl;
+                cache.put(entry).await;
+            }
+            return Ok(status);
+        }
+
+        let content_type = head
+            .headers
+            .iter()
+            .find(|header| header.name_lower == "content-type")
+            .map(|header| header.value.as_str());
+        let body_rules =
+            body_rules::matching(&self.config.body_rules, host, url.path(), content_type);
+        // Rewritten responses are not cached, so every client gets them
+        if !body_rules.is_empty() && !upgrade && body != Body::None {
+            let body_rules = body_rules.into_iter().cloned().collect();
+            let status = head.status;
+            self.relay_filtered(conn, &mut upstream.conn, request, head, &body, body_rules)
+                .await?;
+            conn.shutdown().await?;
+            self.release(&target_addr, upstream, reusable);
+            return Ok(status);
+        }
+
+        let headers: Vec<Header> = head
+            .headers
+            .iter()
+            .filter(|header| !(dechunk && header.name_lower == "transfer-encoding"))
+            .cloned()
+            .collect();
+        conn.write(&self.response_head(&head.version, head.status, &head.reason, &headers, None))
+            .await?;
+        let status = head.status;
+
+        // After a 101 both sides speak the new protocol, so relay
+        // transparently; any other response is simply passed through too.
+        if upgrade {
+            forward::forward_bidirectional(conn, &mut upstream.conn, None).await?;
+            return Ok(status);
+        }
+
+        // Cacheable responses are copied aside while they are relayed
+        let mut pending = None;
+        if let Some(cache) = cache {
+            let mut entry = CachedResponse::new(target.to_string(), head);
+            if let Some(ttl) = cache.freshness_lifetime(host, &entry) {
+                entry.expires_at = entry.stored_at + ttl;
+                pending = Some((cache, entry));
+            }
+        }
+
+        // Only the response body is copied back; anything else the client
+        // sends is never forwarded to the origin.
+        let captured = match &pending {
+            Some((cache, _)) => {
+                let mut capture = Capture::new(conn, cache.max_entry_size());
+                stream_body(&mut upstream.conn, &mut capture, &body).await?;
+                capture.captured
+            }
+            None if dechunk => {
+                stream_dechunked_body(&mut upstream.conn, conn).await?;
+                None
+            }
+            None => {
+                stream_body(&mut upstream.conn, conn, &body).await?;
+                None
+            }
+        };
+        conn.shutdown().await?;
+        self.release(&target_addr, upstream, reusable);
+
+        if let (Some((cache, mut entry)), Some(captured)) = (pending, captured) {
+            entry.body = captured;
+            if entry.is_complete() {
+                cache.put(entry).await;
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Relays a response whose body `rules` may rewrite. The body is held
+    /// back, de-chunked, up to `max_filtered_body` bytes; a longer one is
+    /// released untouched as it arrives and ends when the connection closes.
+    async fn relay_filtered(
+        &self,
+        conn: &mut BufferedConnection,
+        upstream: &mut BufferedConnection,
+        request: &HttpRequest,
+        mut head: ResponseHead,
+        body: &Body,
+        rules: Vec<BodyRule>,
+    ) -> Result<(), HttpProxyError> {
+        head.headers
+            .retain(|header| header.name_lower != "transfer-encoding");
+        let released = self.response_head(
+            &head.version,
+            head.status,
+            &head.reason,
+            &head.headers,
+            None,
+        );
+        let limit = self.config.max_filtered_body;
+        let mut holdback = Holdback::new(conn, released, limit);
+        match body {
+            Body::Chunked => stream_dechunked_body(upstream, &mut holdback).await?,
+            _ => stream_body(upstream, &mut holdback, body).await?,
+        }
+        let Some(held) = holdback.held else {
+            debug!("Response body over {} bytes relayed unfiltered", limit);
+            return Ok(());
+        };
+
+        let coding = Coding::parse(
+            head.headers
+                .iter()
+                .find(|header| header.name_lower == "content-encoding")
+                .map(|header| header.value.as_str()),
+        );
+        let accept_encoding = request.get_header("accept-encoding").map(str::to_string);
+        let decompress = self.config.decompress_bodies;
+        // Decoding and encoding are CPU-bound
+        let (held, filtered) = tokio::task::spawn_blocking(move || {
+            let filtered = body_rules::filter(
+                &rules,
+                &held,
+                coding,
+                decompress,
+                limit,
+                accept_encoding.as_deref(),
+            );
+            (held, filtered)
+        })
+        .await
+        .map_err(std::io::Error::other)?;
+
+        let body = match filtered? {
+            Some((rewritten, sent)) => {
+                head.headers
+                    .retain(|header| header.name_lower != "content-encoding");
+                if sent != Coding::Identity {
+                    head.headers.push(Header {
+                        name: "Content-Encoding".to_string(),
+                        name_lower: "content-encoding".to_string(),
+                        value: sent.token().to_string(),
+                    });
+                }
+                rewritten
+            }
+            None => held,
+        };
+        head.headers
+            .retain(|header| header.name_lower != "content-length");
+        head.headers.push(Header {
+            name: "Content-Length".to_string(),
+            name_lower: "content-length".to_string(),
+            value: body.len().to_string(),
+        });
+        let mut response = self.response_head(
+            &head.version,
+            head.status,
+            &head.reason,
+            &head.headers,
+            None,
+        );
+        response.extend_from_slice(&body);
+        conn.write(&response).await?;
+        Ok(())
+    }
+}
+
+async fn read_response_head(
+    target_conn: &mut BufferedConnection,
+) -> Result<ResponseHead, HttpProxyError> {
+    loop {
+        if let Some((head, consumed)) = codec::decode_response(target_conn.buffered())? {
+            target_conn.drain_buffer(consumed);
+            return Ok(head);
+        }
+        if target_conn.read().await? == 0 {
+            return Err(HttpProxyError::IoError(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Upstream closed before end of response head",
+            )));
+        }
+    }
+}
+
+/// How the response to `request` is delimited (RFC 9112 §6.3).
+fn response_body(request: &HttpRequest, head: &ResponseHead) -> Body {
+    if request.method == "HEAD" || matches!(head.status, 100..=199 | 204 | 304) {
+        return Body::None;
+    }
+    let header = |name: &str| {
+        head.headers
+            .iter()
+            .find(|header| header.name_lower == name)
+            .map(|header| header.value.as_str())
+    };
+    if let Some(transfer_encoding) = header("transfer-encoding") {
+        return if is_chunked(transfer_encoding) {
+            Body::Chunked
+        } else {
+            Body::UntilClose
+        };
+    }
+    match header("content-length").map(str::parse::<u64>) {
+        Some(Ok(len)) => Body::Length(len),
+        _ => Body::UntilClose,
+    }
+}
+
+/// Whether the origin keeps the connection open after this response.
+fn is_persistent(head: &ResponseHead) -> bool {
+    let tokens: Vec<String> = head
+        .headers
+        .iter()
+        .filter(|header| header.name_lower == "connection")
+        .flat_map(|header| header.value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .collect();
+    if tokens.iter().any(|token| token == "close") {
+        return false;
+    }
+    head.version == "HTTP/1.1" || tokens.iter().any(|token| token == "keep-alive")
+}
+
+/// Minimal plain-text response for requests the proxy refuses itself.
+fn error_response(status: &str, reason: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason.len(),
+        reason
+    )
+    .into_bytes()
+}
+
+/// Parses an authority-form CONNECT target. IPv6 literals are taken both
+/// bracketed (`[::1]:443`) and bare (`::1:443`, the last colon-separated
+/// field being the port).
+fn connect_target(target: &str) -> Option<TargetAddr> {
+    let (host, port) = target.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok()?;
+    if let Some(literal) = host.strip_prefix('[') {
+        let ip = literal
+            .strip_suffix(']')?
+            .parse::<std::net::Ipv6Addr>()
+            .ok()?;
+        Some(TargetAddr::Ip(SocketAddr::new(ip.into(), port)))
+    } else if let Ok(ip) = host.parse::<IpAddr>() {
+        Some(TargetAddr::Ip(SocketAddr::new(ip, port)))
+    } else if host.is_empty() || host.contains([':', '[', ']']) {
+        None
+    } else {
+        Some(TargetAddr::Domain(host.to_string(), port))
+    }
+}
+
+/// Whether `target` is the listener bound at `listener`, which would make
+/// the proxy connect to itself. A wildcard listener is reached through any
+/// local address of its family, or of both for a dual-stack `[::]`.
+fn is_own_listener(target: SocketAddr, listener: SocketAddr) -> bool {
+    let target_ip = target.ip().to_canonical();
+    if target.port() != listener.port() {
+        return false;
+    }
+    if !listener.ip().is_unspecified() {
+        return target_ip == listener.ip().to_canonical();
+    }
+    (listener.is_ipv6() || target_ip.is_ipv4()) && is_local_ip(target_ip)
+}
+
+/// Whether `ip` is assigned to this host, i.e. a socket can be bound to it.
+fn is_local_ip(ip: IpAddr) -> bool {
+    ip.is_loopback() || std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+/// Joins every value already present for `name` and appends `element`.
+fn appended_value(headers: &[Header], name: &str, element: String) -> String {
+    let mut values: Vec<&str> = headers
+        .iter()
+        .filter(|header| header.name_lower == name)
+        .map(|header| header.value.as_str())
+        .collect();
+    values.push(&element);
+    values.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::auth::AuthManager;
+    use crate::proxy::dialer::DirectDialer;
+    use std::collections::HashMap;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn spawn_proxy() -> std::net::SocketAddr {
+        spawn_proxy_with(HttpConfig::default()).await
+    }
+
+    async fn spawn_proxy_with(config: HttpConfig) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let auth_manager = Arc::new(AuthManager::new(&HashMap::new()).unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            let proxy = HttpProxy::new(
+                auth_manager,
+                4096,
+                Arc::new(DirectDialer::new(Duration::from_secs(5))),
+                Arc::new(config),
+                None,
+                None,
+                None,
+            );
+            let _ = proxy.handle_connection(&mut conn).await;
+        });
+        addr
+    }
+
+    /// Serves any number of connections, sharing the cache and pool given.
+    async fn spawn_shared_proxy(
+        cache: Option<Arc<HttpCache>>,
+        pool: Option<Arc<ConnectionPool>>,
+    ) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let auth_manager = Arc::new(AuthManager::new(&HashMap::new()).unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let proxy = HttpProxy::new(
+                    auth_manager.clone(),
+                    4096,
+                    Arc::new(DirectDialer::new(Duration::from_secs(5))),
+                    Arc::new(HttpConfig::default()),
+                    cache.clone(),
+                    pool.clone(),
+                    None,
+                );
+                tokio::spawn(async move {
+                    let mut conn = BufferedConnection::new(stream, 4096);
+                    let _ = proxy.handle_connection(&mut conn).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn spawn_caching_proxy() -> std::net::SocketAddr {
+        let cache = HttpCache::new(&Default::default()).unwrap();
+        spawn_shared_proxy(Some(Arc::new(cache)), None).await
+    }
+
+    async fn fetch(proxy_addr: std::net::SocketAddr, url: &str) -> String {
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(format!("GET {} HTTP/1.1\r\n\r\n", url).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    /// Accepts one request on `origin` and answers it with `response`,
+    /// returning the request head.
+    async fn serve_once(origin: &TcpListener, response: &[u8]) -> String {
+        let (upstream, _) = origin.accept().await.unwrap();
+        let mut upstream = BufferedConnection::new(upstream, 4096);
+        serve_request(&mut upstream, response).await
+    }
+
+    async fn serve_request(upstream: &mut BufferedConnection, response: &[u8]) -> String {
+        let mut head = String::new();
+        loop {
+            let line = upstream.read_line().await.unwrap();
+            if line.is_empty() {
+                break;
+            }
+            head.push_str(&line);
+            head.push_str("\r\n");
+        }
+        upstream.write(response).await.unwrap();
+        head
+    }
+
+    #[tokio::test]
+    async fn test_upstream_connection_is_reused() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", origin.local_addr().unwrap());
+        let pool = ConnectionPool::new(1, Duration::from_secs(30), Duration::from_secs(30));
+        let proxy_addr = spawn_shared_proxy(None, Some(Arc::new(pool))).await;
+
+        let origin_task = tokio::spawn(async move {
+            let (upstream, _) = origin.accept().await.unwrap();
+            let mut upstream = BufferedConnection::new(upstream, 4096);
+            let mut heads = Vec::new();
+            for body in ["one", "two"] {
+                let response = format!("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n{}", body);
+                heads.push(serve_request(&mut upstream, response.as_bytes()).await);
+            }
+            heads
+        });
+
+        assert!(fetch(proxy_addr, &url).await.ends_with("one"));
+        assert!(fetch(proxy_addr, &url).await.ends_with("two"));
+        let heads = origin_task.await.unwrap();
+        assert!(heads[0].contains("Connection: keep-alive\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_fresh_response_served_from_cache() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/logo.png", origin.local_addr().unwrap());
+        let proxy_addr = spawn_caching_proxy().await;
+
+        let (_, first) = tokio::join!(
+            serve_once(
+                &origin,
+                b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 5\r\n\r\nhello",
+            ),
+            fetch(proxy_addr, &url)
+        );
+        assert!(first.ends_with("hello"));
+
+        // The origin is gone, so only the cache can answer
+        drop(origin);
+        let second = fetch(proxy_addr, &url).await;
+        assert!(second.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(second.contains("Age: 0\r\nVia: 1.1 rust-proxy\r\n"));
+        assert!(second.ends_with("hello"));
+    }
+
+    #[tokio::test]
+    async fn test_stale_response_is_revalidated() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/feed", origin.local_addr().unwrap());
+        let proxy_addr = spawn_caching_proxy().await;
+
+        let (_, first) = tokio::join!(
+            serve_once(
+                &origin,
+                b"HTTP/1.1 200 OK\r\nCache-Control: no-cache\r\nETag: \"v1\"\r\n\
+                  Content-Length: 4\r\n\r\nfeed",
+            ),
+            fetch(proxy_addr, &url)
+        );
+        assert!(first.ends_with("feed"));
+
+        let (revalidation, second) = tokio::join!(
+            serve_once(
+                &origin,
+                b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n"
+            ),
+            fetch(proxy_addr, &url)
+        );
+        assert!(revalidation.contains("If-None-Match: \"v1\"\r\n"));
+        assert!(second.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(second.ends_with("feed"));
+    }
+
+    #[test]
+    fn test_proxy_auth_required() {
+        let auth_manager = Arc::new(AuthManager::new(&HashMap::new()).unwrap());
+        let config = HttpConfig {
+            auth_realm: "Corp \"Edge\"".to_string(),
+            auth_headers: HashMap::from([("X-Help".to_string(), "ext. 42".to_string())]),
+            auth_body: Some("<h1>Sign in</h1>".to_string()),
+            ..HttpConfig::default()
+        };
+        let proxy = HttpProxy::new(
+            auth_manager,
+            4096,
+            Arc::new(DirectDialer::new(Duration::from_secs(5))),
+            Arc::new(config),
+            None,
+            None,
+            None,
+        );
+
+        let response = String::from_utf8(proxy.proxy_auth_required()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n"));
+        assert!(response.contains("Proxy-Authenticate: Basic realm=\"Corp \\\"Edge\\\"\"\r\n"));
+        assert!(response.contains("X-Help: ext. 42\r\n"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.ends_with("Content-Length: 16\r\n\r\n<h1>Sign in</h1>"));
+    }
+
+    #[test]
+    fn test_forwarding_header_policy() {
+        let header = |name: &str, value: &str| Header {
+            name: name.to_string(),
+            name_lower: name.to_ascii_lowercase(),
+            value: value.to_string(),
+        };
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            path: "http://example.com/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: vec![
+                header("Host", "example.com"),
+                header("X-Forwarded-For", "192.0.2.1"),
+                header("Forwarded", "for=192.0.2.1"),
+            ],
+            body: Body::None,
+            tls: false,
+        };
+        let head = |x_forwarded_for, forwarded, client_ip: &str| {
+            let config = HttpConfig {
+                x_forwarded_for,
+                forwarded,
+                ..HttpConfig::default()
+            };
+            let auth_manager = Arc::new(AuthManager::new(&HashMap::new()).unwrap());
+            let proxy = HttpProxy::new(
+                auth_manager,
+                4096,
+                Arc::new(DirectDialer::new(Duration::from_secs(5))),
+                Arc::new(config),
+                None,
+                None,
+                None,
+            );
+            let client_ip = client_ip.parse().unwrap();
+            let head = proxy.upstream_request_head(
+                &request,
+                &request.headers,
+                "example.com",
+                "/",
+                client_ip,
+                None,
+            );
+            String::from_utf8(head).unwrap()
+        };
+
+        let passed = head(ForwardedPolicy::Pass, ForwardedPolicy::Pass, "10.0.0.1");
+        assert!(passed.contains("X-Forwarded-For: 192.0.2.1\r\n"));
+        assert!(passed.contains("Forwarded: for=192.0.2.1\r\n"));
+
+        let appended = head(
+            ForwardedPolicy::Append,
+            ForwardedPolicy::Append,
+            "2001:db8::1",
+        );
+        assert!(appended.contains("X-Forwarded-For: 192.0.2.1, 2001:db8::1\r\n"));
+        assert!(appended.contains("Forwarded: for=192.0.2.1, for=\"[2001:db8::1]\"\r\n"));
+        assert_eq!(appended.matches("X-Forwarded-For").count(), 1);
+
+        let stripped = head(ForwardedPolicy::Strip, ForwardedPolicy::Strip, "10.0.0.1");
+        assert!(!stripped.contains("X-Forwarded-For"));
+        assert!(!stripped.contains("Forwarded"));
+        assert!(stripped.starts_with("GET / HTTP/1.1\r\nHost: example.com\r\nVia:"));
+        assert!(stripped.ends_with("Via: 1.1 rust-proxy\r\nConnection: close\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrade_passthrough() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let proxy_addr = spawn_proxy().await;
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!(
+            "GET http://{}/ws HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive, Upgrade\r\n\
+             Upgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n",
+            origin_addr, origin_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (upstream, _) = origin.accept().await.unwrap();
+        let mut upstream = BufferedConnection::new(upstream, 4096);
+        let mut head = Vec::new();
+        loop {
+            let line = upstream.read_line().await.unwrap();
+            if line.is_empty() {
+                break;
+            }
+            head.push(line);
+        }
+        assert!(head.contains(&"Upgrade: websocket".to_string()));
+        assert!(head.contains(&"Connection: Upgrade".to_string()));
+        assert!(head.contains(&"Via: 1.1 rust-proxy".to_string()));
+
+        upstream
+            .write(
+                b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let switching = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nVia: 1.1 rust-proxy\r\n\r\n";
+        let mut response = vec![0u8; switching.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, switching);
+
+        // Frames now flow both ways
+        client.write_all(b"ping").await.unwrap();
+        assert_eq!(upstream.read_exact_buffered(4).await.unwrap(), &b"ping"[..]);
+        upstream.write(b"pong").await.unwrap();
+        let mut frame = [0u8; 4];
+        client.read_exact(&mut frame).await.unwrap();
+        assert_eq!(&frame, b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_connect_port_not_allowed() {
+        let proxy_addr = spawn_proxy().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"CONNECT mail.example.com:25 HTTP/1.1\r\nHost: mail.example.com:25\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(response.ends_with("CONNECT to port 25 is not allowed\n"));
+    }
+
+    /// Lets in anyone with the password `open-sesame`, over HTTP only.
+    struct SesameProvider;
+
+    #[async_trait::async_trait]
+    impl AuthProvider for SesameProvider {
+        fn is_required(&self) -> bool {
+            true
+        }
+
+        async fn authenticate(
+            &self,
+            _username: &str,
+            password: &str,
+            context: &AuthContext,
+        ) -> AuthDecision {
+            if password == "open-sesame" && context.protocol == ListenerProtocol::Http {
+                AuthDecision::Allow
+            } else {
+                AuthDecision::Deny
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_auth_provider() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let proxy = HttpProxy::new(
+                    Arc::new(SesameProvider),
+                    4096,
+                    Arc::new(DirectDialer::new(Duration::from_secs(5))),
+                    Arc::new(HttpConfig::default()),
+                    None,
+                    None,
+                    None,
+                );
+                tokio::spawn(async move {
+                    let mut conn = BufferedConnection::new(stream, 4096);
+                    let _ = proxy.handle_connection(&mut conn).await;
+                });
+            }
+        });
+
+        let connect = |credentials: String| async move {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let request = format!(
+                "CONNECT mail.example.com:25 HTTP/1.1\r\nProxy-Authorization: Basic {}\r\n\r\n",
+                credentials
+            );
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let basic = |credentials: &str| general_purpose::STANDARD.encode(credentials);
+        assert!(
+            connect(basic("anyone:wrong"))
+                .await
+                .starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n")
+        );
+        // Neither base64 nor UTF-8: wrong credentials all the same
+        for garbage in [
+            "%%%".to_string(),
+            general_purpose::STANDARD.encode([0xff, b':']),
+        ] {
+            assert!(
+                connect(garbage)
+                    .await
+                    .starts_with("HTTP/1.1 407 Proxy Authentication Required\r\n")
+            );
+        }
+        // Past authentication, the port check answers
+        assert!(
+            connect(basic("anyone:open-sesame"))
+                .await
+                .starts_with("HTTP/1.1 403 Forbidden\r\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_method() {
+        let proxy_addr = spawn_proxy().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"TRACE http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        assert!(response.ends_with("TRACE is not supported by this proxy\n"));
+    }
+
+    #[tokio::test]
+    async fn test_via_loop_detected() {
+        let proxy_addr = spawn_proxy().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(
+                b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+                  Via: 1.0 fred, 1.1 rust-proxy (rust-proxy)\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 508 Loop Detected\r\n"));
+    }
+
+    #[test]
+    fn test_is_own_listener() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        assert!(is_own_listener(
+            addr("127.0.0.1:8080"),
+            addr("127.0.0.1:8080")
+        ));
+        assert!(!is_own_listener(
+            addr("127.0.0.2:8080"),
+            addr("127.0.0.1:8080")
+        ));
+        assert!(!is_own_listener(
+            addr("127.0.0.1:8081"),
+            addr("127.0.0.1:8080")
+        ));
+        assert!(is_own_listener(
+            addr("[::ffff:127.0.0.1]:8080"),
+            addr("127.0.0.1:8080")
+        ));
+
+        // A wildcard listener is reached through any local address
+        assert!(is_own_listener(
+            addr("127.0.0.1:8080"),
+            addr("0.0.0.0:8080")
+        ));
+        assert!(is_own_listener(addr("[::1]:8080"), addr("[::]:8080")));
+        assert!(is_own_listener(addr("127.0.0.1:8080"), addr("[::]:8080")));
+        assert!(!is_own_listener(addr("[::1]:8080"), addr("0.0.0.0:8080")));
+        assert!(!is_own_listener(
+            addr("192.0.2.1:8080"),
+            addr("0.0.0.0:8080")
+        ));
+    }
+
+    #[test]
+    fn test_is_chunked() {
+        assert!(is_chunked("chunked"));
+        assert!(is_chunked("gzip, Chunked"));
+        assert!(!is_chunked("chunked, gzip"));
+        assert!(!is_chunked("identity"));
+    }
+
+    #[tokio::test]
+    async fn test_content_length_body_is_streamed() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let proxy_addr = spawn_proxy().await;
+
+        // Much larger than the connection buffer
+        let body = vec![b'x'; 1 << 20];
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let head = format!(
+            "PUT http://{}/blob HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            origin_addr,
+            body.len()
+        );
+        let upload = {
+            let body = body.clone();
+            tokio::spawn(async move {
+                client.write_all(head.as_bytes()).await.unwrap();
+                client.write_all(&body).await.unwrap();
+                client
+            })
+        };
+
+        let (upstream, _) = origin.accept().await.unwrap();
+        let mut upstream = BufferedConnection::new(upstream, 4096);
+        while !upstream.read_line().await.unwrap().is_empty() {}
+        let received = upstream.read_exact_buffered(body.len()).await.unwrap();
+        assert_eq!(received, body);
+        upload.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunked_request_body_is_relayed() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let proxy_addr = spawn_proxy().await;
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!(
+            "POST http://{}/upload HTTP/1.1\r\nHost: {}\r\n\
+             Transfer-Encoding: chunked\r\n\r\n\
+             5;ext=1\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\n",
+            origin_addr, origin_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let (mut upstream, _) = origin.accept().await.unwrap();
+        let expected_tail = "5;ext=1\r\nhello\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let mut received = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !received.ends_with(expected_tail.as_bytes()) {
+            let n = upstream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "origin saw: {}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&buf[..n]);
+        }
+        let received = String::from_utf8(received).unwrap();
+        assert!(received.starts_with("POST /upload HTTP/1.1\r\n"));
+        assert!(received.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!received.contains("Content-Length"));
+
+        upstream
+            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+        drop(upstream);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("2\r\nok\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_http10_client_gets_dechunked_response() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let proxy_addr = spawn_proxy().await;
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!("GET http://{}/path HTTP/1.0\r\n\r\n", origin_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let head = serve_once(
+            &origin,
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n",
+        )
+        .await;
+        assert!(head.starts_with(&format!("GET /path HTTP/1.1\r\nHost: {}\r\n", origin_addr)));
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("Transfer-Encoding"));
+        assert!(response.ends_with("\r\n\r\nhello world"));
+    }
+
+    #[tokio::test]
+    async fn test_body_rules() {
+        use std::io::Write;
+
+        let rule = BodyRule {
+            host: None,
+            path_prefix: None,
+            content_type: Some("text/html".to_string()),
+            pattern: "tracker.js".to_string(),
+            replacement: "blank.js".to_string(),
+        };
+        let config = || HttpConfig {
+            body_rules: vec![rule.clone()],
+            decompress_bodies: true,
+            max_filtered_body: 64,
+            ..Default::default()
+        };
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+
+        // A chunked gzip body, for a client that takes no gzip
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(b"<script src=tracker.js>").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Encoding: gzip\r\n\
+             Transfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+            gzipped.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&gzipped);
+        response.extend_from_slice(b"\r\n0\r\n\r\n");
+
+        let proxy_addr = spawn_proxy_with(config()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!(
+            "GET http://{}/ HTTP/1.1\r\nAccept-Encoding: identity\r\n\r\n",
+            origin_addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        serve_once(&origin, &response).await;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(!response.contains("Content-Encoding"));
+        assert!(!response.contains("Transfer-Encoding"));
+        assert!(response.contains("Content-Length: 21\r\n"));
+        assert!(response.ends_with("\r\n\r\n<script src=blank.js>"));
+
+        // Past max_filtered_body the body passes through untouched
+        let long = format!("<script src=tracker.js>{}", "x".repeat(64));
+        let proxy_addr = spawn_proxy_with(config()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!("GET http://{}/ HTTP/1.1\r\n\r\n", origin_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+            long.len(),
+            long
+        );
+        serve_once(&origin, response.as_bytes()).await;
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.contains(&format!("Content-Length: {}\r\n", long.len())));
+        assert!(response.ends_with(&format!("\r\n\r\n{}", long)));
+    }
+
+    #[tokio::test]
+    async fn test_origin_form_uses_host_header() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin_addr = origin.local_addr().unwrap();
+        let proxy_addr = spawn_shared_proxy(None, None).await;
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!("GET /search?q=1 HTTP/1.1\r\nHost: {}\r\n\r\n", origin_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let head = serve_once(&origin, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        assert!(head.starts_with(&format!(
+            "GET /search?q=1 HTTP/1.1\r\nHost: {}\r\n",
+            origin_addr
+        )));
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("ok"));
+
+        // Without a Host header there is no target to forward to
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.ends_with("No Host header for origin-form target\n"));
+    }
+
+    #[tokio::test]
+    async fn test_gateway_routes_by_host() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = crate::common::config::GatewayConfig {
+            listen_address: "127.0.0.1:0".to_string(),
+            routes: vec![crate::common::config::GatewayRoute {
+                host: "app.example.com".to_string(),
+                backend: backend.local_addr().unwrap().to_string(),
+                tls: false,
+                ca_path: None,
+            }],
+        };
+        let gateway = Arc::new(Gateway::new(&config, Duration::from_secs(5)).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway_addr = listener.local_addr().unwrap();
+        // Users are configured, yet gateway clients do not authenticate
+        let users = HashMap::from([("alice".to_string(), "secret".to_string().into())]);
+        let auth_manager = Arc::new(AuthManager::new(&users).unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let proxy = HttpProxy::new(
+                    auth_manager.clone(),
+                    4096,
+                    Arc::new(DirectDialer::new(Duration::from_secs(5))),
+                    Arc::new(HttpConfig::default()),
+                    None,
+                    None,
+                    None,
+                )
+                .with_gateway(gateway.clone());
+                tokio::spawn(async move {
+                    let mut conn = BufferedConnection::new(stream, 4096);
+                    let _ = proxy.handle_connection(&mut conn).await;
+                });
+            }
+        });
+
+        let mut client = TcpStream::connect(gateway_addr).await.unwrap();
+        client
+            .write_all(b"GET /status HTTP/1.1\r\nHost: app.example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let head = serve_once(&backend, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        assert!(head.starts_with("GET /status HTTP/1.1\r\nHost: app.example.com\r\n"));
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+
+        let response = fetch(gateway_addr, "http://other.example.com/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+
+        let mut client = TcpStream::connect(gateway_addr).await.unwrap();
+        client
+            .write_all(b"CONNECT app.example.com:443 HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed"));
+    }
+
+    #[test]
+    fn test_ambiguous_request_framing() {
+        let headers = |fields: &[(&str, &str)]| -> Vec<Header> {
+            fields
+                .iter()
+                .map(|(name, value)| Header {
+                    name: name.to_string(),
+                    name_lower: name.to_ascii_lowercase(),
+                    value: value.to_string(),
+                })
+                .collect()
+        };
+        let framing = |fields: &[(&str, &str)]| request_body(&headers(fields));
+
+        assert_eq!(
+            framing(&[("Content-Length", "5")]).unwrap(),
+            Body::Length(5)
+        );
+        assert_eq!(
+            framing(&[
+                ("Transfer-Encoding", "gzip"),
+                ("Transfer-Encoding", "chunked")
+            ])
+            .unwrap(),
+            Body::Chunked
+        );
+        for fields in [
+            &[("Transfer-Encoding", "chunked"), ("Content-Length", "5")][..],
+            &[("Content-Length", "5"), ("Content-Length", "5")],
+            &[("Content-Length", "5, 6")],
+            &[("Content-Length", "+5")],
+        ] {
+            assert!(matches!(
+                framing(fields),
+                Err(HttpProxyError::AmbiguousFraming(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_smuggling_attempt_rejected() {
+        let proxy_addr = spawn_proxy().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(
+                b"POST http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\
+                  Content-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_head_rejected() {
+        let proxy_addr = spawn_proxy().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!(
+            "GET http://example.com/ HTTP/1.1\r\nX-Large: {}\r\n\r\n",
+            "a".repeat(HttpConfig::default().max_header_bytes)
+        );
+        // The proxy may answer and close before reading the whole head
+        let _ = client.write_all(request.as_bytes()).await;
+        let mut response = Vec::new();
+        let _ = client.read_to_end(&mut response).await;
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_connect_target() {
+        let parsed = |target: &str| connect_target(target).map(|addr| addr.to_string());
+        assert_eq!(parsed("example.com:443").unwrap(), "example.com:443");
+        assert_eq!(parsed("192.0.2.1:8443").unwrap(), "192.0.2.1:8443");
+        assert_eq!(parsed("[::1]:443").unwrap(), "[::1]:443");
+        assert_eq!(parsed("2001:db8::1:443").unwrap(), "[2001:db8::1]:443");
+        for invalid in [
+            "example.com",
+            "[::1]",
+            "[::1:443",
+            "[example.com]:443",
+            ":443",
+            "a:b:443",
+        ] {
+            assert_eq!(connect_target(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_header_timeout() {
+        let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/slow", origin.local_addr().unwrap());
+        let proxy_addr = spawn_proxy_with(HttpConfig {
+            response_header_timeout: 1,
+            ..HttpConfig::default()
+        })
+        .await;
+
+        let (response, _upstream) = tokio::join!(fetch(proxy_addr, &url), origin.accept());
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            let proxy = HttpProxy::new(
+                Arc::new(AuthManager::new(&HashMap::new()).unwrap()),
+                4096,
+                Arc::new(DirectDialer::new(Duration::from_secs(5))),
+                Arc::new(HttpConfig::default()),
+                None,
+                None,
+                None,
+            )
+            .with_handshake_timeout(Duration::from_millis(200));
+            let _ = proxy.handle_connection(&mut conn).await;
+        });
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"G").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_string(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+}
@@ -0,0 +1,181 @@
+//! `[[http.header_rules]]`: header rewrites selected by request host and
+//! path, applied in configuration order.
+
+use crate::common::config::{HeaderAction, HeaderDirection, HeaderRule};
+use crate::net::addr::host_matches;
+use crate::proxy::http::codec::Header;
+
+/// Applies the rules for `direction` that match `host` and `path` to
+/// `headers`.
+pub fn apply(
+    rules: &[HeaderRule],
+    direction: HeaderDirection,
+    host: &str,
+    path: &str,
+    headers: &mut Vec<Header>,
+) {
+    for rule in rules {
+        if rule.direction != direction || !matches(rule, host, path) {
+            continue;
+        }
+        let name_lower = rule.name.to_ascii_lowercase();
+        let value = rule.value.as_deref().unwrap_or_default();
+        match rule.action {
+            HeaderAction::Set => {
+                headers.retain(|header| header.name_lower != name_lower);
+                headers.push(header(rule, name_lower, value));
+            }
+            HeaderAction::Add => headers.push(header(rule, name_lower, value)),
+            HeaderAction::Remove => headers.retain(|header| header.name_lower != name_lower),
+            HeaderAction::Replace => {
+                let Some(regex) = rule.regex() else {
+                    continue;
+                };
+                for header in headers.iter_mut().filter(|h| h.name_lower == name_lower) {
+                    header.value = regex.replace_all(&header.value, value).into_owned();
+                }
+            }
+        }
+    }
+}
+
+fn matches(rule: &HeaderRule, host: &str, path: &str) -> bool {
+    rule.host
+        .as_deref()
+        .is_none_or(|pattern| host_matches(pattern, host))
+        && rule
+            .path_prefix
+            .as_deref()
+            .is_none_or(|prefix| path.starts_with(prefix))
+}
+
+fn header(rule: &HeaderRule, name_lower: String, value: &str) -> Header {
+    Header {
+        name: rule.name.clone(),
+        name_lower,
+        value: value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: HeaderAction, name: &str, value: Option<&str>) -> HeaderRule {
+        HeaderRule {
+            host: None,
+            path_prefix: None,
+            direction: HeaderDirection::Request,
+            action,
+            name: name.to_string(),
+            value: value.map(str::to_string),
+            pattern: None,
+            regex: Default::default(),
+        }
+    }
+
+    fn headers(fields: &[(&str, &str)]) -> Vec<Header> {
+        fields
+            .iter()
+            .map(|(name, value)| Header {
+                name: name.to_string(),
+                name_lower: name.to_ascii_lowercase(),
+                value: value.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_actions() {
+        let mut replace = rule(HeaderAction::Replace, "Cookie", Some("id=x"));
+        replace.pattern = Some("id=123".to_string());
+        let rules = [
+            rule(HeaderAction::Remove, "x-tracking-id", None),
+            rule(HeaderAction::Set, "X-Token", Some("internal")),
+            rule(HeaderAction::Add, "X-Extra", Some("1")),
+            replace,
+        ];
+        let mut fields = headers(&[
+            ("X-Tracking-Id", "abc"),
+            ("x-token", "client"),
+            ("X-Extra", "0"),
+            ("Cookie", "id=123; lang=en"),
+        ]);
+        apply(
+            &rules,
+            HeaderDirection::Request,
+            "example.com",
+            "/",
+            &mut fields,
+        );
+
+        let fields: Vec<(&str, &str)> = fields
+            .iter()
+            .map(|h| (h.name.as_str(), h.value.as_str()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("X-Extra", "0"),
+                ("Cookie", "id=x; lang=en"),
+                ("X-Token", "internal"),
+                ("X-Extra", "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace_with_capture_groups() {
+        let mut replace = rule(HeaderAction::Replace, "Location", Some("https://$1/"));
+        replace.direction = HeaderDirection::Response;
+        replace.pattern = Some(r"^http://([^/]+)\.internal/".to_string());
+        let mut fields = headers(&[("Location", "http://app.internal/login?next=/a.internal/")]);
+        apply(
+            &[replace],
+            HeaderDirection::Response,
+            "example.com",
+            "/",
+            &mut fields,
+        );
+        assert_eq!(fields[0].value, "https://app/login?next=/a.internal/");
+
+        // Every match is replaced
+        let mut replace = rule(HeaderAction::Replace, "Cookie", Some("${key}=x"));
+        replace.pattern = Some(r"(?<key>id|sid)=\d+".to_string());
+        let mut fields = headers(&[("Cookie", "id=1; lang=en; sid=22")]);
+        apply(
+            &[replace],
+            HeaderDirection::Request,
+            "example.com",
+            "/",
+            &mut fields,
+        );
+        assert_eq!(fields[0].value, "id=x; lang=en; sid=x");
+    }
+
+    #[test]
+    fn test_matching() {
+        let mut scoped = rule(HeaderAction::Remove, "X-A", None);
+        scoped.host = Some("*.example.com".to_string());
+        scoped.path_prefix = Some("/api".to_string());
+
+        assert!(matches(&scoped, "api.example.com", "/api/v1"));
+        assert!(!matches(&scoped, "example.com", "/api"));
+        assert!(!matches(&scoped, "badexample.com", "/api"));
+        assert!(!matches(&scoped, "api.example.com", "/static"));
+
+        scoped.host = Some("Example.com".to_string());
+        assert!(matches(&scoped, "example.com", "/api"));
+
+        // Rules only touch their own direction
+        let mut fields = headers(&[("X-A", "1")]);
+        apply(
+            &[scoped],
+            HeaderDirection::Response,
+            "example.com",
+            "/api",
+            &mut fields,
+        );
+        assert_eq!(fields.len(), 1);
+    }
+}
@@ -0,0 +1,272 @@
+//! `[lockout]`: brute-force protection. Failed logins are counted per
+//! source address and per username; a source failing too often is banned
+//! and refused as soon as it connects, and a username attacked from many
+//! sources is locked, refusing even its right password until the ban ends.
+
+use async_trait::async_trait;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::common::auth::{AuthContext, AuthDecision, AuthError, AuthProvider};
+use crate::common::config::LockoutConfig;
+use crate::net::rate_limit::Limit;
+
+/// Tracked sources or usernames beyond which stale entries are swept out
+const SWEEP_SIZE: usize = 4096;
+
+/// Failures of one source or username within the current window
+struct Failures {
+    window_start: Instant,
+    count: u32,
+    banned_until: Option<Instant>,
+}
+
+impl Failures {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+}
+
+/// Failures counted by key, banning a key once it reaches `limit` within
+/// `window`.
+struct Tracker<K> {
+    limit: u32,
+    entries: Mutex<HashMap<K, Failures>>,
+}
+
+impl<K: std::hash::Hash + Eq + Clone> Tracker<K> {
+    fn new(limit: u32) -> Self {
+        Tracker {
+            limit,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn is_banned(&self, key: &K) -> bool {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .is_some_and(|failures| failures.is_banned(now))
+    }
+
+    /// Counts a failure of `key`. Returns the number of failures when this
+    /// one bans it.
+    fn fail(&self, key: &K, window: Duration, ban: Duration) -> Option<u32> {
+        if self.limit == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= SWEEP_SIZE {
+            entries.retain(|_, failures| {
+                failures.is_banned(now) || now.duration_since(failures.window_start) < window
+            });
+        }
+        let failures = entries.entry(key.clone()).or_insert(Failures {
+            window_start: now,
+            count: 0,
+            banned_until: None,
+        });
+        if failures.is_banned(now) {
+            return None;
+        }
+        if now.duration_since(failures.window_start) >= window {
+            failures.window_start = now;
+            failures.count = 0;
+        }
+        failures.count += 1;
+        if failures.count < self.limit {
+            return None;
+        }
+        failures.banned_until = Some(now + ban);
+        Some(std::mem::take(&mut failures.count))
+    }
+
+    fn clear(&self, key: &K) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .get(key)
+            .is_some_and(|failures| !failures.is_banned(Instant::now()))
+        {
+            entries.remove(key);
+        }
+    }
+}
+
+pub struct Lockout {
+    window: Duration,
+    ban_duration: Duration,
+    sources: Tracker<IpAddr>,
+    users: Tracker<String>,
+}
+
+impl Lockout {
+    pub fn new(config: &LockoutConfig) -> Self {
+        Lockout {
+            window: Duration::from_secs(config.window),
+            ban_duration: Duration::from_secs(config.ban_duration),
+            sources: Tracker::new(config.max_failures),
+            users: Tracker::new(config.max_user_failures),
+        }
+    }
+
+    /// Whether connections from `ip` are refused for now.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.sources.is_banned(&ip.to_canonical())
+    }
+
+    fn is_locked(&self, username: &str) -> bool {
+        self.users.is_banned(&username.to_string())
+    }
+
+    fn record_failure(&self, context: &AuthContext, username: &str) {
+        let ip = context.client.ip().to_canonical();
+        if let Some(count) = self.sources.fail(&ip, self.window, self.ban_duration) {
+            warn!(
+                "Security: banned {} for {}s after {} failed logins, the last as '{}' over {:?}",
+                ip,
+                self.ban_duration.as_secs(),
+                count,
+                username,
+                context.protocol
+            );
+        }
+        let name = username.to_string();
+        if let Some(count) = self.users.fail(&name, self.window, self.ban_duration) {
+            warn!(
+                "Security: locked user '{}' for {}s after {} failed logins, the last from {}",
+                username,
+                self.ban_duration.as_secs(),
+                count,
+                ip
+            );
+        }
+    }
+
+    fn record_success(&self, context: &AuthContext) {
+        self.sources.clear(&context.client.ip().to_canonical());
+    }
+}
+
+/// Counts the failures of `inner` against a `Lockout`, refusing locked
+/// usernames without asking it.
+pub struct LockoutProvider {
+    inner: Arc<dyn AuthProvider>,
+    lockout: Arc<Lockout>,
+}
+
+impl LockoutProvider {
+    pub fn new(inner: Arc<dyn AuthProvider>, lockout: Arc<Lockout>) -> Self {
+        LockoutProvider { inner, lockout }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for LockoutProvider {
+    fn is_required(&self) -> bool {
+        self.inner.is_required()
+    }
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        context: &AuthContext,
+    ) -> AuthDecision {
+        if self.lockout.is_locked(username) {
+            info!("Refused locked user '{}' from {}", username, context.client);
+            return AuthDecision::Deny;
+        }
+        let decision = self.inner.authenticate(username, password, context).await;
+        match decision {
+            AuthDecision::Allow => self.lockout.record_success(context),
+            AuthDecision::Deny => self.lockout.record_failure(context, username),
+            AuthDecision::Error(_) => {}
+        }
+        decision
+    }
+
+    fn certificate_user(&self, identity: Option<String>) -> Option<String> {
+        self.inner.certificate_user(identity)
+    }
+
+    fn allows_port(&self, username: &str, port: u16) -> bool {
+        self.inner.allows_port(username, port)
+    }
+
+    fn quota(&self, username: &str) -> Option<u64> {
+        self.inner.quota(username)
+    }
+
+    fn admits(&self, username: &str) -> Result<(), AuthError> {
+        self.inner.admits(username)
+    }
+
+    fn record_usage(&self, username: &str, bytes: u64) {
+        self.inner.record_usage(username, bytes);
+    }
+
+    fn rate_limits(&self, username: &str) -> Vec<Arc<dyn Limit>> {
+        self.inner.rate_limits(username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::auth::AuthManager;
+    use crate::common::config::ListenerProtocol;
+
+    #[tokio::test]
+    async fn test_lockout() {
+        let mut users = HashMap::new();
+        users.insert("alice".to_string(), "secret".to_string().into());
+        users.insert("bob".to_string(), "hunter2".to_string().into());
+        let lockout = Arc::new(Lockout::new(&LockoutConfig {
+            max_failures: 3,
+            max_user_failures: 4,
+            window: 60,
+            ban_duration: 60,
+        }));
+        let auth =
+            LockoutProvider::new(Arc::new(AuthManager::new(&users).unwrap()), lockout.clone());
+        let attempt = |client: &str, username: &'static str, password: &'static str| {
+            let context = AuthContext {
+                client: client.parse().unwrap(),
+                protocol: ListenerProtocol::Socks5,
+            };
+            let auth = &auth;
+            async move {
+                matches!(
+                    auth.authenticate(username, password, &context).await,
+                    AuthDecision::Allow
+                )
+            }
+        };
+
+        // A success clears the failures of its source
+        assert!(!attempt("192.0.2.1:1000", "alice", "wrong").await);
+        assert!(!attempt("192.0.2.1:1000", "alice", "wrong").await);
+        assert!(attempt("192.0.2.1:1000", "alice", "secret").await);
+        assert!(!attempt("192.0.2.1:1000", "alice", "wrong").await);
+        assert!(!lockout.is_banned("192.0.2.1".parse().unwrap()));
+
+        // The third failure in a row bans the source, IPv4-mapped too
+        assert!(!attempt("192.0.2.2:1000", "bob", "wrong").await);
+        assert!(!attempt("192.0.2.2:1000", "bob", "wrong").await);
+        assert!(!lockout.is_banned("192.0.2.2".parse().unwrap()));
+        assert!(!attempt("192.0.2.2:1000", "bob", "wrong").await);
+        assert!(lockout.is_banned("192.0.2.2".parse().unwrap()));
+        assert!(lockout.is_banned("::ffff:192.0.2.2".parse().unwrap()));
+
+        // Failures of a username add up across sources, locking it
+        assert!(attempt("192.0.2.3:1000", "bob", "hunter2").await);
+        assert!(!attempt("192.0.2.4:1000", "bob", "wrong").await);
+        assert!(!attempt("192.0.2.3:1000", "bob", "hunter2").await);
+        assert!(attempt("192.0.2.3:1000", "alice", "secret").await);
+    }
+}
@@ -1,4 +1,26 @@
+pub mod accept_queue;
+pub mod access;
+pub mod bandwidth;
+pub mod blocklist;
+pub mod client_limits;
+pub mod dialer;
+pub mod dns;
+pub mod dns_forwarder;
+pub mod fake_ip;
 pub mod forward;
+pub mod hosts;
 pub mod http;
+pub mod lockout;
+pub mod policy;
+pub mod quota;
+pub mod router;
+pub mod shadowsocks;
+pub mod socks4;
 pub mod socks5;
+#[cfg(feature = "socks6")]
+pub mod socks6;
+pub mod ssrf;
 pub mod tcp;
+pub mod transparent;
+pub mod udp;
+pub mod upstream;
@@ -0,0 +1,50 @@
+//! Outbound policy every handler shares, whatever protocol the client
+//! spoke: `blocked_ports` no connection is opened to, so the proxy cannot
+//! be used to relay mail spam or reach IRC.
+
+use crate::common::config::PortRange;
+use crate::net::addr::TargetAddr;
+use crate::proxy::forward::ConnectError;
+
+pub struct OutboundPolicy {
+    blocked_ports: Vec<PortRange>,
+}
+
+impl OutboundPolicy {
+    pub fn new(blocked_ports: &[PortRange]) -> Self {
+        OutboundPolicy {
+            blocked_ports: blocked_ports.to_vec(),
+        }
+    }
+
+    pub fn check(&self, target: &TargetAddr) -> Result<(), ConnectError> {
+        let port = target.port();
+        if self.blocked_ports.iter().any(|range| range.contains(port)) {
+            return Err(ConnectError::Blocked(target.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let ports: Vec<PortRange> = ["25", "465", "6660-6669"]
+            .into_iter()
+            .map(|port| port.to_string().try_into().unwrap())
+            .collect();
+        let policy = OutboundPolicy::new(&ports);
+        let check = |target: &str| policy.check(&TargetAddr::parse(target).unwrap());
+        assert!(matches!(
+            check("smtp.example.com:25"),
+            Err(ConnectError::Blocked(_))
+        ));
+        assert!(check("192.0.2.1:6667").is_err());
+        assert!(check("[2001:db8::1]:465").is_err());
+        assert!(check("smtp.example.com:587").is_ok());
+        assert!(check("example.com:443").is_ok());
+    }
+}
@@ -0,0 +1,400 @@
+//! `[quotas]`: bytes each user transfers per day or month, counted as they
+//! pass through the user's sessions and saved to a state file now and then.
+//! A user over their quota is cut off and refused new sessions, or with
+//! `action = "throttle"` held to a rate all of their sessions share, those
+//! in progress included.
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::common::auth::{AuthContext, AuthDecision, AuthError, AuthProvider};
+use crate::common::config::{QuotaAction, QuotaConfig, QuotaPeriod};
+use crate::net::rate_limit::{Limit, RateLimiter};
+
+#[derive(Error, Debug)]
+pub enum QuotaError {
+    #[error("Failed to read quota state '{0}': {1}")]
+    Load(String, String),
+    #[error("Failed to save quota state '{0}': {1}")]
+    Save(String, String),
+}
+
+/// Usage in one period, as kept in the state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Usage {
+    period: String,
+    bytes: HashMap<String, u64>,
+}
+
+pub struct Quotas {
+    period: QuotaPeriod,
+    default_quota: Option<u64>,
+    action: QuotaAction,
+    throttle_rate: u64,
+    state_path: Option<PathBuf>,
+    save_interval: Duration,
+    usage: Mutex<Usage>,
+    /// Whether usage changed since it was last saved
+    dirty: AtomicBool,
+    /// Limiters of users throttled for going over quota
+    throttles: Mutex<HashMap<String, Arc<RateLimiter>>>,
+}
+
+impl Quotas {
+    /// Picks up the usage `state_path` holds for the current period, if any.
+    pub fn new(config: &QuotaConfig) -> Result<Self, QuotaError> {
+        let state_path = config.state_path.as_ref().map(PathBuf::from);
+        let usage = match &state_path {
+            Some(path) if path.exists() => {
+                let failed = |e: String| QuotaError::Load(path.display().to_string(), e);
+                let contents = fs::read_to_string(path).map_err(|e| failed(e.to_string()))?;
+                serde_json::from_str(&contents).map_err(|e| failed(e.to_string()))?
+            }
+            _ => Usage::default(),
+        };
+        Ok(Quotas {
+            period: config.period,
+            default_quota: config.default_quota,
+            action: config.action,
+            throttle_rate: config.throttle_rate,
+            state_path,
+            save_interval: Duration::from_secs(config.save_interval),
+            usage: Mutex::new(usage),
+            dirty: AtomicBool::new(false),
+            throttles: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn period_key(&self) -> String {
+        let format = match self.period {
+            QuotaPeriod::Daily => "%Y-%m-%d",
+            QuotaPeriod::Monthly => "%Y-%m",
+        };
+        Utc::now().format(format).to_string()
+    }
+
+    /// The usage of the current period, emptied when a new one has begun.
+    fn usage(&self) -> MutexGuard<'_, Usage> {
+        let mut usage = self.usage.lock().unwrap();
+        let period = self.period_key();
+        if usage.period != period {
+            if !usage.period.is_empty() {
+                info!("Quota period {} began, usage starts over", period);
+            }
+            *usage = Usage {
+                period,
+                bytes: HashMap::new(),
+            };
+            self.throttles.lock().unwrap().clear();
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        usage
+    }
+
+    /// Bytes `username` transferred this period.
+    pub fn used(&self, username: &str) -> u64 {
+        self.usage().bytes.get(username).copied().unwrap_or(0)
+    }
+
+    /// Counts `bytes` more transferred by `username`.
+    pub fn add(&self, username: &str, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let mut usage = self.usage();
+        let used = usage.bytes.entry(username.to_string()).or_insert(0);
+        *used = used.saturating_add(bytes);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `username`, whose own quota is `quota`, has used it up.
+    fn is_exceeded(&self, username: &str, quota: Option<u64>) -> bool {
+        quota
+            .or(self.default_quota)
+            .is_some_and(|quota| self.used(username) >= quota)
+    }
+
+    fn throttle(&self, username: &str) -> Arc<RateLimiter> {
+        self.throttles
+            .lock()
+            .unwrap()
+            .entry(username.to_string())
+            .or_insert_with(|| {
+                info!("Throttling user '{}', over quota", username);
+                Arc::new(RateLimiter::new(self.throttle_rate, self.throttle_rate))
+            })
+            .clone()
+    }
+
+    /// Writes the usage to `state_path` when it changed.
+    pub fn save(&self) -> Result<(), QuotaError> {
+        let Some(path) = &self.state_path else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let failed = |e: String| QuotaError::Save(path.display().to_string(), e);
+        let contents = serde_json::to_string(&*self.usage()).map_err(|e| failed(e.to_string()))?;
+        // Written aside and renamed, so a crash never leaves half a file
+        let temporary = path.with_extension("tmp");
+        let written = fs::write(&temporary, contents).and_then(|_| fs::rename(&temporary, path));
+        if let Err(e) = written {
+            self.dirty.store(true, Ordering::Relaxed);
+            return Err(failed(e.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Saves the usage every `save_interval` until Ctrl-C / SIGINT is
+    /// received, and once more then. Returns at once without a state file.
+    pub async fn run(&self) {
+        if self.state_path.is_none() {
+            return;
+        }
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.save_interval) => {}
+                _ = &mut shutdown => break,
+            }
+            if let Err(e) = self.save() {
+                error!("{}", e);
+            }
+        }
+        if let Err(e) = self.save() {
+            error!("{}", e);
+        }
+    }
+}
+
+/// The quota of one user, drawn on by the reads and writes of a session of
+/// theirs: bytes count as they pass, and once the quota is used up the
+/// session is cut off, or held to the user's shared throttle.
+struct QuotaLimit {
+    quotas: Arc<Quotas>,
+    username: String,
+    /// The user's own quota, over `default_quota`
+    quota: Option<u64>,
+}
+
+impl QuotaLimit {
+    /// The limiter the user is held to, once over quota with `throttle`.
+    fn throttle(&self) -> Option<Arc<RateLimiter>> {
+        (self.quotas.action == QuotaAction::Throttle
+            && self.quotas.is_exceeded(&self.username, self.quota))
+        .then(|| self.quotas.throttle(&self.username))
+    }
+}
+
+impl Limit for QuotaLimit {
+    fn delay(&self) -> io::Result<Duration> {
+        if !self.quotas.is_exceeded(&self.username, self.quota) {
+            return Ok(Duration::ZERO);
+        }
+        match self.throttle() {
+            Some(throttle) => throttle.delay(),
+            None => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                AuthError::QuotaExceeded(self.username.clone()),
+            )),
+        }
+    }
+
+    fn consume(&self, bytes: usize) {
+        self.quotas.add(&self.username, bytes as u64);
+        if let Some(throttle) = self.throttle() {
+            throttle.consume(bytes);
+        }
+    }
+}
+
+/// Enforces `Quotas` on the users of `inner`, counting their usage.
+pub struct QuotaProvider {
+    inner: Arc<dyn AuthProvider>,
+    quotas: Arc<Quotas>,
+}
+
+impl QuotaProvider {
+    pub fn new(inner: Arc<dyn AuthProvider>, quotas: Arc<Quotas>) -> Self {
+        QuotaProvider { inner, quotas }
+    }
+}
+
+#[async_trait]
+impl AuthProvider for QuotaProvider {
+    fn is_required(&self) -> bool {
+        self.inner.is_required()
+    }
+
+    async fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+        context: &AuthContext,
+    ) -> AuthDecision {
+        match self.inner.authenticate(username, password, context).await {
+            AuthDecision::Allow => match self.admits(username) {
+                Ok(()) => AuthDecision::Allow,
+                Err(e) => AuthDecision::Error(e),
+            },
+            decision => decision,
+        }
+    }
+
+    fn certificate_user(&self, identity: Option<String>) -> Option<String> {
+        self.inner
+            .certificate_user(identity)
+            .filter(|user| self.admits(user).is_ok())
+    }
+
+    fn allows_port(&self, username: &str, port: u16) -> bool {
+        self.inner.allows_port(username, port)
+    }
+
+    fn quota(&self, username: &str) -> Option<u64> {
+        self.inner.quota(username)
+    }
+
+    /// Refuses users over quota unless they are throttled instead.
+    fn admits(&self, username: &str) -> Result<(), AuthError> {
+        self.inner.admits(username)?;
+        if self.quotas.action == QuotaAction::Block
+            && self
+                .quotas
+                .is_exceeded(username, self.inner.quota(username))
+        {
+            warn!(
+                "Refused user '{}', over quota with {} bytes this period",
+                username,
+                self.quotas.used(username)
+            );
+            return Err(AuthError::QuotaExceeded(username.to_string()));
+        }
+        Ok(())
+    }
+
+    fn record_usage(&self, username: &str, bytes: u64) {
+        self.quotas.add(username, bytes);
+        self.inner.record_usage(username, bytes);
+    }
+
+    /// Adds the user's quota, which counts the session's bytes as they
+    /// pass.
+    fn rate_limits(&self, username: &str) -> Vec<Arc<dyn Limit>> {
+        let mut limits = self.inner.rate_limits(username);
+        limits.push(Arc::new(QuotaLimit {
+            quotas: self.quotas.clone(),
+            username: username.to_string(),
+            quota: self.inner.quota(username),
+        }));
+        limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::auth::AuthManager;
+    use crate::common::config::{ListenerProtocol, UserConfig};
+
+    fn config(action: QuotaAction, state_path: Option<String>) -> QuotaConfig {
+        QuotaConfig {
+            period: QuotaPeriod::Monthly,
+            default_quota: Some(1000),
+            state_path,
+            save_interval: 60,
+            action,
+            throttle_rate: 1024,
+        }
+    }
+
+    fn provider(quotas: Arc<Quotas>) -> QuotaProvider {
+        let mut users = HashMap::new();
+        users.insert(
+            "alice".to_string(),
+            UserConfig {
+                quota: Some(100),
+                ..UserConfig::from("secret".to_string())
+            },
+        );
+        users.insert("bob".to_string(), "hunter2".to_string().into());
+        QuotaProvider::new(Arc::new(AuthManager::new(&users).unwrap()), quotas)
+    }
+
+    #[tokio::test]
+    async fn test_quota_blocks() {
+        let quotas = Arc::new(Quotas::new(&config(QuotaAction::Block, None)).unwrap());
+        let auth = provider(quotas.clone());
+        let context = AuthContext {
+            client: "127.0.0.1:50000".parse().unwrap(),
+            protocol: ListenerProtocol::Socks5,
+        };
+
+        // A session in progress counts its bytes as they pass, and is cut
+        // off once they use the quota up
+        let session = auth.rate_limits("alice");
+        assert_eq!(session.len(), 1);
+        session[0].consume(60);
+        assert_eq!(quotas.used("alice"), 60);
+        assert!(auth.admits("alice").is_ok());
+        assert!(session[0].delay().is_ok());
+        session[0].consume(60);
+        assert_eq!(
+            session[0].delay().unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert!(matches!(
+            auth.authenticate("alice", "secret", &context).await,
+            AuthDecision::Error(AuthError::QuotaExceeded(_))
+        ));
+        // A wrong password is still just wrong
+        assert!(matches!(
+            auth.authenticate("alice", "wrong", &context).await,
+            AuthDecision::Deny
+        ));
+
+        // bob falls back to default_quota; datagrams count as the
+        // association ends
+        auth.record_usage("bob", 999);
+        assert!(auth.admits("bob").is_ok());
+        auth.record_usage("bob", 1);
+        assert!(auth.admits("bob").is_err());
+    }
+
+    #[test]
+    fn test_quota_throttles_and_persists() {
+        let dir = std::env::temp_dir().join(format!("rust-proxy-quota-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("usage.json").to_string_lossy().into_owned();
+
+        let quotas =
+            Arc::new(Quotas::new(&config(QuotaAction::Throttle, Some(path.clone()))).unwrap());
+        let auth = provider(quotas.clone());
+        let session = auth.rate_limits("alice");
+        session[0].consume(150);
+        assert!(auth.admits("alice").is_ok());
+        // Over quota, every session of the user, the one in progress
+        // included, draws on one throttle_rate bucket
+        assert!(session[0].delay().unwrap().is_zero());
+        auth.rate_limits("alice")[0].consume(2048);
+        assert!(!session[0].delay().unwrap().is_zero());
+        assert!(auth.rate_limits("bob")[0].delay().unwrap().is_zero());
+        quotas.save().unwrap();
+
+        let reloaded = Quotas::new(&config(QuotaAction::Throttle, Some(path))).unwrap();
+        assert_eq!(reloaded.used("alice"), 150 + 2048);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
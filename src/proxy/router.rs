@@ -0,0 +1,428 @@
+//! Rule-based outbound routing: `[[rules]]` decide, target by target,
+//! whether a connection is dialed directly, through `[upstream]` or a named
+//! `[upstreams]` parent, or refused. `Router` is itself a `Dialer`, so every
+//! handler consults it before connecting.
+//!
+//! When a rule has a `cidr`, domain targets are resolved once here and the
+//! rules matched against their addresses. Those same addresses are then
+//! dialed, so a DNS answer that changes between the check and the connect,
+//! as in DNS rebinding, cannot route a connection past the rules.
+
+use async_trait::async_trait;
+use regex::Regex;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::common::config::{RuleAction, RuleConfig};
+use crate::net::addr::TargetAddr;
+use crate::net::sockopt::Bind;
+use crate::net::stream::Stream;
+use crate::proxy::dialer::Dialer;
+use crate::proxy::forward::{self, ConnectError};
+
+struct Rule {
+    config: RuleConfig,
+    regex: Option<Regex>,
+    /// Dialer of a `direct` rule binding its sockets, instead of the
+    /// router's
+    direct: Option<Arc<dyn Dialer>>,
+}
+
+impl Rule {
+    /// Whether the rule holds for `target`, which resolved to `addrs` if it
+    /// is a domain that was looked up.
+    fn matches(&self, target: &TargetAddr, addrs: &[SocketAddr]) -> bool {
+        let config = &self.config;
+        if !config.ports.is_empty()
+            && !config
+                .ports
+                .iter()
+                .any(|range| range.contains(target.port()))
+        {
+            return false;
+        }
+        match target {
+            TargetAddr::Ip(addr) => {
+                config.domain.is_none()
+                    && config.domain_suffix.is_none()
+                    && self.regex.is_none()
+                    && config.cidr.is_none_or(|cidr| cidr.contains(&addr.ip()))
+            }
+            TargetAddr::Domain(domain, _) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                config
+                    .cidr
+                    .is_none_or(|cidr| addrs.iter().any(|addr| cidr.contains(&addr.ip())))
+                    && config
+                        .domain
+                        .as_ref()
+                        .is_none_or(|exact| exact.eq_ignore_ascii_case(&domain))
+                    && config
+                        .domain_suffix
+                        .as_ref()
+                        .is_none_or(|suffix| has_suffix(&domain, suffix))
+                    && self
+                        .regex
+                        .as_ref()
+                        .is_none_or(|regex| regex.is_match(&domain))
+            }
+        }
+    }
+}
+
+/// Whether `domain` is `suffix` or one of its subdomains.
+fn has_suffix(domain: &str, suffix: &str) -> bool {
+    let suffix = suffix.trim_start_matches('.').to_ascii_lowercase();
+    domain
+        .strip_suffix(&suffix)
+        .is_some_and(|sub| sub.is_empty() || sub.ends_with('.'))
+}
+
+pub struct Router {
+    rules: Vec<Rule>,
+    direct: Arc<dyn Dialer>,
+    /// `[upstream]`, or direct without it: for `proxy` rules naming no
+    /// upstream and for targets no rule matches
+    default: Arc<dyn Dialer>,
+    upstreams: HashMap<String, Arc<dyn Dialer>>,
+    /// Some rule has a `cidr`, so domains are resolved to match it
+    resolve: bool,
+}
+
+impl Router {
+    pub fn new(
+        rules: &[RuleConfig],
+        direct: Arc<dyn Dialer>,
+        default: Arc<dyn Dialer>,
+        upstreams: HashMap<String, Arc<dyn Dialer>>,
+    ) -> Self {
+        let rules = rules
+            .iter()
+            .map(|config| Rule {
+                // Validated along with the rest of the configuration
+                regex: config
+                    .domain_regex
+                    .as_ref()
+                    .map(|pattern| Regex::new(pattern).unwrap()),
+                config: config.clone(),
+                direct: None,
+            })
+            .collect::<Vec<Rule>>();
+        Router {
+            resolve: rules.iter().any(|rule| rule.config.cidr.is_some()),
+            rules,
+            direct,
+            default,
+            upstreams,
+        }
+    }
+
+    /// Gives each `direct` rule with a `bind_address` or `interface` the
+    /// dialer `bound` makes for its binding.
+    pub fn with_bound(mut self, bound: impl Fn(Bind) -> Arc<dyn Dialer>) -> Self {
+        for rule in &mut self.rules {
+            let bind = Bind {
+                address: rule.config.bind_address,
+                interface: rule.config.interface.clone(),
+            };
+            if !bind.is_empty() {
+                rule.direct = Some(bound(bind));
+            }
+        }
+        self
+    }
+
+    /// The dialer for `target`, resolved to `addrs`, or `None` when it is
+    /// blocked.
+    fn select(&self, target: &TargetAddr, addrs: &[SocketAddr]) -> Option<&Arc<dyn Dialer>> {
+        let Some(rule) = self.rules.iter().find(|rule| rule.matches(target, addrs)) else {
+            return Some(&self.default);
+        };
+        match (rule.config.action, &rule.config.upstream) {
+            (RuleAction::Direct, _) => Some(rule.direct.as_ref().unwrap_or(&self.direct)),
+            // Validated to name an `[upstreams]` entry
+            (RuleAction::Proxy, Some(name)) => self.upstreams.get(name),
+            (RuleAction::Proxy, None) => Some(&self.default),
+            (RuleAction::Block, _) => None,
+        }
+    }
+
+    fn dialer_for(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+    ) -> Result<&Arc<dyn Dialer>, ConnectError> {
+        self.select(target, addrs)
+            .ok_or_else(|| ConnectError::Blocked(target.to_string()))
+    }
+
+    /// Resolves `target` if the rules need its addresses, then dials it
+    /// through the dialer they pick, at those addresses. A name that does
+    /// not resolve is refused unless a rule ahead of every `cidr` one
+    /// decides it, rather than routed past the rules it was not checked
+    /// against.
+    async fn dial_routed(
+        &self,
+        target: &TargetAddr,
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let addrs = if self.resolves(target) {
+            match forward::resolve_all(&target.to_string()).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    let first = self
+                        .rules
+                        .iter()
+                        .find(|rule| rule.config.cidr.is_some() || rule.matches(target, &[]));
+                    if first.is_none_or(|rule| rule.config.cidr.is_some()) {
+                        return Err(e);
+                    }
+                    // A name only a parent can resolve
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        let dialer = self.dialer_for(target, &addrs)?;
+        match client {
+            _ if !addrs.is_empty() => dialer.dial_resolved(target, &addrs, client).await,
+            Some((source, destination)) => dialer.dial_from(target, source, destination).await,
+            None => dialer.dial(target).await,
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for Router {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        self.dial_routed(target, None).await
+    }
+
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        self.dial_routed(target, Some((source, destination))).await
+    }
+
+    fn is_per_client(&self, target: &TargetAddr) -> bool {
+        self.select(target, &[])
+            .is_some_and(|dialer| dialer.is_per_client(target))
+    }
+
+    fn route(&self, target: &TargetAddr) -> Result<Option<Arc<dyn Dialer>>, ConnectError> {
+        // The choice waits for the lookup
+        if self.resolves(target) {
+            return Ok(None);
+        }
+        self.dialer_for(target, &[])
+            .map(|dialer| Some(dialer.clone()))
+    }
+
+    fn resolves(&self, target: &TargetAddr) -> bool {
+        self.resolve && matches!(target, TargetAddr::Domain(..))
+    }
+
+    async fn dial_resolved(
+        &self,
+        target: &TargetAddr,
+        addrs: &[SocketAddr],
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        self.dialer_for(target, addrs)?
+            .dial_resolved(target, addrs, client)
+            .await
+    }
+
+    fn check_datagram(&self, target: &TargetAddr, addr: SocketAddr) -> Result<(), ConnectError> {
+        self.dialer_for(target, &[addr])?
+            .check_datagram(target, addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::dialer::DirectDialer;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    fn rule(action: RuleAction) -> RuleConfig {
+        RuleConfig {
+            domain: None,
+            domain_suffix: None,
+            domain_regex: None,
+            cidr: None,
+            ports: Vec::new(),
+            action,
+            upstream: None,
+            bind_address: None,
+            interface: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select() {
+        let dialer = || -> Arc<dyn Dialer> { Arc::new(DirectDialer::new(Duration::from_secs(5))) };
+        let (direct, default, corp) = (dialer(), dialer(), dialer());
+        let rules = vec![
+            RuleConfig {
+                domain_suffix: Some("ads.example".to_string()),
+                ..rule(RuleAction::Block)
+            },
+            RuleConfig {
+                domain_regex: Some("^intranet[0-9]*\\.".to_string()),
+                upstream: Some("corp".to_string()),
+                ..rule(RuleAction::Proxy)
+            },
+            RuleConfig {
+                cidr: Some("10.0.0.0/8".parse().unwrap()),
+                ports: vec!["22".to_string().try_into().unwrap()],
+                ..rule(RuleAction::Direct)
+            },
+            RuleConfig {
+                domain: Some("example.com".to_string()),
+                ..rule(RuleAction::Direct)
+            },
+        ];
+        let router = Router::new(
+            &rules,
+            direct.clone(),
+            default.clone(),
+            HashMap::from([("corp".to_string(), corp.clone())]),
+        );
+        let select = |target: &str| router.select(&TargetAddr::parse(target).unwrap(), &[]);
+
+        assert!(select("ads.example:443").is_none());
+        assert!(select("tracker.ADS.example:443").is_none());
+        assert!(Arc::ptr_eq(select("notads.example:443").unwrap(), &default));
+        assert!(Arc::ptr_eq(select("intranet2.corp:80").unwrap(), &corp));
+        assert!(Arc::ptr_eq(select("10.1.2.3:22").unwrap(), &direct));
+        assert!(Arc::ptr_eq(select("10.1.2.3:80").unwrap(), &default));
+        assert!(Arc::ptr_eq(select("Example.com.:80").unwrap(), &direct));
+        assert!(Arc::ptr_eq(select("www.example.com:80").unwrap(), &default));
+
+        let target = TargetAddr::parse("ads.example:443").unwrap();
+        assert!(matches!(
+            router.dial(&target).await,
+            Err(ConnectError::Blocked(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cidr_matches_resolved_domain() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let direct: Arc<dyn Dialer> = Arc::new(DirectDialer::new(Duration::from_secs(5)));
+        let rules = vec![
+            RuleConfig {
+                cidr: Some("127.0.0.0/8".parse().unwrap()),
+                ports: vec![port.to_string().try_into().unwrap()],
+                ..rule(RuleAction::Direct)
+            },
+            RuleConfig {
+                cidr: Some("127.0.0.0/8".parse().unwrap()),
+                ..rule(RuleAction::Block)
+            },
+        ];
+        let router = Router::new(&rules, direct.clone(), direct, HashMap::new());
+
+        let target = TargetAddr::Domain("localhost".to_string(), port);
+        assert!(router.resolves(&target));
+        assert!(router.route(&target).unwrap().is_none());
+        router.dial(&target).await.unwrap();
+
+        let target = TargetAddr::Domain("localhost".to_string(), 1);
+        assert!(matches!(
+            router.dial(&target).await,
+            Err(ConnectError::Blocked(_))
+        ));
+
+        // Datagrams are matched at the address they were resolved to
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        assert!(matches!(
+            router.check_datagram(&target, addr),
+            Err(ConnectError::Blocked(_))
+        ));
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        router.check_datagram(&TargetAddr::Ip(addr), addr).unwrap();
+    }
+
+    /// Records the addresses each dial was handed, empty for a plain `dial`.
+    #[derive(Default)]
+    struct Recorder {
+        dialed: Mutex<Vec<Vec<SocketAddr>>>,
+    }
+
+    #[async_trait]
+    impl Dialer for Recorder {
+        async fn dial(&self, _target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+            self.dialed.lock().unwrap().push(Vec::new());
+            Ok(Box::new(tokio::io::duplex(64).0))
+        }
+
+        async fn dial_resolved(
+            &self,
+            _target: &TargetAddr,
+            addrs: &[SocketAddr],
+            _client: Option<(SocketAddr, SocketAddr)>,
+        ) -> Result<Box<dyn Stream>, ConnectError> {
+            self.dialed.lock().unwrap().push(addrs.to_vec());
+            Ok(Box::new(tokio::io::duplex(64).0))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unresolved_domain_fails_closed() {
+        let recorder = Arc::new(Recorder::default());
+        let rules = vec![
+            RuleConfig {
+                domain_suffix: Some("onion".to_string()),
+                ..rule(RuleAction::Proxy)
+            },
+            RuleConfig {
+                cidr: Some("10.0.0.0/8".parse().unwrap()),
+                ..rule(RuleAction::Block)
+            },
+        ];
+        let router = Router::new(&rules, recorder.clone(), recorder.clone(), HashMap::new());
+
+        let target = TargetAddr::Domain("nonexistent.invalid".to_string(), 80);
+        assert!(router.dial(&target).await.is_err());
+        assert!(recorder.dialed.lock().unwrap().is_empty());
+
+        // Decided before the `cidr` rule, so left for the parent to resolve
+        let target = TargetAddr::Domain("example.onion".to_string(), 80);
+        router.dial(&target).await.unwrap();
+        assert_eq!(recorder.dialed.lock().unwrap().pop(), Some(Vec::new()));
+
+        // The addresses checked are the ones handed on
+        let target = TargetAddr::Domain("localhost".to_string(), 80);
+        router.dial(&target).await.unwrap();
+        let dialed = recorder.dialed.lock().unwrap();
+        assert_eq!(dialed.len(), 1);
+        assert!(dialed[0].iter().any(|addr| addr.ip().is_loopback()));
+    }
+
+    #[tokio::test]
+    async fn test_bound_direct_rule() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = TargetAddr::Ip(listener.local_addr().unwrap());
+        let direct: Arc<dyn Dialer> = Arc::new(DirectDialer::new(Duration::from_secs(5)));
+        let rules = vec![RuleConfig {
+            bind_address: Some("127.0.0.2".parse().unwrap()),
+            ..rule(RuleAction::Direct)
+        }];
+        let router = Router::new(&rules, direct.clone(), direct, HashMap::new())
+            .with_bound(|bind| Arc::new(DirectDialer::new(Duration::from_secs(5)).with_bind(bind)));
+
+        let _stream = router.dial(&target).await.unwrap();
+        let (_, source) = listener.accept().await.unwrap();
+        assert_eq!(source.ip().to_string(), "127.0.0.2");
+    }
+}
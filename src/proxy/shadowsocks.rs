@@ -0,0 +1,267 @@
+//! Inbound Shadowsocks: clients open an AEAD session (see
+//! `net::shadowsocks`) whose first bytes name the target in SOCKS5 address
+//! form, followed directly by the payload. The server sends no reply.
+
+use log::info;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::AsyncReadExt;
+use tokio::time::timeout;
+
+use crate::common::auth::{self, AuthProvider};
+use crate::common::config::{ShadowsocksConfig, UserConfig};
+use crate::net::addr::TargetAddr;
+use crate::net::conn::BufferedConnection;
+use crate::net::rate_limit::RateLimiter;
+use crate::net::shadowsocks::{self, AeadStream, ShadowsocksKey};
+use crate::net::stream::Stream;
+use crate::proxy::dialer::Dialer;
+use crate::proxy::forward::{self, ConnectError};
+use crate::proxy::socks5::codec::{self, CodecError};
+
+#[derive(Error, Debug)]
+pub enum ShadowsocksProxyError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Handshake timed out")]
+    HandshakeTimeout,
+    #[error("Client key matches no user")]
+    UnknownKey,
+    #[error("Invalid target address: {0}")]
+    InvalidAddress(#[from] CodecError),
+    #[error("User '{0}' may not connect to port {1}")]
+    PortNotAllowed(String, u16),
+    #[error("User refused: {0}")]
+    UserRefused(#[from] auth::AuthError),
+    #[error("Connection error: {0}")]
+    ConnectError(#[from] ConnectError),
+}
+
+/// The keys clients may connect with, and the user each one authenticates.
+pub struct InboundKeys {
+    keys: Vec<ShadowsocksKey>,
+    /// `None` for the shared `shadowsocks.password` key
+    users: Vec<Option<String>>,
+}
+
+impl InboundKeys {
+    pub fn new(config: &ShadowsocksConfig, users: &HashMap<String, UserConfig>) -> Self {
+        // Keys derive from plain passwords, which hashed entries lack
+        let mut names: Vec<&String> = users
+            .iter()
+            .filter(|(_, user)| !auth::is_password_hash(&user.password))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        let mut inbound = InboundKeys {
+            keys: Vec::new(),
+            users: Vec::new(),
+        };
+        for name in names {
+            inbound
+                .keys
+                .push(ShadowsocksKey::new(config.cipher, &users[name].password));
+            inbound.users.push(Some(name.clone()));
+        }
+        if let Some(password) = config.password.as_deref().filter(|_| users.is_empty()) {
+            inbound
+                .keys
+                .push(ShadowsocksKey::new(config.cipher, password));
+            inbound.users.push(None);
+        }
+        inbound
+    }
+}
+
+pub struct ShadowsocksProxy {
+    auth_manager: Arc<dyn AuthProvider>,
+    handshake_timeout: Duration,
+    dialer: Arc<dyn Dialer>,
+    keys: Arc<InboundKeys>,
+}
+
+impl ShadowsocksProxy {
+    pub fn new(
+        auth_manager: Arc<dyn AuthProvider>,
+        handshake_timeout: Duration,
+        dialer: Arc<dyn Dialer>,
+        keys: Arc<InboundKeys>,
+    ) -> Self {
+        ShadowsocksProxy {
+            auth_manager,
+            handshake_timeout,
+            dialer,
+            keys,
+        }
+    }
+
+    pub async fn handle_connection(
+        &self,
+        mut stream: impl Stream + 'static,
+        buffer_size: usize,
+        limits: &[Arc<RateLimiter>],
+    ) -> Result<(), ShadowsocksProxyError> {
+        let Some(first) = self.keys.keys.first() else {
+            return Err(ShadowsocksProxyError::UnknownKey);
+        };
+        let mut preamble = vec![0u8; first.preamble_len()];
+        timeout(self.handshake_timeout, stream.read_exact(&mut preamble))
+            .await
+            .map_err(|_| ShadowsocksProxyError::HandshakeTimeout)??;
+
+        let Some(index) = shadowsocks::identify(&self.keys.keys, &preamble) else {
+            // Keep reading rather than closing at once, so a prober cannot
+            // tell after how many bytes the server gave up
+            let _ = timeout(
+                self.handshake_timeout,
+                tokio::io::copy(&mut stream, &mut tokio::io::sink()),
+            )
+            .await;
+            return Err(ShadowsocksProxyError::UnknownKey);
+        };
+        let user = self.keys.users[index].as_deref();
+        let stream = AeadStream::resume(stream, self.keys.keys[index].clone(), preamble);
+        let mut conn = BufferedConnection::new(stream, buffer_size);
+        for limiter in limits {
+            conn.throttle(limiter.clone());
+        }
+        if let Some(user) = user {
+            self.auth_manager.admits(user)?;
+            for limiter in self.auth_manager.rate_limits(user) {
+                conn.throttle(limiter);
+            }
+        }
+
+        let target = timeout(self.handshake_timeout, Self::read_target(&mut conn))
+            .await
+            .map_err(|_| ShadowsocksProxyError::HandshakeTimeout)??;
+        info!(
+            "Shadowsocks request for {} from {}",
+            target,
+            user.unwrap_or("shared key")
+        );
+        if let Some(user) = user
+            && !self.auth_manager.allows_port(user, target.port())
+        {
+            return Err(ShadowsocksProxyError::PortNotAllowed(
+                user.to_string(),
+                target.port(),
+            ));
+        }
+
+        let target_stream = self
+            .dialer
+            .dial_from(&target, conn.peer_addr()?, conn.local_addr()?)
+            .await?;
+        info!("Connected to target: {}", target);
+
+        let mut target_conn = BufferedConnection::new(target_stream, buffer_size);
+        forward::forward_bidirectional(&mut conn, &mut target_conn, None).await?;
+        Ok(())
+    }
+
+    async fn read_target(
+        conn: &mut BufferedConnection,
+    ) -> Result<TargetAddr, ShadowsocksProxyError> {
+        loop {
+            if let Some((target, consumed)) = codec::decode_address(conn.buffered())? {
+                conn.drain_buffer(consumed);
+                return Ok(target);
+            }
+            if conn.read().await? == 0 {
+                return Err(ShadowsocksProxyError::IoError(
+                    io::ErrorKind::UnexpectedEof.into(),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::auth::AuthManager;
+    use crate::common::config::ShadowsocksCipher;
+    use crate::proxy::dialer::DirectDialer;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    fn config(password: Option<&str>) -> ShadowsocksConfig {
+        ShadowsocksConfig {
+            listen_address: "127.0.0.1:0".to_string(),
+            cipher: ShadowsocksCipher::Aes256Gcm,
+            password: password.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_keys() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let mut users = HashMap::new();
+        users.insert(
+            "alice".to_string(),
+            UserConfig::from("a-secret".to_string()),
+        );
+        users.insert("bob".to_string(), UserConfig::from("b-secret".to_string()));
+        let proxy = ShadowsocksProxy::new(
+            Arc::new(AuthManager::new(&HashMap::new()).unwrap()),
+            Duration::from_secs(5),
+            Arc::new(DirectDialer::new(Duration::from_secs(5))),
+            Arc::new(InboundKeys::new(&config(None), &users)),
+        );
+
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move { proxy.handle_connection(server, 4096, &[]).await });
+
+        let key = ShadowsocksKey::new(ShadowsocksCipher::Aes256Gcm, "b-secret");
+        let mut client = AeadStream::new(client, key);
+        let mut request = vec![0x01, 127, 0, 0, 1];
+        request.extend_from_slice(&echo_addr.port().to_be_bytes());
+        request.extend_from_slice(b"hello");
+        client.write_all(&request).await.unwrap();
+        client.flush().await.unwrap();
+
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        drop(client);
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_wrong_key() {
+        let proxy = ShadowsocksProxy::new(
+            Arc::new(AuthManager::new(&HashMap::new()).unwrap()),
+            Duration::from_secs(5),
+            Arc::new(DirectDialer::new(Duration::from_secs(5))),
+            Arc::new(InboundKeys::new(&config(Some("shared")), &HashMap::new())),
+        );
+
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move { proxy.handle_connection(server, 4096, &[]).await });
+
+        let key = ShadowsocksKey::new(ShadowsocksCipher::Aes256Gcm, "guess");
+        let mut client = AeadStream::new(client, key);
+        client
+            .write_all(b"\x01\x7f\x00\x00\x01\x00\x50")
+            .await
+            .unwrap();
+        client.flush().await.unwrap();
+        drop(client);
+        assert!(matches!(
+            server.await.unwrap(),
+            Err(ShadowsocksProxyError::UnknownKey)
+        ));
+    }
+}
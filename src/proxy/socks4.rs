@@ -0,0 +1,339 @@
+use log::info;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::timeout;
+
+use crate::common::auth::{AuthContext, AuthDecision, AuthError, AuthProvider};
+use crate::common::config::ListenerProtocol;
+use crate::net::addr::TargetAddr;
+use crate::net::conn::BufferedConnection;
+use crate::proxy::dialer::Dialer;
+use crate::proxy::forward;
+
+#[derive(Error, Debug)]
+pub enum Socks4ProxyError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Invalid SOCKS version: {0:#04x}")]
+    InvalidVersion(u8),
+    #[error("Unsupported command: {0:#04x}")]
+    UnsupportedCommand(u8),
+    #[error("Field exceeds {0} bytes")]
+    FieldTooLong(usize),
+    #[error("Authentication failed")]
+    AuthenticationFailed(#[from] AuthError),
+    #[error("Connection error: {0}")]
+    ConnectError(#[from] crate::proxy::forward::ConnectError),
+    #[error("Invalid UTF-8 data")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+    #[error("Request not received within the handshake timeout")]
+    HandshakeTimeout,
+}
+
+const CMD_CONNECT: u8 = 0x01;
+
+// SOCKS4 reply codes
+const REPLY_GRANTED: u8 = 0x5A;
+const REPLY_REJECTED: u8 = 0x5B;
+const REPLY_USERID_MISMATCH: u8 = 0x5D;
+
+const MAX_FIELD_LEN: usize = 255;
+
+struct Socks4Request {
+    command: u8,
+    target: TargetAddr,
+    userid: String,
+}
+
+pub struct Socks4Proxy {
+    auth_manager: Arc<dyn AuthProvider>,
+    dialer: Arc<dyn Dialer>,
+    handshake_timeout: Option<Duration>,
+}
+
+impl Socks4Proxy {
+    pub fn new(auth_manager: Arc<dyn AuthProvider>, dialer: Arc<dyn Dialer>) -> Self {
+        Socks4Proxy {
+            auth_manager,
+            dialer,
+            handshake_timeout: None,
+        }
+    }
+
+    /// Drops clients whose whole request has not arrived within `limit`.
+    pub fn with_handshake_timeout(mut self, limit: Duration) -> Self {
+        self.handshake_timeout = Some(limit);
+        self
+    }
+
+    pub async fn handle_connection(
+        &self,
+        conn: &mut BufferedConnection,
+    ) -> Result<(), Socks4ProxyError> {
+        info!("Handling SOCKS4 connection");
+
+        let request = match self.handshake_timeout {
+            Some(limit) => timeout(limit, self.handle_request(conn))
+                .await
+                .map_err(|_| Socks4ProxyError::HandshakeTimeout)??,
+            None => self.handle_request(conn).await?,
+        };
+
+        if request.command != CMD_CONNECT {
+            self.send_reply(conn, REPLY_REJECTED).await?;
+            return Err(Socks4ProxyError::UnsupportedCommand(request.command));
+        }
+
+        let user = match self.auth_manager.certificate_user(conn.client_identity()) {
+            Some(user) => Some(user),
+            None if self.auth_manager.is_required() => {
+                Some(self.authenticate(conn, &request.userid).await?)
+            }
+            None => None,
+        };
+        if let Some(user) = &user {
+            for limiter in self.auth_manager.rate_limits(user) {
+                conn.throttle(limiter);
+            }
+        }
+
+        let target_stream = match self
+            .dialer
+            .dial_from(&request.target, conn.peer_addr()?, conn.local_addr()?)
+            .await
+        {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = self.send_reply(conn, REPLY_REJECTED).await;
+                return Err(Socks4ProxyError::ConnectError(e));
+            }
+        };
+
+        info!("Connected to target: {}", request.target);
+
+        self.send_reply(conn, REPLY_GRANTED).await?;
+
+        let buffer_size = conn.buffer_size();
+        let mut target_conn = BufferedConnection::new(target_stream, buffer_size);
+        forward::forward_bidirectional(conn, &mut target_conn, None)
+            .await
+            .map_err(Socks4ProxyError::IoError)?;
+
+        Ok(())
+    }
+
+    /// SOCKS4 request, with the SOCKS4a domain extension:
+    /// +----+----+---------+--------+--------+------+----------+------+
+    /// | VN | CD | DSTPORT | DSTIP  | USERID | NULL | [DOMAIN] | NULL |
+    /// +----+----+---------+--------+--------+------+----------+------+
+    /// | 1  | 1  |    2    |   4    |  var   |  1   |   var    |  1   |
+    /// +----+----+---------+--------+--------+------+----------+------+
+    ///
+    /// A DSTIP of `0.0.0.x` with `x != 0` signals that a domain follows.
+    async fn handle_request(
+        &self,
+        conn: &mut BufferedConnection,
+    ) -> Result<Socks4Request, Socks4ProxyError> {
+        let header = conn.read_exact_buffered(8).await?;
+        let version = header[0];
+        let command = header[1];
+
+        if version != 0x04 {
+            return Err(Socks4ProxyError::InvalidVersion(version));
+        }
+
+        let port = u16::from_be_bytes([header[2], header[3]]);
+        let ip = Ipv4Addr::new(header[4], header[5], header[6], header[7]);
+        let userid = Self::read_null_terminated(conn).await?;
+
+        let octets = ip.octets();
+        let target = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+            let domain = Self::read_null_terminated(conn).await?;
+            TargetAddr::Domain(domain, port)
+        } else {
+            TargetAddr::Ip(SocketAddr::from((ip, port)))
+        };
+
+        Ok(Socks4Request {
+            command,
+            target,
+            userid,
+        })
+    }
+
+    /// SOCKS4 carries no password, so the USERID field is expected to hold
+    /// `username:password` whenever users are configured. Returns the
+    /// username.
+    async fn authenticate(
+        &self,
+        conn: &mut BufferedConnection,
+        userid: &str,
+    ) -> Result<String, Socks4ProxyError> {
+        let context = AuthContext {
+            client: conn.peer_addr()?,
+            protocol: ListenerProtocol::Socks4,
+        };
+        let auth_success = match userid.split_once(':') {
+            Some((username, password)) => {
+                match self
+                    .auth_manager
+                    .authenticate(username, password, &context)
+                    .await
+                {
+                    AuthDecision::Allow => true,
+                    AuthDecision::Deny => false,
+                    AuthDecision::Error(e) => {
+                        self.send_reply(conn, REPLY_USERID_MISMATCH).await?;
+                        return Err(Socks4ProxyError::AuthenticationFailed(e));
+                    }
+                }
+            }
+            None => false,
+        };
+
+        if !auth_success {
+            self.send_reply(conn, REPLY_USERID_MISMATCH).await?;
+            return Err(Socks4ProxyError::AuthenticationFailed(
+                AuthError::AuthenticationFailed,
+            ));
+        }
+
+        Ok(userid.split_once(':').unwrap_or_default().0.to_string())
+    }
+
+    async fn read_null_terminated(
+        conn: &mut BufferedConnection,
+    ) -> Result<String, Socks4ProxyError> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = conn.read_exact_buffered(1).await?[0];
+            if byte == 0 {
+                break;
+            }
+            if bytes.len() == MAX_FIELD_LEN {
+                return Err(Socks4ProxyError::FieldTooLong(MAX_FIELD_LEN));
+            }
+            bytes.push(byte);
+        }
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    /// Reply layout: VN (0x00) | CD | DSTPORT | DSTIP — the address fields are
+    /// ignored by clients for CONNECT.
+    async fn send_reply(
+        &self,
+        conn: &mut BufferedConnection,
+        reply_code: u8,
+    ) -> Result<(), Socks4ProxyError> {
+        conn.write(&[0x00, reply_code, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::auth::AuthManager;
+    use crate::common::config::UserConfig;
+    use crate::proxy::dialer::DirectDialer;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn spawn_proxy(users: HashMap<String, UserConfig>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let auth_manager = Arc::new(AuthManager::new(&users).unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            let proxy = Socks4Proxy::new(
+                auth_manager,
+                Arc::new(DirectDialer::new(Duration::from_secs(5))),
+            );
+            let _ = proxy.handle_connection(&mut conn).await;
+        });
+        addr
+    }
+
+    async fn spawn_echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_socks4a_connect() {
+        let echo_addr = spawn_echo().await;
+        let proxy_addr = spawn_proxy(HashMap::new()).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        let mut request = vec![0x04, 0x01];
+        request.extend_from_slice(&echo_addr.port().to_be_bytes());
+        request.extend_from_slice(&[0, 0, 0, 1]);
+        request.extend_from_slice(b"anonymous\x00127.0.0.1\x00");
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], REPLY_GRANTED);
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_socks4_userid_auth() {
+        let mut users = HashMap::new();
+        users.insert("admin".to_string(), "password".to_string().into());
+        let proxy_addr = spawn_proxy(users).await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+
+        client
+            .write_all(b"\x04\x01\x00\x50\x7f\x00\x00\x01admin\0")
+            .await
+            .unwrap();
+
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], REPLY_USERID_MISMATCH);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            let proxy = Socks4Proxy::new(
+                Arc::new(AuthManager::new(&HashMap::new()).unwrap()),
+                Arc::new(DirectDialer::new(Duration::from_secs(5))),
+            )
+            .with_handshake_timeout(Duration::from_millis(200));
+            proxy.handle_connection(&mut conn).await
+        });
+
+        // A lone version byte never completes the request
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"\x04").await.unwrap();
+        let result = timeout(Duration::from_secs(5), server).await.unwrap();
+        assert!(matches!(
+            result.unwrap(),
+            Err(Socks4ProxyError::HandshakeTimeout)
+        ));
+        let mut buf = [0u8; 8];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+}
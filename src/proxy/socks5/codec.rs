@@ -0,0 +1,314 @@
+//! SOCKS5 wire format, decoupled from IO.
+//!
+//! Each `decode_*` function inspects a byte slice and returns
+//! `Ok(Some((frame, consumed)))` once a complete frame is available,
+//! `Ok(None)` when more bytes are needed, or an error as soon as the input
+//! is known to be malformed.
+
+use std::net::SocketAddr;
+use std::string::FromUtf8Error;
+use thiserror::Error;
+
+use crate::net::addr::TargetAddr;
+
+const SOCKS_VERSION: u8 = 0x05;
+
+// Address types (RFC 1928 §5)
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+    #[error("Invalid SOCKS version: {0:#04x}")]
+    InvalidVersion(u8),
+    #[error("Invalid address type: {0:#04x}")]
+    InvalidAddressType(u8),
+    #[error("Invalid UTF-8 data")]
+    InvalidUtf8(#[from] FromUtf8Error),
+}
+
+pub type Decoded<T> = Result<Option<(T, usize)>, CodecError>;
+
+/// Client greeting: VER | NMETHODS | METHODS
+#[derive(Debug, PartialEq, Eq)]
+pub struct Greeting {
+    pub methods: Vec<u8>,
+}
+
+/// RFC 1929 sub-negotiation: VER | ULEN | UNAME | PLEN | PASSWD
+#[derive(Debug, PartialEq, Eq)]
+pub struct AuthRequest {
+    pub version: u8,
+    pub username: String,
+    pub password: String,
+}
+
+/// Client request: VER | CMD | RSV | ATYP | DST.ADDR | DST.PORT
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub command: u8,
+    pub target: TargetAddr,
+}
+
+pub fn decode_greeting(buf: &[u8]) -> Decoded<Greeting> {
+    let Some(&version) = buf.first() else {
+        return Ok(None);
+    };
+    if version != SOCKS_VERSION {
+        return Err(CodecError::InvalidVersion(version));
+    }
+    let Some(&nmethods) = buf.get(1) else {
+        return Ok(None);
+    };
+    let end = 2 + nmethods as usize;
+    match buf.get(2..end) {
+        Some(methods) => Ok(Some((
+            Greeting {
+                methods: methods.to_vec(),
+            },
+            end,
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// The sub-negotiation version is returned rather than validated so the
+/// caller can decide how strict to be.
+pub fn decode_auth_request(buf: &[u8]) -> Decoded<AuthRequest> {
+    let Some(&version) = buf.first() else {
+        return Ok(None);
+    };
+    let Some((username, pos)) = length_prefixed(buf, 1) else {
+        return Ok(None);
+    };
+    let Some((password, end)) = length_prefixed(buf, pos) else {
+        return Ok(None);
+    };
+    Ok(Some((
+        AuthRequest {
+            version,
+            username: String::from_utf8(username.to_vec())?,
+            password: String::from_utf8(password.to_vec())?,
+        },
+        end,
+    )))
+}
+
+pub fn decode_request(buf: &[u8]) -> Decoded<Request> {
+    let Some(&version) = buf.first() else {
+        return Ok(None);
+    };
+    if version != SOCKS_VERSION {
+        return Err(CodecError::InvalidVersion(version));
+    }
+    if buf.len() < 3 {
+        return Ok(None);
+    }
+    let command = buf[1];
+    Ok(decode_address(&buf[3..])?.map(|(target, len)| (Request { command, target }, 3 + len)))
+}
+
+/// UDP datagram header (RFC 1928 §7): RSV | FRAG | ATYP | DST.ADDR | DST.PORT.
+/// Returns the fragment number alongside the destination.
+pub fn decode_udp_header(buf: &[u8]) -> Decoded<(u8, TargetAddr)> {
+    if buf.len() < 3 {
+        return Ok(None);
+    }
+    let frag = buf[2];
+    Ok(decode_address(&buf[3..])?.map(|(target, len)| ((frag, target), 3 + len)))
+}
+
+/// ATYP | ADDR | PORT, which Shadowsocks also uses for its target
+pub fn decode_address(buf: &[u8]) -> Decoded<TargetAddr> {
+    let Some(&addr_type) = buf.first() else {
+        return Ok(None);
+    };
+    let addr_end = match addr_type {
+        ATYP_IPV4 => 5,
+        ATYP_IPV6 => 17,
+        ATYP_DOMAIN => match buf.get(1) {
+            Some(&len) => 2 + len as usize,
+            None => return Ok(None),
+        },
+        other => return Err(CodecError::InvalidAddressType(other)),
+    };
+    let Some(port_bytes) = buf.get(addr_end..addr_end + 2) else {
+        return Ok(None);
+    };
+    let port = u16::from_be_bytes([port_bytes[0], port_bytes[1]]);
+
+    let target = match addr_type {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = buf[1..5].try_into().expect("length checked");
+            TargetAddr::Ip(SocketAddr::from((octets, port)))
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = buf[1..17].try_into().expect("length checked");
+            TargetAddr::Ip(SocketAddr::from((octets, port)))
+        }
+        _ => TargetAddr::Domain(String::from_utf8(buf[2..addr_end].to_vec())?, port),
+    };
+    Ok(Some((target, addr_end + 2)))
+}
+
+/// Reply: VER | REP | RSV | ATYP | BND.ADDR | BND.PORT
+pub fn encode_reply(reply_code: u8, bound_addr: SocketAddr) -> Vec<u8> {
+    let mut reply = vec![SOCKS_VERSION, reply_code, 0x00];
+    encode_address(&mut reply, bound_addr);
+    reply
+}
+
+/// UDP datagram header for a reply from `source`, without fragmentation.
+pub fn encode_udp_header(source: SocketAddr) -> Vec<u8> {
+    let mut header = vec![0x00, 0x00, 0x00];
+    encode_address(&mut header, source);
+    header
+}
+
+fn encode_address(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr {
+        SocketAddr::V4(addr) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Reads a one-byte length followed by that many bytes, starting at `pos`.
+fn length_prefixed(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let len = *buf.get(pos)? as usize;
+    let end = pos + 1 + len;
+    buf.get(pos + 1..end).map(|bytes| (bytes, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every strict prefix of a valid frame must ask for more input.
+    fn assert_incomplete_prefixes<T: std::fmt::Debug>(
+        frame: &[u8],
+        decode: fn(&[u8]) -> Decoded<T>,
+    ) {
+        for len in 0..frame.len() {
+            assert!(
+                matches!(decode(&frame[..len]), Ok(None)),
+                "prefix of length {} should be incomplete",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn test_greeting() {
+        let frame = b"\x05\x02\x00\x02";
+        assert_incomplete_prefixes(frame, decode_greeting);
+
+        let (greeting, consumed) = decode_greeting(b"\x05\x02\x00\x02extra").unwrap().unwrap();
+        assert_eq!(greeting.methods, vec![0x00, 0x02]);
+        assert_eq!(consumed, 4);
+
+        assert!(matches!(
+            decode_greeting(b"\x04\x01"),
+            Err(CodecError::InvalidVersion(0x04))
+        ));
+    }
+
+    #[test]
+    fn test_auth_request() {
+        let frame = b"\x01\x05alice\x06secret";
+        assert_incomplete_prefixes(frame, decode_auth_request);
+
+        let (auth, consumed) = decode_auth_request(frame).unwrap().unwrap();
+        assert_eq!(auth.version, 0x01);
+        assert_eq!(auth.username, "alice");
+        assert_eq!(auth.password, "secret");
+        assert_eq!(consumed, frame.len());
+
+        assert!(matches!(
+            decode_auth_request(b"\x01\x01\xff\x00"),
+            Err(CodecError::InvalidUtf8(_))
+        ));
+    }
+
+    #[test]
+    fn test_request() {
+        let frame = b"\x05\x01\x00\x03\x0bexample.com\x01\xbb";
+        assert_incomplete_prefixes(frame, decode_request);
+
+        let (request, consumed) = decode_request(frame).unwrap().unwrap();
+        assert_eq!(request.command, 0x01);
+        assert_eq!(
+            request.target,
+            TargetAddr::Domain("example.com".to_string(), 443)
+        );
+        assert_eq!(consumed, frame.len());
+
+        let ipv6 = b"\x05\x01\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x50";
+        let (request, _) = decode_request(ipv6).unwrap().unwrap();
+        assert_eq!(request.target, TargetAddr::Ip("[::1]:80".parse().unwrap()));
+
+        assert!(matches!(
+            decode_request(b"\x05\x01\x00\x09"),
+            Err(CodecError::InvalidAddressType(0x09))
+        ));
+        assert!(matches!(
+            decode_request(b"\x04\x01\x00\x01"),
+            Err(CodecError::InvalidVersion(0x04))
+        ));
+    }
+
+    #[test]
+    fn test_reply_and_udp_header_roundtrip() {
+        let addr: SocketAddr = "10.1.2.3:5353".parse().unwrap();
+        assert_eq!(
+            encode_reply(0x00, addr),
+            b"\x05\x00\x00\x01\x0a\x01\x02\x03\x14\xe9"
+        );
+
+        let header = encode_udp_header(addr);
+        assert_incomplete_prefixes(&header, decode_udp_header);
+        let mut packet = header.clone();
+        packet.extend_from_slice(b"payload");
+        let ((frag, target), consumed) = decode_udp_header(&packet).unwrap().unwrap();
+        assert_eq!(frag, 0);
+        assert_eq!(target, TargetAddr::Ip(addr));
+        assert_eq!(consumed, header.len());
+        assert_eq!(&packet[consumed..], b"payload");
+    }
+
+    #[test]
+    fn test_udp_header_domain_and_malformed() {
+        let packet = b"\x00\x00\x00\x03\x0bexample.com\x00\x35query";
+        let ((frag, target), consumed) = decode_udp_header(packet).unwrap().unwrap();
+        assert_eq!(frag, 0);
+        assert_eq!(target, TargetAddr::Domain("example.com".to_string(), 53));
+        assert_eq!(&packet[consumed..], b"query");
+
+        // Fragmented: the fragment number is handed back for the relay to
+        // drop the datagram
+        let ((frag, _), _) = decode_udp_header(b"\x00\x00\x01\x01\x7f\x00\x00\x01\x00\x35")
+            .unwrap()
+            .unwrap();
+        assert_eq!(frag, 1);
+        // Truncated address
+        assert!(matches!(
+            decode_udp_header(b"\x00\x00\x00\x04\x00\x00"),
+            Ok(None)
+        ));
+        assert!(matches!(
+            decode_udp_header(b"\x00\x00\x00\x09\x00"),
+            Err(CodecError::InvalidAddressType(0x09))
+        ));
+        assert!(matches!(
+            decode_udp_header(b"\x00\x00\x00\x03\x01\xff\x00\x35"),
+            Err(CodecError::InvalidUtf8(_))
+        ));
+    }
+}
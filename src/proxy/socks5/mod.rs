@@ -0,0 +1,997 @@
+use log::info;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+use crate::common::auth::{AuthContext, AuthDecision, AuthError, AuthProvider};
+use crate::common::config::{
+    ListenerProtocol, ResolveStrategy, Socks5Command, Socks5Config, UdpConfig,
+};
+use crate::net::addr::TargetAddr;
+use crate::net::conn::BufferedConnection;
+use crate::proxy::dialer::Dialer;
+use crate::proxy::forward::{self, ConnectError, Transfer};
+use crate::proxy::ssrf::SsrfGuard;
+use crate::proxy::udp;
+
+pub mod codec;
+
+use codec::{CodecError, Decoded};
+
+#[derive(Error, Debug)]
+pub enum Socks5ProxyError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Invalid SOCKS version: {0:#04x}")]
+    InvalidVersion(u8),
+    #[error("No supported authentication method")]
+    NoSupportedAuthMethod,
+    #[error("Invalid authentication sub-negotiation version: {0:#04x}")]
+    InvalidAuthVersion(u8),
+    #[error("Authentication failed")]
+    AuthenticationFailed(#[from] AuthError),
+    #[error("Unsupported command: {0:#04x}")]
+    UnsupportedCommand(u8),
+    #[error("Invalid address type: {0:#04x}")]
+    InvalidAddressType(u8),
+    #[error("Connection error: {0}")]
+    ConnectError(#[from] ConnectError),
+    #[error("Invalid UTF-8 data")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+    #[error("Timed out waiting for BIND peer")]
+    BindTimeout,
+    #[error("Unexpected BIND peer: {0}")]
+    BindPeerMismatch(SocketAddr),
+    #[error("Handshake timed out")]
+    HandshakeTimeout,
+    #[error("User '{0}' may not connect to port {1}")]
+    PortNotAllowed(String, u16),
+}
+
+// SOCKS5 commands (RFC 1928 §4)
+const CMD_CONNECT: u8 = 0x01;
+const CMD_BIND: u8 = 0x02;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+// SOCKS5 reply codes (RFC 1928 §6)
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+// Username/password sub-negotiation (RFC 1929 §2)
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCESS: u8 = 0x00;
+const AUTH_FAILURE: u8 = 0x01;
+
+const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+impl From<CodecError> for Socks5ProxyError {
+    fn from(e: CodecError) -> Self {
+        match e {
+            CodecError::InvalidVersion(version) => Socks5ProxyError::InvalidVersion(version),
+            CodecError::InvalidAddressType(addr_type) => {
+                Socks5ProxyError::InvalidAddressType(addr_type)
+            }
+            CodecError::InvalidUtf8(e) => Socks5ProxyError::InvalidUtf8(e),
+        }
+    }
+}
+
+impl Socks5ProxyError {
+    /// Maps a failure to the REP code sent to the client, so it can report an
+    /// actionable error rather than a reset connection.
+    fn reply_code(&self) -> u8 {
+        match self {
+            Socks5ProxyError::UnsupportedCommand(_) => REPLY_COMMAND_NOT_SUPPORTED,
+            Socks5ProxyError::InvalidAddressType(_) => REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
+            Socks5ProxyError::BindTimeout => REPLY_TTL_EXPIRED,
+            Socks5ProxyError::BindPeerMismatch(_) | Socks5ProxyError::PortNotAllowed(..) => {
+                REPLY_NOT_ALLOWED
+            }
+            Socks5ProxyError::ConnectError(e) => match e {
+                ConnectError::ConnectionTimeout => REPLY_TTL_EXPIRED,
+                ConnectError::ConnectionRefused(_) => REPLY_CONNECTION_REFUSED,
+                ConnectError::AddressResolutionFailed(_) | ConnectError::AddressNotFound => {
+                    REPLY_HOST_UNREACHABLE
+                }
+                ConnectError::IoError(e) => io_reply_code(e),
+                // The parent speaks SOCKS5 too, so its reply code carries over
+                ConnectError::UpstreamRejected(code) => *code,
+                ConnectError::UpstreamAuthFailed
+                | ConnectError::UpstreamCredentialsTooLong
+                | ConnectError::UpstreamStatus(_)
+                | ConnectError::UpstreamProtocol(_) => REPLY_GENERAL_FAILURE,
+                ConnectError::Blocked(_) | ConnectError::Denied(_) => REPLY_NOT_ALLOWED,
+            },
+            Socks5ProxyError::IoError(e) => io_reply_code(e),
+            _ => REPLY_GENERAL_FAILURE,
+        }
+    }
+}
+
+fn io_reply_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        io::ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable => REPLY_HOST_UNREACHABLE,
+        io::ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        io::ErrorKind::TimedOut => REPLY_TTL_EXPIRED,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+pub struct Socks5Proxy {
+    auth_manager: Arc<dyn AuthProvider>,
+    connect_timeout: Duration,
+    dialer: Arc<dyn Dialer>,
+    config: Arc<Socks5Config>,
+    udp_config: Arc<UdpConfig>,
+    ssrf_guard: Option<Arc<SsrfGuard>>,
+}
+
+impl Socks5Proxy {
+    pub fn new(
+        auth_manager: Arc<dyn AuthProvider>,
+        connect_timeout: Duration,
+        dialer: Arc<dyn Dialer>,
+        config: Arc<Socks5Config>,
+        udp_config: Arc<UdpConfig>,
+    ) -> Self {
+        Socks5Proxy {
+            auth_manager,
+            connect_timeout,
+            dialer,
+            config,
+            udp_config,
+            ssrf_guard: None,
+        }
+    }
+
+    /// Drops UDP datagrams to the addresses `guard` denies. The relay sends
+    /// them from this host even when connections go through a parent.
+    pub fn with_ssrf_guard(mut self, guard: Option<Arc<SsrfGuard>>) -> Self {
+        self.ssrf_guard = guard;
+        self
+    }
+
+    pub async fn handle_connection(
+        &self,
+        conn: &mut BufferedConnection,
+    ) -> Result<(), Socks5ProxyError> {
+        info!("Handling SOCKS5 connection");
+        let started = Instant::now();
+
+        let handshake_timeout = Duration::from_secs(self.config.handshake_timeout);
+        let (request, user) = match timeout(handshake_timeout, self.negotiate(conn)).await {
+            Ok(result) => result?,
+            Err(_) => return Err(Socks5ProxyError::HandshakeTimeout),
+        };
+        if let Some(user) = &user {
+            for limiter in self.auth_manager.rate_limits(user) {
+                conn.throttle(limiter);
+            }
+        }
+
+        let (command, transfer) = match request.command {
+            CMD_BIND => ("bind", self.handle_bind(conn, &request.target).await?),
+            CMD_UDP_ASSOCIATE => (
+                "udp-associate",
+                self.handle_udp_associate(conn, &request.target, user.as_deref())
+                    .await?,
+            ),
+            _ => ("connect", self.handle_connect(conn, &request.target).await?),
+        };
+        // Connection bytes counted as they passed; datagrams only now
+        if let Some(user) = &user
+            && command == "udp-associate"
+        {
+            self.auth_manager
+                .record_usage(user, transfer.up + transfer.down);
+        }
+
+        info!(
+            "SOCKS5 session closed: client={} user={} command={} target={} up={} down={} duration_ms={}",
+            conn.peer_addr()?,
+            user.as_deref().unwrap_or("-"),
+            command,
+            request.target,
+            transfer.up,
+            transfer.down,
+            started.elapsed().as_millis(),
+        );
+        Ok(())
+    }
+
+    /// Greeting, optional sub-negotiation and request; bounded by
+    /// `handshake_timeout` so idle clients cannot pin a connection permit.
+    /// Returns the request along with the authenticated user, if any.
+    async fn negotiate(
+        &self,
+        conn: &mut BufferedConnection,
+    ) -> Result<(codec::Request, Option<String>), Socks5ProxyError> {
+        let certificate_user = self.auth_manager.certificate_user(conn.client_identity());
+        let selected_method = self.handshake(conn, certificate_user.is_some()).await?;
+
+        let user = match selected_method {
+            0x02 => Some(self.authenticate(conn).await?),
+            _ => certificate_user,
+        };
+
+        match self.handle_request(conn, user.as_deref()).await {
+            Ok(request) => Ok((request, user)),
+            Err(e) => Err(self.reject(conn, e).await),
+        }
+    }
+
+    async fn handle_connect(
+        &self,
+        conn: &mut BufferedConnection,
+        target: &TargetAddr,
+    ) -> Result<Transfer, Socks5ProxyError> {
+        // Rules match the requested domain, so they apply before the lookup
+        let dialer = match self.dialer.route(target) {
+            Ok(dialer) => dialer.unwrap_or_else(|| self.dialer.clone()),
+            Err(e) => return Err(self.reject(conn, e.into()).await),
+        };
+        // Rules on addresses need the domain, to look it up once themselves
+        let target = if dialer.resolves(target) {
+            target.clone()
+        } else {
+            match self.resolve_target(target).await {
+                Ok(target) => target,
+                Err(e) => return Err(self.reject(conn, e.into()).await),
+            }
+        };
+
+        let target_stream = match dialer
+            .dial_from(&target, conn.peer_addr()?, conn.local_addr()?)
+            .await
+        {
+            Ok(stream) => stream,
+            Err(e) => return Err(self.reject(conn, e.into()).await),
+        };
+
+        info!("Connected to target: {}", target);
+
+        // Some clients validate BND.ADDR, so report the outbound socket's address
+        let bound_addr = target_stream.local_addr()?;
+        self.send_reply(conn, REPLY_SUCCEEDED, bound_addr).await?;
+
+        let buffer_size = conn.buffer_size();
+        let mut target_conn = BufferedConnection::new(target_stream, buffer_size);
+        forward::forward_bidirectional(conn, &mut target_conn, self.idle_timeout())
+            .await
+            .map_err(Socks5ProxyError::IoError)
+    }
+
+    /// BIND (RFC 1928 §4): listen on the client-facing interface, report the
+    /// bound address in the first reply, then report the peer that connected
+    /// in the second reply before relaying. `DST.ADDR` restricts which peer
+    /// may connect unless it is the unspecified address.
+    async fn handle_bind(
+        &self,
+        conn: &mut BufferedConnection,
+        target: &TargetAddr,
+    ) -> Result<Transfer, Socks5ProxyError> {
+        let expected_ip = forward::resolve_address(&target.to_string())
+            .await
+            .ok()
+            .map(|addr| addr.ip())
+            .filter(|ip| !ip.is_unspecified());
+
+        let listener = match TcpListener::bind((conn.local_addr()?.ip(), 0)).await {
+            Ok(listener) => listener,
+            Err(e) => return Err(self.reject(conn, e.into()).await),
+        };
+        let bound_addr = listener.local_addr()?;
+        self.send_reply(conn, REPLY_SUCCEEDED, bound_addr).await?;
+        info!("BIND listening on {} for {}", bound_addr, target);
+
+        let (peer_stream, peer_addr) = match timeout(self.connect_timeout, listener.accept()).await
+        {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => return Err(self.reject(conn, e.into()).await),
+            Err(_) => return Err(self.reject(conn, Socks5ProxyError::BindTimeout).await),
+        };
+        drop(listener);
+
+        if let Some(ip) = expected_ip
+            && ip != peer_addr.ip()
+        {
+            let e = Socks5ProxyError::BindPeerMismatch(peer_addr);
+            return Err(self.reject(conn, e).await);
+        }
+
+        self.send_reply(conn, REPLY_SUCCEEDED, peer_addr).await?;
+        info!("BIND peer {} connected", peer_addr);
+
+        let buffer_size = conn.buffer_size();
+        let mut peer_conn = BufferedConnection::new(peer_stream, buffer_size);
+        forward::forward_bidirectional(conn, &mut peer_conn, self.idle_timeout())
+            .await
+            .map_err(Socks5ProxyError::IoError)
+    }
+
+    /// Reads from the connection until `decode` yields a complete frame.
+    async fn read_frame<T>(
+        conn: &mut BufferedConnection,
+        decode: fn(&[u8]) -> Decoded<T>,
+    ) -> Result<T, Socks5ProxyError> {
+        loop {
+            if let Some((frame, consumed)) = decode(conn.buffered())? {
+                conn.drain_buffer(consumed);
+                return Ok(frame);
+            }
+            conn.fill_to(conn.buffer_len() + 1).await?;
+        }
+    }
+
+    /// `certified` tells whether a TLS client certificate already names a
+    /// user, which makes the password sub-negotiation unnecessary.
+    async fn handshake(
+        &self,
+        conn: &mut BufferedConnection,
+        certified: bool,
+    ) -> Result<u8, Socks5ProxyError> {
+        let greeting = Self::read_frame(conn, codec::decode_greeting).await?;
+        let methods = greeting.methods;
+
+        let selected_method = if self.auth_manager.is_required() {
+            if methods.contains(&0x00) && certified {
+                info!("Selected no authentication (client certificate)");
+                0x00
+            } else if methods.contains(&0x00) && self.allows_anonymous(conn) {
+                info!("Selected no authentication (anonymous access allowed)");
+                0x00
+            } else if methods.contains(&0x02) {
+                info!("Selected username/password authentication");
+                0x02
+            } else {
+                conn.write(&[0x05, 0xFF]).await?;
+                return Err(Socks5ProxyError::NoSupportedAuthMethod);
+            }
+        } else if methods.contains(&0x00) {
+            info!("Selected no authentication");
+            0x00
+        } else if methods.contains(&0x02) {
+            info!("Selected username/password authentication (no auth required, client will pass)");
+            0x02
+        } else {
+            conn.write(&[0x05, 0xFF]).await?;
+            return Err(Socks5ProxyError::NoSupportedAuthMethod);
+        };
+
+        conn.write(&[0x05, selected_method]).await?;
+        Ok(selected_method)
+    }
+
+    /// RFC 1929 Username/Password sub-negotiation:
+    /// +----+------+----------+------+----------+
+    /// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    /// +----+------+----------+------+----------+
+    /// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+    /// +----+------+----------+------+----------+
+    async fn authenticate(
+        &self,
+        conn: &mut BufferedConnection,
+    ) -> Result<String, Socks5ProxyError> {
+        let request = match Self::read_frame(conn, codec::decode_auth_request).await {
+            Ok(request) => request,
+            Err(e) => {
+                conn.write(&[AUTH_VERSION, AUTH_FAILURE]).await?;
+                return Err(e);
+            }
+        };
+
+        // Buggy clients that send the SOCKS version here get it echoed back
+        let reply_version = match request.version {
+            AUTH_VERSION => AUTH_VERSION,
+            0x05 if self.config.lenient_auth_version => 0x05,
+            other => {
+                conn.write(&[AUTH_VERSION, AUTH_FAILURE]).await?;
+                return Err(Socks5ProxyError::InvalidAuthVersion(other));
+            }
+        };
+
+        let username = request.username;
+        let context = AuthContext {
+            client: conn.peer_addr()?,
+            protocol: ListenerProtocol::Socks5,
+        };
+        let auth_success = match self
+            .auth_manager
+            .authenticate(&username, &request.password, &context)
+            .await
+        {
+            AuthDecision::Allow => true,
+            AuthDecision::Deny => false,
+            AuthDecision::Error(e) => {
+                conn.write(&[reply_version, AUTH_FAILURE]).await?;
+                return Err(Socks5ProxyError::AuthenticationFailed(e));
+            }
+        };
+
+        let status = if auth_success {
+            AUTH_SUCCESS
+        } else {
+            AUTH_FAILURE
+        };
+        conn.write(&[reply_version, status]).await?;
+
+        if !auth_success {
+            return Err(Socks5ProxyError::AuthenticationFailed(
+                AuthError::AuthenticationFailed,
+            ));
+        }
+
+        info!("User '{}' authenticated", username);
+        Ok(username)
+    }
+
+    /// For UDP ASSOCIATE `DST.PORT` is the client's own port, so the user's
+    /// port list is applied per datagram by the relay instead.
+    async fn handle_request(
+        &self,
+        conn: &mut BufferedConnection,
+        user: Option<&str>,
+    ) -> Result<codec::Request, Socks5ProxyError> {
+        let request = Self::read_frame(conn, codec::decode_request).await?;
+
+        if !self.is_command_allowed(request.command) {
+            return Err(Socks5ProxyError::UnsupportedCommand(request.command));
+        }
+
+        let port = request.target.port();
+        if let Some(user) = user
+            && request.command != CMD_UDP_ASSOCIATE
+            && !self.auth_manager.allows_port(user, port)
+        {
+            return Err(Socks5ProxyError::PortNotAllowed(user.to_string(), port));
+        }
+
+        Ok(request)
+    }
+
+    /// Whether this client may skip authentication although users exist.
+    fn allows_anonymous(&self, conn: &BufferedConnection) -> bool {
+        if !self.config.allow_anonymous {
+            return false;
+        }
+        if self.config.anonymous_cidrs.is_empty() {
+            return true;
+        }
+        conn.peer_addr().is_ok_and(|addr| {
+            self.config
+                .anonymous_cidrs
+                .iter()
+                .any(|net| net.contains(&addr.ip()))
+        })
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        match self.config.idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    fn is_command_allowed(&self, command: u8) -> bool {
+        let command = match command {
+            CMD_CONNECT => Socks5Command::Connect,
+            CMD_BIND => Socks5Command::Bind,
+            CMD_UDP_ASSOCIATE => Socks5Command::UdpAssociate,
+            _ => return false,
+        };
+        self.config.allowed_commands.contains(&command)
+    }
+
+    /// With the `local` strategy domains are looked up here, before dialing;
+    /// `remote-via-upstream` leaves them for the outbound side to resolve.
+    async fn resolve_target(&self, target: &TargetAddr) -> Result<TargetAddr, ConnectError> {
+        match (self.config.resolve, target) {
+            (ResolveStrategy::Local, TargetAddr::Domain(..)) => {
+                let addr = forward::resolve_address(&target.to_string()).await?;
+                log::debug!("Resolved {} to {}", target, addr);
+                Ok(TargetAddr::Ip(addr))
+            }
+            _ => Ok(target.clone()),
+        }
+    }
+
+    /// UDP ASSOCIATE (RFC 1928 §7): relay datagrams for the client for as
+    /// long as this control connection stays open. A non-zero `DST.PORT`
+    /// restricts which client port may use the relay.
+    async fn handle_udp_associate(
+        &self,
+        conn: &mut BufferedConnection,
+        target: &TargetAddr,
+        user: Option<&str>,
+    ) -> Result<Transfer, Socks5ProxyError> {
+        let client_port = Some(target.port()).filter(|port| *port != 0);
+        let client_ip = conn.peer_addr()?.ip();
+
+        let socket = match udp::bind_relay(conn.local_addr()?.ip(), &self.udp_config).await {
+            Ok(socket) => socket,
+            Err(e) => return Err(self.reject(conn, e.into()).await),
+        };
+        let bound_addr = socket.local_addr()?;
+        let advertised_addr = udp::advertised_addr(bound_addr, &self.udp_config);
+        self.send_reply(conn, REPLY_SUCCEEDED, advertised_addr)
+            .await?;
+        info!(
+            "UDP relay on {} (advertised {}) for {}",
+            bound_addr, advertised_addr, client_ip
+        );
+
+        let mut transfer = Transfer::default();
+        let allows = |target: &TargetAddr, addr: SocketAddr| {
+            user.is_none_or(|user| self.auth_manager.allows_port(user, addr.port()))
+                && self.check_datagram(target, addr).is_ok()
+        };
+        tokio::select! {
+            result = udp::relay(
+                socket,
+                client_ip,
+                client_port,
+                self.udp_config.dns_fast_path,
+                self.idle_timeout(),
+                &mut transfer,
+                allows,
+            ) => result?,
+            result = Self::wait_for_close(conn) => result?,
+        }
+
+        info!("UDP association for {} closed", client_ip);
+        Ok(transfer)
+    }
+
+    /// The checks a connection to `target` at `addr` would pass through.
+    fn check_datagram(&self, target: &TargetAddr, addr: SocketAddr) -> Result<(), ConnectError> {
+        if let Some(guard) = &self.ssrf_guard {
+            guard.check(target, vec![addr])?;
+        }
+        self.dialer.check_datagram(target, addr)
+    }
+
+    /// Drains the control connection until the client closes it.
+    async fn wait_for_close(conn: &mut BufferedConnection) -> io::Result<()> {
+        while conn.read().await? > 0 {
+            conn.clear_buffer();
+        }
+        Ok(())
+    }
+
+    /// Best-effort failure reply; the original error is handed back to the caller.
+    async fn reject(
+        &self,
+        conn: &mut BufferedConnection,
+        error: Socks5ProxyError,
+    ) -> Socks5ProxyError {
+        let _ = self
+            .send_reply(conn, error.reply_code(), UNSPECIFIED_ADDR)
+            .await;
+        error
+    }
+
+    async fn send_reply(
+        &self,
+        conn: &mut BufferedConnection,
+        reply_code: u8,
+        bound_addr: SocketAddr,
+    ) -> Result<(), Socks5ProxyError> {
+        conn.write(&codec::encode_reply(reply_code, bound_addr))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::auth::AuthManager;
+    use crate::common::config::UserConfig;
+    use crate::net::stream::Stream;
+    use crate::proxy::dialer::DirectDialer;
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use tokio::io::DuplexStream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn spawn_proxy() -> SocketAddr {
+        spawn_proxy_with(Socks5Config::default()).await
+    }
+
+    async fn spawn_proxy_with(config: Socks5Config) -> SocketAddr {
+        spawn_proxy_with_users(config, HashMap::new()).await
+    }
+
+    async fn spawn_proxy_with_users(
+        config: Socks5Config,
+        users: HashMap<String, UserConfig>,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let auth_manager = Arc::new(AuthManager::new(&users).unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            let proxy = Socks5Proxy::new(
+                auth_manager,
+                Duration::from_secs(5),
+                Arc::new(DirectDialer::new(Duration::from_secs(5))),
+                Arc::new(config),
+                Arc::new(UdpConfig::default()),
+            );
+            let _ = proxy.handle_connection(&mut conn).await;
+        });
+        addr
+    }
+
+    async fn read_reply(stream: &mut TcpStream) -> (u8, SocketAddr) {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], 0x05);
+        assert_eq!(header[3], 0x01);
+        let mut body = [0u8; 6];
+        stream.read_exact(&mut body).await.unwrap();
+        let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+        let port = u16::from_be_bytes([body[4], body[5]]);
+        (header[1], SocketAddr::from((ip, port)))
+    }
+
+    async fn connect_no_auth(proxy_addr: SocketAddr) -> TcpStream {
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"\x05\x01\x00").await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [0x05, 0x00]);
+        client
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let config = Socks5Config {
+            handshake_timeout: 1,
+            ..Socks5Config::default()
+        };
+        let auth_manager = Arc::new(AuthManager::new(&HashMap::new()).unwrap());
+        let proxy = Socks5Proxy::new(
+            auth_manager,
+            Duration::from_secs(5),
+            Arc::new(DirectDialer::new(Duration::from_secs(5))),
+            Arc::new(config),
+            Arc::new(UdpConfig::default()),
+        );
+        let mut conn = BufferedConnection::new(stream, 4096);
+
+        let result = proxy.handle_connection(&mut conn).await;
+        assert!(matches!(result, Err(Socks5ProxyError::HandshakeTimeout)));
+    }
+
+    /// Hands out one end of an in-memory pipe, remembering the target.
+    struct PipeDialer {
+        stream: Mutex<Option<DuplexStream>>,
+        target: Mutex<Option<TargetAddr>>,
+    }
+
+    #[async_trait]
+    impl Dialer for PipeDialer {
+        async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+            *self.target.lock().unwrap() = Some(target.clone());
+            let stream = self.stream.lock().unwrap().take();
+            Ok(Box::new(stream.ok_or(ConnectError::AddressNotFound)?))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_dialer() {
+        let (stream, mut remote) = tokio::io::duplex(1024);
+        let dialer = Arc::new(PipeDialer {
+            stream: Mutex::new(Some(stream)),
+            target: Mutex::new(None),
+        });
+        let config = Socks5Config {
+            resolve: ResolveStrategy::RemoteViaUpstream,
+            ..Socks5Config::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let proxy = Socks5Proxy::new(
+            Arc::new(AuthManager::new(&HashMap::new()).unwrap()),
+            Duration::from_secs(5),
+            dialer.clone(),
+            Arc::new(config),
+            Arc::new(UdpConfig::default()),
+        );
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            let _ = proxy.handle_connection(&mut conn).await;
+        });
+
+        let mut client = connect_no_auth(proxy_addr).await;
+        client
+            .write_all(b"\x05\x01\x00\x03\x0fexample.invalid\x00\x50")
+            .await
+            .unwrap();
+        assert_eq!(read_reply(&mut client).await, (0x00, UNSPECIFIED_ADDR));
+
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        assert_eq!(
+            *dialer.target.lock().unwrap(),
+            Some(TargetAddr::Domain("example.invalid".to_string(), 80))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_allow_anonymous_by_cidr() {
+        let users = HashMap::from([("alice".to_string(), "secret".to_string().into())]);
+        for (cidr, expected_method) in [("10.0.0.0/8", 0x02), ("127.0.0.0/8", 0x00)] {
+            let config = Socks5Config {
+                allow_anonymous: true,
+                anonymous_cidrs: vec![cidr.parse().unwrap()],
+                ..Socks5Config::default()
+            };
+            let proxy_addr = spawn_proxy_with_users(config, users.clone()).await;
+
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(b"\x05\x02\x00\x02").await.unwrap();
+            let mut method = [0u8; 2];
+            client.read_exact(&mut method).await.unwrap();
+            assert_eq!(
+                method,
+                [0x05, expected_method],
+                "anonymous_cidrs = {}",
+                cidr
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_version_tolerance() {
+        let users = HashMap::from([("alice".to_string(), "secret".to_string().into())]);
+        for (lenient, expected) in [(false, [0x01, 0x01]), (true, [0x05, 0x00])] {
+            let config = Socks5Config {
+                lenient_auth_version: lenient,
+                ..Socks5Config::default()
+            };
+            let proxy_addr = spawn_proxy_with_users(config, users.clone()).await;
+
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            client.write_all(b"\x05\x01\x02").await.unwrap();
+            let mut method = [0u8; 2];
+            client.read_exact(&mut method).await.unwrap();
+            assert_eq!(method, [0x05, 0x02]);
+
+            client.write_all(b"\x05\x05alice\x06secret").await.unwrap();
+            let mut status = [0u8; 2];
+            client.read_exact(&mut status).await.unwrap();
+            assert_eq!(status, expected, "lenient_auth_version = {}", lenient);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_port_not_allowed_reply() {
+        let user = UserConfig {
+            allowed_ports: vec!["80".to_string().try_into().unwrap()],
+            ..UserConfig::from("secret".to_string())
+        };
+        let users = HashMap::from([("alice".to_string(), user)]);
+        let proxy_addr = spawn_proxy_with_users(Socks5Config::default(), users).await;
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"\x05\x01\x02\x01\x05alice\x06secret")
+            .await
+            .unwrap();
+        let mut replies = [0u8; 4];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, [0x05, 0x02, 0x01, 0x00]);
+
+        client
+            .write_all(b"\x05\x01\x00\x01\x7f\x00\x00\x01\x01\xbb")
+            .await
+            .unwrap();
+        let (reply, _) = read_reply(&mut client).await;
+        assert_eq!(reply, REPLY_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let proxy_addr = spawn_proxy().await;
+        let mut client = connect_no_auth(proxy_addr).await;
+
+        client
+            .write_all(b"\x05\x02\x00\x01\x7f\x00\x00\x01\x00\x00")
+            .await
+            .unwrap();
+        let (rep, bound_addr) = read_reply(&mut client).await;
+        assert_eq!(rep, REPLY_SUCCEEDED);
+        assert_ne!(bound_addr.port(), 0);
+
+        let mut peer = TcpStream::connect(bound_addr).await.unwrap();
+        let (rep, peer_addr) = read_reply(&mut client).await;
+        assert_eq!(rep, REPLY_SUCCEEDED);
+        assert_eq!(peer_addr, peer.local_addr().unwrap());
+
+        peer.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        client.write_all(b"pong").await.unwrap();
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[tokio::test]
+    async fn test_connect_reports_bound_addr() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+
+        let proxy_addr = spawn_proxy().await;
+        let mut client = connect_no_auth(proxy_addr).await;
+
+        let mut request = b"\x05\x01\x00\x01\x7f\x00\x00\x01".to_vec();
+        request.extend_from_slice(&target_port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let (rep, bound_addr) = read_reply(&mut client).await;
+        let (_, outbound_addr) = target.accept().await.unwrap();
+        assert_eq!(rep, REPLY_SUCCEEDED);
+        assert_eq!(bound_addr, outbound_addr);
+    }
+
+    #[tokio::test]
+    async fn test_connection_refused_reply() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+
+        let proxy_addr = spawn_proxy().await;
+        let mut client = connect_no_auth(proxy_addr).await;
+
+        let mut request = b"\x05\x01\x00\x01\x7f\x00\x00\x01".to_vec();
+        request.extend_from_slice(&closed_port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let (rep, _) = read_reply(&mut client).await;
+        assert_eq!(rep, REPLY_CONNECTION_REFUSED);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_command_reply() {
+        let proxy_addr = spawn_proxy().await;
+        let mut client = connect_no_auth(proxy_addr).await;
+
+        client
+            .write_all(b"\x05\x09\x00\x01\x7f\x00\x00\x01\x00\x35")
+            .await
+            .unwrap();
+
+        let (rep, _) = read_reply(&mut client).await;
+        assert_eq!(rep, REPLY_COMMAND_NOT_SUPPORTED);
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_command_reply() {
+        let proxy_addr = spawn_proxy_with(Socks5Config {
+            allowed_commands: vec![Socks5Command::Connect],
+            ..Socks5Config::default()
+        })
+        .await;
+        let mut client = connect_no_auth(proxy_addr).await;
+
+        client
+            .write_all(b"\x05\x02\x00\x01\x7f\x00\x00\x01\x00\x00")
+            .await
+            .unwrap();
+
+        let (rep, _) = read_reply(&mut client).await;
+        assert_eq!(rep, REPLY_COMMAND_NOT_SUPPORTED);
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate() {
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..n], from).await.unwrap();
+        });
+
+        let proxy_addr = spawn_proxy().await;
+        let mut client = connect_no_auth(proxy_addr).await;
+        client
+            .write_all(b"\x05\x03\x00\x01\x00\x00\x00\x00\x00\x00")
+            .await
+            .unwrap();
+        let (rep, relay_addr) = read_reply(&mut client).await;
+        assert_eq!(rep, REPLY_SUCCEEDED);
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut packet = b"\x00\x00\x00\x01\x7f\x00\x00\x01".to_vec();
+        packet.extend_from_slice(&echo_addr.port().to_be_bytes());
+        packet.extend_from_slice(b"datagram");
+        socket.send_to(&packet, relay_addr).await.unwrap();
+
+        let mut buf = [0u8; 1500];
+        let (n, _) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &packet[..]);
+    }
+
+    #[tokio::test]
+    async fn test_udp_associate_drops_denied_targets() {
+        let denied = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..n], from).await.unwrap();
+        });
+
+        // Loopback is denied but for the trusted echo server
+        let guard = SsrfGuard::new(
+            &crate::common::config::SsrfConfig::default(),
+            vec![TargetAddr::Ip(echo_addr)],
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            let proxy = Socks5Proxy::new(
+                Arc::new(AuthManager::new(&HashMap::new()).unwrap()),
+                Duration::from_secs(5),
+                Arc::new(DirectDialer::new(Duration::from_secs(5))),
+                Arc::new(Socks5Config::default()),
+                Arc::new(UdpConfig::default()),
+            )
+            .with_ssrf_guard(Some(Arc::new(guard)));
+            let _ = proxy.handle_connection(&mut conn).await;
+        });
+
+        let mut client = connect_no_auth(proxy_addr).await;
+        client
+            .write_all(b"\x05\x03\x00\x01\x00\x00\x00\x00\x00\x00")
+            .await
+            .unwrap();
+        let (rep, relay_addr) = read_reply(&mut client).await;
+        assert_eq!(rep, REPLY_SUCCEEDED);
+
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for target in [denied.local_addr().unwrap(), echo_addr] {
+            let mut packet = codec::encode_udp_header(target);
+            packet.extend_from_slice(b"datagram");
+            socket.send_to(&packet, relay_addr).await.unwrap();
+        }
+
+        // Datagrams are relayed in order, so the echo means the first was dropped
+        let mut buf = [0u8; 1500];
+        let (n, _) = socket.recv_from(&mut buf).await.unwrap();
+        assert!(buf[..n].ends_with(b"datagram"));
+        assert!(denied.try_recv_from(&mut buf).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Experimental SOCKS 6 support (draft-olteanu-intarea-socks-6).
+//!
+//! Only the request format and CONNECT are implemented. Options are parsed
+//! and skipped, and authentication through options is not supported, so a
+//! proxy with users configured rejects every SOCKS 6 client.
+
+use log::{debug, info};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::timeout;
+
+use crate::common::auth::AuthProvider;
+use crate::net::addr::TargetAddr;
+use crate::net::conn::BufferedConnection;
+use crate::proxy::dialer::Dialer;
+use crate::proxy::forward::{self, ConnectError};
+
+#[derive(Error, Debug)]
+pub enum Socks6ProxyError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Invalid SOCKS version: {0:#04x}")]
+    InvalidVersion(u8),
+    #[error("Invalid address type: {0:#04x}")]
+    InvalidAddressType(u8),
+    #[error("Unsupported command: {0:#04x}")]
+    UnsupportedCommand(u8),
+    #[error("Authentication is not supported over SOCKS 6")]
+    AuthenticationRequired,
+    #[error("Connection error: {0}")]
+    ConnectError(#[from] ConnectError),
+    #[error("Invalid UTF-8 data")]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+    #[error("Request not received within the handshake timeout")]
+    HandshakeTimeout,
+}
+
+const SOCKS_VERSION: u8 = 0x06;
+
+const CMD_NOOP: u8 = 0x00;
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const AUTH_SUCCESS: u8 = 0x00;
+const AUTH_FAILURE: u8 = 0x01;
+
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_TTL_EXPIRED: u8 = 0x06;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+struct Socks6Request {
+    command: u8,
+    target: TargetAddr,
+}
+
+pub struct Socks6Proxy {
+    auth_manager: Arc<dyn AuthProvider>,
+    dialer: Arc<dyn Dialer>,
+    handshake_timeout: Option<Duration>,
+}
+
+impl Socks6Proxy {
+    pub fn new(auth_manager: Arc<dyn AuthProvider>, dialer: Arc<dyn Dialer>) -> Self {
+        Socks6Proxy {
+            auth_manager,
+            dialer,
+            handshake_timeout: None,
+        }
+    }
+
+    /// Drops clients whose whole request has not arrived within `limit`.
+    pub fn with_handshake_timeout(mut self, limit: Duration) -> Self {
+        self.handshake_timeout = Some(limit);
+        self
+    }
+
+    pub async fn handle_connection(
+        &self,
+        conn: &mut BufferedConnection,
+    ) -> Result<(), Socks6ProxyError> {
+        info!("Handling SOCKS6 connection");
+
+        let request = match self.handshake_timeout {
+            Some(limit) => timeout(limit, Self::read_request(conn))
+                .await
+                .map_err(|_| Socks6ProxyError::HandshakeTimeout)??,
+            None => Self::read_request(conn).await?,
+        };
+
+        if self.auth_manager.is_required() {
+            conn.write(&[SOCKS_VERSION, AUTH_FAILURE, 0x00, 0x00])
+                .await?;
+            return Err(Socks6ProxyError::AuthenticationRequired);
+        }
+        conn.write(&[SOCKS_VERSION, AUTH_SUCCESS, 0x00, 0x00])
+            .await?;
+
+        match request.command {
+            CMD_NOOP => {
+                Self::send_reply(conn, REPLY_SUCCEEDED, UNSPECIFIED_ADDR).await?;
+                Ok(())
+            }
+            CMD_CONNECT => self.handle_connect(conn, &request.target).await,
+            other => {
+                Self::send_reply(conn, REPLY_COMMAND_NOT_SUPPORTED, UNSPECIFIED_ADDR).await?;
+                Err(Socks6ProxyError::UnsupportedCommand(other))
+            }
+        }
+    }
+
+    async fn handle_connect(
+        &self,
+        conn: &mut BufferedConnection,
+        target: &TargetAddr,
+    ) -> Result<(), Socks6ProxyError> {
+        let target_stream = match self
+            .dialer
+            .dial_from(target, conn.peer_addr()?, conn.local_addr()?)
+            .await
+        {
+            Ok(stream) => stream,
+            Err(e) => {
+                let reply_code = match e {
+                    ConnectError::ConnectionRefused(_) => REPLY_CONNECTION_REFUSED,
+                    ConnectError::ConnectionTimeout => REPLY_TTL_EXPIRED,
+                    _ => REPLY_GENERAL_FAILURE,
+                };
+                let _ = Self::send_reply(conn, reply_code, UNSPECIFIED_ADDR).await;
+                return Err(e.into());
+            }
+        };
+
+        info!("Connected to target: {}", target);
+
+        let bound_addr = target_stream.local_addr()?;
+        Self::send_reply(conn, REPLY_SUCCEEDED, bound_addr).await?;
+
+        // Any initial data the client sent after the request is still
+        // buffered and goes out first.
+        let buffer_size = conn.buffer_size();
+        let mut target_conn = BufferedConnection::new(target_stream, buffer_size);
+        forward::forward_bidirectional(conn, &mut target_conn, None).await?;
+
+        Ok(())
+    }
+
+    /// SOCKS 6 request:
+    /// +-----+-----+-------------+----------+---------+------+----------+---------+
+    /// | VER | CMD | OPTIONS LEN | DST.PORT | PADDING | ATYP | DST.ADDR | OPTIONS |
+    /// +-----+-----+-------------+----------+---------+------+----------+---------+
+    /// |  1  |  1  |      2      |    2     |    1    |  1   |   var    |   var   |
+    /// +-----+-----+-------------+----------+---------+------+----------+---------+
+    ///
+    /// Domain addresses are length-prefixed and zero-padded to a multiple of
+    /// four bytes.
+    async fn read_request(
+        conn: &mut BufferedConnection,
+    ) -> Result<Socks6Request, Socks6ProxyError> {
+        let header = conn.read_exact_buffered(8).await?;
+        if header[0] != SOCKS_VERSION {
+            return Err(Socks6ProxyError::InvalidVersion(header[0]));
+        }
+        let command = header[1];
+        let options_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let port = u16::from_be_bytes([header[4], header[5]]);
+
+        let target = match header[7] {
+            ATYP_IPV4 => {
+                let octets: [u8; 4] = conn.read_exact_buffered(4).await?[..].try_into().unwrap();
+                TargetAddr::Ip(SocketAddr::from((octets, port)))
+            }
+            ATYP_IPV6 => {
+                let octets: [u8; 16] = conn.read_exact_buffered(16).await?[..].try_into().unwrap();
+                TargetAddr::Ip(SocketAddr::from((octets, port)))
+            }
+            ATYP_DOMAIN => {
+                let len = conn.read_exact_buffered(1).await?[0] as usize;
+                let padded = (1 + len).next_multiple_of(4) - 1;
+                let mut name = conn.read_exact_buffered(padded).await?;
+                name.truncate(len);
+                TargetAddr::Domain(String::from_utf8(name.into())?, port)
+            }
+            other => return Err(Socks6ProxyError::InvalidAddressType(other)),
+        };
+
+        if options_len > 0 {
+            conn.read_exact_buffered(options_len).await?;
+            debug!("Skipped {} bytes of SOCKS6 options", options_len);
+        }
+
+        Ok(Socks6Request { command, target })
+    }
+
+    /// Operation reply: VER | REP | OPTIONS LEN | BND.PORT | PADDING | ATYP | BND.ADDR
+    async fn send_reply(
+        conn: &mut BufferedConnection,
+        reply_code: u8,
+        bound_addr: SocketAddr,
+    ) -> io::Result<()> {
+        let mut reply = vec![SOCKS_VERSION, reply_code, 0x00, 0x00];
+        reply.extend_from_slice(&bound_addr.port().to_be_bytes());
+        reply.push(0x00);
+        match bound_addr {
+            SocketAddr::V4(addr) => {
+                reply.push(ATYP_IPV4);
+                reply.extend_from_slice(&addr.ip().octets());
+            }
+            SocketAddr::V6(addr) => {
+                reply.push(ATYP_IPV6);
+                reply.extend_from_slice(&addr.ip().octets());
+            }
+        }
+        conn.write(&reply).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::auth::AuthManager;
+    use crate::proxy::dialer::DirectDialer;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn spawn_proxy() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let auth_manager = Arc::new(AuthManager::new(&HashMap::new()).unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufferedConnection::new(stream, 4096);
+            let proxy = Socks6Proxy::new(
+                auth_manager,
+                Arc::new(DirectDialer::new(Duration::from_secs(5))),
+            );
+            let _ = proxy.handle_connection(&mut conn).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_domain_and_initial_data() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        let proxy_addr = spawn_proxy().await;
+
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00, 0x04];
+        request.extend_from_slice(&target_port.to_be_bytes());
+        request.extend_from_slice(&[0x00, ATYP_DOMAIN, 9]);
+        request.extend_from_slice(b"localhost\x00\x00");
+        // One empty option (kind 0, length 4), then initial data
+        request.extend_from_slice(&[0x00, 0x00, 0x00, 0x04]);
+        request.extend_from_slice(b"hello");
+        client.write_all(&request).await.unwrap();
+
+        let (mut accepted, _) = target.accept().await.unwrap();
+        let mut data = [0u8; 5];
+        accepted.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"hello");
+
+        let mut auth_reply = [0u8; 4];
+        client.read_exact(&mut auth_reply).await.unwrap();
+        assert_eq!(auth_reply, [SOCKS_VERSION, AUTH_SUCCESS, 0x00, 0x00]);
+
+        let mut reply = [0u8; 12];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply[..2], &[SOCKS_VERSION, REPLY_SUCCEEDED]);
+        assert_eq!(reply[7], ATYP_IPV4);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_command_reply() {
+        let proxy_addr = spawn_proxy().await;
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client
+            .write_all(b"\x06\x02\x00\x00\x00\x50\x00\x01\x7f\x00\x00\x01")
+            .await
+            .unwrap();
+
+        let mut reply = [0u8; 16];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[4..6], [SOCKS_VERSION, REPLY_COMMAND_NOT_SUPPORTED]);
+    }
+}
@@ -0,0 +1,199 @@
+//! `[ssrf]`: refuses direct connections to loopback, private, link-local
+//! and other reserved addresses, so clients cannot use the proxy to reach
+//! the host itself, its network or a cloud metadata service. Addresses are
+//! checked after resolution, and only the addresses checked are dialed, so
+//! a domain that rebinds to a private address between lookups gains
+//! nothing. IPv6 addresses that carry an IPv4 one, through NAT64 or 6to4,
+//! are judged by the IPv4 address they lead to as well.
+
+use ipnet::{IpNet, Ipv6Net};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::common::config::SsrfConfig;
+use crate::net::addr::{self, TargetAddr};
+use crate::proxy::forward::ConnectError;
+
+/// Networks refused unless `allow` covers them.
+const DENIED: &[&str] = &[
+    // "This" network: 0.0.0.0 reaches the host itself
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    // Shared address space (RFC 6598), used inside some clouds
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    // Link-local, with the 169.254.169.254 metadata service
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    // IETF protocol assignments (RFC 6890)
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    // Benchmarking (RFC 2544)
+    "198.18.0.0/15",
+    // Multicast
+    "224.0.0.0/4",
+    // Reserved, with the 255.255.255.255 broadcast address
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    // Unique local, with fd00:ec2::254
+    "fc00::/7",
+    "fe80::/10",
+    // Multicast
+    "ff00::/8",
+];
+
+/// The well-known NAT64 prefix (RFC 6052), checked whatever `[nat64]` says
+const WELL_KNOWN_NAT64: &str = "64:ff9b::/96";
+
+/// 6to4 (RFC 3056): the IPv4 address follows the first 16 bits
+const SIX_TO_FOUR: &str = "2002::/16";
+
+pub struct SsrfGuard {
+    denied: Vec<IpNet>,
+    allow: Vec<IpNet>,
+    /// NAT64 prefixes whose addresses reach the IPv4 one embedded
+    nat64: Vec<Ipv6Net>,
+    /// Targets the configuration names itself, dialed wherever they resolve
+    trusted: Vec<TargetAddr>,
+}
+
+impl SsrfGuard {
+    pub fn new(config: &SsrfConfig, trusted: Vec<TargetAddr>) -> Self {
+        SsrfGuard {
+            denied: DENIED.iter().map(|net| net.parse().unwrap()).collect(),
+            allow: config.allow.clone(),
+            nat64: vec![WELL_KNOWN_NAT64.parse().unwrap()],
+            trusted,
+        }
+    }
+
+    /// Also judges addresses in the `[nat64]` prefix by the IPv4 address
+    /// they are synthesized from.
+    pub fn with_nat64_prefix(mut self, prefix: Option<Ipv6Net>) -> Self {
+        if let Some(prefix) = prefix
+            && !self.nat64.contains(&prefix)
+        {
+            self.nat64.push(prefix);
+        }
+        self
+    }
+
+    pub fn is_denied(&self, ip: IpAddr) -> bool {
+        // An IPv4-mapped IPv6 address reaches the IPv4 one
+        let ip = ip.to_canonical();
+        if self.refuses(ip) {
+            return true;
+        }
+        match ip {
+            IpAddr::V6(v6) => self
+                .embedded_ipv4(v6)
+                .any(|v4| self.refuses(IpAddr::V4(v4))),
+            IpAddr::V4(_) => false,
+        }
+    }
+
+    fn refuses(&self, ip: IpAddr) -> bool {
+        !self.allow.iter().any(|net| net.contains(&ip))
+            && self.denied.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The IPv4 addresses a translator or relay may take `ip` to.
+    fn embedded_ipv4(&self, ip: Ipv6Addr) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        let six_to_four: Ipv6Net = SIX_TO_FOUR.parse().unwrap();
+        let relayed = six_to_four.contains(&ip).then(|| {
+            let octets = ip.octets();
+            Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])
+        });
+        self.nat64
+            .iter()
+            .filter_map(move |prefix| addr::extract_ipv4(*prefix, ip))
+            .chain(relayed)
+    }
+
+    /// The addresses `target` resolved to that may be dialed, or an error
+    /// when none may.
+    pub fn check(
+        &self,
+        target: &TargetAddr,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<Vec<SocketAddr>, ConnectError> {
+        if self.trusted.contains(target) {
+            return Ok(addrs);
+        }
+        let allowed: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| !self.is_denied(addr.ip()))
+            .collect();
+        if allowed.is_empty() {
+            return Err(ConnectError::Denied(target.to_string()));
+        }
+        Ok(allowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let config = SsrfConfig {
+            enabled: true,
+            allow: vec!["10.1.0.0/16".parse().unwrap()],
+        };
+        let trusted = TargetAddr::Domain("db.internal".to_string(), 5432);
+        let guard = SsrfGuard::new(&config, vec![trusted.clone()]);
+        for ip in [
+            "127.0.0.1",
+            "0.0.0.0",
+            "10.0.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "169.254.169.254",
+            "::1",
+            "::ffff:127.0.0.1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "224.0.0.251",
+            "255.255.255.255",
+            "192.0.0.170",
+            "198.18.0.1",
+            "ff02::1",
+            // NAT64 and 6to4 addresses of private IPv4 ones
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::a00:1",
+            "2002:7f00:1::",
+        ] {
+            assert!(guard.is_denied(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "10.1.2.3",
+            "93.184.216.34",
+            "2606:2800:220:1::",
+            "64:ff9b::5db8:d822",
+            "2002:5db8:d822::",
+            // The allowed network stays allowed through NAT64
+            "64:ff9b::a01:203",
+        ] {
+            assert!(!guard.is_denied(ip.parse().unwrap()), "{}", ip);
+        }
+
+        let nat64_guard = SsrfGuard::new(&config, Vec::new())
+            .with_nat64_prefix(Some("2001:db8:64::/96".parse().unwrap()));
+        assert!(nat64_guard.is_denied("2001:db8:64::a9fe:a9fe".parse().unwrap()));
+        assert!(!nat64_guard.is_denied("2001:db8:64::5db8:d822".parse().unwrap()));
+
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let target = TargetAddr::Domain("rebound.example".to_string(), 80);
+        let addrs = vec![addr("127.0.0.1:80"), addr("93.184.216.34:80")];
+        assert_eq!(
+            guard.check(&target, addrs.clone()).unwrap(),
+            vec![addr("93.184.216.34:80")]
+        );
+        assert!(matches!(
+            guard.check(&target, vec![addr("169.254.169.254:80")]),
+            Err(ConnectError::Denied(_))
+        ));
+        assert_eq!(guard.check(&trusted, addrs.clone()).unwrap(), addrs);
+    }
+}
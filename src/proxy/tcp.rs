@@ -1,56 +1,637 @@
+use ipnet::IpNet;
 use log::info;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
-use tokio::task;
+use tokio::task::{self, JoinSet};
+use tokio::time::timeout;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
-use crate::common::auth::AuthManager;
+use crate::common::auth::{AuthManager, AuthProvider};
+use crate::common::config::{
+    AccessLogFormat, BalanceStrategy, Config, HttpConfig, ListenerConfig, ListenerProtocol,
+    SniffConfig, Socks5Config, UdpConfig,
+};
+use crate::net::addr::TargetAddr;
 use crate::net::conn::BufferedConnection;
+use crate::net::pool::ConnectionPool;
+use crate::net::quic::QuicStream;
+use crate::net::rate_limit::RateLimiter;
+use crate::net::sockopt;
+use crate::proxy::accept_queue::AcceptQueue;
+use crate::proxy::access::AccessList;
+use crate::proxy::bandwidth::{Bandwidth, BandwidthProvider};
+use crate::proxy::blocklist::Blocklist;
+use crate::proxy::client_limits::ClientLimits;
+use crate::proxy::dialer::{self, Dialer, DirectDialer, SpoofingDialer};
+use crate::proxy::fake_ip::FakeIpPool;
+use crate::proxy::forward;
+use crate::proxy::hosts::Hosts;
 use crate::proxy::http::HttpProxy;
+use crate::proxy::http::adblock::FilterList;
+use crate::proxy::http::cache::HttpCache;
+use crate::proxy::http::gateway::Gateway;
+use crate::proxy::http::http2;
+use crate::proxy::http::jwt::JwtAuth;
+#[cfg(feature = "mitm")]
+use crate::proxy::http::mitm::Mitm;
+use crate::proxy::lockout::{Lockout, LockoutProvider};
+use crate::proxy::policy::OutboundPolicy;
+use crate::proxy::router::Router;
+use crate::proxy::shadowsocks::{InboundKeys, ShadowsocksProxy};
+use crate::proxy::socks4::Socks4Proxy;
 use crate::proxy::socks5::Socks5Proxy;
+use crate::proxy::ssrf::SsrfGuard;
+use crate::proxy::transparent::TransparentProxy;
+use crate::proxy::upstream::Balancer;
 
 #[derive(Error, Debug)]
 pub enum TcpProxyError {
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("TLS handshake timed out")]
+    TlsHandshakeTimeout,
+    #[error("Client sent nothing within the handshake timeout")]
+    HandshakeTimeout,
     #[error("No data received from client")]
     NoDataReceived,
     #[error("Unsupported protocol (first byte: {0:#04x})")]
     UnsupportedProtocol(u8),
+    #[error("{0:?} is not accepted on this listener")]
+    ProtocolNotAllowed(ListenerProtocol),
     #[error("HTTP proxy error: {0}")]
     HttpProxyError(#[from] crate::proxy::http::HttpProxyError),
+    #[error("SOCKS4 proxy error: {0}")]
+    Socks4ProxyError(#[from] crate::proxy::socks4::Socks4ProxyError),
     #[error("SOCKS5 proxy error: {0}")]
     Socks5ProxyError(#[from] crate::proxy::socks5::Socks5ProxyError),
+    #[error("QUIC connection error: {0}")]
+    QuicError(#[from] quinn::ConnectionError),
+    #[error("Shadowsocks proxy error: {0}")]
+    ShadowsocksProxyError(#[from] crate::proxy::shadowsocks::ShadowsocksProxyError),
+    #[error("Connection error: {0}")]
+    ConnectError(#[from] forward::ConnectError),
+    #[error("Transparent proxy error: {0}")]
+    TransparentProxyError(#[from] crate::proxy::transparent::TransparentProxyError),
+    #[cfg(feature = "socks6")]
+    #[error("SOCKS6 proxy error: {0}")]
+    Socks6ProxyError(#[from] crate::proxy::socks6::Socks6ProxyError),
 }
 
+/// How long a client refused by `[client_limits]` is given to show which
+/// protocol it speaks
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tells a client refused by `[client_limits]` so in its own protocol, when
+/// its listener speaks one without TLS, and closes the connection.
+async fn refuse(mut stream: TcpStream, inbound: Inbound) {
+    let too_many: &[u8] = b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\
+                            Connection: close\r\n\r\n";
+    let reply = match inbound {
+        Inbound::Detect(settings) if settings.tls_acceptor.is_none() => {
+            let mut first = [0u8; 1];
+            match timeout(REFUSAL_TIMEOUT, stream.read(&mut first)).await {
+                // SOCKS4 request rejected
+                Ok(Ok(1)) if first[0] == 0x04 => &[0x00, 0x5B, 0, 0, 0, 0, 0, 0][..],
+                // SOCKS5 with no acceptable method
+                Ok(Ok(1)) if first[0] == 0x05 => &[0x05, 0xFF][..],
+                Ok(Ok(1)) if first[0].is_ascii_alphabetic() => too_many,
+                _ => return,
+            }
+        }
+        Inbound::Gateway => too_many,
+        _ => return,
+    };
+    let _ = stream.write_all(reply).await;
+    // Reading what the client already sent before closing keeps the reply
+    // from being lost to a reset
+    let _ = stream.shutdown().await;
+    let mut rest = [0u8; 1024];
+    let _ = timeout(REFUSAL_TIMEOUT, async {
+        while matches!(stream.read(&mut rest).await, Ok(n) if n > 0) {}
+    })
+    .await;
+}
+
+/// Cheap to clone: each accepted connection gets its own handle to the
+/// shared settings.
+#[derive(Clone)]
 pub struct TcpProxy {
-    auth_manager: Arc<AuthManager>,
+    auth_manager: Arc<dyn AuthProvider>,
+    /// Lets every client through, for listeners without `auth` and clients
+    /// in `auth.exempt_cidrs`
+    open_auth: Arc<dyn AuthProvider>,
+    auth_exempt: Arc<Vec<IpNet>>,
     buffer_size: usize,
-    semaphore: Arc<Semaphore>,
+    accept_queue: Arc<AcceptQueue>,
     connect_timeout: Duration,
+    handshake_timeout: Duration,
+    dialer: Arc<dyn Dialer>,
+    /// For the UDP relay, which sends from this host whatever the dialer
+    ssrf_guard: Option<Arc<SsrfGuard>>,
+    socks5_config: Arc<Socks5Config>,
+    udp_config: Arc<UdpConfig>,
+    http_config: Arc<HttpConfig>,
+    http_cache: Option<Arc<HttpCache>>,
+    http_pool: Option<Arc<ConnectionPool>>,
+    access_log: Option<AccessLogFormat>,
+    tls_acceptor: Option<TlsAcceptor>,
+    shadowsocks_keys: Option<Arc<InboundKeys>>,
+    transparent: Option<Arc<TransparentProxy>>,
+    gateway: Option<Arc<Gateway>>,
+    adblock: Option<Arc<FilterList>>,
+    jwt: Option<Arc<JwtAuth>>,
+    access: Arc<AccessList>,
+    lockout: Option<Arc<Lockout>>,
+    bandwidth: Option<Arc<Bandwidth>>,
+    client_limits: Option<Arc<ClientLimits>>,
+    sniff: Option<Arc<SniffConfig>>,
+    /// `url-test` groups, timing their members in the background
+    url_tests: Vec<Arc<Balancer>>,
+    #[cfg(feature = "mitm")]
+    mitm: Option<Arc<Mitm>>,
+}
+
+/// What a SOCKS/HTTP listener accepts, and from whom.
+struct ListenerSettings {
+    protocols: Vec<ListenerProtocol>,
+    tls_acceptor: Option<TlsAcceptor>,
+    auth_manager: Arc<dyn AuthProvider>,
+}
+
+/// What a listener speaks.
+#[derive(Clone)]
+enum Inbound {
+    /// SOCKS or HTTP, told apart by the first byte
+    Detect(Arc<ListenerSettings>),
+    Shadowsocks,
+    /// Redirected by iptables, with no handshake
+    Transparent,
+    /// Relayed to a fixed `[[forward]]` target, with no handshake
+    Forward(Arc<TargetAddr>),
+    /// HTTP requests for the `[gateway]` backends
+    Gateway,
 }
 
 impl TcpProxy {
     pub fn new(
-        auth_manager: Arc<AuthManager>,
-        buffer_size: usize,
-        max_connections: usize,
-        connect_timeout: Duration,
+        auth_manager: Arc<dyn AuthProvider>,
+        config: &Config,
+        tls_acceptor: Option<TlsAcceptor>,
+        http_cache: Option<Arc<HttpCache>>,
+        upstream_tls: Option<TlsConnector>,
+        upstreams_tls: HashMap<String, TlsConnector>,
+        gateway: Option<Arc<Gateway>>,
     ) -> Self {
+        let http = &config.http;
+        let http_pool = (http.pool_max_idle_per_host > 0).then(|| {
+            Arc::new(ConnectionPool::new(
+                http.pool_max_idle_per_host,
+                Duration::from_secs(http.pool_idle_timeout),
+                Duration::from_secs(http.pool_max_lifetime),
+            ))
+        });
+        let connect_timeout = Duration::from_secs(config.connect_timeout);
+        // Targets named in the configuration may sit on a private network
+        let trusted = config
+            .forward
+            .iter()
+            .map(|forward| forward.target.as_str())
+            .chain(
+                config
+                    .gateway
+                    .iter()
+                    .flat_map(|gateway| gateway.routes.iter().map(|route| route.backend.as_str())),
+            )
+            .filter_map(TargetAddr::parse)
+            .collect();
+        let guard = config.ssrf.enabled.then(|| {
+            let nat64_prefix = config.nat64.as_ref().map(|nat64| nat64.prefix);
+            Arc::new(SsrfGuard::new(&config.ssrf, trusted).with_nat64_prefix(nat64_prefix))
+        });
+        let wrap = |dialer| {
+            dialer::with_proxy_protocol(
+                dialer::with_nat64(dialer, config.nat64.clone()),
+                config.proxy_protocol.clone(),
+            )
+        };
+        let mut dialer = wrap(dialer::from_config(
+            connect_timeout,
+            config.upstream.clone(),
+            upstream_tls,
+            guard.clone(),
+        ));
+        let mut url_tests = Vec::new();
+        if !config.rules.is_empty() {
+            let mut upstreams: HashMap<String, Arc<dyn Dialer>> = config
+                .upstreams
+                .iter()
+                .map(|(name, upstream)| {
+                    let tls = upstreams_tls.get(name).cloned();
+                    let dialer =
+                        dialer::from_config(connect_timeout, Some(upstream.clone()), tls, None);
+                    (name.clone(), wrap(dialer))
+                })
+                .collect();
+            let groups: Vec<(String, Arc<dyn Dialer>)> = config
+                .upstream_groups
+                .iter()
+                .map(|(name, group)| {
+                    let balancer = Arc::new(Balancer::new(name, group, &upstreams));
+                    if group.strategy == BalanceStrategy::UrlTest {
+                        url_tests.push(balancer.clone());
+                    }
+                    (name.clone(), balancer as Arc<dyn Dialer>)
+                })
+                .collect();
+            upstreams.extend(groups);
+            let direct = DirectDialer::new(connect_timeout).with_guard(guard.clone());
+            let direct = wrap(Arc::new(direct));
+            let router = Router::new(&config.rules, direct, dialer, upstreams).with_bound(|bind| {
+                let direct = DirectDialer::new(connect_timeout)
+                    .with_guard(guard.clone())
+                    .with_bind(bind);
+                wrap(Arc::new(direct))
+            });
+            dialer = Arc::new(router);
+        }
+        let policy = (!config.blocked_ports.is_empty())
+            .then(|| Arc::new(OutboundPolicy::new(&config.blocked_ports)));
+        if let Some(policy) = &policy {
+            dialer = dialer::with_policy(dialer, policy.clone());
+        }
+        let sniff = config.sniff.clone().map(Arc::new);
+        let transparent = config.transparent.as_ref().map(|transparent| {
+            let dialer: Arc<dyn Dialer> = if transparent.spoof_source {
+                let spoofing = SpoofingDialer::new(connect_timeout).with_guard(guard.clone());
+                match &policy {
+                    Some(policy) => dialer::with_policy(Arc::new(spoofing), policy.clone()),
+                    None => Arc::new(spoofing),
+                }
+            } else {
+                dialer.clone()
+            };
+            // Validated along with the rest of the configuration
+            let listen_addr = transparent.listen_address.parse().unwrap();
+            let mut proxy = TransparentProxy::new(dialer, transparent.mode, listen_addr);
+            if let Some(sniff) = &sniff {
+                proxy = proxy.with_sniff(sniff.clone());
+            }
+            Arc::new(proxy)
+        });
         TcpProxy {
             auth_manager,
-            buffer_size,
-            semaphore: Arc::new(Semaphore::new(max_connections)),
-            connect_timeout,
+            // With no users every client is let through
+            open_auth: Arc::new(AuthManager::new(&HashMap::new()).unwrap()),
+            auth_exempt: Arc::new(config.auth.exempt_cidrs.clone()),
+            buffer_size: config.buffer_size,
+            accept_queue: Arc::new(AcceptQueue::new(
+                config.max_connections,
+                config.accept_queue.as_ref(),
+            )),
+            connect_timeout: Duration::from_secs(config.connect_timeout),
+            handshake_timeout: Duration::from_secs(config.handshake_timeout),
+            dialer,
+            ssrf_guard: guard,
+            socks5_config: Arc::new(config.socks5.clone()),
+            udp_config: Arc::new(config.udp.clone()),
+            http_config: Arc::new(config.http.clone()),
+            http_cache,
+            http_pool,
+            access_log: config
+                .access_log
+                .path
+                .as_ref()
+                .map(|_| config.access_log.format),
+            tls_acceptor,
+            shadowsocks_keys: config
+                .shadowsocks
+                .as_ref()
+                .map(|shadowsocks| Arc::new(InboundKeys::new(shadowsocks, &config.users))),
+            transparent,
+            gateway,
+            adblock: None,
+            jwt: None,
+            access: Arc::new(AccessList::new(&config.access)),
+            lockout: None,
+            bandwidth: None,
+            client_limits: config
+                .client_limits
+                .as_ref()
+                .map(|limits| Arc::new(ClientLimits::new(limits))),
+            sniff,
+            url_tests,
+            #[cfg(feature = "mitm")]
+            mitm: None,
+        }
+    }
+
+    /// Refuses the domains on `blocklist` on every path dialing domain
+    /// targets.
+    pub fn with_blocklist(mut self, blocklist: Arc<Blocklist>) -> Self {
+        self.dialer = dialer::with_blocklist(self.dialer, blocklist);
+        self
+    }
+
+    /// Dials the domains `hosts` lists at their fixed addresses.
+    pub fn with_hosts(mut self, hosts: Arc<Hosts>) -> Self {
+        self.dialer = dialer::with_hosts(self.dialer, hosts);
+        self
+    }
+
+    /// Dials the fake addresses `pool` hands out by their domain, for
+    /// transparent connections too. Applied last, so the blocklist and
+    /// `[hosts]` see the domain.
+    pub fn with_fake_ip(mut self, pool: Arc<FakeIpPool>) -> Self {
+        self.dialer = dialer::with_fake_ip(self.dialer, pool.clone());
+        self.transparent = self
+            .transparent
+            .map(|transparent| Arc::new(Arc::unwrap_or_clone(transparent).with_fake_ip(pool)));
+        self
+    }
+
+    /// Answers HTTP proxy requests the filters match; the gateway is not
+    /// filtered.
+    pub fn with_adblock(mut self, adblock: Arc<FilterList>) -> Self {
+        self.adblock = Some(adblock);
+        self
+    }
+
+    /// Lets HTTP proxy clients authenticate with bearer tokens `jwt`
+    /// verifies; the gateway does not authenticate.
+    pub fn with_jwt(mut self, jwt: Arc<JwtAuth>) -> Self {
+        self.jwt = Some(jwt);
+        self
+    }
+
+    /// Counts failed logins against `lockout`, refusing banned sources as
+    /// soon as they connect.
+    pub fn with_lockout(mut self, lockout: Arc<Lockout>) -> Self {
+        self.auth_manager = Arc::new(LockoutProvider::new(self.auth_manager, lockout.clone()));
+        self.lockout = Some(lockout);
+        self
+    }
+
+    /// Holds connections, users and listeners to the rates of `bandwidth`.
+    pub fn with_bandwidth(mut self, bandwidth: Arc<Bandwidth>) -> Self {
+        self.auth_manager = Arc::new(BandwidthProvider::new(self.auth_manager, bandwidth.clone()));
+        self.bandwidth = Some(bandwidth);
+        self
+    }
+
+    /// A listener's own `[bandwidth]` limiter, shared by its connections.
+    fn listener_limiter(&self) -> Option<Arc<RateLimiter>> {
+        self.bandwidth
+            .as_ref()
+            .and_then(|bandwidth| bandwidth.listener_limiter())
+    }
+
+    /// The `[bandwidth]` limits of a new connection to the listener
+    /// limited by `listener`.
+    fn connection_limits(&self, listener: &Option<Arc<RateLimiter>>) -> Vec<Arc<RateLimiter>> {
+        let connection = self
+            .bandwidth
+            .as_ref()
+            .and_then(|bandwidth| bandwidth.connection_limiter());
+        listener.iter().cloned().chain(connection).collect()
+    }
+
+    /// Whether a client at `ip` skips authentication by `auth.exempt_cidrs`.
+    fn is_exempt(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.auth_exempt.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Whether `addr` is refused by `[access]` or a lockout ban, logging
+    /// why.
+    fn rejects(&self, addr: std::net::SocketAddr) -> bool {
+        if let Err(rejected) = self.access.check(addr.ip()) {
+            log::warn!("Rejected {} by [access] ({} so far)", addr, rejected);
+            return true;
+        }
+        if self
+            .lockout
+            .as_ref()
+            .is_some_and(|lockout| lockout.is_banned(addr.ip()))
+        {
+            log::debug!("Rejected {}, banned after failed logins", addr);
+            return true;
         }
+        false
+    }
+
+    /// Inspects the CONNECT tunnels of HTTP proxy clients `mitm` covers.
+    #[cfg(feature = "mitm")]
+    pub fn with_mitm(mut self, mitm: Arc<Mitm>) -> Self {
+        self.mitm = Some(mitm);
+        self
+    }
+
+    /// The outbound dialer, shared with the `[dns]` forwarder.
+    pub fn dialer(&self) -> Arc<dyn Dialer> {
+        self.dialer.clone()
+    }
+
+    /// Admission against `max_connections`, shared by every listener.
+    pub fn accept_queue(&self) -> &AcceptQueue {
+        &self.accept_queue
+    }
+
+    /// Times the members of `url-test` groups until Ctrl-C / SIGINT is
+    /// received.
+    pub async fn run_url_tests(&self) {
+        let mut url_tests = JoinSet::new();
+        for balancer in &self.url_tests {
+            let balancer = balancer.clone();
+            url_tests.spawn(async move { balancer.run_url_test().await });
+        }
+        url_tests.join_all().await;
     }
 
     /// Accept connections until Ctrl-C / SIGINT is received.
     pub async fn run(&self, listener: TcpListener) {
         info!("TCP proxy listening on {}", listener.local_addr().unwrap());
+        let settings = ListenerSettings {
+            protocols: vec![
+                ListenerProtocol::Socks4,
+                ListenerProtocol::Socks5,
+                ListenerProtocol::Socks6,
+                ListenerProtocol::Http,
+            ],
+            tls_acceptor: self.tls_acceptor.clone(),
+            auth_manager: self.auth_manager.clone(),
+        };
+        self.serve(listener, Inbound::Detect(Arc::new(settings)))
+            .await;
+    }
+
+    /// Like `run`, for a `[[listeners]]` entry. `tls_acceptor` is built from
+    /// the entry's own `tls` table.
+    pub async fn run_listener(
+        &self,
+        listener: TcpListener,
+        config: &ListenerConfig,
+        tls_acceptor: Option<TlsAcceptor>,
+    ) {
+        info!(
+            "Listener {} accepting {:?}",
+            listener.local_addr().unwrap(),
+            config.protocols
+        );
+        let auth_manager = if config.auth {
+            self.auth_manager.clone()
+        } else {
+            self.open_auth.clone()
+        };
+        let settings = ListenerSettings {
+            protocols: config.protocols.clone(),
+            tls_acceptor,
+            auth_manager,
+        };
+        self.serve(listener, Inbound::Detect(Arc::new(settings)))
+            .await;
+    }
+
+    /// Like `run`, for the `[shadowsocks]` listener. Both share the
+    /// connection limit.
+    pub async fn run_shadowsocks(&self, listener: TcpListener) {
+        info!(
+            "Shadowsocks listening on {}",
+            listener.local_addr().unwrap()
+        );
+        self.serve(listener, Inbound::Shadowsocks).await;
+    }
+
+    /// Like `run`, for the `[transparent]` listener.
+    pub async fn run_transparent(&self, listener: TcpListener) {
+        info!(
+            "Transparent proxy listening on {}",
+            listener.local_addr().unwrap()
+        );
+        self.serve(listener, Inbound::Transparent).await;
+    }
+
+    /// Like `run`, for a `[[forward]]` listener relaying every connection
+    /// to `target`.
+    pub async fn run_forward(&self, listener: TcpListener, target: TargetAddr) {
+        info!(
+            "Forwarding {} to {}",
+            listener.local_addr().unwrap(),
+            target
+        );
+        self.serve(listener, Inbound::Forward(Arc::new(target)))
+            .await;
+    }
+
+    /// Like `run`, for the `[gateway]` reverse proxy listener.
+    pub async fn run_gateway(&self, listener: TcpListener) {
+        info!("Gateway listening on {}", listener.local_addr().unwrap());
+        self.serve(listener, Inbound::Gateway).await;
+    }
+
+    /// Like `run`, for the connections in traffic routed into the `[tun]`
+    /// device.
+    #[cfg(feature = "tun")]
+    pub async fn run_tun(&self, device: crate::net::tun::TunDevice) {
+        info!("TUN device {} attached", device.name());
+
+        let (accept_tx, mut accept_rx) = tokio::sync::mpsc::channel(64);
+        let mut stack = task::spawn(crate::net::tun::run(device, accept_tx));
+        let listener_limiter = self.listener_limiter();
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                Some(stream) = accept_rx.recv() => {
+                    let Some(permit) = self.accept_queue.try_acquire() else {
+                        log::warn!("Max connections reached, rejecting a TUN connection");
+                        continue;
+                    };
+                    let proxy = self.clone();
+                    let limits = self.connection_limits(&listener_limiter);
+                    task::spawn(async move {
+                        if let Err(e) = proxy.handle_tun(stream, &limits).await {
+                            log::error!("TUN connection error: {}", e);
+                        }
+                        drop(permit);
+                    });
+                }
+                result = &mut stack => {
+                    match result {
+                        Ok(Err(e)) => log::error!("TUN device failed: {}", e),
+                        Err(e) => log::error!("TUN stack panicked: {}", e),
+                        Ok(Ok(())) => {}
+                    }
+                    break;
+                }
+                _ = &mut shutdown => {
+                    info!("Received shutdown signal");
+                    break;
+                }
+            }
+        }
+
+        stack.abort();
+        info!("Stopped accepting TUN connections");
+    }
+
+    /// Like `run`, for the `[quic]` endpoint: every bidirectional stream of
+    /// a connection is a SOCKS5 session and takes a connection permit.
+    pub async fn run_quic(&self, endpoint: quinn::Endpoint) {
+        info!("QUIC listening on {}", endpoint.local_addr().unwrap());
+
+        let listener_limiter = self.listener_limiter();
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                incoming = endpoint.accept() => {
+                    let Some(incoming) = incoming else {
+                        break;
+                    };
+                    let addr = incoming.remote_address();
+                    if self.rejects(addr) {
+                        incoming.refuse();
+                        continue;
+                    }
+                    let client_permit = match self.client_limits.as_ref().map(|limits| limits.admit(addr.ip())) {
+                        Some(Err(refusal)) => {
+                            log::warn!("Rejected {}: {}", addr, refusal);
+                            incoming.refuse();
+                            continue;
+                        }
+                        permit => permit,
+                    };
+                    let proxy = self.clone();
+                    let local_addr = endpoint.local_addr().unwrap();
+                    let listener_limiter = listener_limiter.clone();
+                    task::spawn(async move {
+                        if let Err(e) = proxy.handle_quic(incoming, local_addr, &listener_limiter).await {
+                            log::error!("QUIC connection error from {}: {}", addr, e);
+                        }
+                        drop(client_permit);
+                    });
+                }
+                _ = &mut shutdown => {
+                    info!("Received shutdown signal");
+                    break;
+                }
+            }
+        }
+
+        endpoint.close(0u32.into(), b"shutdown");
+        info!("Stopped accepting new QUIC connections");
+    }
 
+    async fn serve(&self, listener: TcpListener, inbound: Inbound) {
+        let listener_limiter = self.listener_limiter();
+        let listen_address = listener.local_addr().unwrap();
         let shutdown = tokio::signal::ctrl_c();
         tokio::pin!(shutdown);
 
@@ -59,30 +640,43 @@ impl TcpProxy {
                 result = listener.accept() => {
                     match result {
                         Ok((stream, addr)) => {
-                            let permit = match self.semaphore.clone().try_acquire_owned() {
-                                Ok(permit) => permit,
-                                Err(_) => {
-                                    log::warn!("Max connections reached, rejecting {}", addr);
-                                    drop(stream);
+                            if self.rejects(addr) {
+                                drop(stream);
+                                continue;
+                            }
+                            let client_permit = match self.client_limits.as_ref().map(|limits| limits.admit(addr.ip())) {
+                                Some(Err(refusal)) => {
+                                    log::warn!("Rejected {}: {}", addr, refusal);
+                                    task::spawn(refuse(stream, inbound.clone()));
                                     continue;
                                 }
+                                permit => permit,
+                            };
+                            let Some(ticket) = self.accept_queue.enter() else {
+                                log::warn!("Max connections reached, rejecting {}", addr);
+                                drop(stream);
+                                continue;
                             };
-                            let auth_manager = self.auth_manager.clone();
-                            let buffer_size = self.buffer_size;
-                            let connect_timeout = self.connect_timeout;
+                            let proxy = self.clone();
+                            let inbound = inbound.clone();
+                            let limits = self.connection_limits(&listener_limiter);
                             task::spawn(async move {
-                                if let Err(e) = Self::handle_connection(
-                                    stream,
-                                    addr,
-                                    auth_manager,
-                                    buffer_size,
-                                    connect_timeout,
-                                )
-                                .await
-                                {
+                                let Some(permit) = ticket.admit().await else {
+                                    log::warn!("Max connections reached, {} timed out waiting", addr);
+                                    return;
+                                };
+                                let result = match inbound {
+                                    Inbound::Detect(settings) => proxy.handle_connection(stream, addr, listen_address, &settings, &limits).await,
+                                    Inbound::Shadowsocks => proxy.handle_shadowsocks(stream, addr, &limits).await,
+                                    Inbound::Transparent => proxy.handle_transparent(stream, addr, &limits).await,
+                                    Inbound::Forward(target) => proxy.handle_forward(stream, addr, &target, &limits).await,
+                                    Inbound::Gateway => proxy.handle_gateway(stream, addr, listen_address, &limits).await,
+                                };
+                                if let Err(e) = result {
                                     log::error!("Connection error from {}: {}", addr, e);
                                 }
                                 drop(permit);
+                                drop(client_permit);
                             });
                         }
                         Err(e) => {
@@ -98,42 +692,139 @@ impl TcpProxy {
             }
         }
 
-        info!("Stopped accepting new connections");
+        info!(
+            "Stopped accepting new connections ({} rejected by [access])",
+            self.access.rejected()
+        );
     }
 
     async fn handle_connection(
+        &self,
         stream: TcpStream,
         addr: std::net::SocketAddr,
-        auth_manager: Arc<AuthManager>,
-        buffer_size: usize,
-        connect_timeout: Duration,
+        listen_address: std::net::SocketAddr,
+        settings: &ListenerSettings,
+        limits: &[Arc<RateLimiter>],
     ) -> Result<(), TcpProxyError> {
-        stream.set_nodelay(true)?;
-        let mut conn = BufferedConnection::new(stream, buffer_size);
+        sockopt::tune_inbound(&stream)?;
+        let mut conn = match &settings.tls_acceptor {
+            Some(acceptor) => {
+                match timeout(self.handshake_timeout, acceptor.accept(stream)).await {
+                    Ok(tls_stream) => BufferedConnection::new(tls_stream?, self.buffer_size),
+                    Err(_) => return Err(TcpProxyError::TlsHandshakeTimeout),
+                }
+            }
+            None => BufferedConnection::new(stream, self.buffer_size),
+        };
+        for limiter in limits {
+            conn.throttle(limiter.clone());
+        }
 
-        let bytes_read = conn.read().await?;
-        if bytes_read == 0 || !conn.has_data() {
-            return Err(TcpProxyError::NoDataReceived);
+        match timeout(self.handshake_timeout, conn.fill_to(1)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(TcpProxyError::NoDataReceived);
+            }
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(TcpProxyError::HandshakeTimeout),
         }
+        let first_byte = conn.peek(1).ok_or(TcpProxyError::NoDataReceived)?[0];
 
-        let first_byte = conn
-            .read_from_buffer(1)
-            .map(|b| b[0])
-            .ok_or(TcpProxyError::NoDataReceived)?;
-        conn.unread(&[first_byte]);
+        let protocol = match first_byte {
+            0x04 => Some(ListenerProtocol::Socks4),
+            0x05 => Some(ListenerProtocol::Socks5),
+            0x06 => Some(ListenerProtocol::Socks6),
+            b'A'..=b'Z' | b'a'..=b'z' => Some(ListenerProtocol::Http),
+            _ => None,
+        };
+        if let Some(protocol) = protocol
+            && !settings.protocols.contains(&protocol)
+        {
+            return Err(TcpProxyError::ProtocolNotAllowed(protocol));
+        }
+        let exempt = self.is_exempt(addr.ip());
+        let auth_manager = if exempt {
+            &self.open_auth
+        } else {
+            &settings.auth_manager
+        };
 
         match first_byte {
+            // SOCKS4 / SOCKS4a protocol starts with 0x04
+            0x04 => {
+                info!("SOCKS4 connection from {}", addr);
+                let socks4_proxy = Socks4Proxy::new(auth_manager.clone(), self.dialer.clone())
+                    .with_handshake_timeout(self.handshake_timeout);
+                socks4_proxy.handle_connection(&mut conn).await?;
+            }
             // SOCKS5 protocol starts with 0x05
             0x05 => {
                 info!("SOCKS5 connection from {}", addr);
-                let socks5_proxy = Socks5Proxy::new(auth_manager, connect_timeout);
+                let socks5_proxy = Socks5Proxy::new(
+                    auth_manager.clone(),
+                    self.connect_timeout,
+                    self.dialer.clone(),
+                    self.socks5_config.clone(),
+                    self.udp_config.clone(),
+                )
+                .with_ssrf_guard(self.ssrf_guard.clone());
                 socks5_proxy.handle_connection(&mut conn).await?;
             }
+            // SOCKS6 (draft) starts with 0x06
+            #[cfg(feature = "socks6")]
+            0x06 => {
+                info!("SOCKS6 connection from {}", addr);
+                let socks6_proxy = crate::proxy::socks6::Socks6Proxy::new(
+                    auth_manager.clone(),
+                    self.dialer.clone(),
+                )
+                .with_handshake_timeout(self.handshake_timeout);
+                socks6_proxy.handle_connection(&mut conn).await?;
+            }
             // HTTP methods start with ASCII letters
             b'A'..=b'Z' | b'a'..=b'z' => {
-                info!("HTTP connection from {}", addr);
-                let http_proxy = HttpProxy::new(auth_manager, buffer_size, connect_timeout);
-                http_proxy.handle_connection(&mut conn).await?;
+                // An HTTP/2 client opens with "PRI * HTTP/2.0"; anything that
+                // stops matching the preface is HTTP/1
+                let h2 = first_byte == b'P'
+                    && timeout(self.handshake_timeout, http2::is_preface(&mut conn))
+                        .await
+                        .unwrap_or(Ok(false))?;
+                info!(
+                    "HTTP{} connection from {}",
+                    if h2 { "/2" } else { "" },
+                    addr
+                );
+                let mut http_proxy = HttpProxy::new(
+                    auth_manager.clone(),
+                    self.buffer_size,
+                    self.dialer.clone(),
+                    self.http_config.clone(),
+                    self.http_cache.clone(),
+                    self.http_pool.clone(),
+                    self.access_log,
+                )
+                .with_handshake_timeout(self.handshake_timeout)
+                .with_listen_address(listen_address);
+                if let Some(adblock) = &self.adblock {
+                    http_proxy = http_proxy.with_adblock(adblock.clone());
+                }
+                if let Some(sniff) = &self.sniff {
+                    http_proxy = http_proxy.with_sniff(sniff.clone());
+                }
+                if let Some(jwt) = self.jwt.as_ref().filter(|_| !exempt) {
+                    http_proxy = http_proxy.with_jwt(jwt.clone());
+                }
+                #[cfg(feature = "mitm")]
+                if let Some(mitm) = &self.mitm {
+                    http_proxy = http_proxy.with_mitm(mitm.clone());
+                }
+                if h2 {
+                    Arc::new(http_proxy)
+                        .handle_h2_connection(&mut conn, self.handshake_timeout)
+                        .await?;
+                } else {
+                    http_proxy.handle_connection(&mut conn).await?;
+                }
             }
             other => {
                 return Err(TcpProxyError::UnsupportedProtocol(other));
@@ -142,4 +833,350 @@ impl TcpProxy {
 
         Ok(())
     }
+    async fn handle_shadowsocks(
+        &self,
+        stream: TcpStream,
+        addr: std::net::SocketAddr,
+        limits: &[Arc<RateLimiter>],
+    ) -> Result<(), TcpProxyError> {
+        sockopt::tune_inbound(&stream)?;
+        info!("Shadowsocks connection from {}", addr);
+        let keys = self
+            .shadowsocks_keys
+            .clone()
+            .expect("run_shadowsocks requires [shadowsocks]");
+        let shadowsocks_proxy = ShadowsocksProxy::new(
+            self.auth_manager.clone(),
+            self.handshake_timeout,
+            self.dialer.clone(),
+            keys,
+        );
+        shadowsocks_proxy
+            .handle_connection(stream, self.buffer_size, limits)
+            .await?;
+        Ok(())
+    }
+    async fn handle_transparent(
+        &self,
+        stream: TcpStream,
+        addr: std::net::SocketAddr,
+        limits: &[Arc<RateLimiter>],
+    ) -> Result<(), TcpProxyError> {
+        sockopt::tune_inbound(&stream)?;
+        info!("Transparent connection from {}", addr);
+        let transparent_proxy = self
+            .transparent
+            .as_ref()
+            .expect("run_transparent requires [transparent]");
+        transparent_proxy
+            .handle_connection(stream, self.buffer_size, limits)
+            .await?;
+        Ok(())
+    }
+    async fn handle_forward(
+        &self,
+        stream: TcpStream,
+        addr: std::net::SocketAddr,
+        target: &TargetAddr,
+        limits: &[Arc<RateLimiter>],
+    ) -> Result<(), TcpProxyError> {
+        sockopt::tune_inbound(&stream)?;
+        info!("Forwarding connection from {} to {}", addr, target);
+        let target_stream = self
+            .dialer
+            .dial_from(target, addr, stream.local_addr()?)
+            .await?;
+        let mut conn = BufferedConnection::new(stream, self.buffer_size);
+        for limiter in limits {
+            conn.throttle(limiter.clone());
+        }
+        let mut target_conn = BufferedConnection::new(target_stream, self.buffer_size);
+        forward::forward_bidirectional(&mut conn, &mut target_conn, None).await?;
+        Ok(())
+    }
+    async fn handle_gateway(
+        &self,
+        stream: TcpStream,
+        addr: std::net::SocketAddr,
+        listen_address: std::net::SocketAddr,
+        limits: &[Arc<RateLimiter>],
+    ) -> Result<(), TcpProxyError> {
+        sockopt::tune_inbound(&stream)?;
+        info!("Gateway connection from {}", addr);
+        let gateway = self
+            .gateway
+            .clone()
+            .expect("run_gateway requires [gateway]");
+        let http_proxy = HttpProxy::new(
+            self.auth_manager.clone(),
+            self.buffer_size,
+            self.dialer.clone(),
+            self.http_config.clone(),
+            self.http_cache.clone(),
+            self.http_pool.clone(),
+            self.access_log,
+        )
+        .with_gateway(gateway)
+        .with_handshake_timeout(self.handshake_timeout)
+        .with_listen_address(listen_address);
+        let mut conn = BufferedConnection::new(stream, self.buffer_size);
+        for limiter in limits {
+            conn.throttle(limiter.clone());
+        }
+        http_proxy.handle_connection(&mut conn).await?;
+        Ok(())
+    }
+    #[cfg(feature = "tun")]
+    async fn handle_tun(
+        &self,
+        stream: crate::net::tun::TunStream,
+        limits: &[Arc<RateLimiter>],
+    ) -> Result<(), TcpProxyError> {
+        use crate::net::stream::Stream;
+
+        let (source, target) = (stream.peer_addr()?, stream.local_addr()?);
+        info!("TUN connection from {} to {}", source, target);
+        let target_stream = self
+            .dialer
+            .dial_from(&TargetAddr::Ip(target), source, target)
+            .await?;
+        let mut conn = BufferedConnection::new(stream, self.buffer_size);
+        for limiter in limits {
+            conn.throttle(limiter.clone());
+        }
+        let mut target_conn = BufferedConnection::new(target_stream, self.buffer_size);
+        forward::forward_bidirectional(&mut conn, &mut target_conn, None).await?;
+        Ok(())
+    }
+    async fn handle_quic(
+        &self,
+        incoming: quinn::Incoming,
+        local_addr: std::net::SocketAddr,
+        listener_limiter: &Option<Arc<RateLimiter>>,
+    ) -> Result<(), TcpProxyError> {
+        let connection = match timeout(self.handshake_timeout, incoming).await {
+            Ok(connection) => connection?,
+            Err(_) => return Err(TcpProxyError::TlsHandshakeTimeout),
+        };
+        let addr = connection.remote_address();
+        info!("QUIC connection from {}", addr);
+        let auth_manager = if self.is_exempt(addr.ip()) {
+            self.open_auth.clone()
+        } else {
+            self.auth_manager.clone()
+        };
+
+        loop {
+            let streams = match connection.accept_bi().await {
+                Ok(streams) => streams,
+                // Closed by either side, or idle past the transport timeout
+                Err(quinn::ConnectionError::ApplicationClosed(_))
+                | Err(quinn::ConnectionError::LocallyClosed)
+                | Err(quinn::ConnectionError::TimedOut) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let stream = QuicStream::new(&connection, local_addr, streams);
+            let Some(permit) = self.accept_queue.try_acquire() else {
+                log::warn!("Max connections reached, rejecting a stream from {}", addr);
+                continue;
+            };
+            let proxy = self.clone();
+            let auth_manager = auth_manager.clone();
+            let limits = self.connection_limits(listener_limiter);
+            task::spawn(async move {
+                let mut conn = BufferedConnection::new(stream, proxy.buffer_size);
+                for limiter in limits {
+                    conn.throttle(limiter);
+                }
+                let socks5_proxy = Socks5Proxy::new(
+                    auth_manager,
+                    proxy.connect_timeout,
+                    proxy.dialer.clone(),
+                    proxy.socks5_config.clone(),
+                    proxy.udp_config.clone(),
+                )
+                .with_ssrf_guard(proxy.ssrf_guard.clone());
+                if let Err(e) = socks5_proxy.handle_connection(&mut conn).await {
+                    log::error!("QUIC stream error from {}: {}", addr, e);
+                }
+                drop(permit);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv6Addr, SocketAddr};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn parse(toml: &str) -> Config {
+        ::config::Config::builder()
+            .add_source(::config::File::from_str(toml, ::config::FileFormat::Toml))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap()
+    }
+
+    fn new_proxy(config: &Config) -> TcpProxy {
+        let auth_manager = Arc::new(AuthManager::new(&config.users).unwrap());
+        TcpProxy::new(auth_manager, config, None, None, None, HashMap::new(), None)
+    }
+
+    /// With alice as the only user, exempting clients from `exempt`.
+    fn exempt_config(exempt: &str) -> Config {
+        parse(&format!(
+            "[users]\nalice = \"secret\"\n\
+             [auth]\nexempt_cidrs = [\"{}\"]\n\
+             [ssrf]\nenabled = false\n\
+             [http]\nallowed_connect_ports = []\n\
+             [http.jwt]\nsecret = \"jwt-secret\"\n",
+            exempt
+        ))
+    }
+
+    async fn spawn_proxy(config: &Config, jwt: bool) -> SocketAddr {
+        let mut proxy = new_proxy(config);
+        if jwt {
+            let jwt = JwtAuth::new(config.http.jwt.as_ref().unwrap()).unwrap();
+            proxy = proxy.with_jwt(Arc::new(jwt));
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.run(listener).await });
+        addr
+    }
+
+    /// The method the proxy picks from NO AUTH and USERNAME/PASSWORD.
+    async fn socks5_method(proxy_addr: SocketAddr) -> u8 {
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        client.write_all(b"\x05\x02\x00\x02").await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        reply[1]
+    }
+
+    /// The status line the proxy answers a CONNECT to a listening target
+    /// with.
+    async fn http_connect_status(proxy_addr: SocketAddr) -> String {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        client
+            .write_all(
+                format!(
+                    "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+                    target_addr, target_addr
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut conn = BufferedConnection::new(client, 4096);
+        let line = conn.read_line().await.unwrap();
+        line.trim_end().to_string()
+    }
+
+    async fn spawn_echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_forward_relays_to_its_target() {
+        let echo_addr = spawn_echo().await;
+        // Named in the configuration, so dialed despite the SSRF guard
+        let config = parse(&format!(
+            "[[forward]]\nlisten_address = \"127.0.0.1:0\"\ntarget = \"{}\"\n",
+            echo_addr
+        ));
+        let target = TargetAddr::parse(&config.forward[0].target).unwrap();
+        let proxy = new_proxy(&config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let forward_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.run_forward(listener, target).await });
+
+        // No handshake: the first bytes already reach the target
+        let mut client = TcpStream::connect(forward_addr).await.unwrap();
+        client.write_all(b"\x05\x01\x00").await.unwrap();
+        let mut echoed = [0u8; 3];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"\x05\x01\x00");
+    }
+
+    #[tokio::test]
+    async fn test_listener_protocols_and_auth() {
+        let config = parse(
+            "[users]\nalice = \"secret\"\n\
+             [ssrf]\nenabled = false\n\
+             [http]\nallowed_connect_ports = []\n\
+             [[listeners]]\nlisten_address = \"127.0.0.1:0\"\n\
+             protocols = [\"http\"]\nauth = false\n",
+        );
+        let listener_config = config.listeners[0].clone();
+        let proxy = new_proxy(&config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { proxy.run_listener(listener, &listener_config, None).await });
+
+        // HTTP without credentials, though the main listener would want them
+        assert_eq!(
+            http_connect_status(addr).await,
+            "HTTP/1.1 200 Connection Established"
+        );
+
+        // SOCKS5 is not among the listener's protocols
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"\x05\x01\x00").await.unwrap();
+        let mut reply = [0u8; 2];
+        assert!(client.read_exact(&mut reply).await.is_err());
+    }
+
+    #[test]
+    fn test_is_exempt() {
+        let config = exempt_config("127.0.0.0/8");
+        let proxy = new_proxy(&config);
+
+        assert!(proxy.is_exempt("127.0.0.1".parse().unwrap()));
+        assert!(!proxy.is_exempt("10.0.0.1".parse().unwrap()));
+        // An IPv4-mapped source is judged by its IPv4 address
+        let mapped = Ipv6Addr::from(0xffff_7f00_0001u128);
+        assert!(proxy.is_exempt(IpAddr::V6(mapped)));
+        assert!(!proxy.is_exempt(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_method_for_exempt_clients() {
+        let exempt = spawn_proxy(&exempt_config("127.0.0.0/8"), false).await;
+        assert_eq!(socks5_method(exempt).await, 0x00);
+
+        let other = spawn_proxy(&exempt_config("10.0.0.0/8"), false).await;
+        assert_eq!(socks5_method(other).await, 0x02);
+    }
+
+    #[tokio::test]
+    async fn test_http_exempt_clients_get_no_407() {
+        for jwt in [false, true] {
+            let exempt = spawn_proxy(&exempt_config("127.0.0.0/8"), jwt).await;
+            assert_eq!(
+                http_connect_status(exempt).await,
+                "HTTP/1.1 200 Connection Established"
+            );
+
+            let other = spawn_proxy(&exempt_config("10.0.0.0/8"), jwt).await;
+            assert!(
+                http_connect_status(other)
+                    .await
+                    .starts_with("HTTP/1.1 407 ")
+            );
+        }
+    }
 }
@@ -0,0 +1,170 @@
+//! Inbound transparent proxying: clients are unaware of the proxy, and an
+//! iptables `REDIRECT` or `TPROXY` rule steers their connections to the
+//! `[transparent]` listener. The target is the address each connection was
+//! originally sent to; there is no handshake and no authentication.
+
+use log::info;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::TcpStream;
+
+use crate::common::config::{SniffConfig, TransparentMode};
+use crate::net::addr::TargetAddr;
+use crate::net::conn::BufferedConnection;
+use crate::net::rate_limit::RateLimiter;
+use crate::net::{sni, transparent};
+use crate::proxy::dialer::{self, Dialer};
+use crate::proxy::fake_ip::FakeIpPool;
+use crate::proxy::forward::{self, ConnectError};
+
+#[derive(Error, Debug)]
+pub enum TransparentProxyError {
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Connection was not redirected to the transparent listener")]
+    NotRedirected,
+    #[error("Connection error: {0}")]
+    ConnectError(#[from] ConnectError),
+}
+
+#[derive(Clone)]
+pub struct TransparentProxy {
+    dialer: Arc<dyn Dialer>,
+    mode: TransparentMode,
+    /// Where the listener is bound, which no redirected target can be
+    listen_addr: SocketAddr,
+    sniff: Option<Arc<SniffConfig>>,
+}
+
+impl TransparentProxy {
+    pub fn new(dialer: Arc<dyn Dialer>, mode: TransparentMode, listen_addr: SocketAddr) -> Self {
+        TransparentProxy {
+            dialer,
+            mode,
+            listen_addr,
+            sniff: None,
+        }
+    }
+
+    /// Reads the server name from TLS connections to the sniffed ports, so
+    /// they are matched by domain rather than by the bare address.
+    pub fn with_sniff(mut self, sniff: Arc<SniffConfig>) -> Self {
+        self.sniff = Some(sniff);
+        self
+    }
+
+    /// Dials connections to the fake addresses of the `[dns]` forwarder by
+    /// the domain each stands for.
+    pub fn with_fake_ip(mut self, pool: Arc<FakeIpPool>) -> Self {
+        self.dialer = dialer::with_fake_ip(self.dialer, pool);
+        self
+    }
+
+    pub async fn handle_connection(
+        &self,
+        stream: TcpStream,
+        buffer_size: usize,
+        limits: &[Arc<RateLimiter>],
+    ) -> Result<(), TransparentProxyError> {
+        let peer_addr = stream.peer_addr()?;
+        // TPROXY leaves the destination address on the socket
+        let original_dst = match self.mode {
+            TransparentMode::Redirect => transparent::original_dst(&stream),
+            TransparentMode::Tproxy => stream.local_addr(),
+        };
+        let target = match original_dst {
+            // A client that connected to the listener directly would
+            // otherwise have the proxy connect to itself
+            Ok(target) if !self.is_listener(target) => target,
+            Ok(_) => return Err(TransparentProxyError::NotRedirected),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(TransparentProxyError::NotRedirected);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        info!("Transparent request for {} from {}", target, peer_addr);
+
+        let mut conn = BufferedConnection::new(stream, buffer_size);
+        for limiter in limits {
+            conn.throttle(limiter.clone());
+        }
+        let server_name = match &self.sniff {
+            Some(sniff)
+                if sniff
+                    .ports
+                    .iter()
+                    .any(|range| range.contains(target.port())) =>
+            {
+                sni::sniff(&mut conn, Duration::from_millis(sniff.timeout)).await
+            }
+            _ => None,
+        };
+        let target_stream = match server_name {
+            // Rules and the blocklist see the name; a direct connection
+            // still goes to the address the client chose
+            Some(name) => {
+                info!("Sniffed server name {} for {}", name, target);
+                let named = TargetAddr::Domain(name, target.port());
+                self.dialer
+                    .dial_resolved(&named, &[target], Some((peer_addr, target)))
+                    .await?
+            }
+            None => {
+                self.dialer
+                    .dial_from(&TargetAddr::Ip(target), peer_addr, target)
+                    .await?
+            }
+        };
+        info!("Connected to target: {}", target);
+
+        let mut target_conn = BufferedConnection::new(target_stream, buffer_size);
+        forward::forward_bidirectional(&mut conn, &mut target_conn, None).await?;
+        Ok(())
+    }
+
+    fn is_listener(&self, addr: SocketAddr) -> bool {
+        addr.port() == self.listen_addr.port()
+            && (self.listen_addr.ip().is_unspecified() || addr.ip() == self.listen_addr.ip())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::dialer::DirectDialer;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_direct_connection_refused() {
+        for mode in [TransparentMode::Redirect, TransparentMode::Tproxy] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+
+            let dialer = Arc::new(DirectDialer::new(Duration::from_secs(5)));
+            let proxy = TransparentProxy::new(dialer, mode, addr);
+            assert!(matches!(
+                proxy.handle_connection(stream, 4096, &[]).await,
+                Err(TransparentProxyError::NotRedirected)
+            ));
+            drop(client);
+        }
+    }
+
+    #[test]
+    fn test_is_listener() {
+        let dialer = Arc::new(DirectDialer::new(Duration::from_secs(5)));
+        let proxy = TransparentProxy::new(
+            dialer,
+            TransparentMode::Tproxy,
+            "0.0.0.0:12345".parse().unwrap(),
+        );
+        assert!(proxy.is_listener("10.0.0.1:12345".parse().unwrap()));
+        assert!(!proxy.is_listener("10.0.0.1:80".parse().unwrap()));
+    }
+}
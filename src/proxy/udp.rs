@@ -0,0 +1,254 @@
+use log::{debug, info};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::common::config::UdpConfig;
+use crate::net::addr::TargetAddr;
+use crate::net::sockopt;
+use crate::proxy::dns;
+use crate::proxy::forward::{self, Transfer};
+use crate::proxy::socks5::codec;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// DNS fast-path lookups one association may have running; queries past it
+/// are dropped, for the client to retry
+const MAX_DNS_IN_FLIGHT: usize = 64;
+
+/// Binds the relay socket on `ip`, honouring `udp.port_range` when set,
+/// and marks it like other outbound sockets.
+pub async fn bind_relay(ip: IpAddr, config: &UdpConfig) -> io::Result<UdpSocket> {
+    let Some(range) = config.port_range else {
+        let socket = UdpSocket::bind((ip, 0)).await?;
+        sockopt::mark(&socket)?;
+        return Ok(socket);
+    };
+
+    for port in range.start..=range.end {
+        if let Ok(socket) = UdpSocket::bind((ip, port)).await {
+            sockopt::mark(&socket)?;
+            return Ok(socket);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("No free UDP port in {}-{}", range.start, range.end),
+    ))
+}
+
+/// Address reported in the UDP ASSOCIATE reply: the configured external
+/// address, if any, with the port actually bound.
+pub fn advertised_addr(bound_addr: SocketAddr, config: &UdpConfig) -> SocketAddr {
+    match config.external_address {
+        Some(ip) => SocketAddr::new(ip, bound_addr.port()),
+        None => bound_addr,
+    }
+}
+
+/// Relays datagrams between the SOCKS client and remote hosts until an IO
+/// error occurs or no datagram arrives for `idle_timeout`; the caller can
+/// also end the association by dropping this future.
+///
+/// The first datagram from `client_ip` (and `client_port`, when the client
+/// announced one) pins the client address; everything else is treated as a
+/// reply from a remote host. Datagrams whose target, once resolved, is
+/// rejected by `allows` are dropped; payload bytes are added to `transfer`
+/// as they are relayed.
+///
+/// With `dns_fast_path`, A/AAAA queries sent to port 53 are answered from
+/// the proxy's resolver instead of being forwarded, up to
+/// `MAX_DNS_IN_FLIGHT` at a time.
+pub async fn relay(
+    socket: UdpSocket,
+    client_ip: IpAddr,
+    client_port: Option<u16>,
+    dns_fast_path: bool,
+    idle_timeout: Option<Duration>,
+    transfer: &mut Transfer,
+    allows: impl Fn(&TargetAddr, SocketAddr) -> bool,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut client_addr: Option<SocketAddr> = None;
+    // Lookups run concurrently so a slow name does not stall the relay
+    let mut dns_answers: JoinSet<(Vec<u8>, SocketAddr)> = JoinSet::new();
+
+    loop {
+        let received = tokio::select! {
+            received = recv_with_idle_timeout(&socket, &mut buf, idle_timeout) => received?,
+            Some(answered) = dns_answers.join_next(), if !dns_answers.is_empty() => {
+                if let (Ok((response, server)), Some(client)) = (answered, client_addr) {
+                    let mut packet = codec::encode_udp_header(server);
+                    packet.extend_from_slice(&response);
+                    socket.send_to(&packet, client).await?;
+                    transfer.down += response.len() as u64;
+                }
+                continue;
+            }
+        };
+        let Some((n, from)) = received else {
+            info!(
+                "UDP association for {} idle for {}s, closing",
+                client_ip,
+                idle_timeout.unwrap_or_default().as_secs()
+            );
+            return Ok(());
+        };
+
+        let is_client = match client_addr {
+            Some(addr) => from == addr,
+            None => from.ip() == client_ip && client_port.is_none_or(|port| port == from.port()),
+        };
+
+        if is_client {
+            client_addr = Some(from);
+            let (target, header_len) = match codec::decode_udp_header(&buf[..n]) {
+                Ok(Some(((0, target), header_len))) => (target, header_len),
+                // Fragmentation is optional (RFC 1928 §7) and not supported
+                _ => {
+                    debug!(
+                        "Dropping malformed or fragmented UDP datagram from {}",
+                        from
+                    );
+                    continue;
+                }
+            };
+            let target_addr = match target {
+                TargetAddr::Ip(addr) => addr,
+                TargetAddr::Domain(..) => match forward::resolve_address(&target.to_string()).await
+                {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        debug!("Dropping UDP datagram to {}: {}", target, e);
+                        continue;
+                    }
+                },
+            };
+            if !allows(&target, target_addr) {
+                debug!("Dropping UDP datagram to disallowed target {}", target);
+                continue;
+            }
+            let payload = &buf[header_len..n];
+            if dns_fast_path
+                && target_addr.port() == 53
+                && let Some(query) = dns::parse_query(payload)
+            {
+                if dns_answers.len() >= MAX_DNS_IN_FLIGHT {
+                    debug!(
+                        "Dropping DNS query from {}: {} lookups in flight",
+                        from, MAX_DNS_IN_FLIGHT
+                    );
+                    continue;
+                }
+                transfer.up += payload.len() as u64;
+                let packet = payload.to_vec();
+                dns_answers.spawn(async move { (dns::answer(&packet, &query).await, target_addr) });
+                continue;
+            }
+            transfer.up += payload.len() as u64;
+            socket.send_to(payload, target_addr).await?;
+        } else if let Some(client) = client_addr {
+            let mut packet = codec::encode_udp_header(from);
+            packet.extend_from_slice(&buf[..n]);
+            socket.send_to(&packet, client).await?;
+            transfer.down += n as u64;
+        }
+    }
+}
+
+/// `Ok(None)` once nothing has arrived for `idle_timeout`.
+async fn recv_with_idle_timeout(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    idle_timeout: Option<Duration>,
+) -> io::Result<Option<(usize, SocketAddr)>> {
+    match idle_timeout {
+        Some(idle_timeout) => match timeout(idle_timeout, socket.recv_from(buf)).await {
+            Ok(received) => received.map(Some),
+            Err(_) => Ok(None),
+        },
+        None => socket.recv_from(buf).await.map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::config::PortRange;
+
+    #[tokio::test]
+    async fn test_bind_relay_port_range() {
+        let probe = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = probe.local_addr().unwrap().port();
+        drop(probe);
+
+        let config = UdpConfig {
+            external_address: Some("203.0.113.7".parse().unwrap()),
+            port_range: Some(PortRange {
+                start: port,
+                end: port,
+            }),
+            ..UdpConfig::default()
+        };
+        let socket = bind_relay("127.0.0.1".parse().unwrap(), &config)
+            .await
+            .unwrap();
+        let bound_addr = socket.local_addr().unwrap();
+        assert_eq!(bound_addr.port(), port);
+        assert_eq!(
+            advertised_addr(bound_addr, &config),
+            SocketAddr::new("203.0.113.7".parse().unwrap(), port)
+        );
+
+        // The only port in range is taken now
+        assert!(
+            bind_relay("127.0.0.1".parse().unwrap(), &config)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_relay_drops_fragments() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = socket.local_addr().unwrap();
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let datagram = |frag: u8, payload: &[u8]| {
+            let mut packet = codec::encode_udp_header(target.local_addr().unwrap());
+            packet[2] = frag;
+            packet.extend_from_slice(payload);
+            packet
+        };
+        client
+            .send_to(&datagram(1, b"fragment"), relay_addr)
+            .await
+            .unwrap();
+        client
+            .send_to(&datagram(0, b"whole"), relay_addr)
+            .await
+            .unwrap();
+
+        let mut transfer = Transfer::default();
+        let mut buf = [0u8; 64];
+        let (relayed, received) = tokio::join!(
+            relay(
+                socket,
+                "127.0.0.1".parse().unwrap(),
+                None,
+                false,
+                Some(Duration::from_millis(200)),
+                &mut transfer,
+                |_, _| true,
+            ),
+            target.recv_from(&mut buf),
+        );
+        relayed.unwrap();
+        let (n, _) = received.unwrap();
+        assert_eq!(&buf[..n], b"whole");
+        assert_eq!(transfer.up, 5);
+    }
+}
@@ -0,0 +1,576 @@
+//! `[upstream_groups]`: a `Balancer` spreads the connections of the rules
+//! naming a group over its member parents, by the group's strategy, and
+//! keeps the statistics the strategies go by for each member. A member
+//! that fails to connect is passed over for a while, its connections going
+//! to the others.
+//!
+//! A `url-test` group instead fetches a test URL through every member on an
+//! interval and sticks to the fastest, switching only when another beats it
+//! by more than the tolerance, so that close timings do not flap between
+//! members.
+
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use url::Url;
+
+use crate::common::config::{BalanceStrategy, UpstreamGroupConfig};
+use crate::net::addr::TargetAddr;
+use crate::net::stream::Stream;
+use crate::net::tls::TlsError;
+use crate::proxy::dialer::Dialer;
+use crate::proxy::forward::ConnectError;
+use crate::proxy::http::client::{ClientError, HttpClient};
+
+/// How long a member that failed to connect is passed over
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+/// Time a member has to answer the test URL before it fails the test
+const URL_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a member has done so far.
+#[derive(Default)]
+struct MemberStats {
+    /// Streams dialed through the member and still open
+    active: AtomicUsize,
+    /// Moving average of the time taken to connect, in microseconds; 0
+    /// until the first connection
+    latency_micros: AtomicU64,
+    /// When the member last failed to connect, if it has not connected
+    /// since
+    failed_at: Mutex<Option<Instant>>,
+    /// Time the last URL test took through the member, in microseconds;
+    /// 0 when it failed or has not run
+    rtt_micros: AtomicU64,
+}
+
+impl MemberStats {
+    fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        match self.latency_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        match self.rtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.failed_at
+            .lock()
+            .unwrap()
+            .is_none_or(|failed_at| failed_at.elapsed() >= RETRY_AFTER)
+    }
+
+    fn record_success(&self, elapsed: Duration) {
+        let sample = (elapsed.as_micros() as u64).max(1);
+        // Weighs the new sample a quarter, so one slow connect does not
+        // undo the member's record
+        let _ = self
+            .latency_micros
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                Some(match average {
+                    0 => sample,
+                    average => (average * 3 + sample) / 4,
+                })
+            });
+        *self.failed_at.lock().unwrap() = None;
+    }
+
+    fn record_failure(&self) {
+        *self.failed_at.lock().unwrap() = Some(Instant::now());
+    }
+}
+
+struct Member {
+    name: String,
+    dialer: Arc<dyn Dialer>,
+    stats: Arc<MemberStats>,
+}
+
+pub struct Balancer {
+    name: String,
+    strategy: BalanceStrategy,
+    members: Vec<Member>,
+    /// Round-robin position, also where ties are broken from
+    next: AtomicUsize,
+    rng: SystemRandom,
+    url_test: Option<UrlTest>,
+    /// Member a `url-test` group sends connections to
+    selected: AtomicUsize,
+}
+
+/// Settings of a `url-test` group.
+struct UrlTest {
+    url: Url,
+    interval: Duration,
+    tolerance: Duration,
+}
+
+impl Balancer {
+    /// `dialers` holds every `[upstreams]` entry; validation ensures the
+    /// group's members are among them.
+    pub fn new(
+        name: &str,
+        config: &UpstreamGroupConfig,
+        dialers: &HashMap<String, Arc<dyn Dialer>>,
+    ) -> Self {
+        let members = config
+            .members
+            .iter()
+            .map(|member| Member {
+                name: member.clone(),
+                dialer: dialers[member].clone(),
+                stats: Arc::new(MemberStats::default()),
+            })
+            .collect();
+        Balancer {
+            name: name.to_string(),
+            strategy: config.strategy,
+            members,
+            next: AtomicUsize::new(0),
+            rng: SystemRandom::new(),
+            url_test: (config.strategy == BalanceStrategy::UrlTest).then(|| UrlTest {
+                // Validated along with the rest of the configuration
+                url: Url::parse(&config.url).unwrap(),
+                interval: Duration::from_secs(config.interval),
+                tolerance: Duration::from_millis(config.tolerance),
+            }),
+            selected: AtomicUsize::new(0),
+        }
+    }
+
+    /// Times every member of a `url-test` group, a round at a time with
+    /// the interval between, until Ctrl-C / SIGINT is received. Returns at
+    /// once for other groups.
+    pub async fn run_url_test(&self) {
+        let Some(url_test) = &self.url_test else {
+            return;
+        };
+        let probe = match Probe::new(&url_test.url) {
+            Ok(probe) => probe,
+            Err(e) => {
+                error!("Group {}: cannot test {}: {}", self.name, url_test.url, e);
+                return;
+            }
+        };
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                _ = self.test_round(&probe, url_test.tolerance) => {}
+                _ = &mut shutdown => break,
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(url_test.interval) => {}
+                _ = &mut shutdown => break,
+            }
+        }
+    }
+
+    /// Times each member, then moves to the fastest unless the member in
+    /// use passed and is within `tolerance` of it.
+    async fn test_round(&self, probe: &Probe, tolerance: Duration) {
+        let mut fastest: Option<(usize, Duration)> = None;
+        for (i, member) in self.members.iter().enumerate() {
+            match probe.measure(member.dialer.as_ref()).await {
+                Ok(rtt) => {
+                    debug!("Group {}: {} took {:?}", self.name, member.name, rtt);
+                    let micros = (rtt.as_micros() as u64).max(1);
+                    member.stats.rtt_micros.store(micros, Ordering::Relaxed);
+                    if fastest.is_none_or(|(_, best)| rtt < best) {
+                        fastest = Some((i, rtt));
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Group {}: {} failed the URL test: {}",
+                        self.name, member.name, e
+                    );
+                    member.stats.rtt_micros.store(0, Ordering::Relaxed);
+                }
+            }
+        }
+        let Some((best, best_rtt)) = fastest else {
+            warn!("Group {}: no member passed the URL test", self.name);
+            return;
+        };
+        let current = self.selected.load(Ordering::Relaxed);
+        let keep = self.members[current]
+            .stats
+            .rtt()
+            .is_some_and(|rtt| rtt <= best_rtt + tolerance);
+        if !keep {
+            info!(
+                "Group {}: switching from {} to {} ({:?})",
+                self.name, self.members[current].name, self.members[best].name, best_rtt
+            );
+            self.selected.store(best, Ordering::Relaxed);
+        }
+    }
+
+    /// Members in the order they are tried: the one the strategy picks
+    /// among those available, then the others.
+    fn order(&self) -> Vec<usize> {
+        let count = self.members.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        let rotated: Vec<usize> = (0..count).map(|i| (start + i) % count).collect();
+        let available: Vec<usize> = rotated
+            .iter()
+            .copied()
+            .filter(|&i| self.members[i].stats.is_available())
+            .collect();
+        // With every member failing, each is as good a guess as another
+        let candidates = if available.is_empty() {
+            &rotated
+        } else {
+            &available
+        };
+        let first = match self.strategy {
+            BalanceStrategy::RoundRobin => candidates[0],
+            BalanceStrategy::LeastConnections => *candidates
+                .iter()
+                .min_by_key(|&&i| self.members[i].stats.active())
+                .unwrap(),
+            BalanceStrategy::LatencyWeighted => self.weighted_pick(candidates),
+            BalanceStrategy::UrlTest => {
+                let selected = self.selected.load(Ordering::Relaxed);
+                match candidates.contains(&selected) {
+                    true => selected,
+                    false => candidates[0],
+                }
+            }
+        };
+        let mut order = vec![first];
+        order.extend(rotated.into_iter().filter(|&i| i != first));
+        order
+    }
+
+    /// A member of `candidates` at random, with odds inverse to its
+    /// latency. Members not measured yet are given the best odds, so they
+    /// get measured.
+    fn weighted_pick(&self, candidates: &[usize]) -> usize {
+        let latencies: Vec<Option<Duration>> = candidates
+            .iter()
+            .map(|&i| self.members[i].stats.latency())
+            .collect();
+        let fastest = latencies.iter().flatten().min().copied();
+        let weights: Vec<f64> = latencies
+            .iter()
+            .map(|latency| match latency.or(fastest) {
+                Some(latency) => 1.0 / latency.as_secs_f64().max(1e-6),
+                None => 1.0,
+            })
+            .collect();
+        let mut bytes = [0u8; 8];
+        if self.rng.fill(&mut bytes).is_err() {
+            return candidates[0];
+        }
+        let mut point =
+            (u64::from_be_bytes(bytes) as f64 / u64::MAX as f64) * weights.iter().sum::<f64>();
+        for (&i, weight) in candidates.iter().zip(&weights) {
+            if point < *weight {
+                return i;
+            }
+            point -= weight;
+        }
+        candidates[candidates.len() - 1]
+    }
+
+    async fn dial_member(
+        &self,
+        target: &TargetAddr,
+        client: Option<(SocketAddr, SocketAddr)>,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        let mut last_error = ConnectError::AddressNotFound;
+        for i in self.order() {
+            let member = &self.members[i];
+            debug!("Group {} dials {} via {}", self.name, target, member.name);
+            let start = Instant::now();
+            let result = match client {
+                Some((source, destination)) => {
+                    member.dialer.dial_from(target, source, destination).await
+                }
+                None => member.dialer.dial(target).await,
+            };
+            match result {
+                Ok(stream) => {
+                    member.stats.record_success(start.elapsed());
+                    member.stats.active.fetch_add(1, Ordering::Relaxed);
+                    return Ok(Box::new(CountedStream {
+                        inner: stream,
+                        stats: member.stats.clone(),
+                    }));
+                }
+                Err(e) if is_member_failure(&e) => {
+                    warn!(
+                        "Group {}: {} failed to connect to {}: {}",
+                        self.name, member.name, target, e
+                    );
+                    member.stats.record_failure();
+                    last_error = e;
+                }
+                // The parent answered; another would not fare better
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Whether `error` tells against the member rather than the target.
+fn is_member_failure(error: &ConnectError) -> bool {
+    matches!(
+        error,
+        ConnectError::IoError(_)
+            | ConnectError::AddressResolutionFailed(_)
+            | ConnectError::ConnectionTimeout
+            | ConnectError::ConnectionRefused(_)
+            | ConnectError::AddressNotFound
+            | ConnectError::UpstreamAuthFailed
+            | ConnectError::UpstreamCredentialsTooLong
+            | ConnectError::UpstreamProtocol(_)
+    )
+}
+
+#[async_trait]
+impl Dialer for Balancer {
+    async fn dial(&self, target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+        self.dial_member(target, None).await
+    }
+
+    async fn dial_from(
+        &self,
+        target: &TargetAddr,
+        source: SocketAddr,
+        destination: SocketAddr,
+    ) -> Result<Box<dyn Stream>, ConnectError> {
+        self.dial_member(target, Some((source, destination))).await
+    }
+
+    fn is_per_client(&self, target: &TargetAddr) -> bool {
+        self.members
+            .iter()
+            .any(|member| member.dialer.is_per_client(target))
+    }
+}
+
+/// A request for the test URL of a `url-test` group.
+struct Probe {
+    url: Url,
+    client: HttpClient,
+}
+
+impl Probe {
+    fn new(url: &Url) -> Result<Self, TlsError> {
+        let mut client = HttpClient::new(URL_TEST_TIMEOUT);
+        if url.scheme() == "https" {
+            client = client.with_tls(None)?;
+        }
+        Ok(Probe {
+            url: url.clone(),
+            client,
+        })
+    }
+
+    /// Time from dialing through `dialer` to the end of the response.
+    async fn measure(&self, dialer: &dyn Dialer) -> Result<Duration, ClientError> {
+        let start = Instant::now();
+        self.client
+            .send(dialer, "GET", &self.url, &[], None)
+            .await?;
+        Ok(start.elapsed())
+    }
+}
+
+/// A stream through a member, counted among its open ones until dropped.
+struct CountedStream {
+    inner: Box<dyn Stream>,
+    stats: Arc<MemberStats>,
+}
+
+impl Drop for CountedStream {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for CountedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Stream for CountedStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Answers after `delay` with an in-memory stream whose far end sends
+    /// an HTTP response, or refuses when `up` is false.
+    struct TestDialer {
+        up: bool,
+        delay: Duration,
+        dials: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Dialer for TestDialer {
+        async fn dial(&self, _target: &TargetAddr) -> Result<Box<dyn Stream>, ConnectError> {
+            self.dials.fetch_add(1, Ordering::Relaxed);
+            if !self.up {
+                return Err(ConnectError::ConnectionRefused("down".to_string()));
+            }
+            tokio::time::sleep(self.delay).await;
+            let (client, mut server) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                let _ = server.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+                let _ = server.read_to_end(&mut Vec::new()).await;
+            });
+            Ok(Box::new(client))
+        }
+    }
+
+    impl Balancer {
+        fn stats(&self, name: &str) -> Option<&MemberStats> {
+            self.members
+                .iter()
+                .find(|member| member.name == name)
+                .map(|member| member.stats.as_ref())
+        }
+    }
+
+    /// A group of members that are up or not, each taking its delay in
+    /// milliseconds to connect.
+    fn balancer(
+        strategy: BalanceStrategy,
+        members: &[(bool, u64)],
+    ) -> (Balancer, Vec<Arc<TestDialer>>) {
+        let dialers: Vec<Arc<TestDialer>> = members
+            .iter()
+            .map(|&(up, delay)| {
+                Arc::new(TestDialer {
+                    up,
+                    delay: Duration::from_millis(delay),
+                    dials: AtomicUsize::new(0),
+                })
+            })
+            .collect();
+        let names: Vec<String> = (0..members.len()).map(|i| format!("p{}", i)).collect();
+        let by_name: HashMap<String, Arc<dyn Dialer>> = names
+            .iter()
+            .cloned()
+            .zip(dialers.iter().map(|d| d.clone() as Arc<dyn Dialer>))
+            .collect();
+        let config = UpstreamGroupConfig {
+            members: names,
+            strategy,
+            url: "http://192.0.2.1/".to_string(),
+            interval: 300,
+            tolerance: 50,
+        };
+        (Balancer::new("group", &config, &by_name), dialers)
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_and_failover() {
+        let target = TargetAddr::parse("example.com:443").unwrap();
+        let (group, dialers) = balancer(BalanceStrategy::RoundRobin, &[(true, 0), (true, 0)]);
+        for _ in 0..4 {
+            group.dial(&target).await.unwrap();
+        }
+        assert_eq!(dialers[0].dials.load(Ordering::Relaxed), 2);
+        assert_eq!(dialers[1].dials.load(Ordering::Relaxed), 2);
+
+        // The failed member is passed over once it has failed
+        let (group, dialers) = balancer(BalanceStrategy::RoundRobin, &[(false, 0), (true, 0)]);
+        for _ in 0..4 {
+            group.dial(&target).await.unwrap();
+        }
+        assert_eq!(dialers[0].dials.load(Ordering::Relaxed), 1);
+        assert_eq!(dialers[1].dials.load(Ordering::Relaxed), 4);
+        assert!(group.stats("p1").unwrap().latency().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_least_connections() {
+        let target = TargetAddr::parse("example.com:443").unwrap();
+        let (group, _) = balancer(BalanceStrategy::LeastConnections, &[(true, 0), (true, 0)]);
+        let first = group.dial(&target).await.unwrap();
+        let _second = group.dial(&target).await.unwrap();
+        assert_eq!(group.stats("p0").unwrap().active(), 1);
+        assert_eq!(group.stats("p1").unwrap().active(), 1);
+        drop(first);
+        assert_eq!(group.stats("p0").unwrap().active(), 0);
+        // p0 has fewer open, whichever member is next in turn
+        let _third = group.dial(&target).await.unwrap();
+        let _fourth = group.dial(&target).await.unwrap();
+        assert_eq!(group.stats("p0").unwrap().active(), 1);
+        assert_eq!(group.stats("p1").unwrap().active(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_url_test_hysteresis() {
+        let target = TargetAddr::parse("example.com:443").unwrap();
+        let (group, dialers) = balancer(BalanceStrategy::UrlTest, &[(true, 100), (true, 0)]);
+        let probe = Probe::new(&Url::parse("http://example.com/generate_204").unwrap()).unwrap();
+
+        // p0 is slower, but not by more than the tolerance
+        group.test_round(&probe, Duration::from_secs(1)).await;
+        assert!(group.stats("p0").unwrap().rtt().is_some());
+        group.dial(&target).await.unwrap();
+        assert_eq!(dialers[0].dials.load(Ordering::Relaxed), 2);
+
+        group.test_round(&probe, Duration::from_millis(10)).await;
+        group.dial(&target).await.unwrap();
+        assert_eq!(dialers[1].dials.load(Ordering::Relaxed), 3);
+    }
+}